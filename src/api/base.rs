@@ -22,16 +22,10 @@ impl IntoResponse for GetBaseResponse {
     fn into_response(self) -> Response {
         match self {
             GetBaseResponse::Ok => (StatusCode::OK, Json(json!({}))).into_response(),
-            GetBaseResponse::Unauthorized => (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({
-                    "errors":
-                        vec![RegistryErrorDetails::from(
-                            errors::RegistryError::Unauthorized,
-                        )]
-                })),
-            )
-                .into_response(),
+            GetBaseResponse::Unauthorized => crate::auth::unauthorized(&crate::auth::challenge()),
+            GetBaseResponse::TooManyRequests => {
+                errors::RegistryError::TooManyRequests.into_response()
+            }
             _ => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({
@@ -2,19 +2,46 @@ use axum::body::Full;
 use axum::extract::{Path, Query};
 use axum::http::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, RANGE};
 use axum::http::{HeaderName, StatusCode};
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use bytes::{BufMut, Bytes, BytesMut};
 
-use crate::db;
-
-pub async fn get_blob(Path((_name, digest)): Path<(String, String)>) -> impl IntoResponse {
-    let blob = db::blobs::get(&digest).await;
-    if blob.is_err() {
-        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+use super::RegistryError;
+use crate::events::{self, Action, Event, RequestInfo, Target, BLOB_MEDIA_TYPE};
+use crate::{db, metrics, proxy, quota};
+
+/// Describe a blob as the target of an event
+fn target(request: &RequestInfo, name: &str, digest: &str, size: usize) -> Target {
+    Target {
+        media_type: Some(BLOB_MEDIA_TYPE.to_string()),
+        size: Some(size as i64),
+        length: Some(size as i64),
+        digest: Some(digest.to_string()),
+        repository: name.to_string(),
+        url: Some(request.url(name, "blobs", digest)),
+        tag: None,
     }
-    let blob = blob.unwrap();
+}
+
+pub async fn get_blob(
+    Path((name, digest)): Path<(String, String)>,
+    request: RequestInfo,
+) -> impl IntoResponse {
+    let blob = match db::blobs::get(&digest).await {
+        Ok(blob) => blob,
+        Err(_) => match proxy::fetch_blob(&name, &digest).await {
+            Some(blob) => blob,
+            None => return (StatusCode::NOT_FOUND, "Not Found").into_response(),
+        },
+    };
 
     tracing::info!("serving blob with digest {} (size: {})", digest, blob.len());
+    metrics::downloaded(blob.len());
+    events::emit(Event::new(
+        Action::Pull,
+        target(&request, &name, &digest, blob.len()),
+        &request,
+    ))
+    .await;
 
     (
         StatusCode::OK,
@@ -28,12 +55,14 @@ pub async fn get_blob(Path((_name, digest)): Path<(String, String)>) -> impl Int
         .into_response()
 }
 
-pub async fn head_blob(Path((_name, digest)): Path<(String, String)>) -> impl IntoResponse {
-    let blob = db::blobs::length(&digest).await;
-    if blob.is_err() {
-        return (StatusCode::NOT_FOUND, "Not Found").into_response();
-    }
-    let size = blob.unwrap();
+pub async fn head_blob(Path((name, digest)): Path<(String, String)>) -> impl IntoResponse {
+    let size = match db::blobs::length(&digest).await {
+        Ok(size) => size,
+        Err(_) => match proxy::fetch_blob(&name, &digest).await {
+            Some(blob) => blob.len() as _,
+            None => return (StatusCode::NOT_FOUND, "Not Found").into_response(),
+        },
+    };
 
     tracing::info!(
         "giving size of blob with digest {} (size: {})",
@@ -52,7 +81,39 @@ pub async fn head_blob(Path((_name, digest)): Path<(String, String)>) -> impl In
         .into_response()
 }
 
+/// Refuse more of an upload before it is saved when the repository's byte quota can't hold it, along with what
+/// was uploaded before. The body counts with its `Content-Length` when that says more than was received.
+async fn check_upload(
+    name: &str,
+    uuid: &str,
+    headers: &HeaderMap,
+    body: &Bytes,
+) -> Option<Response> {
+    let declared = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok())
+        .unwrap_or_default();
+    let uploaded = db::blobs::length(uuid).await.unwrap_or_default() as u64;
+    let incoming = uploaded + declared.max(body.len() as u64);
+
+    let exceeded = quota::check_adding(name, &[], 0, incoming).await.unwrap()?;
+    tracing::warn!("refusing upload {}: {}", uuid, exceeded);
+    Some(
+        RegistryError::Denied
+            .with_detail(exceeded.to_string())
+            .into_response(),
+    )
+}
+
 pub async fn post_uploads(Path(name): Path<String>) -> impl IntoResponse {
+    if let Some(exceeded) = quota::check(&name, &[], false).await.unwrap() {
+        tracing::warn!("refusing to start upload: {}", exceeded);
+        return RegistryError::Denied
+            .with_detail(exceeded.to_string())
+            .into_response();
+    }
+
     let uuid = uuid::Uuid::new_v4().as_hyphenated().to_string();
 
     (
@@ -62,13 +123,19 @@ pub async fn post_uploads(Path(name): Path<String>) -> impl IntoResponse {
             (HeaderName::from_static("docker-upload-uuid"), uuid),
         ],
     )
+        .into_response()
 }
 
 pub async fn patch_uploads(
     Path((name, uuid)): Path<(String, String)>,
-    _headers: HeaderMap,
+    headers: HeaderMap,
     body: Bytes,
 ) -> impl IntoResponse {
+    if let Some(refused) = check_upload(&name, &uuid, &headers, &body).await {
+        return refused;
+    }
+
+    metrics::uploaded(body.len());
     let current = db::blobs::get(&uuid).await;
     let (starting, ending) = match current {
         Err(_) => {
@@ -96,15 +163,22 @@ pub async fn patch_uploads(
             (HeaderName::from_static("docker-upload-uuid"), uuid),
         ],
     )
+        .into_response()
 }
 
 pub async fn finish_uploads(
     Path((name, uuid)): Path<(String, String)>,
     Query(query): Query<std::collections::HashMap<String, String>>,
-    _headers: HeaderMap,
+    request: RequestInfo,
+    headers: HeaderMap,
     body: Bytes,
 ) -> impl IntoResponse {
+    if let Some(refused) = check_upload(&name, &uuid, &headers, &body).await {
+        return refused;
+    }
+
     if !body.is_empty() {
+        metrics::uploaded(body.len());
         let current = db::blobs::get(&uuid).await.unwrap();
         let mut new = BytesMut::new();
         new.put(current.to_owned());
@@ -117,6 +191,12 @@ pub async fn finish_uploads(
     let blob = db::blobs::get(&digest).await.unwrap();
 
     tracing::info!("saved blob with digest {} (size: {})", digest, blob.len());
+    events::emit(Event::new(
+        Action::Push,
+        target(&request, &name, &digest, blob.len()),
+        &request,
+    ))
+    .await;
 
     (
         StatusCode::CREATED,
@@ -126,6 +206,7 @@ pub async fn finish_uploads(
             (HeaderName::from_static("docker-content-digest"), digest),
         ],
     )
+        .into_response()
 }
 
 pub async fn delete(Path((name, digest)): Path<(String, String)>) -> impl IntoResponse {
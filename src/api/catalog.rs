@@ -7,7 +7,9 @@ use axum::response::IntoResponse;
 use axum::Json;
 use serde_json::json;
 
+use crate::auth::policy::{Action, Policy};
 use crate::db;
+use crate::events::RequestInfo;
 
 /// Retrieve a sorted, json list of repositories available in the registry.
 /// ##  Catalog Fetch
@@ -84,12 +86,16 @@ use crate::db;
 /// |`Content-Length`|Length of the JSON response body.|
 /// |`Link`|RFC5988 compliant rel='next' with URL to next result set, if available|
 ///
+/// Only the repositories the caller may pull are listed.
+///
 /// [Reference](https://docs.docker.com/registry/spec/api/#get-catalog)
-pub async fn catalog() -> impl IntoResponse {
+pub async fn catalog(request: RequestInfo) -> impl IntoResponse {
+    let policy = Policy::load().await.unwrap();
     let repos = db::repositories::list().await;
     let names = repos
         .unwrap()
         .iter()
+        .filter(|r| policy.allows(request.principal.as_deref(), &r.name, Action::Pull))
         .map(|r| r.name.clone())
         .collect::<Vec<String>>();
     (StatusCode::OK, Json(json!({ "repositories": names })))
@@ -1,12 +1,18 @@
 #![allow(dead_code)]
 
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use serde::Serialize;
+use serde_json::{json, Value};
 
 /// The details of a [RegistryError] to be returned by the API
 #[derive(Debug, Serialize)]
 pub struct RegistryErrorDetails {
     code: String,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<Value>,
 }
 
 /// The error codes returned via the API
@@ -144,6 +150,13 @@ pub enum RegistryError {
     /// Description: The operation was unsupported due to a missing implementation or invalid set of parameters.
     #[error("The operation is unsupported.")]
     Unsupported,
+    /// Code: `TOOMANYREQUESTS`
+    ///
+    /// Message: `too many requests`
+    ///
+    /// Description: Returned when a client attempts to contact a service too many times.
+    #[error("too many requests")]
+    TooManyRequests,
 }
 
 impl From<RegistryError> for RegistryErrorDetails {
@@ -152,71 +165,156 @@ impl From<RegistryError> for RegistryErrorDetails {
             RegistryError::BlobUnknown => RegistryErrorDetails {
                 code: "BLOB_UNKNOWN".to_string(),
                 message: "blob unknown to registry".to_string(),
+                detail: None,
             },
             RegistryError::BlobUploadInvalid => RegistryErrorDetails {
                 code: "BLOB_UPLOAD_INVALID".to_string(),
                 message: "blob upload invalid".to_string(),
+                detail: None,
             },
             RegistryError::BlobUploadUnknown => RegistryErrorDetails {
                 code: "BLOB_UPLOAD_UNKNOWN".to_string(),
                 message: "blob upload unknown to registry".to_string(),
+                detail: None,
             },
             RegistryError::DigestInvalid => RegistryErrorDetails {
                 code: "DIGEST_INVALID".to_string(),
                 message: "provided digest did not match uploaded content".to_string(),
+                detail: None,
             },
             RegistryError::ManifestBlobUnknown => RegistryErrorDetails {
                 code: "MANIFEST_BLOB_UNKNOWN".to_string(),
                 message: "blob unknown to registry".to_string(),
+                detail: None,
             },
             RegistryError::ManifestInvalid => RegistryErrorDetails {
                 code: "MANIFEST_INVALID".to_string(),
                 message: "manifest invalid".to_string(),
+                detail: None,
             },
             RegistryError::ManifestUnknown => RegistryErrorDetails {
                 code: "MANIFEST_UNKNOWN".to_string(),
                 message: "manifest unknown".to_string(),
+                detail: None,
             },
             RegistryError::ManifestUnverified => RegistryErrorDetails {
                 code: "MANIFEST_UNVERIFIED".to_string(),
                 message: "manifest failed signature verification".to_string(),
+                detail: None,
             },
             RegistryError::NameInvalid => RegistryErrorDetails {
                 code: "NAME_INVALID".to_string(),
                 message: "invalid repository name".to_string(),
+                detail: None,
             },
             RegistryError::NameUnknown => RegistryErrorDetails {
                 code: "NAME_UNKNOWN".to_string(),
                 message: "repository name not known to registry".to_string(),
+                detail: None,
             },
             RegistryError::PaginationNumberInvalid => RegistryErrorDetails {
                 code: "PAGINATION_NUMBER_INVALID".to_string(),
                 message: "invalid number of results requested".to_string(),
+                detail: None,
             },
             RegistryError::RangeInvalid => RegistryErrorDetails {
                 code: "RANGE_INVALID".to_string(),
                 message: "provided range was invalid".to_string(),
+                detail: None,
             },
             RegistryError::SizeInvalid => RegistryErrorDetails {
                 code: "SIZE_INVALID".to_string(),
                 message: "provided length did not match content length".to_string(),
+                detail: None,
             },
             RegistryError::TagInvalid => RegistryErrorDetails {
                 code: "TAG_INVALID".to_string(),
                 message: "manifest tag did not match URI".to_string(),
+                detail: None,
             },
             RegistryError::Unauthorized => RegistryErrorDetails {
                 code: "UNAUTHORIZED".to_string(),
                 message: "authentication required".to_string(),
+                detail: None,
             },
             RegistryError::Denied => RegistryErrorDetails {
                 code: "DENIED".to_string(),
                 message: "requested access to the resource is denied".to_string(),
+                detail: None,
             },
             RegistryError::Unsupported => RegistryErrorDetails {
                 code: "UNSUPPORTED".to_string(),
                 message: "the operation is unsupported".to_string(),
+                detail: None,
+            },
+            RegistryError::TooManyRequests => RegistryErrorDetails {
+                code: "TOOMANYREQUESTS".to_string(),
+                message: "too many requests".to_string(),
+                detail: None,
             },
         }
     }
 }
+
+impl RegistryError {
+    /// The HTTP status code the error is returned with
+    ///
+    /// [Reference](https://docs.docker.com/registry/spec/api/#detail)
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RegistryError::BlobUnknown
+            | RegistryError::BlobUploadUnknown
+            | RegistryError::ManifestUnknown
+            | RegistryError::NameUnknown => StatusCode::NOT_FOUND,
+            RegistryError::BlobUploadInvalid
+            | RegistryError::DigestInvalid
+            | RegistryError::ManifestBlobUnknown
+            | RegistryError::ManifestInvalid
+            | RegistryError::ManifestUnverified
+            | RegistryError::NameInvalid
+            | RegistryError::PaginationNumberInvalid
+            | RegistryError::SizeInvalid
+            | RegistryError::TagInvalid => StatusCode::BAD_REQUEST,
+            RegistryError::RangeInvalid => StatusCode::RANGE_NOT_SATISFIABLE,
+            RegistryError::Unauthorized => StatusCode::UNAUTHORIZED,
+            RegistryError::Denied => StatusCode::FORBIDDEN,
+            RegistryError::Unsupported => StatusCode::METHOD_NOT_ALLOWED,
+            RegistryError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// Attach a detail explaining the error to the response body
+    pub fn with_detail(self, detail: impl Serialize) -> RegistryErrorResponse {
+        RegistryErrorResponse {
+            error: self,
+            detail: serde_json::to_value(detail).ok(),
+        }
+    }
+}
+
+/// A [RegistryError] as returned by the API, optionally with a detail explaining what went wrong
+#[derive(Debug)]
+pub struct RegistryErrorResponse {
+    error: RegistryError,
+    detail: Option<Value>,
+}
+
+impl IntoResponse for RegistryErrorResponse {
+    fn into_response(self) -> Response {
+        let status = self.error.status_code();
+        let mut details = RegistryErrorDetails::from(self.error);
+        details.detail = self.detail;
+
+        (status, Json(json!({ "errors": vec![details] }))).into_response()
+    }
+}
+
+impl IntoResponse for RegistryError {
+    fn into_response(self) -> Response {
+        RegistryErrorResponse {
+            error: self,
+            detail: None,
+        }
+        .into_response()
+    }
+}
@@ -4,7 +4,9 @@ use axum::http::{HeaderName, StatusCode};
 use axum::response::IntoResponse;
 use serde::{Deserialize, Serialize};
 
-use crate::db;
+use super::RegistryError;
+use crate::events::{self, Action, Event, RequestInfo, Target};
+use crate::{db, metrics, protection, proxy, quota, replication};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
@@ -69,28 +71,86 @@ pub struct ImageManifestLayer {
     pub urls: Option<Vec<String>>,
 }
 
-pub async fn get(Path((name, reference)): Path<(String, String)>) -> impl IntoResponse {
-    let digest = match crate::DIGEST_REGEX.is_match(&reference) {
-        true => reference,
-        false => {
+/// The media type of a stored manifest, taken from its `mediaType` field
+pub fn media_type(raw: &str) -> String {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct MediaType {
+        media_type: Option<String>,
+    }
+
+    serde_json::from_str::<MediaType>(raw)
+        .ok()
+        .and_then(|m| m.media_type)
+        .unwrap_or_else(|| "application/vnd.docker.distribution.manifest.v2+json".to_string())
+}
+
+/// Describe a manifest as the target of an event
+fn target(
+    request: &RequestInfo,
+    name: &str,
+    tag: Option<&str>,
+    digest: &str,
+    raw: Option<&str>,
+) -> Target {
+    Target {
+        media_type: raw.map(media_type),
+        size: raw.map(|r| r.len() as i64),
+        length: raw.map(|r| r.len() as i64),
+        digest: Some(digest.to_string()),
+        repository: name.to_string(),
+        url: Some(request.url(name, "manifests", digest)),
+        tag: tag.map(str::to_string),
+    }
+}
+
+pub async fn get(
+    Path((name, reference)): Path<(String, String)>,
+    request: RequestInfo,
+) -> impl IntoResponse {
+    let tag = match crate::DIGEST_REGEX.is_match(&reference) {
+        true => None,
+        false => Some(reference.as_str()),
+    };
+    let digest = match tag {
+        None => Some(reference.clone()),
+        Some(_) => {
             tracing::info!("resolving tag: {}:{}", name, reference);
-            let digest = db::tags::get_manifest(&name, &reference).await.unwrap();
-            tracing::info!("resolved tag {}:{} to digest {}", name, reference, digest);
+            let digest = match proxy::enabled() {
+                true => proxy::resolve_tag(&name, &reference).await,
+                false => db::tags::get_manifest(&name, &reference).await.ok(),
+            };
+            if let Some(digest) = &digest {
+                tracing::info!("resolved tag {}:{} to digest {}", name, reference, digest);
+            }
             digest
         }
     };
-    let raw = db::manifests::get(&name, &digest).await.unwrap();
+    let digest = match digest {
+        Some(digest) => digest,
+        None => return RegistryError::ManifestUnknown.into_response(),
+    };
+
+    let raw = match db::manifests::get(&name, &digest).await {
+        Ok(raw) => raw,
+        Err(_) => match proxy::fetch_manifest(&name, &digest).await {
+            Some(raw) => raw,
+            None => return RegistryError::ManifestUnknown.into_response(),
+        },
+    };
+    let media_type = media_type(&raw);
+    metrics::downloaded(raw.len());
+    events::emit(Event::new(
+        Action::Pull,
+        target(&request, &name, tag, &digest, Some(&raw)),
+        &request,
+    ))
+    .await;
     (
         [
             (HeaderName::from_static("docker-content-digest"), digest),
-            (
-                CONTENT_TYPE,
-                "application/vnd.docker.distribution.manifest.v2+json".to_string(),
-            ),
-            (
-                ACCEPT,
-                "application/vnd.docker.distribution.manifest.v2+json".to_string(),
-            ),
+            (CONTENT_TYPE, media_type.clone()),
+            (ACCEPT, media_type),
             (
                 HeaderName::from_static("docker-distribution-api-version"),
                 "registry/2.0".to_string(),
@@ -103,21 +163,54 @@ pub async fn get(Path((name, reference)): Path<(String, String)>) -> impl IntoRe
 
 pub async fn put(
     Path((name, reference)): Path<(String, String)>,
+    request: RequestInfo,
     body: String,
 ) -> impl IntoResponse {
     let hash = sha256::digest(body.clone());
     let digest = format!("sha256:{hash}");
 
+    if !crate::DIGEST_REGEX.is_match(&reference) {
+        if let Some(violation) = protection::check_overwrite(&name, &reference, &digest)
+            .await
+            .unwrap()
+        {
+            tracing::warn!("refusing to push manifest: {}", violation);
+            return RegistryError::Denied
+                .with_detail(violation.to_string())
+                .into_response();
+        }
+    }
+
+    let parsed = serde_json::from_str::<Manifest>(&body);
+    let blobs = match &parsed {
+        Ok(Manifest::Image(image)) => std::iter::once(&image.config.digest)
+            .chain(image.layers.iter().map(|l| &l.digest))
+            .cloned()
+            .collect(),
+        _ => Vec::new(),
+    };
+    let new_tag = !crate::DIGEST_REGEX.is_match(&reference)
+        && db::tags::get_manifest(&name, &reference).await.is_err();
+    if let Some(exceeded) = quota::check(&name, &blobs, new_tag).await.unwrap() {
+        tracing::warn!("refusing to push manifest: {}", exceeded);
+        return RegistryError::Denied
+            .with_detail(exceeded.to_string())
+            .into_response();
+    }
+
     db::repositories::save(&name).await.unwrap();
     db::manifests::save(&name, &digest, &body).await.unwrap();
+    metrics::uploaded(body.len());
 
     if !crate::DIGEST_REGEX.is_match(&reference) {
-        db::tags::save(&name, &reference, &digest).await.unwrap();
+        db::tags::save(&name, &reference, &digest, Some(&request.actor))
+            .await
+            .unwrap();
+        replication::enqueue(&name, &reference, Some(&digest)).await;
     }
 
     tracing::info!("manifest saved: {}", digest);
 
-    let parsed = serde_json::from_str::<Manifest>(&body);
     if let Ok(manifest) = parsed {
         match manifest {
             Manifest::Image(image) => {
@@ -147,13 +240,75 @@ pub async fn put(
         }
     }
 
+    let tag = match crate::DIGEST_REGEX.is_match(&reference) {
+        true => None,
+        false => Some(reference.as_str()),
+    };
+    events::emit(Event::new(
+        Action::Push,
+        target(&request, &name, tag, &digest, Some(&body)),
+        &request,
+    ))
+    .await;
+
     (
         StatusCode::CREATED,
         [(HeaderName::from_static("docker-content-digest"), digest)],
     )
+        .into_response()
 }
 
-pub async fn delete(Path((name, reference)): Path<(String, String)>) -> impl IntoResponse {
+pub async fn delete(
+    Path((name, reference)): Path<(String, String)>,
+    request: RequestInfo,
+) -> impl IntoResponse {
+    if !crate::DIGEST_REGEX.is_match(&reference) {
+        if let Some(violation) = protection::check_delete(&name, &reference).await.unwrap() {
+            tracing::warn!("refusing to delete tag: {}", violation);
+            return RegistryError::Denied
+                .with_detail(violation.to_string())
+                .into_response();
+        }
+        let digest = db::tags::get_manifest(&name, &reference).await;
+        db::tags::delete(&name, &reference, Some(&request.actor))
+            .await
+            .unwrap();
+        replication::enqueue(&name, &reference, None).await;
+        events::emit(Event::new(
+            Action::Delete,
+            Target {
+                digest: digest.ok(),
+                repository: name,
+                tag: Some(reference),
+                ..Default::default()
+            },
+            &request,
+        ))
+        .await;
+        return StatusCode::ACCEPTED.into_response();
+    }
+
+    if let Some(violation) = protection::check_delete_manifest(&name, &reference)
+        .await
+        .unwrap()
+    {
+        tracing::warn!("refusing to delete manifest: {}", violation);
+        return RegistryError::Denied
+            .with_detail(violation.to_string())
+            .into_response();
+    }
     db::manifests::delete(&name, &reference).await.unwrap();
     db::cleanup().await.unwrap();
+    events::emit(Event::new(
+        Action::Delete,
+        Target {
+            digest: Some(reference),
+            repository: name,
+            ..Default::default()
+        },
+        &request,
+    ))
+    .await;
+
+    StatusCode::ACCEPTED.into_response()
 }
@@ -0,0 +1,191 @@
+//! HTTP Basic authentication against bcrypt password hashes.
+//!
+//! Users are looked up in the htpasswd file at `AUTH_HTPASSWD` first, then in the users table managed in the
+//! admin UI. The file is read again whenever it changes, and only bcrypt entries (`htpasswd -B`) are
+//! supported. As bcrypt is slow on purpose and clients send their credentials with every request,
+//! successfully verified credentials are remembered for a few minutes. This also means a robot's last use is
+//! only recorded every few minutes.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use base64::Engine;
+use lazy_static::lazy_static;
+
+use super::{robots, Principal};
+use crate::db;
+
+/// How long verified credentials are remembered
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Default)]
+struct Htpasswd {
+    modified: Option<SystemTime>,
+    users: HashMap<String, String>,
+}
+
+lazy_static! {
+    static ref HTPASSWD_PATH: Option<PathBuf> = std::env::var("AUTH_HTPASSWD").ok().map(PathBuf::from);
+    static ref HTPASSWD: Mutex<Htpasswd> = Mutex::new(Htpasswd::default());
+    /// Usernames by the SHA-256 of the credentials they were verified with, and when
+    static ref VERIFIED: Mutex<HashMap<String, (String, Instant)>> = Mutex::new(HashMap::new());
+}
+
+/// Parse the contents of an htpasswd file into password hashes by username
+pub fn parse_htpasswd(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match line.split_once(':') {
+            Some((user, hash)) if hash.starts_with("$2") => {
+                Some((user.to_string(), hash.to_string()))
+            }
+            Some((user, _)) => {
+                tracing::warn!(
+                    "ignoring htpasswd entry for {}, only bcrypt is supported",
+                    user
+                );
+                None
+            }
+            None => None,
+        })
+        .collect()
+}
+
+/// Look a user up in the htpasswd file, reading it again if it changed
+fn htpasswd_hash(username: &str) -> Option<String> {
+    let path = HTPASSWD_PATH.as_ref()?;
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let mut htpasswd = HTPASSWD.lock().unwrap();
+    if htpasswd.modified != modified || modified.is_none() {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                htpasswd.users = parse_htpasswd(&contents);
+                tracing::info!(
+                    "loaded {} users from {}",
+                    htpasswd.users.len(),
+                    path.display()
+                );
+            }
+            Err(e) => {
+                tracing::error!("failed to read {}: {}", path.display(), e);
+                htpasswd.users.clear();
+            }
+        }
+        htpasswd.modified = modified;
+    }
+
+    htpasswd.users.get(username).cloned()
+}
+
+/// Split the value of a Basic `Authorization` header into username and password
+pub fn decode(authorization: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(credentials.trim())
+        .ok()?;
+    let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Hash a password for the users table
+pub async fn hash(password: &str) -> String {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || bcrypt::hash(password, bcrypt::DEFAULT_COST))
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+/// Check a password against the htpasswd file and the users table
+#[async_backtrace::framed]
+pub async fn verify(username: &str, password: &str) -> bool {
+    let hash = match htpasswd_hash(username) {
+        Some(hash) => hash,
+        None => match db::users::get(username).await {
+            Ok(user) => user.password,
+            Err(_) => return false,
+        },
+    };
+
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+        .await
+        .unwrap_or(false)
+}
+
+/// Whether a user exists in the htpasswd file or the users table
+#[async_backtrace::framed]
+pub async fn exists(username: &str) -> bool {
+    htpasswd_hash(username).is_some() || db::users::get(username).await.is_ok()
+}
+
+/// Forget all verified credentials, e.g. after a password changed
+pub fn forget() {
+    VERIFIED.lock().unwrap().clear();
+}
+
+/// Authenticate the value of an `Authorization` header, holding the Basic credentials of a user or robot, or
+/// a robot token as bearer token
+#[async_backtrace::framed]
+pub async fn authenticate(authorization: &str) -> Option<Principal> {
+    let key = sha256::digest(authorization);
+    if let Some((name, verified)) = VERIFIED.lock().unwrap().get(&key) {
+        if verified.elapsed() < CACHE_TTL {
+            return Some(Principal { name: name.clone() });
+        }
+    }
+
+    let principal = match robots::bearer(authorization) {
+        Some(token) => robots::authenticate(None, token).await?,
+        None => {
+            let (username, password) = decode(authorization)?;
+            match username.strip_prefix(robots::PREFIX) {
+                Some(robot) => robots::authenticate(Some(robot), &password).await?,
+                None if verify(&username, &password).await => Principal { name: username },
+                None => {
+                    tracing::warn!("invalid credentials for {}", username);
+                    return None;
+                }
+            }
+        }
+    };
+
+    VERIFIED
+        .lock()
+        .unwrap()
+        .insert(key, (principal.name.clone(), Instant::now()));
+    Some(principal)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, parse_htpasswd};
+
+    #[test]
+    fn test_parse_htpasswd() {
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        let users = parse_htpasswd(&format!(
+            "# registry users\nalice:{}\n\nbob:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n",
+            hash
+        ));
+        assert_eq!(users.len(), 1);
+        assert!(bcrypt::verify("hunter2", &users["alice"]).unwrap());
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decode("Basic YWxpY2U6aHVudGVyOjI="),
+            Some(("alice".to_string(), "hunter:2".to_string()))
+        );
+        assert_eq!(decode("Bearer YWxpY2U6aHVudGVyMg=="), None);
+    }
+}
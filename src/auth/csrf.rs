@@ -0,0 +1,101 @@
+//! Cross-site request forgery protection for the web UI.
+//!
+//! Every browser gets a random token in the `pequod_csrf` cookie, and the forms of the web UI send it back in
+//! a `csrf` field, which a page on another site can't do as it can't read the cookie. Form submissions
+//! without a matching token are refused, whether or not authentication is on. JSON requests to the admin
+//! API need no token, as browsers don't send those cross-site without the server's consent, and neither do
+//! admin API requests carrying an `Authorization` header, as sent by scripts rather than by forms.
+use axum::body::{Body, Bytes};
+use axum::extract::FromRequest;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, SET_COOKIE};
+use axum::http::{HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use uuid::Uuid;
+
+use super::cookie;
+
+/// The name of the cookie holding the token
+pub const COOKIE_NAME: &str = "pequod_csrf";
+
+/// Whether a request could have been sent by a form on another site
+fn is_form(req: &Request<Body>) -> bool {
+    if req.method() != Method::POST {
+        return false;
+    }
+    let path = req.uri().path();
+    if path == "/v2" || path.starts_with("/v2/") {
+        return false;
+    }
+    if path.starts_with("/admin/api/") && req.headers().contains_key(AUTHORIZATION) {
+        return false;
+    }
+    match content_type(req) {
+        Some(content_type) => {
+            content_type.starts_with("application/x-www-form-urlencoded")
+                || content_type.starts_with("multipart/form-data")
+                || content_type.starts_with("text/plain")
+        }
+        None => true,
+    }
+}
+
+fn content_type(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .map(str::to_ascii_lowercase)
+}
+
+fn refuse(path: &str) -> Response {
+    tracing::warn!("refused {} without a valid CSRF token", path);
+    (
+        StatusCode::FORBIDDEN,
+        "the form was submitted without a valid CSRF token, reload the page and try again",
+    )
+        .into_response()
+}
+
+/// Middleware refusing form submissions without the CSRF token, and handing out tokens to browsers
+#[async_backtrace::framed]
+pub async fn protect(req: Request<Body>, next: Next<Body>) -> Response {
+    let expected = cookie(req.headers(), COOKIE_NAME).map(str::to_string);
+
+    let req = if is_form(&req) {
+        // the forms of the web UI are URL-encoded, other bodies can't carry the token and aren't read
+        let urlencoded =
+            content_type(&req).is_some_and(|c| c.starts_with("application/x-www-form-urlencoded"));
+        if !urlencoded {
+            return refuse(req.uri().path());
+        }
+        let (parts, body) = req.into_parts();
+        let bytes = match Bytes::from_request(Request::new(body), &()).await {
+            Ok(bytes) => bytes,
+            Err(rejection) => return rejection.into_response(),
+        };
+        let token = url::form_urlencoded::parse(&bytes)
+            .find(|(key, _)| key == "csrf")
+            .map(|(_, value)| value.into_owned());
+        if token.is_none() || token != expected {
+            return refuse(parts.uri.path());
+        }
+        Request::from_parts(parts, Body::from(bytes))
+    } else {
+        req
+    };
+
+    let path = req.uri().path();
+    let hand_out = expected.is_none() && !(path == "/v2" || path.starts_with("/v2/"));
+    let mut response = next.run(req).await;
+    if hand_out {
+        let cookie = format!(
+            "{}={}; Path=/; SameSite=Strict",
+            COOKIE_NAME,
+            Uuid::new_v4().simple()
+        );
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            response.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+    response
+}
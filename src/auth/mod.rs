@@ -0,0 +1,210 @@
+//! Authentication.
+//!
+//! Authentication is off unless `AUTH` is set. With `AUTH=basic`, every request needs the HTTP Basic
+//! credentials of a user, see [basic]. Requests without valid credentials are answered with
+//! `401 Unauthorized` and a `WWW-Authenticate` challenge, which is what makes `docker login` ask for and
+//! send credentials. The authenticated [Principal] is added to the request extensions for handlers to
+//! pick up.
+//!
+//! With `AUTH=token`, `/v2` requests need a bearer token instead, see [token]. Everything else, like the web
+//! UI, still uses Basic credentials, or the cookie of a [session] for browsers, who are sent to the login
+//! page when they have neither.
+//!
+//! Clients that present a [client certificate](crate::tls) and send no other credentials are authenticated
+//! as the certificate's principal, in either mode.
+//!
+//! Authenticated requests are then checked against the [policy::Policy], and answered with `DENIED` if it
+//! does not allow them. The admin UI is reserved for admins. Forms of the web UI are protected against
+//! cross-site request forgery by [csrf], even with authentication off.
+use axum::http::header::{ACCEPT, AUTHORIZATION, COOKIE, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use lazy_static::lazy_static;
+
+use crate::api::RegistryError;
+use crate::tls::ClientCertificate;
+
+use self::policy::Policy;
+
+pub mod basic;
+pub mod csrf;
+pub mod policy;
+pub mod robots;
+pub mod scope;
+pub mod session;
+pub mod token;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Everyone can do everything
+    None,
+    /// HTTP Basic authentication
+    Basic,
+    /// Docker token authentication for `/v2`, Basic for everything else
+    Token,
+}
+
+impl Mode {
+    /// The mode set with `AUTH`, which [crate::config::Config::validate] checks at startup
+    pub fn from_env() -> Result<Mode, String> {
+        match std::env::var("AUTH").as_deref() {
+            Ok("basic") => Ok(Mode::Basic),
+            Ok("token") => Ok(Mode::Token),
+            Ok("none") | Err(_) => Ok(Mode::None),
+            Ok(other) => Err(format!(
+                "unknown authentication mode {}, use basic, token or none",
+                other
+            )),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref MODE: Mode = Mode::from_env().expect("AUTH is validated at startup");
+    static ref REALM: String = std::env::var("AUTH_REALM").unwrap_or_else(|_| "pequod".to_string());
+}
+
+/// Who made a request
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+}
+
+/// The `WWW-Authenticate` challenge sent along with `401 Unauthorized`
+pub fn challenge() -> String {
+    format!("Basic realm=\"{}\"", *REALM)
+}
+
+/// Ask the client to authenticate using `challenge`
+pub fn unauthorized(challenge: &str) -> Response {
+    let mut response = RegistryError::Unauthorized.into_response();
+    if let Ok(challenge) = HeaderValue::from_str(challenge) {
+        response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+    }
+    response
+}
+
+/// The value of a cookie sent with a request
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|c| c.to_str().ok())
+        .flat_map(|c| c.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| value)
+}
+
+/// The principal of the client certificate a request was made with
+pub fn certificate<B>(req: &Request<B>) -> Option<Principal> {
+    req.extensions()
+        .get::<ClientCertificate>()
+        .map(|certificate| Principal {
+            name: certificate.principal.clone(),
+        })
+}
+
+/// Whether the policy allows `user` to make a request, based on the scope it needs
+pub fn authorize(policy: &Policy, user: Option<&str>, method: &Method, path: &str) -> bool {
+    if path == "/admin" || path.starts_with("/admin/") {
+        return policy.is_admin(user);
+    }
+    scope::required(method, path).is_none_or(|scope| policy.permits(user, &scope))
+}
+
+/// Middleware rejecting requests without valid credentials
+#[async_backtrace::framed]
+pub async fn authenticate<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let path = req.uri().path();
+    if *MODE == Mode::None || ["/token", "/login", "/logout", "/healthz", "/readyz"].contains(&path)
+    {
+        // everyone may do everything, but events should still say who did it
+        if let Some(principal) = certificate(&req).filter(|_| *MODE == Mode::None) {
+            req.extensions_mut().insert(principal);
+        }
+        return next.run(req).await;
+    }
+    let registry = path == "/v2" || path.starts_with("/v2/");
+    if *MODE == Mode::Token && registry {
+        return token::authorize(req, next).await;
+    }
+
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|a| a.to_str().ok());
+    let principal = match authorization {
+        Some(authorization) => basic::authenticate(authorization).await,
+        None if !registry => match cookie(req.headers(), session::COOKIE).and_then(session::verify)
+        {
+            Some(user) if basic::exists(&user).await => Some(Principal { name: user }),
+            _ => certificate(&req),
+        },
+        None => certificate(&req),
+    };
+
+    let browsing = req.method() == Method::GET
+        && req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|a| a.to_str().ok())
+            .is_some_and(|a| a.contains("text/html"));
+    let principal = match principal {
+        Some(principal) => principal,
+        None if browsing && !registry => {
+            let next = req.uri().path_and_query().map_or("/", |p| p.as_str());
+            let next = url::form_urlencoded::byte_serialize(next.as_bytes()).collect::<String>();
+            return Redirect::to(&format!("/login?next={}", next)).into_response();
+        }
+        None => return unauthorized(&challenge()),
+    };
+
+    let policy = match Policy::load().await {
+        Ok(policy) => policy,
+        Err(e) => {
+            tracing::error!("failed to load permissions: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if !authorize(
+        &policy,
+        Some(&principal.name),
+        req.method(),
+        req.uri().path(),
+    ) {
+        tracing::warn!(
+            "denied {} {} to {}",
+            req.method(),
+            req.uri().path(),
+            principal.name
+        );
+        return RegistryError::Denied.into_response();
+    }
+
+    req.extensions_mut().insert(principal);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::header::COOKIE;
+    use axum::http::HeaderMap;
+
+    use super::cookie;
+
+    #[test]
+    fn test_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            "pequod_session=alice:1:ab; pequod_csrf=1234"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(cookie(&headers, "pequod_csrf"), Some("1234"));
+        assert_eq!(cookie(&headers, "pequod_session"), Some("alice:1:ab"));
+        assert_eq!(cookie(&headers, "other"), None);
+    }
+}
@@ -0,0 +1,248 @@
+//! Repository level authorization.
+//!
+//! Permissions grant `pull`, `push`, `delete` or `admin` on the repositories matching a glob pattern to a
+//! user, to the members of a group (`@team-a`) or to everyone (`*`, including anonymous clients of the token
+//! endpoint). Each action implies the ones before it, so whoever may push may also pull. Admins of `**` may
+//! use the admin UI.
+//!
+//! Until the first permission is defined, authenticated users may do everything, and with authentication
+//! off everyone may. [Robots](super::robots) only get the permissions they were created with, and what
+//! everyone may do.
+use std::collections::HashMap;
+
+use crate::db::{self, GroupMember, Permission};
+use crate::pattern;
+
+use super::robots;
+use super::scope::Scope;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    Pull,
+    Push,
+    Delete,
+    Admin,
+}
+
+impl Action {
+    /// Parse an action as used in permissions and token scopes, where `*` stands for everything
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "pull" => Some(Action::Pull),
+            "push" => Some(Action::Push),
+            "delete" => Some(Action::Delete),
+            "admin" | "*" => Some(Action::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// The permissions and group memberships, loaded once so they can be checked against many repositories
+pub struct Policy {
+    enabled: bool,
+    permissions: Vec<Permission>,
+    members: Vec<GroupMember>,
+    /// Repository patterns and actions by robot name
+    robots: HashMap<String, Vec<(String, Action)>>,
+}
+
+impl Policy {
+    #[async_backtrace::framed]
+    pub async fn load() -> Result<Self, db::Error> {
+        if *super::MODE == super::Mode::None {
+            return Ok(Policy {
+                enabled: false,
+                permissions: Vec::new(),
+                members: Vec::new(),
+                robots: HashMap::new(),
+            });
+        }
+
+        let robots = db::robots::list()
+            .await?
+            .into_iter()
+            .map(|robot| {
+                let permissions =
+                    robots::parse_permissions(&robot.permissions).unwrap_or_else(|e| {
+                        tracing::error!("ignoring permissions of robot {}: {}", robot.name, e);
+                        Vec::new()
+                    });
+                (robot.name, permissions)
+            })
+            .collect();
+
+        Ok(Policy {
+            enabled: true,
+            permissions: db::access::permissions().await?,
+            members: db::access::members().await?,
+            robots,
+        })
+    }
+
+    fn applies(&self, permission: &Permission, user: Option<&str>) -> bool {
+        match (permission.subject.as_str(), user) {
+            ("*", _) => true,
+            (_, None) => false,
+            (subject, Some(user)) => match subject.strip_prefix('@') {
+                Some(group) => self
+                    .members
+                    .iter()
+                    .any(|m| m.group == group && m.user == user),
+                None => subject == user,
+            },
+        }
+    }
+
+    /// The answer to every question while there is nothing to enforce
+    fn unrestricted(&self, user: Option<&str>) -> Option<bool> {
+        if !self.enabled {
+            return Some(true);
+        }
+        self.permissions.is_empty().then_some(user.is_some())
+    }
+
+    /// The permissions of `user` if it is a robot
+    fn robot(&self, user: Option<&str>) -> Option<&[(String, Action)]> {
+        let name = user?.strip_prefix(robots::PREFIX)?;
+        Some(self.robots.get(name).map(Vec::as_slice).unwrap_or_default())
+    }
+
+    /// Whether `user`, `None` for anonymous clients, may perform `action` on `repository`
+    pub fn allows(&self, user: Option<&str>, repository: &str, action: Action) -> bool {
+        if let Some(permissions) = self.robot(user).filter(|_| self.enabled) {
+            return permissions.iter().any(|(pattern, granted)| {
+                pattern::matches(pattern, repository) && *granted >= action
+            }) || self.granted(None, repository, action);
+        }
+        self.unrestricted(user)
+            .unwrap_or_else(|| self.granted(user, repository, action))
+    }
+
+    fn granted(&self, user: Option<&str>, repository: &str, action: Action) -> bool {
+        self.permissions.iter().any(|p| {
+            self.applies(p, user)
+                && pattern::matches(&p.repository, repository)
+                && Action::parse(&p.action).is_some_and(|granted| granted >= action)
+        })
+    }
+
+    /// Whether `user` may use the admin UI
+    pub fn is_admin(&self, user: Option<&str>) -> bool {
+        if let Some(permissions) = self.robot(user).filter(|_| self.enabled) {
+            return permissions
+                .iter()
+                .any(|(pattern, granted)| pattern == "**" && *granted == Action::Admin);
+        }
+        self.unrestricted(user).unwrap_or_else(|| {
+            self.permissions.iter().any(|p| {
+                self.applies(p, user)
+                    && p.repository == "**"
+                    && Action::parse(&p.action) == Some(Action::Admin)
+            })
+        })
+    }
+
+    /// Whether `user` may do everything `scope` asks for. Only repository scopes are restricted.
+    pub fn permits(&self, user: Option<&str>, scope: &Scope) -> bool {
+        scope.kind != "repository"
+            || scope.actions.iter().all(|action| {
+                Action::parse(action).is_some_and(|a| self.allows(user, &scope.name, a))
+            })
+    }
+
+    /// Narrow `scope` down to the actions `user` may perform
+    pub fn restrict(&self, user: Option<&str>, mut scope: Scope) -> Scope {
+        if scope.kind == "repository" {
+            let name = scope.name.clone();
+            scope.actions.retain(|action| {
+                Action::parse(action).is_some_and(|a| self.allows(user, &name, a))
+            });
+        }
+        scope
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{Action, Policy};
+    use crate::auth::scope::Scope;
+    use crate::db::{GroupMember, Permission};
+
+    fn policy() -> Policy {
+        let permissions = [
+            ("@team-a", "team-a/**", "push"),
+            ("*", "base/*", "pull"),
+            ("root", "**", "admin"),
+        ];
+        Policy {
+            enabled: true,
+            permissions: permissions
+                .iter()
+                .enumerate()
+                .map(|(i, (subject, repository, action))| Permission {
+                    id: i as i64,
+                    subject: subject.to_string(),
+                    repository: repository.to_string(),
+                    action: action.to_string(),
+                })
+                .collect(),
+            members: vec![GroupMember {
+                group: "team-a".to_string(),
+                user: "alice".to_string(),
+            }],
+            robots: HashMap::from([(
+                "ci".to_string(),
+                vec![("team-a/app".to_string(), Action::Push)],
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_allows() {
+        let policy = policy();
+        assert!(policy.allows(Some("alice"), "team-a/app", Action::Push));
+        assert!(policy.allows(Some("alice"), "team-a/app", Action::Pull));
+        assert!(!policy.allows(Some("alice"), "team-a/app", Action::Delete));
+        assert!(!policy.allows(Some("bob"), "team-a/app", Action::Pull));
+        assert!(policy.allows(None, "base/debian", Action::Pull));
+        assert!(!policy.allows(None, "base/debian", Action::Push));
+        assert!(policy.allows(Some("root"), "team-a/app", Action::Delete));
+        assert!(policy.is_admin(Some("root")));
+        assert!(!policy.is_admin(Some("alice")));
+    }
+
+    #[test]
+    fn test_restrict() {
+        let policy = policy();
+        let scope = Scope::parse("repository:team-a/app:pull,push,delete").unwrap();
+        assert!(!policy.permits(Some("alice"), &scope));
+        let scope = policy.restrict(Some("alice"), scope);
+        assert_eq!(scope.actions, vec!["pull", "push"]);
+        assert!(policy.permits(Some("alice"), &scope));
+    }
+
+    #[test]
+    fn test_without_permissions() {
+        let policy = Policy {
+            permissions: Vec::new(),
+            ..policy()
+        };
+        assert!(policy.allows(Some("bob"), "team-a/app", Action::Delete));
+        assert!(policy.is_admin(Some("bob")));
+        assert!(!policy.allows(None, "base/debian", Action::Pull));
+        assert!(!policy.allows(Some("robot$ci"), "team-a/other", Action::Pull));
+    }
+
+    #[test]
+    fn test_robots() {
+        let policy = policy();
+        assert!(policy.allows(Some("robot$ci"), "team-a/app", Action::Push));
+        assert!(!policy.allows(Some("robot$ci"), "team-a/app", Action::Delete));
+        assert!(policy.allows(Some("robot$ci"), "base/debian", Action::Pull));
+        assert!(!policy.allows(Some("robot$ci"), "team-a/other", Action::Pull));
+        assert!(!policy.allows(Some("robot$gone"), "team-a/app", Action::Pull));
+        assert!(!policy.is_admin(Some("robot$ci")));
+    }
+}
@@ -0,0 +1,107 @@
+//! Robot accounts.
+//!
+//! Robots are accounts for CI pipelines and other automation. They authenticate with a generated token,
+//! either as the Basic password of the user `robot$<name>` or as a bearer token, and may only do what their
+//! own permissions allow, whatever the [policy](super::policy) says. Only the SHA-256 of a token is stored,
+//! so a lost token can't be recovered, only replaced.
+use chrono::Utc;
+use uuid::Uuid;
+
+use super::policy::Action;
+use super::Principal;
+use crate::db;
+
+/// The prefix of the names robots authenticate as
+pub const PREFIX: &str = "robot$";
+/// The prefix of robot tokens, which tells them apart from the tokens of the token endpoint
+const TOKEN_PREFIX: &str = "pqd_";
+
+/// Generate a new robot token
+pub fn generate() -> String {
+    format!(
+        "{}{}{}",
+        TOKEN_PREFIX,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    )
+}
+
+/// The hash a token is stored as
+pub fn hash(token: &str) -> String {
+    sha256::digest(token)
+}
+
+/// The robot token in a bearer `Authorization` header, if it holds one
+pub fn bearer(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && token.starts_with(TOKEN_PREFIX)).then_some(token)
+}
+
+/// Parse permissions such as `team-a/**:push base/*:pull` into repository patterns and actions
+pub fn parse_permissions(permissions: &str) -> Result<Vec<(String, Action)>, String> {
+    permissions
+        .split_whitespace()
+        .map(|permission| {
+            let (pattern, action) = permission
+                .rsplit_once(':')
+                .filter(|(pattern, _)| !pattern.is_empty())
+                .ok_or_else(|| format!("invalid permission {}, use pattern:action", permission))?;
+            let action = Action::parse(action)
+                .filter(|_| action != "*")
+                .ok_or_else(|| format!("unknown action {} in {}", action, permission))?;
+            Ok((pattern.to_string(), action))
+        })
+        .collect()
+}
+
+/// Authenticate a robot by its token. `name` is the robot's name when it was sent as the Basic username.
+#[async_backtrace::framed]
+pub async fn authenticate(name: Option<&str>, token: &str) -> Option<Principal> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return None;
+    }
+    let robot = db::robots::get_by_token(&hash(token)).await.ok()?;
+    if name.is_some_and(|name| name != robot.name) {
+        tracing::warn!("token of robot {} used as {}", robot.name, name.unwrap());
+        return None;
+    }
+    if robot.expires.is_some_and(|expires| expires < Utc::now()) {
+        tracing::warn!("expired token of robot {}", robot.name);
+        return None;
+    }
+
+    if let Err(e) = db::robots::touch(&robot.name).await {
+        tracing::error!("failed to record use of robot {}: {}", robot.name, e);
+    }
+    Some(Principal {
+        name: format!("{}{}", PREFIX, robot.name),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bearer, generate, parse_permissions, Action};
+
+    #[test]
+    fn test_parse_permissions() {
+        assert_eq!(
+            parse_permissions(" team/**:push  base/*:pull ").unwrap(),
+            vec![
+                ("team/**".to_string(), Action::Push),
+                ("base/*".to_string(), Action::Pull)
+            ]
+        );
+        assert!(parse_permissions("team/**").is_err());
+        assert!(parse_permissions("team/**:fly").is_err());
+        assert!(parse_permissions(":pull").is_err());
+    }
+
+    #[test]
+    fn test_bearer() {
+        let token = generate();
+        assert_eq!(bearer(&format!("Bearer {}", token)), Some(token.as_str()));
+        assert_eq!(bearer("Bearer eyJhbGciOiJIUzI1NiJ9.e30.sig"), None);
+        assert_eq!(bearer(&format!("Basic {}", token)), None);
+    }
+}
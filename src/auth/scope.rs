@@ -0,0 +1,138 @@
+//! Scopes of the Docker token authentication protocol.
+//!
+//! A scope such as `repository:library/nginx:pull,push` names a resource and the actions wanted on it.
+//! [required] works out the scope a `/v2` request needs from its method and path.
+use std::fmt;
+
+use axum::http::Method;
+use serde::{Deserialize, Serialize};
+
+/// A resource and the actions on it, as requested in a scope and granted in a token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scope {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    pub actions: Vec<String>,
+}
+
+impl Scope {
+    pub fn repository(name: &str, action: &str) -> Self {
+        Scope {
+            kind: "repository".to_string(),
+            name: name.to_string(),
+            actions: vec![action.to_string()],
+        }
+    }
+
+    /// Parse a scope like `repository:library/nginx:pull,push`
+    pub fn parse(scope: &str) -> Option<Self> {
+        let (kind, rest) = scope.split_once(':')?;
+        let (name, actions) = rest.rsplit_once(':')?;
+        if kind.is_empty() || name.is_empty() {
+            return None;
+        }
+
+        Some(Scope {
+            kind: kind.to_string(),
+            name: name.to_string(),
+            actions: actions
+                .split(',')
+                .filter(|a| !a.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+
+    /// Whether `granted` allows everything this scope asks for. `*` grants all actions.
+    pub fn allowed_by(&self, granted: &[Scope]) -> bool {
+        self.actions.iter().all(|action| {
+            granted.iter().any(|g| {
+                g.kind == self.kind
+                    && g.name == self.name
+                    && g.actions.iter().any(|a| a == action || a == "*")
+            })
+        })
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.kind, self.name, self.actions.join(","))
+    }
+}
+
+/// The scope a `/v2` request needs, or `None` if any authenticated client may make it
+pub fn required(method: &Method, path: &str) -> Option<Scope> {
+    if path == "/v2/_catalog" {
+        return Some(Scope {
+            kind: "registry".to_string(),
+            name: "catalog".to_string(),
+            actions: vec!["*".to_string()],
+        });
+    }
+
+    // the name the router hands the handler, not just any prefix followed by a resource
+    let name = crate::RepositoryPath::parse(path)?.name;
+    let action = match *method {
+        Method::GET | Method::HEAD => "pull",
+        Method::DELETE => "delete",
+        _ => "push",
+    };
+
+    Some(Scope::repository(&name, action))
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::Method;
+
+    use super::{required, Scope};
+
+    #[test]
+    fn test_parse() {
+        let scope = Scope::parse("repository:library/nginx:pull,push").unwrap();
+        assert_eq!(scope.kind, "repository");
+        assert_eq!(scope.name, "library/nginx");
+        assert_eq!(scope.actions, vec!["pull", "push"]);
+        assert_eq!(scope.to_string(), "repository:library/nginx:pull,push");
+        assert_eq!(Scope::parse("repository"), None);
+    }
+
+    #[test]
+    fn test_allowed_by() {
+        let granted = vec![Scope::parse("repository:app:pull,push").unwrap()];
+        assert!(Scope::repository("app", "pull").allowed_by(&granted));
+        assert!(!Scope::repository("app", "delete").allowed_by(&granted));
+        assert!(!Scope::repository("other", "pull").allowed_by(&granted));
+    }
+
+    #[test]
+    fn test_required() {
+        assert_eq!(required(&Method::GET, "/v2/"), None);
+        assert_eq!(
+            required(&Method::GET, "/v2/library%2Fnginx/manifests/latest"),
+            Some(Scope::repository("library/nginx", "pull"))
+        );
+        assert_eq!(
+            required(&Method::PATCH, "/v2/app/blobs/uploads/1234"),
+            Some(Scope::repository("app", "push"))
+        );
+        assert_eq!(
+            required(&Method::DELETE, "/v2/team/app/manifests/sha256:abc"),
+            Some(Scope::repository("team/app", "delete"))
+        );
+        assert_eq!(
+            required(&Method::PUT, "/v2/a/blobs/b/manifests/latest"),
+            Some(Scope::repository("a/blobs/b", "push"))
+        );
+        assert_eq!(
+            required(&Method::GET, "/v2/a%2Fmanifests%2Fb/tags/list"),
+            Some(Scope::repository("a/manifests/b", "pull"))
+        );
+        assert_eq!(
+            required(&Method::GET, "/v2/_catalog").unwrap().to_string(),
+            "registry:catalog:*"
+        );
+    }
+}
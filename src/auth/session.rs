@@ -0,0 +1,159 @@
+//! Cookie sessions for the web UI.
+//!
+//! Browsers log in once at `/login` instead of sending Basic credentials with every request. The session
+//! cookie holds the user name and an expiry, signed with HMAC-SHA256 using `AUTH_SESSION_SECRET`, so no
+//! session state is kept. Without a secret, one is generated at startup and everyone has to log in again
+//! after a restart. Sessions last `AUTH_SESSION_TTL` seconds, twelve hours by default.
+use axum::extract::Query;
+use axum::http::header::SET_COOKIE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::{Extension, Form};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use sha2::Sha256;
+use tera::{Context, Tera};
+use uuid::Uuid;
+
+use super::basic;
+use crate::events::{self, RequestInfo};
+
+/// The name of the session cookie
+pub const COOKIE: &str = "pequod_session";
+
+lazy_static! {
+    static ref SECRET: Vec<u8> = match std::env::var("AUTH_SESSION_SECRET") {
+        Ok(secret) => secret.into_bytes(),
+        Err(_) => format!("{}{}", Uuid::new_v4(), Uuid::new_v4()).into_bytes(),
+    };
+    static ref TTL: i64 = std::env::var("AUTH_SESSION_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(12 * 60 * 60);
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginQuery {
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+    next: Option<String>,
+}
+
+fn mac(payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&SECRET).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Create the cookie value of a new session for `user`
+pub fn create(user: &str) -> String {
+    let payload = format!("{}:{}", user, Utc::now().timestamp() + *TTL);
+    let signature = mac(&payload)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("{}:{}", payload, signature)
+}
+
+/// The user a session cookie belongs to, unless it was tampered with or expired
+pub fn verify(cookie: &str) -> Option<String> {
+    let (payload, signature) = cookie.rsplit_once(':')?;
+    let signature = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(signature.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    mac(payload).verify_slice(&signature).ok()?;
+
+    let (user, expires) = payload.rsplit_once(':')?;
+    (expires.parse::<i64>().ok()? > Utc::now().timestamp()).then(|| user.to_string())
+}
+
+/// Only redirect to paths on this server after logging in
+fn local(next: Option<&str>) -> &str {
+    next.filter(|n| n.starts_with('/') && !n.starts_with("//"))
+        .unwrap_or("/")
+}
+
+fn render(tera: &Tera, context: Context, status: StatusCode) -> Response {
+    (
+        status,
+        [("Content-Type", "text/html; charset=utf-8")],
+        tera.render("login.html", &context).unwrap(),
+    )
+        .into_response()
+}
+
+/// # GET /login
+#[async_backtrace::framed]
+pub async fn form(Extension(tera): Extension<Tera>, Query(query): Query<LoginQuery>) -> Response {
+    let mut context = Context::new();
+    context.insert("next", local(query.next.as_deref()));
+    render(&tera, context, StatusCode::OK)
+}
+
+/// # POST /login
+#[async_backtrace::framed]
+pub async fn login(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    let next = local(form.next.as_deref()).to_string();
+    if form.username.contains(':') || !basic::verify(&form.username, &form.password).await {
+        tracing::warn!("failed login for {}", form.username);
+        let mut context = Context::new();
+        context.insert("next", &next);
+        context.insert("error", "invalid user name or password");
+        return render(&tera, context, StatusCode::UNAUTHORIZED);
+    }
+
+    tracing::info!("{} logged in", form.username);
+    events::audit(&request, "session.login", None, Some(&form.username), None).await;
+    let mut cookie = format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+        COOKIE,
+        create(&form.username),
+        *TTL
+    );
+    if crate::tls::enabled() {
+        cookie.push_str("; Secure");
+    }
+    ([(SET_COOKIE, cookie)], Redirect::to(&next)).into_response()
+}
+
+/// # POST /logout
+#[async_backtrace::framed]
+pub async fn logout() -> Response {
+    let cookie = format!("{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax", COOKIE);
+    ([(SET_COOKIE, cookie)], Redirect::to("/login")).into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{create, local, verify};
+
+    #[test]
+    fn test_verify() {
+        let cookie = create("alice");
+        assert_eq!(verify(&cookie), Some("alice".to_string()));
+        assert_eq!(verify(&cookie.replacen("alice", "admin", 1)), None);
+        assert_eq!(verify("alice:99999999999:00"), None);
+    }
+
+    #[test]
+    fn test_local() {
+        assert_eq!(local(Some("/admin")), "/admin");
+        assert_eq!(local(Some("//evil.example.com")), "/");
+        assert_eq!(local(Some("https://evil.example.com")), "/");
+        assert_eq!(local(None), "/");
+    }
+}
@@ -0,0 +1,321 @@
+//! Docker token authentication.
+//!
+//! With `AUTH=token`, `/v2` requests need a bearer token listing the [Scope]s the client was granted.
+//! Clients without one are sent to the token endpoint at `AUTH_TOKEN_REALM` with a challenge naming the
+//! scope the request needs. By default that is the built-in [issue] endpoint at `/token`, which checks the
+//! client's Basic credentials and signs its tokens with HS256 using `AUTH_TOKEN_SECRET`. Without a secret,
+//! one is generated at startup, so tokens don't survive a restart and every instance behind a load balancer
+//! needs the same secret.
+//!
+//! Tokens issued by an external token server are accepted if they are signed with the private key belonging
+//! to the PEM public key at `AUTH_TOKEN_ISSUER_KEY`. Either way, a token must name `AUTH_TOKEN_ISSUER` as
+//! its issuer and `AUTH_TOKEN_SERVICE` as its audience, both `pequod` by default.
+use std::collections::HashMap;
+
+use axum::extract::RawQuery;
+use axum::http::header::{AUTHORIZATION, HOST};
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use super::policy::Policy;
+use super::scope::{self, Scope};
+use super::{basic, robots, Principal};
+use crate::api::RegistryError;
+
+lazy_static! {
+    static ref ISSUER: String =
+        std::env::var("AUTH_TOKEN_ISSUER").unwrap_or_else(|_| "pequod".to_string());
+    static ref SERVICE: String =
+        std::env::var("AUTH_TOKEN_SERVICE").unwrap_or_else(|_| "pequod".to_string());
+    static ref REALM: Option<String> = std::env::var("AUTH_TOKEN_REALM").ok();
+    static ref TTL: i64 = std::env::var("AUTH_TOKEN_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(5 * 60);
+    static ref SECRET: Vec<u8> = match std::env::var("AUTH_TOKEN_SECRET") {
+        Ok(secret) => secret.into_bytes(),
+        Err(_) => format!("{}{}", Uuid::new_v4(), Uuid::new_v4()).into_bytes(),
+    };
+    static ref ISSUER_KEY: Option<(DecodingKey, Vec<Algorithm>)> =
+        issuer_key_from_env().expect("AUTH_TOKEN_ISSUER_KEY is validated at startup");
+}
+
+/// The claims of a token, as issued by [issue]
+#[derive(Debug, Serialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: String,
+    exp: i64,
+    nbf: i64,
+    iat: i64,
+    jti: String,
+    access: Vec<Scope>,
+}
+
+/// The claims of a token this registry cares about once it has been validated
+#[derive(Debug, Deserialize)]
+pub struct Grant {
+    #[serde(default)]
+    pub sub: String,
+    #[serde(default)]
+    pub access: Vec<Scope>,
+}
+
+/// The key at `AUTH_TOKEN_ISSUER_KEY`, if it is set, which [crate::config::Config::validate] checks at
+/// startup
+pub fn issuer_key_from_env() -> Result<Option<(DecodingKey, Vec<Algorithm>)>, String> {
+    let Ok(path) = std::env::var("AUTH_TOKEN_ISSUER_KEY") else {
+        return Ok(None);
+    };
+    let pem = std::fs::read(&path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    issuer_key(&pem)
+        .map(Some)
+        .ok_or_else(|| format!("{} is not a PEM encoded public key", path))
+}
+
+/// The key of an external token server and the algorithms it can be used with
+fn issuer_key(pem: &[u8]) -> Option<(DecodingKey, Vec<Algorithm>)> {
+    use Algorithm::*;
+
+    if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
+        return Some((key, vec![RS256, RS384, RS512, PS256, PS384, PS512]));
+    }
+    if let Ok(key) = DecodingKey::from_ec_pem(pem) {
+        return Some((key, vec![ES256, ES384]));
+    }
+    if let Ok(key) = DecodingKey::from_ed_pem(pem) {
+        return Some((key, vec![EdDSA]));
+    }
+    None
+}
+
+/// Sign a token granting `access` to `subject`
+pub fn sign(subject: &str, access: Vec<Scope>) -> Result<String, jsonwebtoken::errors::Error> {
+    let now = Utc::now().timestamp();
+    let claims = Claims {
+        iss: ISSUER.clone(),
+        sub: subject.to_string(),
+        aud: SERVICE.clone(),
+        exp: now + *TTL,
+        nbf: now,
+        iat: now,
+        jti: Uuid::new_v4().to_string(),
+        access,
+    };
+    jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(&SECRET),
+    )
+}
+
+/// Validate a token issued by [sign] or by the external token server
+pub fn verify(token: &str) -> Result<Grant, jsonwebtoken::errors::Error> {
+    let header = jsonwebtoken::decode_header(token)?;
+    let (key, algorithms) = match (header.alg, ISSUER_KEY.as_ref()) {
+        (Algorithm::HS256, _) => (DecodingKey::from_secret(&SECRET), vec![Algorithm::HS256]),
+        (_, Some((key, algorithms))) => (key.clone(), algorithms.clone()),
+        (_, None) => return Err(jsonwebtoken::errors::ErrorKind::InvalidAlgorithm.into()),
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.algorithms = algorithms;
+    validation.set_issuer(&[ISSUER.as_str()]);
+    validation.set_audience(&[SERVICE.as_str()]);
+    validation.validate_nbf = true;
+
+    Ok(jsonwebtoken::decode::<Grant>(token, &key, &validation)?.claims)
+}
+
+/// The `WWW-Authenticate` challenge sent to clients that need a (better) token
+pub fn challenge(host: &str, scope: Option<&Scope>, error: Option<&str>) -> String {
+    let realm = REALM
+        .clone()
+        .unwrap_or_else(|| format!("{}://{}/token", crate::tls::scheme(), host));
+    let mut challenge = format!("Bearer realm=\"{}\",service=\"{}\"", realm, *SERVICE);
+    if let Some(scope) = scope {
+        challenge.push_str(&format!(",scope=\"{}\"", scope));
+    }
+    if let Some(error) = error {
+        challenge.push_str(&format!(",error=\"{}\"", error));
+    }
+    challenge
+}
+
+/// Check the bearer token of a `/v2` request against the scope the request needs
+#[async_backtrace::framed]
+pub async fn authorize<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("localhost")
+        .to_string();
+    let required = scope::required(req.method(), req.uri().path());
+
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|a| a.to_str().ok())
+        .unwrap_or_default();
+    // robots may skip the token endpoint and send their own token, which grants what their permissions allow
+    let grant = match robots::bearer(authorization) {
+        Some(_) => basic::authenticate(authorization)
+            .await
+            .map(|principal| (principal.name, None)),
+        // so may clients with a certificate, whose principal is held to the permissions alone
+        None if authorization.is_empty() => {
+            super::certificate(&req).map(|principal| (principal.name, None))
+        }
+        None => authorization
+            .split_once(' ')
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .and_then(|(_, token)| match verify(token.trim()) {
+                Ok(grant) => Some((grant.sub, Some(grant.access))),
+                Err(e) => {
+                    tracing::warn!("rejected token: {}", e);
+                    None
+                }
+            }),
+    };
+    let (subject, access) = match grant {
+        Some(grant) => grant,
+        None => return super::unauthorized(&challenge(&host, required.as_ref(), None)),
+    };
+
+    let policy = match Policy::load().await {
+        Ok(policy) => policy,
+        Err(e) => {
+            tracing::error!("failed to load permissions: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let user = (!subject.is_empty()).then_some(subject.as_str());
+    // tokens issued by an external token server are held to the local permissions as well, and anonymous
+    // clients are asked to log in rather than denied
+    if !super::authorize(&policy, user, req.method(), req.uri().path()) {
+        if user.is_none() {
+            return super::unauthorized(&challenge(
+                &host,
+                required.as_ref(),
+                Some("insufficient_scope"),
+            ));
+        }
+        tracing::warn!(
+            "denied {} {} to {}",
+            req.method(),
+            req.uri().path(),
+            subject
+        );
+        return RegistryError::Denied.into_response();
+    }
+    if let (Some(required), Some(access)) = (&required, &access) {
+        if !required.allowed_by(access) {
+            return super::unauthorized(&challenge(
+                &host,
+                Some(required),
+                Some("insufficient_scope"),
+            ));
+        }
+    }
+
+    if !subject.is_empty() {
+        req.extensions_mut().insert(Principal { name: subject });
+    }
+    next.run(req).await
+}
+
+/// # GET /token
+/// The built-in token endpoint. Clients that send valid Basic credentials get a token granting the scopes
+/// they ask for as far as the [Policy] allows them, anonymous clients get what the policy allows everyone.
+#[async_backtrace::framed]
+pub async fn issue(headers: HeaderMap, RawQuery(query): RawQuery) -> Response {
+    if *super::MODE != super::Mode::Token {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let mut params: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        params
+            .entry(key.into_owned())
+            .or_default()
+            .push(value.into_owned());
+    }
+    if let Some(service) = params.get("service").and_then(|s| s.first()) {
+        if *service != *SERVICE {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "details": format!("unknown service {}", service) })),
+            )
+                .into_response();
+        }
+    }
+
+    let subject = match headers.get(AUTHORIZATION).and_then(|a| a.to_str().ok()) {
+        Some(authorization) => match basic::authenticate(authorization).await {
+            Some(principal) => Some(principal.name),
+            None => return super::unauthorized(&super::challenge()),
+        },
+        None => None,
+    };
+
+    let policy = match Policy::load().await {
+        Ok(policy) => policy,
+        Err(e) => {
+            tracing::error!("failed to load permissions: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    // docker sends one scope parameter per repository, each of which may hold several space separated scopes
+    let access = params
+        .get("scope")
+        .into_iter()
+        .flatten()
+        .flat_map(|s| s.split_whitespace())
+        .filter_map(Scope::parse)
+        .map(|scope| policy.restrict(subject.as_deref(), scope))
+        .filter(|scope| !scope.actions.is_empty())
+        .collect();
+    let subject = subject.unwrap_or_default();
+
+    match sign(&subject, access) {
+        Ok(token) => Json(json!({
+            "token": token,
+            "access_token": token,
+            "expires_in": *TTL,
+            "issued_at": Utc::now().to_rfc3339(),
+        }))
+        .into_response(),
+        Err(e) => {
+            tracing::error!("failed to sign token for {}: {}", subject, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sign, verify, Scope};
+
+    #[test]
+    fn test_sign_and_verify() {
+        let access = vec![Scope::parse("repository:app:pull,push").unwrap()];
+        let token = sign("alice", access.clone()).unwrap();
+        let grant = verify(&token).unwrap();
+        assert_eq!(grant.sub, "alice");
+        assert_eq!(grant.access, access);
+
+        let mut tampered = token.clone();
+        tampered.push('x');
+        assert!(verify(&tampered).is_err());
+    }
+}
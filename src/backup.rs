@@ -0,0 +1,145 @@
+//! Backups of the database.
+//!
+//! sqlite databases are copied with the online backup API, postgres databases are dumped table by table in
+//! one transaction, so either way a backup is consistent while the registry keeps serving. Blobs live in the
+//! database, so a backup is the whole registry. Scheduled backups and those made through the admin API are
+//! written to `backup.directory`, keeping the newest `backup.keep` of them.
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::db::{self, BackupError, Snapshot};
+
+const PREFIX: &str = "pequod-";
+
+/// A backup in the backup directory
+#[derive(Debug, Serialize)]
+pub struct Backup {
+    pub name: String,
+    pub size: u64,
+}
+
+/// The name of a backup made now, which sorts by when it was made
+pub fn name() -> String {
+    format!(
+        "{}{}.{}",
+        PREFIX,
+        Utc::now().format("%Y%m%dT%H%M%SZ"),
+        db::backup::EXTENSION
+    )
+}
+
+/// Back up the database to `path`. The backup is written next to it first, so `path` only ever holds a
+/// complete backup.
+#[async_backtrace::framed]
+pub async fn create(path: &Path) -> Result<Snapshot, BackupError> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    std::fs::remove_file(&partial).ok();
+
+    match db::backup::create(&partial).await {
+        Ok(snapshot) => {
+            std::fs::rename(&partial, path)?;
+            Ok(snapshot)
+        }
+        Err(e) => {
+            std::fs::remove_file(&partial).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Back up the database into the backup directory and delete the backups beyond `backup.keep`
+#[async_backtrace::framed]
+pub async fn create_in_directory() -> Result<(Backup, Snapshot), BackupError> {
+    let directory = &crate::config::get().backup.directory;
+    std::fs::create_dir_all(directory)?;
+    let name = name();
+    let path = directory.join(&name);
+    let snapshot = create(&path).await?;
+    let size = std::fs::metadata(&path)?.len();
+
+    let names = list()?.into_iter().map(|b| b.name).collect::<Vec<_>>();
+    for name in expired(&names, crate::config::get().backup.keep) {
+        tracing::info!("deleting old backup {}", name);
+        std::fs::remove_file(directory.join(name))?;
+    }
+
+    Ok((Backup { name, size }, snapshot))
+}
+
+/// The backups in the backup directory, newest first
+pub fn list() -> Result<Vec<Backup>, std::io::Error> {
+    let directory = &crate::config::get().backup.directory;
+    if !directory.is_dir() {
+        return Ok(Vec::new());
+    }
+    let suffix = format!(".{}", db::backup::EXTENSION);
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(PREFIX) && name.ends_with(&suffix) && entry.file_type()?.is_file() {
+            backups.push(Backup {
+                name,
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    backups.sort_by(|a, b| b.name.cmp(&a.name));
+    Ok(backups)
+}
+
+/// The backups to delete to keep only the newest `keep`
+fn expired(names: &[String], keep: usize) -> Vec<&str> {
+    let mut names = names.iter().map(String::as_str).collect::<Vec<_>>();
+    names.sort_unstable_by(|a, b| b.cmp(a));
+    names.into_iter().skip(keep).collect()
+}
+
+/// Back up the database every `backup.interval`, which is unset by default
+#[async_backtrace::framed]
+pub async fn schedule() {
+    let Some(seconds) = crate::config::get()
+        .backup
+        .interval
+        .as_deref()
+        .and_then(crate::retention::parse_duration)
+    else {
+        tracing::info!("scheduled backups are disabled");
+        return;
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(seconds as u64));
+    // the first tick is immediate, the first backup is one interval after startup
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match create_in_directory().await {
+            Ok((backup, _)) => {
+                tracing::info!("backed up to {} ({} bytes)", backup.name, backup.size)
+            }
+            Err(e) => tracing::error!("scheduled backup failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::expired;
+
+    #[test]
+    fn test_expired() {
+        let names = [
+            "pequod-20240102T000000Z.db",
+            "pequod-20240103T000000Z.db",
+            "pequod-20240101T000000Z.db",
+        ]
+        .map(String::from);
+        assert_eq!(expired(&names, 2), vec!["pequod-20240101T000000Z.db"]);
+        assert!(expired(&names, 3).is_empty());
+    }
+}
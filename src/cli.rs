@@ -0,0 +1,473 @@
+//! The command line.
+//!
+//! Without a command, or with `serve`, the registry serves HTTP. The other commands work on the configured
+//! database directly, so maintenance can be scripted without the server running.
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+
+use crate::events::{self, RequestInfo};
+use crate::{backup, config, db, distribution, fsck, oci, replication};
+
+/// A container registry
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub overrides: config::Overrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the registry, the default
+    Serve,
+    /// Delete the blobs, tags and repositories no manifest refers to
+    Gc {
+        /// Only report what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Bring the database schema up to date
+    Migrate,
+    /// Inspect repositories
+    #[command(subcommand)]
+    Repos(ReposCommand),
+    /// Manage tags
+    #[command(subcommand)]
+    Tags(TagsCommand),
+    /// Check that tags, manifests and blobs are consistent
+    Fsck,
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Write images to an OCI image layout
+    Export {
+        /// A directory, or a tarball if it ends in .tar, .tar.gz or .tgz
+        output: PathBuf,
+        /// Images to export, as repository for all its tags or repository:tag
+        #[arg(required = true)]
+        images: Vec<String>,
+    },
+    /// Load images from an OCI image layout or a docker save archive
+    Import {
+        /// A directory or a tarball, which may be gzipped
+        input: PathBuf,
+        /// Import into this repository rather than the ones named in the layout
+        #[arg(long)]
+        repository: Option<String>,
+    },
+    /// Copy repositories, manifests, tags and blobs from one database backend to the other
+    #[cfg(all(feature = "sqlite", feature = "postgres"))]
+    Copy {
+        /// The backend to copy from
+        #[arg(long)]
+        from: crate::copy::Backend,
+        /// The backend to copy to, whose schema is brought up to date first
+        #[arg(long)]
+        to: crate::copy::Backend,
+        /// How many blobs to go through at a time
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+        batch_size: u32,
+    },
+    /// Write a consistent backup of the database, while the registry keeps serving
+    Backup {
+        /// The file to write, by default a new backup in `backup.directory`, deleting the ones beyond
+        /// `backup.keep`
+        output: Option<PathBuf>,
+    },
+    /// Replace the contents of the database with a backup, after validating it
+    Restore {
+        /// A backup written by `pequod backup`
+        snapshot: PathBuf,
+        /// Check the restored registry with fsck
+        #[arg(long)]
+        fsck: bool,
+    },
+    /// Import the repositories of a docker/distribution registry from its storage directory
+    ImportRegistry {
+        /// The storage root directory of the registry, holding docker/registry/v2
+        path: PathBuf,
+        /// Import only these repositories
+        repositories: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ReposCommand {
+    /// List repositories with their number of tags
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TagsCommand {
+    /// Delete a tag, regardless of the protection rules
+    Rm {
+        /// The tag to delete, as repository:tag
+        reference: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the configuration in effect, as TOML
+    Show,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CliError {
+    #[error(transparent)]
+    Database(#[from] db::Error),
+    #[error(transparent)]
+    Migration(#[from] db::MigrationError),
+    #[error(transparent)]
+    Oci(#[from] oci::OciError),
+    #[error(transparent)]
+    Distribution(#[from] distribution::DistributionError),
+    #[error(transparent)]
+    Backup(#[from] db::BackupError),
+    #[cfg(all(feature = "sqlite", feature = "postgres"))]
+    #[error(transparent)]
+    Copy(#[from] crate::copy::CopyError),
+    #[error("{0}")]
+    Failed(String),
+}
+
+/// Run a command other than `serve`
+#[async_backtrace::framed]
+pub async fn run(command: Command) -> Result<(), CliError> {
+    match command {
+        Command::Serve => unreachable!("serve is run by main"),
+        Command::Gc { dry_run } => gc(dry_run).await,
+        Command::Migrate => migrate().await,
+        Command::Repos(ReposCommand::List) => list_repositories().await,
+        Command::Tags(TagsCommand::Rm { reference }) => remove_tag(&reference).await,
+        Command::Fsck => check().await,
+        Command::Config(ConfigCommand::Show) => {
+            print!("{}", config::get().show());
+            Ok(())
+        }
+        Command::Export { output, images } => export(&output, &images).await,
+        Command::Import { input, repository } => import(&input, repository.as_deref()).await,
+        Command::ImportRegistry { path, repositories } => {
+            import_registry(&path, &repositories).await
+        }
+        Command::Backup { output } => create_backup(output.as_deref()).await,
+        Command::Restore { snapshot, fsck } => restore(&snapshot, fsck).await,
+        #[cfg(all(feature = "sqlite", feature = "postgres"))]
+        Command::Copy {
+            from,
+            to,
+            batch_size,
+        } => copy(from, to, batch_size).await,
+    }
+}
+
+#[async_backtrace::framed]
+async fn gc(dry_run: bool) -> Result<(), CliError> {
+    let garbage = db::collect_garbage(dry_run).await?;
+    println!(
+        "{} {} blobs ({} bytes), {} tags, {} repositories and {} associations",
+        if dry_run { "would delete" } else { "deleted" },
+        garbage.blobs,
+        garbage.bytes,
+        garbage.tags,
+        garbage.repositories,
+        garbage.associations
+    );
+    if !dry_run {
+        events::audit(
+            &RequestInfo::command("gc"),
+            "registry.cleanup",
+            None,
+            None,
+            None,
+        )
+        .await;
+    }
+    Ok(())
+}
+
+#[async_backtrace::framed]
+async fn migrate() -> Result<(), CliError> {
+    for name in db::migrate().await? {
+        println!("applied {}", name);
+    }
+    println!("the database schema is at version {}", db::latest_version());
+    Ok(())
+}
+
+#[async_backtrace::framed]
+async fn list_repositories() -> Result<(), CliError> {
+    for repository in db::repositories::list().await? {
+        let tags = db::tags::list(&repository.name).await?;
+        println!(
+            "{}\t{}\t{}",
+            repository.name,
+            tags.len(),
+            if repository.immutable {
+                "immutable"
+            } else {
+                "mutable"
+            }
+        );
+    }
+    Ok(())
+}
+
+#[async_backtrace::framed]
+async fn remove_tag(reference: &str) -> Result<(), CliError> {
+    let (repository, tag) = reference
+        .rsplit_once(':')
+        .filter(|(repository, tag)| !repository.is_empty() && !tag.is_empty())
+        .ok_or_else(|| {
+            CliError::Failed(format!("invalid tag {}, use repository:tag", reference))
+        })?;
+
+    let digest = db::tags::get_manifest(repository, tag)
+        .await
+        .map_err(|_| CliError::Failed(format!("tag {}:{} does not exist", repository, tag)))?;
+    let request = RequestInfo::command("tags rm");
+    db::tags::delete(repository, tag, Some(&request.actor)).await?;
+    replication::enqueue(repository, tag, None).await;
+    events::audit(
+        &request,
+        "tag.force_delete",
+        Some(repository),
+        Some(tag),
+        Some(&digest),
+    )
+    .await;
+
+    println!("deleted {}:{}", repository, tag);
+    Ok(())
+}
+
+#[async_backtrace::framed]
+async fn check() -> Result<(), CliError> {
+    let report = fsck::check().await?;
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    println!(
+        "checked {} repositories, {} tags, {} manifests and {} blobs, skipped {} unfinished uploads",
+        report.repositories, report.tags, report.manifests, report.blobs, report.uploads
+    );
+
+    match report.problems.len() {
+        0 => Ok(()),
+        n => Err(CliError::Failed(format!("found {} problems", n))),
+    }
+}
+
+fn print_snapshot(snapshot: &db::Snapshot) {
+    println!("schema version {}", snapshot.schema_version);
+    for (table, rows) in &snapshot.tables {
+        println!("{}\t{}", table, rows);
+    }
+}
+
+#[async_backtrace::framed]
+async fn create_backup(output: Option<&Path>) -> Result<(), CliError> {
+    let (path, snapshot) = match output {
+        Some(path) => (path.to_path_buf(), backup::create(path).await?),
+        None => {
+            let (created, snapshot) = backup::create_in_directory().await?;
+            (config::get().backup.directory.join(created.name), snapshot)
+        }
+    };
+    events::audit(
+        &RequestInfo::command("backup"),
+        "registry.backup",
+        None,
+        Some(&path.to_string_lossy()),
+        None,
+    )
+    .await;
+
+    println!("backed up to {}", path.display());
+    print_snapshot(&snapshot);
+    Ok(())
+}
+
+#[async_backtrace::framed]
+async fn restore(snapshot: &Path, check_after: bool) -> Result<(), CliError> {
+    let restored = db::backup::restore(snapshot).await?;
+    events::audit(
+        &RequestInfo::command("restore"),
+        "registry.restore",
+        None,
+        Some(&snapshot.to_string_lossy()),
+        None,
+    )
+    .await;
+
+    println!("restored {}", snapshot.display());
+    print_snapshot(&restored);
+    match check_after {
+        true => check().await,
+        false => Ok(()),
+    }
+}
+
+#[async_backtrace::framed]
+async fn export(output: &Path, images: &[String]) -> Result<(), CliError> {
+    let (layout, exported) = oci::export(images).await?;
+    layout.write(output).await?;
+
+    let request = RequestInfo::command("export");
+    for image in &exported {
+        events::audit(
+            &request,
+            "image.export",
+            Some(&image.repository),
+            image.tag.as_deref(),
+            Some(&image.digest),
+        )
+        .await;
+        println!("exported {}", image);
+    }
+    Ok(())
+}
+
+#[async_backtrace::framed]
+async fn import(input: &Path, repository: Option<&str>) -> Result<(), CliError> {
+    let layout = oci::Layout::load(input.to_path_buf()).await?;
+    let request = RequestInfo::command("import");
+    for image in oci::import(layout, repository, &request.actor).await? {
+        events::audit(
+            &request,
+            "image.import",
+            Some(&image.repository),
+            image.tag.as_deref(),
+            Some(&image.digest),
+        )
+        .await;
+        println!("imported {} ({})", image, image.digest);
+    }
+    Ok(())
+}
+
+#[async_backtrace::framed]
+async fn import_registry(path: &Path, only: &[String]) -> Result<(), CliError> {
+    let mut importer = distribution::Importer::open(path, RequestInfo::command("import-registry"))?;
+    let repositories = importer.repositories(only)?;
+    for (i, repository) in repositories.iter().enumerate() {
+        let problems = importer.report.problems.len();
+        let refused = importer.report.refused.len();
+        importer.import(repository).await?;
+        for problem in &importer.report.problems[problems..] {
+            println!("{}", problem);
+        }
+        for refused in &importer.report.refused[refused..] {
+            println!("skipped {}", refused);
+        }
+        println!(
+            "[{}/{}] imported {}",
+            i + 1,
+            repositories.len(),
+            repository.name
+        );
+    }
+
+    let report = &importer.report;
+    println!(
+        "imported {} blobs, {} manifests and {} tags from {} repositories, skipped {} blobs, {} manifests and {} tags already stored and {} protected tags",
+        report.blobs.imported,
+        report.manifests.imported,
+        report.tags.imported,
+        report.repositories,
+        report.blobs.existing,
+        report.manifests.existing,
+        report.tags.existing,
+        report.refused.len()
+    );
+
+    let verification = importer.verify(&repositories).await?;
+    for problem in &verification.problems {
+        println!("{}", problem);
+    }
+    println!(
+        "verified {} tags, {} manifests and {} blobs",
+        verification.tags, verification.manifests, verification.blobs
+    );
+    match verification.problems.len() {
+        0 => Ok(()),
+        n => Err(CliError::Failed(format!(
+            "found {} differences, run the import again once they are fixed",
+            n
+        ))),
+    }
+}
+
+#[cfg(all(feature = "sqlite", feature = "postgres"))]
+#[async_backtrace::framed]
+async fn copy(
+    from: crate::copy::Backend,
+    to: crate::copy::Backend,
+    batch: u32,
+) -> Result<(), CliError> {
+    use crate::copy;
+
+    if from == to {
+        return Err(CliError::Failed(format!(
+            "nothing to do, copying from {} to itself",
+            from
+        )));
+    }
+    for name in copy::prepare(from, to).await? {
+        println!("applied {} to {}", name, to);
+    }
+
+    let mut report = copy::Report::default();
+    copy::copy_blobs(from, to, batch, &mut report, |report| {
+        println!(
+            "blobs: {} copied, {} already there",
+            report.blobs.copied, report.blobs.existing
+        )
+    })
+    .await?;
+
+    let request = RequestInfo::command("copy");
+    let repositories = from.repositories().await?;
+    for (i, repository) in repositories.iter().enumerate() {
+        copy::copy_repository(from, to, repository, &request.actor, &mut report).await?;
+        println!(
+            "[{}/{}] copied {}",
+            i + 1,
+            repositories.len(),
+            repository.name
+        );
+    }
+    for skipped in &report.skipped {
+        println!("skipped {}", skipped);
+    }
+    println!(
+        "copied {} blobs, {} manifests and {} tags from {} repositories, skipped {} blobs, {} manifests and {} tags already there, removed {} tags",
+        report.blobs.copied,
+        report.manifests.copied,
+        report.tags.copied,
+        report.repositories,
+        report.blobs.existing,
+        report.manifests.existing,
+        report.tags.existing,
+        report.removed
+    );
+
+    let verification = copy::verify(from, to, batch).await?;
+    for problem in &verification.problems {
+        println!("{}", problem);
+    }
+    println!(
+        "verified {} repositories, {} tags, {} manifests and {} blobs",
+        verification.repositories, verification.tags, verification.manifests, verification.blobs
+    );
+    match verification.problems.len() {
+        0 => Ok(()),
+        n => Err(CliError::Failed(format!(
+            "found {} differences, run the copy again",
+            n
+        ))),
+    }
+}
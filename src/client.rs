@@ -0,0 +1,355 @@
+//! A minimal client for other registries implementing the distribution API.
+//!
+//! Requests are first sent with whatever credentials worked last time for the same scope. If the registry
+//! answers with `401 Unauthorized`, the client follows the `WWW-Authenticate` challenge, using Basic
+//! credentials directly or exchanging them for a token at the realm of a Bearer challenge, and retries once.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use base64::Engine;
+use bytes::Bytes;
+use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, LOCATION, WWW_AUTHENTICATE};
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+
+/// The manifest media types the client asks for
+pub const MANIFEST_MEDIA_TYPES: [&str; 4] = [
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.oci.image.index.v1+json",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("unexpected status {0} from {1}")]
+    Status(StatusCode, String),
+    #[error("can not answer authentication challenge: {0}")]
+    Challenge(String),
+    #[error("digest of content from {0} does not match {1}")]
+    DigestMismatch(String, String),
+    #[error("invalid upload location {0}")]
+    Location(String),
+}
+
+/// A manifest as served by a remote registry
+#[derive(Debug)]
+pub struct RemoteManifest {
+    pub digest: String,
+    pub media_type: String,
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+pub struct Client {
+    url: String,
+    credentials: Option<(String, String)>,
+    http: reqwest::Client,
+    /// `Authorization` header values that were accepted, by scope
+    authorizations: Mutex<HashMap<String, String>>,
+}
+
+/// Split a `WWW-Authenticate` header into its scheme and parameters
+fn parse_challenge(challenge: &str) -> (String, HashMap<String, String>) {
+    let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+
+    let mut parsed = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_lowercase();
+        let value = value.trim_start();
+        let (value, remainder) = match value.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        parsed.insert(key, value.to_string());
+        rest = remainder.trim_start_matches(',').trim();
+    }
+
+    (scheme.to_lowercase(), parsed)
+}
+
+impl Client {
+    pub fn new(url: &str, credentials: Option<(String, String)>) -> Self {
+        Client {
+            url: url.trim_end_matches('/').to_string(),
+            credentials,
+            http: reqwest::Client::new(),
+            authorizations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn basic(&self) -> Option<String> {
+        self.credentials.as_ref().map(|(username, password)| {
+            let encoded = base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", username, password));
+            format!("Basic {}", encoded)
+        })
+    }
+
+    /// Answer an authentication challenge, returning the `Authorization` header to retry with
+    async fn authenticate(&self, challenge: &str, scope: &str) -> Result<String, ClientError> {
+        let (scheme, params) = parse_challenge(challenge);
+        match scheme.as_str() {
+            "basic" => self
+                .basic()
+                .ok_or_else(|| ClientError::Challenge("no credentials configured".to_string())),
+            "bearer" => {
+                let realm = params
+                    .get("realm")
+                    .ok_or_else(|| ClientError::Challenge(challenge.to_string()))?;
+                let mut query = vec![("scope", params.get("scope").map_or(scope, |s| s))];
+                if let Some(service) = params.get("service") {
+                    query.push(("service", service));
+                }
+
+                let mut request = self.http.get(realm).query(&query);
+                if let Some(basic) = self.basic() {
+                    request = request.header(AUTHORIZATION, basic);
+                }
+                let response = request.send().await?;
+                if !response.status().is_success() {
+                    return Err(ClientError::Status(response.status(), realm.to_string()));
+                }
+                let token = response.json::<TokenResponse>().await?;
+                token
+                    .token
+                    .or(token.access_token)
+                    .map(|t| format!("Bearer {}", t))
+                    .ok_or_else(|| {
+                        ClientError::Challenge(format!("no token returned by {}", realm))
+                    })
+            }
+            _ => Err(ClientError::Challenge(challenge.to_string())),
+        }
+    }
+
+    /// Send a request built by `build`, authenticating for `scope` if the registry asks for it
+    pub async fn send(
+        &self,
+        scope: &str,
+        build: impl Fn(&reqwest::Client) -> RequestBuilder,
+    ) -> Result<Response, ClientError> {
+        let authorization = self.authorizations.lock().unwrap().get(scope).cloned();
+        let mut request = build(&self.http).headers(crate::telemetry::propagation());
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+
+        let response = request.send().await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|c| c.to_str().ok())
+            .ok_or_else(|| ClientError::Status(response.status(), response.url().to_string()))?
+            .to_string();
+        let authorization = self.authenticate(&challenge, scope).await?;
+        self.authorizations
+            .lock()
+            .unwrap()
+            .insert(scope.to_string(), authorization.clone());
+
+        let response = build(&self.http)
+            .headers(crate::telemetry::propagation())
+            .header(AUTHORIZATION, authorization)
+            .send()
+            .await?;
+        Ok(response)
+    }
+
+    /// Fetch a manifest by tag or digest, returning `None` if the registry does not know it
+    pub async fn get_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<Option<RemoteManifest>, ClientError> {
+        let url = format!("{}/v2/{}/manifests/{}", self.url, name, reference);
+        let response = self
+            .send(&format!("repository:{}:pull", name), |http| {
+                http.get(&url)
+                    .header(ACCEPT, MANIFEST_MEDIA_TYPES.join(", "))
+            })
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            status if !status.is_success() => return Err(ClientError::Status(status, url)),
+            _ => {}
+        }
+
+        let media_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|c| c.to_str().ok())
+            .unwrap_or(MANIFEST_MEDIA_TYPES[0])
+            .to_string();
+        let body = response.text().await?;
+        let digest = format!("sha256:{}", sha256::digest(body.as_str()));
+        if crate::DIGEST_REGEX.is_match(reference) && reference != digest {
+            return Err(ClientError::DigestMismatch(url, reference.to_string()));
+        }
+
+        Ok(Some(RemoteManifest {
+            digest,
+            media_type,
+            body,
+        }))
+    }
+
+    /// Fetch a blob, returning `None` if the registry does not know it
+    pub async fn get_blob(&self, name: &str, digest: &str) -> Result<Option<Bytes>, ClientError> {
+        let url = format!("{}/v2/{}/blobs/{}", self.url, name, digest);
+        let response = self
+            .send(&format!("repository:{}:pull", name), |http| http.get(&url))
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            status if !status.is_success() => return Err(ClientError::Status(status, url)),
+            _ => {}
+        }
+
+        let blob = response.bytes().await?;
+        if format!("sha256:{}", sha256::digest(blob.as_ref())) != digest {
+            return Err(ClientError::DigestMismatch(url, digest.to_string()));
+        }
+
+        Ok(Some(blob))
+    }
+
+    /// Check whether the registry already has a blob
+    pub async fn has_blob(&self, name: &str, digest: &str) -> Result<bool, ClientError> {
+        let url = format!("{}/v2/{}/blobs/{}", self.url, name, digest);
+        let response = self
+            .send(&format!("repository:{}:pull,push", name), |http| {
+                http.head(&url)
+            })
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => Err(ClientError::Status(status, url)),
+        }
+    }
+
+    /// Resolve the `Location` header of an upload response against the registry URL
+    fn upload_location(&self, response: &Response) -> Result<Url, ClientError> {
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .ok_or_else(|| ClientError::Location("missing".to_string()))?;
+        Url::parse(&self.url)
+            .and_then(|url| url.join(location))
+            .map_err(|_| ClientError::Location(location.to_string()))
+    }
+
+    /// Upload a blob in a single chunk
+    pub async fn upload_blob(
+        &self,
+        name: &str,
+        digest: &str,
+        blob: Bytes,
+    ) -> Result<(), ClientError> {
+        let scope = format!("repository:{}:pull,push", name);
+        let url = format!("{}/v2/{}/blobs/uploads/", self.url, name);
+        let response = self.send(&scope, |http| http.post(&url)).await?;
+        if response.status() != StatusCode::ACCEPTED {
+            return Err(ClientError::Status(response.status(), url));
+        }
+
+        let location = self.upload_location(&response)?;
+        let response = self
+            .send(&scope, |http| {
+                http.patch(location.clone())
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .body(blob.clone())
+            })
+            .await?;
+        if !response.status().is_success() {
+            return Err(ClientError::Status(response.status(), location.to_string()));
+        }
+
+        let mut location = self.upload_location(&response)?;
+        location.query_pairs_mut().append_pair("digest", digest);
+        let response = self.send(&scope, |http| http.put(location.clone())).await?;
+        if response.status() != StatusCode::CREATED {
+            return Err(ClientError::Status(response.status(), location.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Push a manifest under a tag or its digest
+    pub async fn put_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        media_type: &str,
+        body: &str,
+    ) -> Result<(), ClientError> {
+        let url = format!("{}/v2/{}/manifests/{}", self.url, name, reference);
+        let response = self
+            .send(&format!("repository:{}:pull,push", name), |http| {
+                http.put(&url)
+                    .header(CONTENT_TYPE, media_type)
+                    .body(body.to_string())
+            })
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(ClientError::Status(status, url)),
+        }
+    }
+
+    /// Delete a manifest by tag or digest. Deleting something the registry does not know is not an error.
+    pub async fn delete_manifest(&self, name: &str, reference: &str) -> Result<(), ClientError> {
+        let url = format!("{}/v2/{}/manifests/{}", self.url, name, reference);
+        let response = self
+            .send(&format!("repository:{}:delete", name), |http| {
+                http.delete(&url)
+            })
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(()),
+            status if status.is_success() => Ok(()),
+            status => Err(ClientError::Status(status, url)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_challenge;
+
+    #[test]
+    fn test_parse_bearer_challenge() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/nginx:pull""#,
+        );
+        assert_eq!(scheme, "bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/nginx:pull");
+    }
+
+    #[test]
+    fn test_parse_basic_challenge() {
+        let (scheme, params) = parse_challenge(r#"Basic realm="pequod""#);
+        assert_eq!(scheme, "basic");
+        assert_eq!(params["realm"], "pequod");
+    }
+}
@@ -0,0 +1,509 @@
+//! Configuration.
+//!
+//! Settings are read from the TOML file given with `--config` or `PEQUOD_CONFIG`, or from `pequod.toml` if
+//! it exists. Every setting can be overridden by an environment variable and a command line flag, which
+//! take precedence in that order. The configuration is validated at startup, and `pequod config show`
+//! prints the one in effect.
+//!
+//! ```toml
+//! [server]
+//! bind = "0.0.0.0:5000"
+//! body_limit = "1 GiB"
+//! templates = "src/ui/templates"
+//!
+//! [database]
+//! path = "registry.db"                          # sqlite
+//! url = "postgresql://postgres@localhost:5432"  # postgres
+//! migrate = true
+//!
+//! [backup]
+//! directory = "backups"
+//! interval = "24h"  # unset to only back up on demand
+//! keep = 7
+//!
+//! [telemetry]
+//! otlp_endpoint = "http://localhost:4318"  # OTLP over HTTP, unset to not export spans
+//! file = "spans.jsonl"                     # spans as JSON lines, unset to not write them
+//! service_name = "pequod"
+//!
+//! [health]
+//! min_free_space = "1 GiB"  # /readyz fails with less free where pequod writes
+//! shutdown_delay = "5s"     # how long /readyz fails before the server stops when asked to
+//!
+//! [rate_limit]              # <amount>/<period> token buckets, unset ones don't limit
+//! ip = "600/1m"             # registry requests by client IP
+//! principal = "1200/1m"     # registry requests by authenticated principal
+//! repository = "6000/1m"    # registry requests to a repository
+//! manifests = "300/1m"      # manifest requests by client
+//! blob_bytes = "10 GiB/1m"  # blob bytes pulled and pushed by client
+//! ```
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use bytesize::ByteSize;
+use serde::{Deserialize, Serialize, Serializer};
+
+/// The file read when no configuration file is given, if it exists
+const DEFAULT_PATH: &str = "pequod.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("invalid configuration in {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("invalid {0}: {1}")]
+    Invalid(&'static str, String),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: Server,
+    pub database: Database,
+    pub backup: Backup,
+    pub telemetry: Telemetry,
+    pub health: Health,
+    pub rate_limit: RateLimit,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    /// The address to listen on
+    pub bind: SocketAddr,
+    /// The largest blob upload chunk accepted
+    #[serde(serialize_with = "bytes")]
+    pub body_limit: ByteSize,
+    /// The directory holding the templates of the web UI
+    pub templates: PathBuf,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            bind: SocketAddr::from(([0, 0, 0, 0], 5000)),
+            body_limit: ByteSize::gib(1),
+            templates: PathBuf::from("src/ui/templates"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Database {
+    /// The sqlite database file
+    pub path: PathBuf,
+    /// The postgres connection URL
+    pub url: String,
+    /// Whether to bring the schema up to date at startup, rather than only with `pequod migrate`
+    pub migrate: bool,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        let credentials = match std::env::var("POSTGRES_PASSWORD") {
+            Ok(password) if !password.is_empty() => format!("postgres:{}", password),
+            _ => "postgres".to_string(),
+        };
+        Database {
+            path: PathBuf::from("registry.db"),
+            url: format!("postgresql://{}@localhost:5432", credentials),
+            migrate: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backup {
+    /// Where backups made by the schedule and the admin API are kept
+    pub directory: PathBuf,
+    /// How often to back up, like `24h`, or `None` to only back up on demand
+    pub interval: Option<String>,
+    /// How many backups to keep in the directory, older ones are deleted after each backup
+    pub keep: usize,
+}
+
+impl Default for Backup {
+    fn default() -> Self {
+        Backup {
+            directory: PathBuf::from("backups"),
+            interval: None,
+            keep: 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Telemetry {
+    /// The OTLP/HTTP endpoint of a collector to export spans to, without the `/v1/traces` path
+    pub otlp_endpoint: Option<String>,
+    /// A file to append spans to as JSON lines
+    pub file: Option<PathBuf>,
+    /// The service spans are exported as
+    pub service_name: String,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Telemetry {
+            otlp_endpoint: None,
+            file: None,
+            service_name: "pequod".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Health {
+    /// The free space below which the registry is not ready, on the filesystems of the database and backups
+    #[serde(serialize_with = "bytes")]
+    pub min_free_space: ByteSize,
+    /// How long the registry reports not being ready before it stops accepting connections, so it can be taken
+    /// out of rotation first
+    pub shutdown_delay: String,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            min_free_space: ByteSize::gib(1),
+            shutdown_delay: "5s".to_string(),
+        }
+    }
+}
+
+/// Budgets of requests and bytes, written as `<amount>/<period>` like `600/1m`. Unset budgets don't limit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    /// Registry requests by client IP
+    pub ip: Option<String>,
+    /// Registry requests by authenticated principal
+    pub principal: Option<String>,
+    /// Registry requests to a repository
+    pub repository: Option<String>,
+    /// Manifest requests by client, the principal if authenticated and the IP otherwise
+    pub manifests: Option<String>,
+    /// Blob bytes pulled and pushed by client, like `10 GiB/1m`
+    pub blob_bytes: Option<String>,
+}
+
+/// Settings given on the command line or in the environment, which override the configuration file
+#[derive(Debug, Default, clap::Args)]
+pub struct Overrides {
+    /// The configuration file, `pequod.toml` by default
+    #[arg(long, env = "PEQUOD_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    /// The address to listen on
+    #[arg(long, env = "PEQUOD_BIND", global = true)]
+    pub bind: Option<SocketAddr>,
+    /// The largest blob upload chunk accepted, like `512 MiB`
+    #[arg(long, env = "PEQUOD_BODY_LIMIT", global = true)]
+    pub body_limit: Option<ByteSize>,
+    /// The directory holding the templates of the web UI
+    #[arg(long, env = "PEQUOD_TEMPLATES", global = true)]
+    pub templates: Option<PathBuf>,
+    /// The sqlite database file
+    #[arg(long, env = "PEQUOD_DATABASE_PATH", global = true)]
+    pub database_path: Option<PathBuf>,
+    /// The postgres connection URL
+    #[arg(long, env = "PEQUOD_DATABASE_URL", global = true)]
+    pub database_url: Option<String>,
+    /// Whether to bring the schema up to date at startup
+    #[arg(long, env = "PEQUOD_DATABASE_MIGRATE", global = true)]
+    pub database_migrate: Option<bool>,
+    /// Where backups are kept
+    #[arg(long, env = "PEQUOD_BACKUP_DIRECTORY", global = true)]
+    pub backup_directory: Option<PathBuf>,
+    /// How often to back up, like `24h`
+    #[arg(long, env = "PEQUOD_BACKUP_INTERVAL", global = true)]
+    pub backup_interval: Option<String>,
+    /// How many backups to keep
+    #[arg(long, env = "PEQUOD_BACKUP_KEEP", global = true)]
+    pub backup_keep: Option<usize>,
+    /// The OTLP/HTTP endpoint to export spans to, like `http://localhost:4318`
+    #[arg(long, env = "PEQUOD_TELEMETRY_OTLP_ENDPOINT", global = true)]
+    pub telemetry_otlp_endpoint: Option<String>,
+    /// A file to append spans to as JSON lines
+    #[arg(long, env = "PEQUOD_TELEMETRY_FILE", global = true)]
+    pub telemetry_file: Option<PathBuf>,
+    /// The service spans are exported as
+    #[arg(long, env = "PEQUOD_TELEMETRY_SERVICE_NAME", global = true)]
+    pub telemetry_service_name: Option<String>,
+    /// The free space below which the registry is not ready, like `1 GiB`
+    #[arg(long, env = "PEQUOD_HEALTH_MIN_FREE_SPACE", global = true)]
+    pub health_min_free_space: Option<ByteSize>,
+    /// How long to report not being ready before shutting down, like `5s`
+    #[arg(long, env = "PEQUOD_HEALTH_SHUTDOWN_DELAY", global = true)]
+    pub health_shutdown_delay: Option<String>,
+    /// Registry requests allowed by client IP, like `600/1m`
+    #[arg(long, env = "PEQUOD_RATE_LIMIT_IP", global = true)]
+    pub rate_limit_ip: Option<String>,
+    /// Registry requests allowed by authenticated principal, like `1200/1m`
+    #[arg(long, env = "PEQUOD_RATE_LIMIT_PRINCIPAL", global = true)]
+    pub rate_limit_principal: Option<String>,
+    /// Registry requests allowed to a repository, like `6000/1m`
+    #[arg(long, env = "PEQUOD_RATE_LIMIT_REPOSITORY", global = true)]
+    pub rate_limit_repository: Option<String>,
+    /// Manifest requests allowed by client, like `300/1m`
+    #[arg(long, env = "PEQUOD_RATE_LIMIT_MANIFESTS", global = true)]
+    pub rate_limit_manifests: Option<String>,
+    /// Blob bytes allowed by client, like `10 GiB/1m`
+    #[arg(long, env = "PEQUOD_RATE_LIMIT_BLOB_BYTES", global = true)]
+    pub rate_limit_blob_bytes: Option<String>,
+}
+
+/// Sizes are written as plain numbers of bytes, so that reading them back gives the same size
+fn bytes<S: Serializer>(size: &ByteSize, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(size.as_u64())
+}
+
+impl Config {
+    /// Read the configuration file and apply the overrides. `serving` is whether the server is about to
+    /// start, as opposed to a command.
+    pub fn load(overrides: Overrides, serving: bool) -> Result<Self, ConfigError> {
+        let mut config = match &overrides.config {
+            Some(path) => Self::read(path)?,
+            None if Path::new(DEFAULT_PATH).exists() => Self::read(Path::new(DEFAULT_PATH))?,
+            None => Config::default(),
+        };
+
+        if let Some(bind) = overrides.bind {
+            config.server.bind = bind;
+        }
+        if let Some(body_limit) = overrides.body_limit {
+            config.server.body_limit = body_limit;
+        }
+        if let Some(templates) = overrides.templates {
+            config.server.templates = templates;
+        }
+        if let Some(path) = overrides.database_path {
+            config.database.path = path;
+        }
+        if let Some(url) = overrides.database_url {
+            config.database.url = url;
+        }
+        if let Some(migrate) = overrides.database_migrate {
+            config.database.migrate = migrate;
+        }
+        if let Some(directory) = overrides.backup_directory {
+            config.backup.directory = directory;
+        }
+        if let Some(interval) = overrides.backup_interval {
+            config.backup.interval = Some(interval);
+        }
+        if let Some(keep) = overrides.backup_keep {
+            config.backup.keep = keep;
+        }
+        if let Some(endpoint) = overrides.telemetry_otlp_endpoint {
+            config.telemetry.otlp_endpoint = Some(endpoint);
+        }
+        if let Some(file) = overrides.telemetry_file {
+            config.telemetry.file = Some(file);
+        }
+        if let Some(service_name) = overrides.telemetry_service_name {
+            config.telemetry.service_name = service_name;
+        }
+        if let Some(min_free_space) = overrides.health_min_free_space {
+            config.health.min_free_space = min_free_space;
+        }
+        if let Some(shutdown_delay) = overrides.health_shutdown_delay {
+            config.health.shutdown_delay = shutdown_delay;
+        }
+        if let Some(ip) = overrides.rate_limit_ip {
+            config.rate_limit.ip = Some(ip);
+        }
+        if let Some(principal) = overrides.rate_limit_principal {
+            config.rate_limit.principal = Some(principal);
+        }
+        if let Some(repository) = overrides.rate_limit_repository {
+            config.rate_limit.repository = Some(repository);
+        }
+        if let Some(manifests) = overrides.rate_limit_manifests {
+            config.rate_limit.manifests = Some(manifests);
+        }
+        if let Some(blob_bytes) = overrides.rate_limit_blob_bytes {
+            config.rate_limit.blob_bytes = Some(blob_bytes);
+        }
+
+        config.validate(serving)?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Check the settings that can be checked before the registry starts
+    pub fn validate(&self, serving: bool) -> Result<(), ConfigError> {
+        if self.server.body_limit.as_u64() == 0 {
+            return Err(ConfigError::Invalid(
+                "server.body_limit",
+                "it must be larger than 0".to_string(),
+            ));
+        }
+        if usize::try_from(self.server.body_limit.as_u64()).is_err() {
+            return Err(ConfigError::Invalid(
+                "server.body_limit",
+                format!("{} is too large", self.server.body_limit),
+            ));
+        }
+        // commands don't render pages, so they can run anywhere
+        if serving && !self.server.templates.is_dir() {
+            return Err(ConfigError::Invalid(
+                "server.templates",
+                format!("{} is not a directory", self.server.templates.display()),
+            ));
+        }
+        // authentication is still set in the environment, but it only matters once requests are served
+        if serving {
+            crate::auth::Mode::from_env().map_err(|e| ConfigError::Invalid("AUTH", e))?;
+            crate::auth::token::issuer_key_from_env()
+                .map_err(|e| ConfigError::Invalid("AUTH_TOKEN_ISSUER_KEY", e))?;
+        }
+
+        #[cfg(feature = "sqlite")]
+        {
+            let directory = match self.database.path.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new("."),
+            };
+            if !directory.is_dir() {
+                return Err(ConfigError::Invalid(
+                    "database.path",
+                    format!("directory {} does not exist", directory.display()),
+                ));
+            }
+        }
+        #[cfg(feature = "postgres")]
+        {
+            let url = url::Url::parse(&self.database.url)
+                .map_err(|e| ConfigError::Invalid("database.url", e.to_string()))?;
+            if !["postgres", "postgresql"].contains(&url.scheme()) {
+                return Err(ConfigError::Invalid(
+                    "database.url",
+                    format!("unsupported scheme {}, use postgresql", url.scheme()),
+                ));
+            }
+        }
+
+        if let Some(interval) = &self.backup.interval {
+            if crate::retention::parse_duration(interval).is_none_or(|seconds| seconds <= 0) {
+                return Err(ConfigError::Invalid(
+                    "backup.interval",
+                    format!("{} is not a duration like 24h", interval),
+                ));
+            }
+        }
+        if self.backup.keep == 0 {
+            return Err(ConfigError::Invalid(
+                "backup.keep",
+                "at least one backup must be kept".to_string(),
+            ));
+        }
+
+        if let Some(endpoint) = &self.telemetry.otlp_endpoint {
+            let url = url::Url::parse(endpoint)
+                .map_err(|e| ConfigError::Invalid("telemetry.otlp_endpoint", e.to_string()))?;
+            if !["http", "https"].contains(&url.scheme()) {
+                return Err(ConfigError::Invalid(
+                    "telemetry.otlp_endpoint",
+                    format!("unsupported scheme {}, use http or https", url.scheme()),
+                ));
+            }
+        }
+        if self.telemetry.service_name.is_empty() {
+            return Err(ConfigError::Invalid(
+                "telemetry.service_name",
+                "it must not be empty".to_string(),
+            ));
+        }
+        if crate::retention::parse_duration(&self.health.shutdown_delay).is_none_or(|s| s < 0) {
+            return Err(ConfigError::Invalid(
+                "health.shutdown_delay",
+                format!("{} is not a duration like 5s", self.health.shutdown_delay),
+            ));
+        }
+        for (key, rate, bytes) in [
+            ("rate_limit.ip", &self.rate_limit.ip, false),
+            ("rate_limit.principal", &self.rate_limit.principal, false),
+            ("rate_limit.repository", &self.rate_limit.repository, false),
+            ("rate_limit.manifests", &self.rate_limit.manifests, false),
+            ("rate_limit.blob_bytes", &self.rate_limit.blob_bytes, true),
+        ] {
+            let Some(rate) = rate else {
+                continue;
+            };
+            if crate::ratelimit::Rate::parse(rate, bytes).is_none() {
+                let example = if bytes { "10 GiB/1m" } else { "600/1m" };
+                return Err(ConfigError::Invalid(
+                    key,
+                    format!("{} is not a positive rate like {}", rate, example),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The configuration as TOML, without passwords
+    pub fn show(&self) -> String {
+        let mut config = self.clone();
+        if let Ok(mut url) = url::Url::parse(&config.database.url) {
+            if url.password().is_some_and(|p| !p.is_empty()) {
+                url.set_password(Some("********")).ok();
+                config.database.url = url.to_string();
+            }
+        }
+        toml::to_string_pretty(&config).expect("the configuration can be written as TOML")
+    }
+}
+
+/// Make `config` the configuration in effect
+pub fn init(config: Config) {
+    CONFIG
+        .set(config)
+        .expect("the configuration is initialized once");
+}
+
+/// The configuration in effect, or the defaults if none was loaded
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod test {
+    use bytesize::ByteSize;
+
+    use super::Config;
+
+    #[test]
+    fn test_parse() {
+        let config: Config = toml::from_str(
+            "[server]\nbind = \"127.0.0.1:5001\"\nbody_limit = \"512 MiB\"\n[database]\npath = \"/var/lib/pequod/registry.db\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.server.bind.port(), 5001);
+        assert_eq!(config.server.body_limit, ByteSize::mib(512));
+        assert_eq!(config.server.templates.to_str(), Some("src/ui/templates"));
+        assert_eq!(
+            config.database.path.to_str(),
+            Some("/var/lib/pequod/registry.db")
+        );
+
+        assert!(toml::from_str::<Config>("[server]\nbind = \"localhost\"\n").is_err());
+        assert!(toml::from_str::<Config>("[server]\nport = 5000\n").is_err());
+
+        let shown: Config = toml::from_str(&config.show()).unwrap();
+        assert_eq!(shown.server.body_limit, config.server.body_limit);
+    }
+}
@@ -0,0 +1,481 @@
+//! Copying between database backends.
+//!
+//! `pequod copy` moves the contents of the registry from sqlite to postgres or back: repositories, blobs, manifests
+//! with their blob associations, and tags, in that order so the destination never refers to something it
+//! doesn't have yet. Blobs go a batch at a time, checked against their digests on the way. What the
+//! destination already holds intact is skipped, so an interrupted copy resumes when run again, and copying
+//! again right before switching over catches up with whatever was pushed in the meantime. Tags the source no
+//! longer has are removed from the destination. Users, policies, webhooks and audit events are not copied.
+//!
+//! Both backends are only built together with `--features postgres`, in which case the registry itself runs
+//! on sqlite. Both connect as configured, through `database.path` and `database.url`.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::db::{self, Repository, Tag};
+use crate::fsck::Problem;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CopyError {
+    #[error(transparent)]
+    Sqlite(#[from] db::sqlite::Error),
+    #[error(transparent)]
+    Postgres(#[from] db::postgres::Error),
+    #[error(transparent)]
+    Migration(#[from] db::MigrationError),
+    #[error("the {0} database schema is at version {1}, run pequod migrate against it first")]
+    Outdated(Backend, i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    Sqlite,
+    Postgres,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Sqlite => write!(f, "sqlite"),
+            Backend::Postgres => write!(f, "postgres"),
+        }
+    }
+}
+
+/// The operations a copy needs, on either backend
+impl Backend {
+    #[async_backtrace::framed]
+    async fn schema_version(self) -> Result<i64, CopyError> {
+        Ok(match self {
+            Backend::Sqlite => db::sqlite::schema_version().await?,
+            Backend::Postgres => db::postgres::schema_version().await?,
+        })
+    }
+
+    #[async_backtrace::framed]
+    async fn migrate(self) -> Result<Vec<&'static str>, CopyError> {
+        Ok(match self {
+            Backend::Sqlite => db::sqlite::migrate().await?,
+            Backend::Postgres => db::postgres::migrate().await?,
+        })
+    }
+
+    #[async_backtrace::framed]
+    pub async fn repositories(self) -> Result<Vec<Repository>, CopyError> {
+        Ok(match self {
+            Backend::Sqlite => db::sqlite::repositories::list().await?,
+            Backend::Postgres => db::postgres::repositories::list().await?,
+        })
+    }
+
+    #[async_backtrace::framed]
+    async fn save_repository(self, repository: &Repository) -> Result<(), CopyError> {
+        let Repository { name, immutable } = repository;
+        match self {
+            Backend::Sqlite => {
+                db::sqlite::repositories::save(name).await?;
+                db::sqlite::repositories::set_immutable(name, *immutable).await?;
+            }
+            Backend::Postgres => {
+                db::postgres::repositories::save(name).await?;
+                db::postgres::repositories::set_immutable(name, *immutable).await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[async_backtrace::framed]
+    async fn blob_digests(self, after: &str, limit: u32) -> Result<Vec<String>, CopyError> {
+        Ok(match self {
+            Backend::Sqlite => db::sqlite::blobs::digests_after(after, limit).await?,
+            Backend::Postgres => db::postgres::blobs::digests_after(after, limit).await?,
+        })
+    }
+
+    #[async_backtrace::framed]
+    async fn blob_length(self, digest: &str) -> Option<u64> {
+        match self {
+            Backend::Sqlite => db::sqlite::blobs::length(digest)
+                .await
+                .ok()
+                .map(|l| l as u64),
+            Backend::Postgres => db::postgres::blobs::length(digest)
+                .await
+                .ok()
+                .map(u64::from),
+        }
+    }
+
+    #[async_backtrace::framed]
+    async fn blob(self, digest: &str) -> Result<Bytes, CopyError> {
+        Ok(match self {
+            Backend::Sqlite => db::sqlite::blobs::get(digest).await?,
+            Backend::Postgres => db::postgres::blobs::get(digest).await?,
+        })
+    }
+
+    /// Whether the blob is there and matches its digest
+    #[async_backtrace::framed]
+    async fn holds_blob(self, digest: &str) -> bool {
+        self.blob(digest)
+            .await
+            .is_ok_and(|content| sha256(&content) == digest)
+    }
+
+    #[async_backtrace::framed]
+    async fn save_blob(self, digest: &str, content: &Bytes) -> Result<(), CopyError> {
+        match self {
+            Backend::Sqlite => db::sqlite::blobs::save(digest, content).await?,
+            Backend::Postgres => db::postgres::blobs::save(digest, content).await?,
+        }
+        Ok(())
+    }
+
+    #[async_backtrace::framed]
+    async fn manifest_digests(self, repository: &str) -> Result<Vec<String>, CopyError> {
+        Ok(match self {
+            Backend::Sqlite => db::sqlite::manifests::digests(repository).await?,
+            Backend::Postgres => db::postgres::manifests::digests(repository).await?,
+        })
+    }
+
+    #[async_backtrace::framed]
+    async fn manifest(self, repository: &str, digest: &str) -> Option<String> {
+        match self {
+            Backend::Sqlite => db::sqlite::manifests::get(repository, digest).await.ok(),
+            Backend::Postgres => db::postgres::manifests::get(repository, digest).await.ok(),
+        }
+    }
+
+    #[async_backtrace::framed]
+    async fn save_manifest(
+        self,
+        repository: &str,
+        digest: &str,
+        raw: &str,
+    ) -> Result<(), CopyError> {
+        match self {
+            Backend::Sqlite => db::sqlite::manifests::save(repository, digest, raw).await?,
+            Backend::Postgres => db::postgres::manifests::save(repository, digest, raw).await?,
+        }
+        Ok(())
+    }
+
+    #[async_backtrace::framed]
+    async fn associated(self, manifest: &str) -> Result<Vec<String>, CopyError> {
+        Ok(match self {
+            Backend::Sqlite => db::sqlite::blobs::associated(manifest).await?,
+            Backend::Postgres => db::postgres::blobs::associated(manifest).await?,
+        })
+    }
+
+    #[async_backtrace::framed]
+    async fn associate(self, manifest: &str, blob: &str) -> Result<(), CopyError> {
+        match self {
+            Backend::Sqlite => db::sqlite::blobs::associate(manifest, blob).await?,
+            Backend::Postgres => db::postgres::blobs::associate(manifest, blob).await?,
+        }
+        Ok(())
+    }
+
+    #[async_backtrace::framed]
+    async fn tags(self, repository: &str) -> Result<Vec<Tag>, CopyError> {
+        Ok(match self {
+            Backend::Sqlite => db::sqlite::tags::list(repository).await?,
+            Backend::Postgres => db::postgres::tags::list(repository).await?,
+        })
+    }
+
+    #[async_backtrace::framed]
+    async fn restore_tag(self, repository: &str, tag: &Tag) -> Result<(), CopyError> {
+        match self {
+            Backend::Sqlite => db::sqlite::tags::restore(repository, tag).await?,
+            Backend::Postgres => db::postgres::tags::restore(repository, tag).await?,
+        }
+        Ok(())
+    }
+
+    #[async_backtrace::framed]
+    async fn delete_tag(self, repository: &str, tag: &str, actor: &str) -> Result<(), CopyError> {
+        match self {
+            Backend::Sqlite => db::sqlite::tags::delete(repository, tag, Some(actor)).await?,
+            Backend::Postgres => db::postgres::tags::delete(repository, tag, Some(actor)).await?,
+        }
+        Ok(())
+    }
+}
+
+/// How many of something were copied and how many the destination had already
+#[derive(Debug, Default, Serialize)]
+pub struct Count {
+    pub copied: usize,
+    pub existing: usize,
+}
+
+/// What a copy did
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub repositories: usize,
+    pub blobs: Count,
+    pub manifests: Count,
+    pub tags: Count,
+    /// Tags removed from the destination because the source no longer has them
+    pub removed: usize,
+    /// Blobs and manifests left out because they don't match their digests, and unfinished uploads
+    pub skipped: Vec<Problem>,
+}
+
+/// How the destination compares to the source
+#[derive(Debug, Default, Serialize)]
+pub struct Verification {
+    pub repositories: usize,
+    pub tags: usize,
+    pub manifests: usize,
+    pub blobs: usize,
+    pub problems: Vec<Problem>,
+}
+
+fn problem(
+    problems: &mut Vec<Problem>,
+    repository: Option<&str>,
+    reference: &str,
+    message: String,
+) {
+    problems.push(Problem {
+        repository: repository.map(str::to_string),
+        reference: reference.to_string(),
+        message,
+    });
+}
+
+fn sha256(content: &[u8]) -> String {
+    format!("sha256:{}", sha256::digest(content))
+}
+
+/// Bring the destination schema up to date, after checking the source schema is, returning the migrations
+/// applied to the destination
+#[async_backtrace::framed]
+pub async fn prepare(from: Backend, to: Backend) -> Result<Vec<&'static str>, CopyError> {
+    let version = from.schema_version().await?;
+    if version != db::latest_version() {
+        return Err(CopyError::Outdated(from, version));
+    }
+    to.migrate().await
+}
+
+/// Copy every blob, a batch of `batch` at a time, calling `progress` after each batch
+#[async_backtrace::framed]
+pub async fn copy_blobs(
+    from: Backend,
+    to: Backend,
+    batch: u32,
+    report: &mut Report,
+    mut progress: impl FnMut(&Report),
+) -> Result<(), CopyError> {
+    let mut after = String::new();
+    loop {
+        let digests = from.blob_digests(&after, batch).await?;
+        let Some(last) = digests.last() else {
+            return Ok(());
+        };
+        after = last.clone();
+
+        for digest in digests {
+            // unfinished uploads are stored under their upload ID, and are of no use elsewhere
+            if !crate::DIGEST_REGEX.is_match(&digest) {
+                problem(
+                    &mut report.skipped,
+                    None,
+                    &digest,
+                    "unfinished upload".into(),
+                );
+                continue;
+            }
+            if let Some(length) = to.blob_length(&digest).await {
+                // a blob of the right length can still be a damaged one, which is copied over
+                if Some(length) == from.blob_length(&digest).await && to.holds_blob(&digest).await {
+                    report.blobs.existing += 1;
+                    continue;
+                }
+            }
+            let content = from.blob(&digest).await?;
+            if sha256(&content) != digest {
+                let message = "blob does not match its digest".to_string();
+                problem(&mut report.skipped, None, &digest, message);
+                continue;
+            }
+            to.save_blob(&digest, &content).await?;
+            report.blobs.copied += 1;
+        }
+        progress(report);
+    }
+}
+
+/// Copy a repository with its manifests and tags, after the blobs they refer to are copied
+#[async_backtrace::framed]
+pub async fn copy_repository(
+    from: Backend,
+    to: Backend,
+    repository: &Repository,
+    actor: &str,
+    report: &mut Report,
+) -> Result<(), CopyError> {
+    let name = repository.name.as_str();
+    to.save_repository(repository).await?;
+    report.repositories += 1;
+
+    for digest in from.manifest_digests(name).await? {
+        if to.manifest(name, &digest).await.is_none() {
+            let raw = match from.manifest(name, &digest).await {
+                Some(raw) if sha256(raw.as_bytes()) == digest => raw,
+                _ => {
+                    let message = "manifest does not match its digest".to_string();
+                    problem(&mut report.skipped, Some(name), &digest, message);
+                    continue;
+                }
+            };
+            to.save_manifest(name, &digest, &raw).await?;
+            report.manifests.copied += 1;
+        } else {
+            report.manifests.existing += 1;
+        }
+        // associating again is harmless, and completes a copy interrupted right after the manifest
+        for blob in from.associated(&digest).await? {
+            to.associate(&digest, &blob).await?;
+        }
+    }
+
+    let tags = from.tags(name).await?;
+    let current = to
+        .tags(name)
+        .await?
+        .into_iter()
+        .map(|tag| (tag.name.clone(), tag))
+        .collect::<BTreeMap<_, _>>();
+    for tag in &tags {
+        match current.get(&tag.name) {
+            Some(existing)
+                if existing.manifest == tag.manifest && existing.updated == tag.updated =>
+            {
+                report.tags.existing += 1;
+            }
+            _ => {
+                to.restore_tag(name, tag).await?;
+                report.tags.copied += 1;
+            }
+        }
+    }
+    let names = tags
+        .iter()
+        .map(|tag| tag.name.as_str())
+        .collect::<BTreeSet<_>>();
+    for stale in current.keys().filter(|tag| !names.contains(tag.as_str())) {
+        to.delete_tag(name, stale, actor).await?;
+        report.removed += 1;
+    }
+
+    tracing::info!("copied repository {} from {} to {}", name, from, to);
+    Ok(())
+}
+
+/// Compare the destination to the source: the same repositories, tags pointing to the same manifests,
+/// manifests and blobs that match their digests
+#[async_backtrace::framed]
+pub async fn verify(from: Backend, to: Backend, batch: u32) -> Result<Verification, CopyError> {
+    let mut verification = Verification::default();
+    let problems = &mut verification.problems;
+
+    let destination = to
+        .repositories()
+        .await?
+        .into_iter()
+        .map(|r| (r.name.clone(), r.immutable))
+        .collect::<BTreeMap<_, _>>();
+    for repository in from.repositories().await? {
+        let name = repository.name.as_str();
+        verification.repositories += 1;
+        match destination.get(name) {
+            None => {
+                problem(problems, Some(name), "", "repository is missing".into());
+                continue;
+            }
+            Some(immutable) if *immutable != repository.immutable => {
+                let message = "repository differs in immutability".to_string();
+                problem(problems, Some(name), "", message);
+            }
+            Some(_) => {}
+        }
+
+        let tags = to
+            .tags(name)
+            .await?
+            .into_iter()
+            .map(|tag| (tag.name, tag.manifest))
+            .collect::<BTreeMap<_, _>>();
+        let source = from.tags(name).await?;
+        verification.tags += source.len();
+        for tag in &source {
+            match tags.get(&tag.name) {
+                Some(manifest) if *manifest == tag.manifest => {}
+                Some(manifest) => {
+                    let message =
+                        format!("tag points to {} rather than {}", manifest, tag.manifest);
+                    problem(problems, Some(name), &tag.name, message);
+                }
+                None => problem(problems, Some(name), &tag.name, "tag is missing".into()),
+            }
+        }
+        if tags.len() != source.len() {
+            let message = format!("{} tags rather than {}", tags.len(), source.len());
+            problem(problems, Some(name), "", message);
+        }
+
+        for digest in from.manifest_digests(name).await? {
+            verification.manifests += 1;
+            match to.manifest(name, &digest).await {
+                Some(raw) if sha256(raw.as_bytes()) == digest => {}
+                Some(_) => {
+                    let message = "manifest does not match its digest".to_string();
+                    problem(problems, Some(name), &digest, message);
+                }
+                None => problem(problems, Some(name), &digest, "manifest is missing".into()),
+            }
+        }
+    }
+
+    let mut after = String::new();
+    loop {
+        let digests = from.blob_digests(&after, batch).await?;
+        let Some(last) = digests.last() else {
+            break;
+        };
+        after = last.clone();
+        for digest in digests.iter().filter(|d| crate::DIGEST_REGEX.is_match(d)) {
+            verification.blobs += 1;
+            let (source, destination) =
+                (from.blob_length(digest).await, to.blob_length(digest).await);
+            match destination {
+                Some(_) if destination == source => {
+                    if !to.holds_blob(digest).await {
+                        let message = "blob does not match its digest".to_string();
+                        problem(problems, None, digest, message);
+                    }
+                }
+                Some(length) => {
+                    let message = format!(
+                        "blob holds {} bytes rather than {}",
+                        length,
+                        source.unwrap_or_default()
+                    );
+                    problem(problems, None, digest, message);
+                }
+                None => problem(problems, None, digest, "blob is missing".into()),
+            }
+        }
+    }
+
+    Ok(verification)
+}
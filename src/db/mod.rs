@@ -2,15 +2,63 @@ use chrono::{DateTime, Utc};
 use serde::Serialize;
 
 #[cfg(feature = "sqlite")]
-mod sqlite;
+pub mod sqlite;
 #[cfg(feature = "sqlite")]
 pub use sqlite::*;
 
+// with both backends built, the registry runs on sqlite and postgres is only there for `pequod copy`
 #[cfg(feature = "postgres")]
-mod postgres;
-#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(all(feature = "postgres", not(feature = "sqlite")))]
 pub use postgres::*;
 
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error(transparent)]
+    Database(#[from] Error),
+    #[cfg(all(feature = "sqlite", feature = "postgres"))]
+    #[error(transparent)]
+    Postgres(#[from] postgres::Error),
+    #[error("the database schema is at version {found}, but this version of pequod only supports up to {supported}")]
+    TooNew { found: i64, supported: i64 },
+    #[error("the database schema is at version {found}, run pequod migrate to upgrade it to {supported}")]
+    Outdated { found: i64, supported: i64 },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error(transparent)]
+    Database(#[from] Error),
+    #[cfg(all(feature = "sqlite", feature = "postgres"))]
+    #[error(transparent)]
+    Postgres(#[from] postgres::Error),
+    #[error(transparent)]
+    Migration(#[from] MigrationError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("invalid backup: {0}")]
+    Invalid(String),
+}
+
+/// What a backup holds
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub schema_version: i64,
+    /// The number of rows in each table
+    pub tables: std::collections::BTreeMap<String, u64>,
+}
+
+/// Check that the schema of the database is the one this build of pequod expects
+#[async_backtrace::framed]
+pub async fn check_schema() -> Result<(), MigrationError> {
+    let (found, supported) = (schema_version().await?, latest_version());
+    match found.cmp(&supported) {
+        std::cmp::Ordering::Greater => Err(MigrationError::TooNew { found, supported }),
+        std::cmp::Ordering::Less => Err(MigrationError::Outdated { found, supported }),
+        std::cmp::Ordering::Equal => Ok(()),
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct Tag {
     pub name: String,
@@ -18,7 +66,225 @@ pub struct Tag {
     pub manifest: String,
 }
 
+/// A tag being created, moved to a different manifest or deleted
+#[derive(Debug, Serialize)]
+pub struct TagHistory {
+    pub id: i64,
+    pub repository: String,
+    pub tag: String,
+    /// The manifest the tag pointed to before, if it existed
+    pub old_manifest: Option<String>,
+    /// The manifest the tag points to now, unless it was deleted
+    pub new_manifest: Option<String>,
+    pub updated: DateTime<Utc>,
+    /// Who changed the tag
+    pub actor: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct Repository {
     pub name: String,
+    /// Tags in an immutable repository can not be moved to a different manifest once pushed
+    pub immutable: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RetentionPolicy {
+    pub id: i64,
+    /// Glob matched against repository names, see [crate::pattern::matches]
+    pub repository: String,
+    /// Always keep this many of the most recently updated tags
+    pub keep_latest: Option<i64>,
+    /// Only delete tags that have not been updated for this many seconds
+    pub max_age: Option<i64>,
+    /// Only consider tags matching this regular expression
+    pub tag_pattern: Option<String>,
+    /// Whitespace-separated regular expressions for tags that are never deleted
+    pub exempt: Option<String>,
+    /// Policies that are not enforced only report what they would delete
+    pub enforce: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagProtection {
+    pub id: i64,
+    /// Glob matched against repository names, see [crate::pattern::matches]
+    pub repository: String,
+    /// Regular expression matched against tag names, see [crate::pattern::tag_regex]
+    pub tag: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Quota {
+    pub id: i64,
+    /// Glob matched against repository names, see [crate::pattern::matches]. The quota applies to all
+    /// matching repositories together.
+    pub repository: String,
+    pub max_bytes: Option<i64>,
+    pub max_tags: Option<i64>,
+}
+
+/// Storage used by a set of repositories, counting blobs shared between them once
+#[derive(Debug, Default, Serialize)]
+pub struct Usage {
+    pub bytes: i64,
+    pub tags: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicationRule {
+    pub id: i64,
+    /// Glob matched against repository names, see [crate::pattern::matches]
+    pub repository: String,
+    /// Only replicate tags matching this regular expression, see [crate::pattern::tag_regex]
+    pub tag_pattern: Option<String>,
+    /// Base URL of the registry to replicate to
+    pub target: String,
+    pub username: Option<String>,
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// When a task for this rule last succeeded
+    pub last_replicated: Option<DateTime<Utc>>,
+    /// Why the last failed task for this rule failed, cleared by the next success
+    pub last_error: Option<String>,
+}
+
+/// A tag waiting to be replicated by a rule
+#[derive(Debug, Serialize)]
+pub struct ReplicationTask {
+    pub id: i64,
+    pub rule: i64,
+    pub repository: String,
+    pub tag: String,
+    /// The manifest to push, or `None` if the tag should be deleted from the target
+    pub manifest: Option<String>,
+    pub attempts: i64,
+    pub next_attempt: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Webhook {
+    pub id: i64,
+    /// Where notifications are posted to
+    pub url: String,
+    /// Glob matched against repository names, see [crate::pattern::matches]
+    pub repository: String,
+    /// Whitespace-separated actions to notify about, or `None` for all of them
+    pub actions: Option<String>,
+    /// Key used to sign notifications with HMAC-SHA256
+    #[serde(skip_serializing)]
+    pub secret: Option<String>,
+    /// When a notification was last delivered
+    pub last_delivered: Option<DateTime<Utc>>,
+    /// Why the last failed delivery failed, cleared by the next success
+    pub last_error: Option<String>,
+}
+
+/// A notification waiting to be delivered to a webhook
+#[derive(Debug, Serialize)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook: i64,
+    /// The notification envelope to post
+    pub payload: String,
+    pub attempts: i64,
+    pub next_attempt: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+/// An operation recorded in the audit log
+#[derive(Debug, Serialize)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    /// `push`, `pull` and `delete` for registry operations, names such as `quota.create` for admin operations
+    pub action: String,
+    pub repository: Option<String>,
+    /// The tag, digest or setting the operation was about
+    pub reference: Option<String>,
+    pub digest: Option<String>,
+    /// IP address of the client
+    pub addr: Option<String>,
+    pub user_agent: Option<String>,
+    /// The authenticated user, if any
+    pub principal: Option<String>,
+}
+
+/// Which audit events to list. Unset criteria match everything.
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    pub action: Option<String>,
+    pub repository: Option<String>,
+    pub principal: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only list events older than this one, for paging through results
+    pub before: Option<i64>,
+    pub limit: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct User {
+    pub name: String,
+    /// bcrypt hash of the password
+    #[serde(skip_serializing)]
+    pub password: String,
+    pub created: DateTime<Utc>,
+}
+
+/// A permission on the repositories matching a glob pattern
+#[derive(Debug, Serialize)]
+pub struct Permission {
+    pub id: i64,
+    /// A user name, a group name prefixed with `@`, or `*` for everyone
+    pub subject: String,
+    pub repository: String,
+    /// `pull`, `push`, `delete` or `admin`, each implying the ones before it
+    pub action: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupMember {
+    pub group: String,
+    pub user: String,
+}
+
+/// An account for automation, authenticating with a generated token instead of a password
+#[derive(Debug, Serialize)]
+pub struct Robot {
+    pub name: String,
+    /// SHA-256 of the token
+    #[serde(skip_serializing)]
+    pub token: String,
+    /// Space separated `pattern:action` pairs such as `team-a/**:push`
+    pub permissions: String,
+    pub expires: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub created: DateTime<Utc>,
+}
+
+/// What garbage collection deleted, or would delete in a dry run
+#[derive(Debug, Default, Serialize)]
+pub struct Garbage {
+    /// Associations between manifests and blobs whose manifest is gone
+    pub associations: u64,
+    pub blobs: u64,
+    /// The size of the deleted blobs
+    pub bytes: u64,
+    pub tags: u64,
+    pub repositories: u64,
+}
+
+/// What the registry stores, for metrics
+#[derive(Debug, Default, Serialize)]
+pub struct Statistics {
+    pub repositories: i64,
+    pub tags: i64,
+    pub manifests: i64,
+    /// Finished blobs
+    pub blobs: i64,
+    pub blob_bytes: i64,
+    /// Blobs of uploads that are not finished yet, stored under their upload ID
+    pub uploads: i64,
 }
@@ -0,0 +1,101 @@
+use tokio_postgres::Error as PostgresError;
+
+use crate::db::{GroupMember, Permission};
+
+#[tracing::instrument(name = "db::access::permissions", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn permissions() -> Result<Vec<Permission>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, subject, repository, action FROM permissions ORDER BY id ASC",
+            &[],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| Permission {
+            id: row.get(0),
+            subject: row.get(1),
+            repository: row.get(2),
+            action: row.get(3),
+        })
+        .collect())
+}
+
+#[tracing::instrument(name = "db::access::grant", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn grant(subject: &str, repository: &str, action: &str) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO permissions (subject, repository, action) VALUES ($1, $2, $3)",
+        &[&subject, &repository, &action],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::access::revoke", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn revoke(id: i64) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute("DELETE FROM permissions WHERE id = $1", &[&id])
+        .await?;
+    tracing::info!("deleted permission {}", id);
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::access::members", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn members() -> Result<Vec<GroupMember>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT group_name, user_name FROM group_members ORDER BY group_name ASC, user_name ASC",
+            &[],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| GroupMember {
+            group: row.get(0),
+            user: row.get(1),
+        })
+        .collect())
+}
+
+#[tracing::instrument(name = "db::access::add_member", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn add_member(group: &str, user: &str) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO group_members (group_name, user_name) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        &[&group, &user],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::access::remove_member", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn remove_member(group: &str, user: &str) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "DELETE FROM group_members WHERE group_name = $1 AND user_name = $2",
+        &[&group, &user],
+    )
+    .await?;
+
+    Ok(())
+}
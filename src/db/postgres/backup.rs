@@ -0,0 +1,355 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio_postgres::{Error as PostgresError, IsolationLevel, Transaction};
+
+use crate::db::{BackupError, Snapshot};
+
+/// Backups are tarballs of the tables in the text format of COPY, described by [MANIFEST]
+pub const EXTENSION: &str = "tar";
+
+const MANIFEST: &str = "backup.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    schema_version: i64,
+    created: i64,
+    /// In the order they can be restored in, tables before those referring to them
+    tables: Vec<Table>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Table {
+    name: String,
+    columns: Vec<String>,
+    rows: u64,
+    sha256: String,
+}
+
+impl Manifest {
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            schema_version: self.schema_version,
+            tables: self
+                .tables
+                .iter()
+                .map(|t| (t.name.clone(), t.rows))
+                .collect(),
+        }
+    }
+}
+
+fn ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn entry_name(table: &str) -> String {
+    format!("tables/{}.copy", table)
+}
+
+/// The tables of the schema but the schema version, those referred to by foreign keys first
+#[async_backtrace::framed]
+async fn tables(trans: &Transaction<'_>) -> Result<Vec<String>, PostgresError> {
+    let names: Vec<String> = trans
+        .query(
+            "SELECT tablename::TEXT FROM pg_tables WHERE schemaname = current_schema() AND tablename <> 'schema_version' ORDER BY tablename",
+            &[],
+        )
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    let references: Vec<(String, String)> = trans
+        .query(
+            "SELECT conrelid::regclass::TEXT, confrelid::regclass::TEXT FROM pg_constraint WHERE contype = 'f' AND connamespace = current_schema()::regnamespace",
+            &[],
+        )
+        .await?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+
+    let mut ordered: Vec<String> = Vec::new();
+    let mut pending = names;
+    while !pending.is_empty() {
+        let done = ordered.iter().collect::<HashSet<_>>();
+        let (ready, rest): (Vec<_>, Vec<_>) = pending.iter().cloned().partition(|table| {
+            references.iter().all(|(from, to)| {
+                from != table || to == table || done.contains(to) || !pending.contains(to)
+            })
+        });
+        // a cycle of references can't be ordered, those tables go last as they are
+        if ready.is_empty() {
+            ordered.extend(rest);
+            break;
+        }
+        ordered.extend(ready);
+        pending = rest;
+    }
+    Ok(ordered)
+}
+
+#[async_backtrace::framed]
+async fn columns(trans: &Transaction<'_>, table: &str) -> Result<Vec<String>, PostgresError> {
+    let rows = trans
+        .query(
+            "SELECT column_name::TEXT FROM information_schema.columns WHERE table_schema = current_schema() AND table_name = $1 ORDER BY ordinal_position",
+            &[&table],
+        )
+        .await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Dump a table into `file`
+#[async_backtrace::framed]
+async fn dump(trans: &Transaction<'_>, table: &str, file: &Path) -> Result<Table, BackupError> {
+    let columns = columns(trans, table).await?;
+    let statement = format!(
+        "COPY {} ({}) TO STDOUT",
+        ident(table),
+        columns
+            .iter()
+            .map(|c| ident(c))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let stream = trans.copy_out(&statement).await?;
+    futures_util::pin_mut!(stream);
+
+    let mut output = File::create(file)?;
+    let mut hash = Sha256::new();
+    let mut rows = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        // rows end in newlines, those within values are escaped
+        rows += chunk.iter().filter(|b| **b == b'\n').count() as u64;
+        hash.update(&chunk);
+        output.write_all(&chunk)?;
+    }
+    output.sync_all()?;
+
+    Ok(Table {
+        name: table.to_string(),
+        columns,
+        rows,
+        sha256: format!("{:x}", hash.finalize()),
+    })
+}
+
+/// Write a consistent logical dump of every table to `path`, all read in one repeatable read transaction
+#[tracing::instrument(name = "db::backup::create", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn create(path: &Path) -> Result<Snapshot, BackupError> {
+    let mut db = super::db().await;
+    let trans = db
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()
+        .await?;
+    let schema_version: i64 = trans
+        .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])
+        .await?
+        .get(0);
+
+    let mut manifest = Manifest {
+        schema_version,
+        created: Utc::now().timestamp(),
+        tables: Vec::new(),
+    };
+    let mut archive = tar::Builder::new(File::create(path)?);
+    // tables are dumped to a file first, as the archive needs to know their size
+    let scratch = path.with_extension("table");
+    let dumped = async {
+        for table in tables(&trans).await? {
+            let table = dump(&trans, &table, &scratch).await?;
+            archive.append_path_with_name(&scratch, entry_name(&table.name))?;
+            manifest.tables.push(table);
+        }
+        Ok::<_, BackupError>(())
+    }
+    .await;
+    std::fs::remove_file(&scratch).ok();
+    dumped?;
+    trans.commit().await?;
+
+    let json = serde_json::to_vec_pretty(&manifest).expect("manifests can be serialized");
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive.append_data(&mut header, MANIFEST, json.as_slice())?;
+    archive.into_inner()?.sync_all()?;
+
+    Ok(manifest.snapshot())
+}
+
+/// The SHA-256 and number of rows of the files in a backup
+type Checksums = BTreeMap<String, (String, u64)>;
+
+/// The manifest of a backup, and the checksum and number of rows of every other file in it
+fn entries(path: &Path) -> Result<(Option<Vec<u8>>, Checksums), std::io::Error> {
+    let mut manifest = None;
+    let mut found = BTreeMap::new();
+    let mut archive = tar::Archive::new(File::open(path)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if name == MANIFEST {
+            let mut json = Vec::new();
+            entry.read_to_end(&mut json)?;
+            manifest = Some(json);
+            continue;
+        }
+
+        let mut hash = Sha256::new();
+        let mut rows = 0;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = entry.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            rows += buffer[..read].iter().filter(|b| **b == b'\n').count() as u64;
+            hash.update(&buffer[..read]);
+        }
+        found.insert(name, (format!("{:x}", hash.finalize()), rows));
+    }
+    Ok((manifest, found))
+}
+
+/// Read the manifest of a backup and check every table in it is complete
+fn read(path: &Path) -> Result<Manifest, BackupError> {
+    let (manifest, found) = entries(path)
+        .map_err(|e| BackupError::Invalid(format!("can't read {}: {}", path.display(), e)))?;
+    let manifest: Manifest = manifest
+        .ok_or_else(|| BackupError::Invalid(format!("{} has no {}", path.display(), MANIFEST)))
+        .and_then(|json| {
+            serde_json::from_slice(&json)
+                .map_err(|e| BackupError::Invalid(format!("unreadable {}: {}", MANIFEST, e)))
+        })?;
+    for table in &manifest.tables {
+        match found.get(&entry_name(&table.name)) {
+            Some((sha256, rows)) if *sha256 == table.sha256 && *rows == table.rows => {}
+            Some(_) => {
+                return Err(BackupError::Invalid(format!(
+                    "table {} does not match its checksum",
+                    table.name
+                )))
+            }
+            None => {
+                return Err(BackupError::Invalid(format!(
+                    "table {} is missing",
+                    table.name
+                )))
+            }
+        }
+    }
+    if manifest.schema_version > super::latest_version() {
+        return Err(BackupError::Invalid(format!(
+            "{} is at schema version {}, newer than the {} this version of pequod supports",
+            path.display(),
+            manifest.schema_version,
+            super::latest_version()
+        )));
+    }
+    Ok(manifest)
+}
+
+/// Check that `path` is an intact backup this build can restore, and describe it
+#[tracing::instrument(name = "db::backup::validate", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn validate(path: &Path) -> Result<Snapshot, BackupError> {
+    read(path).map(|manifest| manifest.snapshot())
+}
+
+/// Replace the contents of the database with the backup at `path`, after validating it. The schema is brought
+/// up to date first, and the whole restore is one transaction.
+#[tracing::instrument(name = "db::backup::restore", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn restore(path: &Path) -> Result<Snapshot, BackupError> {
+    let manifest = read(path)?;
+    super::migrate().await?;
+
+    let mut db = super::db().await;
+    let trans = db.transaction().await?;
+    let existing = tables(&trans).await?;
+    for table in &manifest.tables {
+        if !existing.contains(&table.name) {
+            return Err(BackupError::Invalid(format!(
+                "table {} does not exist in the database",
+                table.name
+            )));
+        }
+    }
+    let all = existing.iter().map(|t| ident(t)).collect::<Vec<_>>();
+    trans
+        .batch_execute(&format!(
+            "TRUNCATE {} RESTART IDENTITY CASCADE",
+            all.join(", ")
+        ))
+        .await?;
+
+    let mut archive = tar::Archive::new(File::open(path)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let Some(table) = manifest.tables.iter().find(|t| entry_name(&t.name) == name) else {
+            continue;
+        };
+
+        let statement = format!(
+            "COPY {} ({}) FROM STDIN",
+            ident(&table.name),
+            table
+                .columns
+                .iter()
+                .map(|c| ident(c))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let sink = trans.copy_in::<_, Bytes>(&statement).await?;
+        futures_util::pin_mut!(sink);
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = entry.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            sink.send(Bytes::copy_from_slice(&buffer[..read])).await?;
+        }
+        sink.finish().await?;
+    }
+
+    // continue the sequences of generated IDs after the restored rows
+    let sequences = trans
+        .query(
+            "SELECT table_name::TEXT, column_name::TEXT, pg_get_serial_sequence(quote_ident(table_name), column_name) FROM information_schema.columns WHERE table_schema = current_schema() AND pg_get_serial_sequence(quote_ident(table_name), column_name) IS NOT NULL",
+            &[],
+        )
+        .await?;
+    for row in sequences {
+        let (table, column, sequence): (String, String, String) =
+            (row.get(0), row.get(1), row.get(2));
+        trans
+            .execute(
+                &format!(
+                    "SELECT setval($1::TEXT::regclass, COALESCE((SELECT MAX({}) FROM {}), 0) + 1, false)",
+                    ident(&column),
+                    ident(&table)
+                ),
+                &[&sequence],
+            )
+            .await?;
+    }
+    trans.commit().await?;
+
+    Ok(manifest.snapshot())
+}
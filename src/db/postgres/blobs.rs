@@ -1,9 +1,10 @@
 use bytes::Bytes;
 use tokio_postgres::Error as PostgresError;
 
+#[tracing::instrument(name = "db::blobs::get", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn get(digest: &str) -> Result<Bytes, PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     let row = db
         .query_one("SELECT value FROM blobs WHERE digest = $1", &[&digest])
@@ -13,9 +14,10 @@ pub async fn get(digest: &str) -> Result<Bytes, PostgresError> {
     Ok(Bytes::from_iter(value))
 }
 
+#[tracing::instrument(name = "db::blobs::length", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn length(digest: &str) -> Result<u32, PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     let size = db
         .query_one(
@@ -28,9 +30,53 @@ pub async fn length(digest: &str) -> Result<u32, PostgresError> {
     Ok(size)
 }
 
+/// The digests of all blobs, including the IDs of unfinished uploads
+#[tracing::instrument(name = "db::blobs::digests", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn digests() -> Result<Vec<String>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db.query("SELECT digest FROM blobs", &[]).await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Up to `limit` digests of blobs after `after`, in order, to go through all blobs a batch at a time
+#[tracing::instrument(name = "db::blobs::digests_after", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn digests_after(after: &str, limit: u32) -> Result<Vec<String>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT digest FROM blobs WHERE digest > $1 ORDER BY digest LIMIT $2",
+            &[&after, &i64::from(limit)],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// The blobs associated with a manifest
+#[tracing::instrument(name = "db::blobs::associated", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn associated(manifest_digest: &str) -> Result<Vec<String>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT blob FROM manifest_blobs WHERE manifest = $1",
+            &[&manifest_digest],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+#[tracing::instrument(name = "db::blobs::save", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn save(digest: &str, value: &Bytes) -> Result<(), PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     db.execute(
         "INSERT INTO blobs (digest, value)
@@ -44,9 +90,10 @@ pub async fn save(digest: &str, value: &Bytes) -> Result<(), PostgresError> {
     Ok(())
 }
 
+#[tracing::instrument(name = "db::blobs::update_digest", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn update_digest(old_digest: &str, new_digest: &str) -> Result<(), PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     db.execute(
         "UPDATE blobs SET digest = $1 WHERE digest = $2 AND NOT EXISTS (SELECT 1 FROM blobs WHERE digest = $1)",
@@ -57,9 +104,10 @@ pub async fn update_digest(old_digest: &str, new_digest: &str) -> Result<(), Pos
     Ok(())
 }
 
+#[tracing::instrument(name = "db::blobs::associate", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn associate(manifest_digest: &str, layer_digest: &str) -> Result<(), PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     db.execute(
         "INSERT INTO manifest_blobs(manifest, blob) VALUES ($1, $2) ON CONFLICT(manifest, blob) DO NOTHING",
@@ -70,9 +118,10 @@ pub async fn associate(manifest_digest: &str, layer_digest: &str) -> Result<(),
     Ok(())
 }
 
+#[tracing::instrument(name = "db::blobs::disassociate", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn disassociate(repository: &str, layer_digest: &str) -> Result<(), PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     let deleted = db
         .query(
@@ -0,0 +1,78 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tokio_postgres::Error as PostgresError;
+
+use crate::db::{AuditEvent, EventFilter};
+
+#[tracing::instrument(name = "db::events::save", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn save(event: &AuditEvent) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO events (timestamp, action, repository, reference, digest, addr, user_agent, principal) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        &[
+            &event.timestamp.timestamp(),
+            &event.action,
+            &event.repository,
+            &event.reference,
+            &event.digest,
+            &event.addr,
+            &event.user_agent,
+            &event.principal,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// List events matching `filter`, newest first
+#[tracing::instrument(name = "db::events::list", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn list(filter: &EventFilter) -> Result<Vec<AuditEvent>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "
+    SELECT id, timestamp, action, repository, reference, digest, addr, user_agent, principal
+        FROM events
+            WHERE ($1::TEXT IS NULL OR action = $1)
+                AND ($2::TEXT IS NULL OR repository = $2)
+                AND ($3::TEXT IS NULL OR principal = $3)
+                AND ($4::BIGINT IS NULL OR timestamp >= $4)
+                AND ($5::BIGINT IS NULL OR timestamp <= $5)
+                AND ($6::BIGINT IS NULL OR id < $6)
+            ORDER BY id DESC
+            LIMIT $7",
+            &[
+                &filter.action,
+                &filter.repository,
+                &filter.principal,
+                &filter.since.map(|s| s.timestamp()),
+                &filter.until.map(|u| u.timestamp()),
+                &filter.before,
+                &(filter.limit as i64),
+            ],
+        )
+        .await?;
+
+    let events = rows
+        .iter()
+        .map(|row| AuditEvent {
+            id: row.get(0),
+            timestamp: DateTime::<Utc>::from_utc(
+                NaiveDateTime::from_timestamp_opt(row.get::<usize, i64>(1), 0).unwrap_or_default(),
+                Utc,
+            ),
+            action: row.get(2),
+            repository: row.get(3),
+            reference: row.get(4),
+            digest: row.get(5),
+            addr: row.get(6),
+            user_agent: row.get(7),
+            principal: row.get(8),
+        })
+        .collect();
+    Ok(events)
+}
@@ -1,8 +1,9 @@
 use tokio_postgres::Error as PostgresError;
 
+#[tracing::instrument(name = "db::manifests::get", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn get(repository: &str, digest: &str) -> Result<String, PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     let row = db
         .query_one(
@@ -17,9 +18,42 @@ pub async fn get(repository: &str, digest: &str) -> Result<String, PostgresError
     Ok(row.get::<usize, String>(0))
 }
 
+/// The digests of all manifests in a repository
+#[tracing::instrument(name = "db::manifests::digests", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn digests(repository: &str) -> Result<Vec<String>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT digest FROM manifests WHERE repository = $1",
+            &[&repository],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// The repository a manifest is stored in, as each is stored once
+#[tracing::instrument(name = "db::manifests::repository", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn repository(digest: &str) -> Result<Option<String>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT repository FROM manifests WHERE digest = $1",
+            &[&digest],
+        )
+        .await?;
+
+    Ok(rows.first().map(|row| row.get(0)))
+}
+
+#[tracing::instrument(name = "db::manifests::save", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn save(repository: &str, digest: &str, value: &str) -> Result<(), PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     db.execute(
         "INSERT INTO manifests (repository, digest, value) VALUES ($1, $2, $3) ON CONFLICT (digest) DO NOTHING",
@@ -30,9 +64,10 @@ pub async fn save(repository: &str, digest: &str, value: &str) -> Result<(), Pos
     Ok(())
 }
 
+#[tracing::instrument(name = "db::manifests::delete", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn delete(repository: &str, digest: &str) -> Result<(), PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     db.execute(
         "DELETE FROM manifests WHERE repository = $1 AND digest = $2",
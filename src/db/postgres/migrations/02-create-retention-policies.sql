@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS retention_policies (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    repository TEXT NOT NULL,
+    keep_latest BIGINT,
+    max_age BIGINT,
+    tag_pattern TEXT,
+    exempt TEXT,
+    enforce BOOLEAN NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS immutable_repositories (
+    name TEXT NOT NULL PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS tag_protections (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    repository TEXT NOT NULL,
+    tag TEXT NOT NULL
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS tag_history (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    repository TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    old_manifest TEXT,
+    new_manifest TEXT,
+    updated BIGINT NOT NULL,
+    actor TEXT
+);
+
+CREATE INDEX IF NOT EXISTS tag_history_repository ON tag_history (repository, tag);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS quotas (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    repository TEXT NOT NULL,
+    max_bytes BIGINT,
+    max_tags BIGINT
+);
@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS replication_rules (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    repository TEXT NOT NULL,
+    tag_pattern TEXT,
+    target TEXT NOT NULL,
+    username TEXT,
+    password TEXT,
+    last_replicated BIGINT,
+    last_error TEXT
+);
+
+CREATE TABLE IF NOT EXISTS replication_tasks (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    rule BIGINT NOT NULL REFERENCES replication_rules(id) ON DELETE CASCADE,
+    repository TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    manifest TEXT,
+    attempts BIGINT NOT NULL DEFAULT 0,
+    next_attempt BIGINT NOT NULL,
+    last_error TEXT
+);
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS webhooks (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    url TEXT NOT NULL,
+    repository TEXT NOT NULL,
+    actions TEXT,
+    secret TEXT,
+    last_delivered BIGINT,
+    last_error TEXT
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    webhook BIGINT NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+    payload TEXT NOT NULL,
+    attempts BIGINT NOT NULL DEFAULT 0,
+    next_attempt BIGINT NOT NULL,
+    last_error TEXT
+);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS users (
+    name TEXT NOT NULL PRIMARY KEY,
+    password TEXT NOT NULL,
+    created BIGINT NOT NULL
+);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS permissions (
+    id BIGSERIAL NOT NULL PRIMARY KEY,
+    subject TEXT NOT NULL,
+    repository TEXT NOT NULL,
+    action TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS group_members (
+    group_name TEXT NOT NULL,
+    user_name TEXT NOT NULL,
+    PRIMARY KEY (group_name, user_name)
+);
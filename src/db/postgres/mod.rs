@@ -1,16 +1,215 @@
+use std::time::Instant;
+
+use chrono::Utc;
 use tokio::sync::OnceCell;
-use tokio_postgres::Client;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, Row};
 use tokio_postgres::{Error as PostgresError, NoTls};
 
+pub use tokio_postgres::Error;
+
+use super::{Garbage, MigrationError, Statistics};
+
+pub mod access;
+pub mod backup;
 pub mod blobs;
+pub mod events;
 pub mod manifests;
+pub mod protections;
+pub mod quotas;
+pub mod replication;
 pub mod repositories;
+pub mod retention;
+pub mod robots;
 pub mod tags;
+pub mod users;
+pub mod webhooks;
+
+pub(crate) static CLIENT: OnceCell<Database> = OnceCell::const_new();
+
+/// The shared connection, timing every statement for [crate::metrics]
+pub(crate) struct Database(Client);
+
+impl Database {
+    pub async fn execute(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<u64, PostgresError> {
+        let start = Instant::now();
+        let result = self.0.execute(statement, params).await;
+        crate::metrics::observe_query("postgres", statement, start.elapsed());
+        result
+    }
+
+    pub async fn query(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, PostgresError> {
+        let start = Instant::now();
+        let result = self.0.query(statement, params).await;
+        crate::metrics::observe_query("postgres", statement, start.elapsed());
+        result
+    }
+
+    pub async fn query_one(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Row, PostgresError> {
+        let start = Instant::now();
+        let result = self.0.query_one(statement, params).await;
+        crate::metrics::observe_query("postgres", statement, start.elapsed());
+        result
+    }
+}
+
+/// The migrations creating the schema, in the order they are applied, numbered by the schema version they
+/// lead to. Applied migrations must never change, changes to the schema go into a new one.
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (
+        1,
+        "01-create-database",
+        include_str!("migrations/01-create-database.sql"),
+    ),
+    (
+        2,
+        "02-create-retention-policies",
+        include_str!("migrations/02-create-retention-policies.sql"),
+    ),
+    (
+        3,
+        "03-create-tag-protections",
+        include_str!("migrations/03-create-tag-protections.sql"),
+    ),
+    (
+        4,
+        "04-create-tag-history",
+        include_str!("migrations/04-create-tag-history.sql"),
+    ),
+    (
+        5,
+        "05-create-quotas",
+        include_str!("migrations/05-create-quotas.sql"),
+    ),
+    (
+        6,
+        "06-create-replication",
+        include_str!("migrations/06-create-replication.sql"),
+    ),
+    (
+        7,
+        "07-create-webhooks",
+        include_str!("migrations/07-create-webhooks.sql"),
+    ),
+    (
+        8,
+        "08-create-events",
+        include_str!("migrations/08-create-events.sql"),
+    ),
+    (
+        9,
+        "09-create-users",
+        include_str!("migrations/09-create-users.sql"),
+    ),
+    (
+        10,
+        "10-create-access",
+        include_str!("migrations/10-create-access.sql"),
+    ),
+    (
+        11,
+        "11-create-robots",
+        include_str!("migrations/11-create-robots.sql"),
+    ),
+];
 
-pub(crate) static CLIENT: OnceCell<Client> = OnceCell::const_new();
+/// Keeps track of the migrations applied to a database
+const SCHEMA_VERSION: &str = "CREATE TABLE IF NOT EXISTS schema_version (
+    version BIGINT NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied BIGINT NOT NULL
+)";
+
+/// The advisory lock held while migrating
+const MIGRATION_LOCK: i64 = 0x7065_7175_6f64;
+
+/// The schema version this build of pequod expects
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |(version, _, _)| *version)
+}
+
+/// The schema version of the database, 0 if it has none
+#[tracing::instrument(name = "db::schema_version", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn schema_version() -> Result<i64, PostgresError> {
+    let db = CLIENT.get_or_init(connect).await;
+
+    let tracked: bool = db
+        .query_one("SELECT to_regclass('schema_version') IS NOT NULL", &[])
+        .await?
+        .get(0);
+    if !tracked {
+        return Ok(0);
+    }
+    let row = db
+        .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])
+        .await?;
+
+    Ok(row.get(0))
+}
 
+/// Apply the migrations the database is missing, returning their names
+#[tracing::instrument(name = "db::migrate", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn migrate() -> Result<Vec<&'static str>, MigrationError> {
+    let mut db = db().await;
+
+    let trans = db.transaction().await?;
+    // keep other instances from migrating at the same time, including creating the version table
+    trans
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+        .await?;
+    trans.batch_execute(SCHEMA_VERSION).await?;
+    let current: i64 = trans
+        .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])
+        .await?
+        .get(0);
+    if current > latest_version() {
+        return Err(MigrationError::TooNew {
+            found: current,
+            supported: latest_version(),
+        });
+    }
+
+    let mut applied = Vec::new();
+    for (version, name, migration) in MIGRATIONS.iter().filter(|(v, _, _)| *v > current) {
+        trans.batch_execute(migration).await?;
+        trans
+            .execute(
+                "INSERT INTO schema_version (version, name, applied) VALUES ($1, $2, $3)",
+                &[version, name, &Utc::now().timestamp()],
+            )
+            .await?;
+        tracing::info!("applied migration {}", name);
+        applied.push(*name);
+    }
+    trans.commit().await?;
+
+    Ok(applied)
+}
+
+#[tracing::instrument(name = "db::cleanup", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn cleanup() -> Result<(), PostgresError> {
+    collect_garbage(false).await.map(|_| ())
+}
+
+/// Delete everything no manifest refers to. A dry run only counts what would be deleted.
+#[tracing::instrument(name = "db::collect_garbage", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn collect_garbage(dry_run: bool) -> Result<Garbage, PostgresError> {
     let mut db = db().await;
 
     let trans = db.transaction().await?;
@@ -25,6 +224,13 @@ pub async fn cleanup() -> Result<(), PostgresError> {
     tracing::info!("deleted {} orphaned assocations", assocs);
 
     // delete blobs we don't have an association for
+    let bytes: i64 = trans
+        .query_one(
+            "SELECT COALESCE(sum(length(value)), 0)::BIGINT FROM blobs WHERE digest NOT IN (SELECT blob FROM manifest_blobs)",
+            &[],
+        )
+        .await?
+        .get(0);
     let blobs = trans
         .execute(
             "DELETE FROM blobs WHERE digest NOT IN (SELECT blob FROM manifest_blobs)",
@@ -51,25 +257,150 @@ pub async fn cleanup() -> Result<(), PostgresError> {
         .await?;
     tracing::info!("deleted {} orphaned repositories", repositories);
 
+    let garbage = Garbage {
+        associations: assocs,
+        blobs,
+        bytes: bytes as u64,
+        tags,
+        repositories,
+    };
+    if dry_run {
+        trans.rollback().await?;
+        return Ok(garbage);
+    }
     trans.commit().await?;
+    crate::metrics::collected(&garbage);
 
     db.execute("VACUUM", &[]).await?;
     tracing::info!("vacuumed database");
 
+    Ok(garbage)
+}
+
+/// Delete what only the given manifests referred to, once they are deleted. Unlike [collect_garbage] this
+/// leaves alone the blobs and unfinished uploads of pushes whose manifest hasn't arrived yet.
+#[tracing::instrument(name = "db::collect_deleted", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn collect_deleted(manifests: &[(&str, &str)]) -> Result<Garbage, PostgresError> {
+    let mut db = db().await;
+    let trans = db.transaction().await?;
+    let mut garbage = Garbage::default();
+
+    for (repository, manifest) in manifests {
+        let blobs = trans
+            .query(
+                "DELETE FROM manifest_blobs WHERE manifest = $1 AND manifest NOT IN (SELECT digest FROM manifests) RETURNING blob",
+                &[manifest],
+            )
+            .await?;
+        garbage.associations += blobs.len() as u64;
+
+        for blob in blobs {
+            let blob: String = blob.get(0);
+            let deleted = trans
+                .query_opt(
+                    "DELETE FROM blobs WHERE digest = $1 AND digest NOT IN (SELECT blob FROM manifest_blobs) RETURNING length(value)::BIGINT",
+                    &[&blob],
+                )
+                .await?;
+            if let Some(deleted) = deleted {
+                garbage.blobs += 1;
+                garbage.bytes += deleted.get::<_, i64>(0) as u64;
+            }
+        }
+
+        garbage.repositories += trans
+            .execute(
+                "DELETE FROM repositories WHERE name = $1 AND name NOT IN (SELECT repository FROM manifests)",
+                &[repository],
+            )
+            .await?;
+    }
+    trans.commit().await?;
+    tracing::info!(
+        "deleted {} blobs of {} deleted manifests",
+        garbage.blobs,
+        manifests.len()
+    );
+    crate::metrics::collected(&garbage);
+
+    Ok(garbage)
+}
+
+/// Check that the database can be reached, connecting to it if that hasn't happened yet
+#[tracing::instrument(name = "db::ping", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn ping() -> Result<(), PostgresError> {
+    shared().await?.query_one("SELECT 1", &[]).await?;
     Ok(())
 }
 
+/// Whether the database accepts writes to blobs, which it doesn't on a standby or without the privilege
+#[tracing::instrument(name = "db::writable", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn writable() -> Result<bool, PostgresError> {
+    let row = shared()
+        .await?
+        .query_one(
+            "SELECT NOT pg_is_in_recovery() AND current_setting('transaction_read_only') = 'off' AND has_table_privilege('blobs', 'INSERT')",
+            &[],
+        )
+        .await?;
+    Ok(row.get(0))
+}
+
+/// Count what the registry stores
+#[tracing::instrument(name = "db::statistics", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn statistics() -> Result<Statistics, PostgresError> {
+    let db = CLIENT.get_or_init(connect).await;
+
+    let row = db
+        .query_one(
+            "SELECT (SELECT count(*) FROM repositories), (SELECT count(*) FROM tags), (SELECT count(*) FROM manifests)",
+            &[],
+        )
+        .await?;
+    // upload IDs are UUIDs, digests have an algorithm before a colon
+    let blobs = db
+        .query_one(
+            "SELECT count(*) FILTER (WHERE digest LIKE '%:%'), COALESCE(sum(length(value)) FILTER (WHERE digest LIKE '%:%'), 0)::BIGINT, count(*) FILTER (WHERE digest NOT LIKE '%:%') FROM blobs",
+            &[],
+        )
+        .await?;
+
+    Ok(Statistics {
+        repositories: row.get(0),
+        tags: row.get(1),
+        manifests: row.get(2),
+        blobs: blobs.get(0),
+        blob_bytes: blobs.get(1),
+        uploads: blobs.get(2),
+    })
+}
+
+#[async_backtrace::framed]
+async fn connect() -> Database {
+    Database(db().await)
+}
+
+/// The shared connection, without panicking when the database can't be reached
+#[async_backtrace::framed]
+async fn shared() -> Result<&'static Database, PostgresError> {
+    CLIENT
+        .get_or_try_init(|| async { connection().await.map(Database) })
+        .await
+}
+
 #[async_backtrace::framed]
 async fn db() -> Client {
-    let (client, connection) = tokio_postgres::connect(
-        &format!(
-            "postgresql://postgres:{}@localhost:5432",
-            std::env::var("POSTGRES_PASSWORD").unwrap_or_default()
-        ),
-        NoTls,
-    )
-    .await
-    .unwrap();
+    connection().await.unwrap()
+}
+
+#[async_backtrace::framed]
+async fn connection() -> Result<Client, PostgresError> {
+    let (client, connection) =
+        tokio_postgres::connect(&crate::config::get().database.url, NoTls).await?;
 
     // The connection object performs the actual communication with the database,
     // so spawn it off to run on its own.
@@ -78,5 +409,5 @@ async fn db() -> Client {
             eprintln!("connection error: {}", e);
         }
     });
-    client
+    Ok(client)
 }
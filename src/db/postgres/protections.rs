@@ -0,0 +1,51 @@
+use crate::db::TagProtection;
+use tokio_postgres::Error as PostgresError;
+
+#[tracing::instrument(name = "db::protections::list", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn list() -> Result<Vec<TagProtection>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, repository, tag FROM tag_protections ORDER BY id ASC",
+            &[],
+        )
+        .await?;
+
+    let protections = rows
+        .iter()
+        .map(|row| TagProtection {
+            id: row.get(0),
+            repository: row.get(1),
+            tag: row.get(2),
+        })
+        .collect();
+    Ok(protections)
+}
+
+#[tracing::instrument(name = "db::protections::save", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn save(repository: &str, tag: &str) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO tag_protections (repository, tag) VALUES ($1, $2)",
+        &[&repository, &tag],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::protections::delete", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn delete(id: i64) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute("DELETE FROM tag_protections WHERE id = $1", &[&id])
+        .await?;
+    tracing::info!("deleted tag protection {}", id);
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use crate::db::{Quota, Usage};
+use tokio_postgres::Error as PostgresError;
+
+#[tracing::instrument(name = "db::quotas::list", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn list() -> Result<Vec<Quota>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, repository, max_bytes, max_tags FROM quotas ORDER BY id ASC",
+            &[],
+        )
+        .await?;
+
+    let quotas = rows
+        .iter()
+        .map(|row| Quota {
+            id: row.get(0),
+            repository: row.get(1),
+            max_bytes: row.get(2),
+            max_tags: row.get(3),
+        })
+        .collect();
+    Ok(quotas)
+}
+
+#[tracing::instrument(name = "db::quotas::save", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn save(quota: &Quota) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO quotas (repository, max_bytes, max_tags) VALUES ($1, $2, $3)",
+        &[&quota.repository, &quota.max_bytes, &quota.max_tags],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::quotas::delete", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn delete(id: i64) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute("DELETE FROM quotas WHERE id = $1", &[&id])
+        .await?;
+    tracing::info!("deleted quota {}", id);
+
+    Ok(())
+}
+
+/// Compute the storage used by `repositories`, as if the blobs in `additional` were also stored in them
+#[tracing::instrument(name = "db::quotas::usage", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn usage(repositories: &[String], additional: &[String]) -> Result<Usage, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let bytes = db
+        .query_one(
+            "
+    SELECT COALESCE(sum(length(value)), 0)::BIGINT
+        FROM blobs
+            WHERE digest IN
+                (SELECT blob
+                    FROM manifest_blobs
+                        WHERE manifest IN
+                            (SELECT digest
+                                FROM manifests
+                                    WHERE repository = ANY($1)))
+            OR digest = ANY($2)",
+            &[&repositories, &additional],
+        )
+        .await
+        .map(|row| row.get(0))?;
+    let tags = db
+        .query_one(
+            "SELECT count(*) FROM tags WHERE repository = ANY($1)",
+            &[&repositories],
+        )
+        .await
+        .map(|row| row.get(0))?;
+
+    Ok(Usage { bytes, tags })
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tokio_postgres::{Error as PostgresError, Row};
+
+use crate::db::{ReplicationRule, ReplicationTask};
+
+fn timestamp(seconds: i64) -> DateTime<Utc> {
+    DateTime::<Utc>::from_utc(
+        NaiveDateTime::from_timestamp_opt(seconds, 0).unwrap_or_default(),
+        Utc,
+    )
+}
+
+fn task(row: &Row) -> ReplicationTask {
+    ReplicationTask {
+        id: row.get(0),
+        rule: row.get(1),
+        repository: row.get(2),
+        tag: row.get(3),
+        manifest: row.get(4),
+        attempts: row.get(5),
+        next_attempt: timestamp(row.get(6)),
+        last_error: row.get(7),
+    }
+}
+
+#[tracing::instrument(name = "db::replication::list_rules", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn list_rules() -> Result<Vec<ReplicationRule>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, repository, tag_pattern, target, username, password, last_replicated, last_error FROM replication_rules ORDER BY id ASC",
+            &[],
+        )
+        .await?;
+
+    let rules = rows
+        .iter()
+        .map(|row| ReplicationRule {
+            id: row.get(0),
+            repository: row.get(1),
+            tag_pattern: row.get(2),
+            target: row.get(3),
+            username: row.get(4),
+            password: row.get(5),
+            last_replicated: row.get::<usize, Option<i64>>(6).map(timestamp),
+            last_error: row.get(7),
+        })
+        .collect();
+    Ok(rules)
+}
+
+#[tracing::instrument(name = "db::replication::save_rule", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn save_rule(rule: &ReplicationRule) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO replication_rules (repository, tag_pattern, target, username, password) VALUES ($1, $2, $3, $4, $5)",
+        &[
+            &rule.repository,
+            &rule.tag_pattern,
+            &rule.target,
+            &rule.username,
+            &rule.password,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::replication::delete_rule", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn delete_rule(id: i64) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute("DELETE FROM replication_rules WHERE id = $1", &[&id])
+        .await?;
+    tracing::info!("deleted replication rule {}", id);
+
+    Ok(())
+}
+
+/// Record the outcome of a task for a rule
+#[tracing::instrument(name = "db::replication::set_status", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn set_status(rule: i64, error: Option<&str>) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    match error {
+        None => {
+            db.execute(
+                "UPDATE replication_rules SET last_replicated = $1, last_error = NULL WHERE id = $2",
+                &[&Utc::now().timestamp(), &rule],
+            )
+            .await?
+        }
+        Some(error) => {
+            db.execute(
+                "UPDATE replication_rules SET last_error = $1 WHERE id = $2",
+                &[&error, &rule],
+            )
+            .await?
+        }
+    };
+
+    Ok(())
+}
+
+/// Queue a tag for replication, replacing any task for the same tag that has not run yet
+#[tracing::instrument(name = "db::replication::enqueue", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn enqueue(
+    rule: i64,
+    repository: &str,
+    tag: &str,
+    manifest: Option<&str>,
+) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "
+    WITH replaced AS (
+        DELETE FROM replication_tasks WHERE rule = $1 AND repository = $2 AND tag = $3
+    )
+    INSERT INTO replication_tasks (rule, repository, tag, manifest, next_attempt)
+        VALUES ($1, $2, $3, $4, $5)",
+        &[&rule, &repository, &tag, &manifest, &Utc::now().timestamp()],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// List queued tasks, oldest first
+#[tracing::instrument(name = "db::replication::tasks", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn tasks(limit: u32) -> Result<Vec<ReplicationTask>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, rule, repository, tag, manifest, attempts, next_attempt, last_error FROM replication_tasks ORDER BY id ASC LIMIT $1",
+            &[&(limit as i64)],
+        )
+        .await?;
+
+    Ok(rows.iter().map(task).collect())
+}
+
+/// List tasks that are due to be attempted, oldest first
+#[tracing::instrument(name = "db::replication::due", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn due() -> Result<Vec<ReplicationTask>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, rule, repository, tag, manifest, attempts, next_attempt, last_error FROM replication_tasks WHERE next_attempt <= $1 ORDER BY id ASC",
+            &[&Utc::now().timestamp()],
+        )
+        .await?;
+
+    Ok(rows.iter().map(task).collect())
+}
+
+/// Count the queued tasks of each rule
+#[tracing::instrument(name = "db::replication::pending", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn pending() -> Result<Vec<(i64, i64)>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT rule, count(*) FROM replication_tasks GROUP BY rule",
+            &[],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+#[tracing::instrument(name = "db::replication::complete", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn complete(id: i64) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute("DELETE FROM replication_tasks WHERE id = $1", &[&id])
+        .await?;
+
+    Ok(())
+}
+
+/// Record a failed attempt and schedule the next one
+#[tracing::instrument(name = "db::replication::retry", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn retry(id: i64, error: &str, next_attempt: DateTime<Utc>) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "UPDATE replication_tasks SET attempts = attempts + 1, last_error = $1, next_attempt = $2 WHERE id = $3",
+        &[&error, &next_attempt.timestamp(), &id],
+    )
+    .await?;
+
+    Ok(())
+}
@@ -1,25 +1,33 @@
 use crate::db::Repository;
 use tokio_postgres::Error as PostgresError;
 
+#[tracing::instrument(name = "db::repositories::list", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn list() -> Result<Vec<Repository>, PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     let rows = db
-        .query("SELECT name FROM repositories ORDER BY name ASC", &[])
+        .query(
+            "SELECT name, name IN (SELECT name FROM immutable_repositories) FROM repositories ORDER BY name ASC",
+            &[],
+        )
         .await?;
 
     let repositories = rows
         .iter()
-        .map(|row| Repository { name: row.get(0) })
+        .map(|row| Repository {
+            name: row.get(0),
+            immutable: row.get(1),
+        })
         .collect();
 
     Ok(repositories)
 }
 
+#[tracing::instrument(name = "db::repositories::save", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn save(name: &str) -> Result<(), PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     db.execute(
         "
@@ -34,3 +42,36 @@ pub async fn save(name: &str) -> Result<(), PostgresError> {
 
     Ok(())
 }
+
+#[tracing::instrument(name = "db::repositories::is_immutable", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn is_immutable(name: &str) -> Result<bool, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let immutable = db
+        .query_one(
+            "SELECT EXISTS (SELECT 1 FROM immutable_repositories WHERE name = $1)",
+            &[&name],
+        )
+        .await
+        .map(|row| row.get(0))?;
+
+    Ok(immutable)
+}
+
+#[tracing::instrument(name = "db::repositories::set_immutable", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn set_immutable(name: &str, immutable: bool) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let query = match immutable {
+        true => {
+            "INSERT INTO immutable_repositories (name) VALUES ($1) ON CONFLICT (name) DO NOTHING"
+        }
+        false => "DELETE FROM immutable_repositories WHERE name = $1",
+    };
+    db.execute(query, &[&name]).await?;
+    tracing::info!("set immutability of repository {} to {}", name, immutable);
+
+    Ok(())
+}
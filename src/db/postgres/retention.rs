@@ -0,0 +1,62 @@
+use crate::db::RetentionPolicy;
+use tokio_postgres::Error as PostgresError;
+
+#[tracing::instrument(name = "db::retention::list", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn list() -> Result<Vec<RetentionPolicy>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, repository, keep_latest, max_age, tag_pattern, exempt, enforce FROM retention_policies ORDER BY id ASC",
+            &[],
+        )
+        .await?;
+
+    let policies = rows
+        .iter()
+        .map(|row| RetentionPolicy {
+            id: row.get(0),
+            repository: row.get(1),
+            keep_latest: row.get(2),
+            max_age: row.get(3),
+            tag_pattern: row.get(4),
+            exempt: row.get(5),
+            enforce: row.get(6),
+        })
+        .collect();
+    Ok(policies)
+}
+
+#[tracing::instrument(name = "db::retention::save", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn save(policy: &RetentionPolicy) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO retention_policies (repository, keep_latest, max_age, tag_pattern, exempt, enforce) VALUES ($1, $2, $3, $4, $5, $6)",
+        &[
+            &policy.repository,
+            &policy.keep_latest,
+            &policy.max_age,
+            &policy.tag_pattern,
+            &policy.exempt,
+            &policy.enforce,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::retention::delete", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn delete(id: i64) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute("DELETE FROM retention_policies WHERE id = $1", &[&id])
+        .await?;
+    tracing::info!("deleted retention policy {}", id);
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tokio_postgres::{Error as PostgresError, Row};
+
+use crate::db::Robot;
+
+fn timestamp(seconds: i64) -> DateTime<Utc> {
+    DateTime::<Utc>::from_utc(
+        NaiveDateTime::from_timestamp_opt(seconds, 0).unwrap_or_default(),
+        Utc,
+    )
+}
+
+fn robot(row: &Row) -> Robot {
+    Robot {
+        name: row.get(0),
+        token: row.get(1),
+        permissions: row.get(2),
+        expires: row.get::<usize, Option<i64>>(3).map(timestamp),
+        last_used: row.get::<usize, Option<i64>>(4).map(timestamp),
+        created: timestamp(row.get(5)),
+    }
+}
+
+#[tracing::instrument(name = "db::robots::list", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn list() -> Result<Vec<Robot>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT name, token, permissions, expires, last_used, created FROM robots ORDER BY name ASC",
+            &[],
+        )
+        .await?;
+
+    Ok(rows.iter().map(robot).collect())
+}
+
+/// Find the robot a token belongs to by the token's hash
+#[tracing::instrument(name = "db::robots::get_by_token", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn get_by_token(token: &str) -> Result<Robot, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let row = db
+        .query_one(
+            "SELECT name, token, permissions, expires, last_used, created FROM robots WHERE token = $1",
+            &[&token],
+        )
+        .await?;
+
+    Ok(robot(&row))
+}
+
+/// Create a robot, or replace the token, permissions and expiry of an existing one
+#[tracing::instrument(name = "db::robots::save", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn save(robot: &Robot) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO robots (name, token, permissions, expires, created) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (name) DO UPDATE SET token = excluded.token, permissions = excluded.permissions, expires = excluded.expires",
+        &[
+            &robot.name,
+            &robot.token,
+            &robot.permissions,
+            &robot.expires.map(|e| e.timestamp()),
+            &robot.created.timestamp(),
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Record that a robot authenticated just now
+#[tracing::instrument(name = "db::robots::touch", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn touch(name: &str) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "UPDATE robots SET last_used = $1 WHERE name = $2",
+        &[&Utc::now().timestamp(), &name],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::robots::delete", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn delete(name: &str) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute("DELETE FROM robots WHERE name = $1", &[&name])
+        .await?;
+    tracing::info!("deleted robot {}", name);
+
+    Ok(())
+}
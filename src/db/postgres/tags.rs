@@ -1,10 +1,11 @@
-use crate::db::Tag;
+use crate::db::{Tag, TagHistory};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use tokio_postgres::Error as PostgresError;
 
+#[tracing::instrument(name = "db::tags::list", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn list(repository: &str) -> Result<Vec<Tag>, PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     let rows = db
         .query(
@@ -27,22 +28,163 @@ pub async fn list(repository: &str) -> Result<Vec<Tag>, PostgresError> {
     Ok(tags)
 }
 
+#[tracing::instrument(name = "db::tags::save", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
-pub async fn save(repository: &str, tag: &str, digest: &str) -> Result<(), PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+pub async fn save(
+    repository: &str,
+    tag: &str,
+    digest: &str,
+    actor: Option<&str>,
+) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
+    // all parts of the statement see the tags table as it was before the upsert
     db.execute(
-        "INSERT INTO tags (repository, name, updated, manifest) VALUES ($1, $2, $3, $4) ON CONFLICT (repository, name) DO UPDATE SET updated = $3, manifest = $4",
-        &[&repository, &tag, &Utc::now().timestamp(), &digest],
+        "
+    WITH old AS (
+        SELECT manifest FROM tags WHERE repository = $1 AND name = $2
+    ), upsert AS (
+        INSERT INTO tags (repository, name, updated, manifest) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (repository, name) DO UPDATE SET updated = $3, manifest = $4
+    )
+    INSERT INTO tag_history (repository, tag, old_manifest, new_manifest, updated, actor)
+        SELECT $1, $2, (SELECT manifest FROM old), $4, $3, $5
+        WHERE (SELECT manifest FROM old) IS DISTINCT FROM $4",
+        &[&repository, &tag, &Utc::now().timestamp(), &digest, &actor],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Store a tag as it is elsewhere, keeping when it was last updated and leaving its history alone
+#[tracing::instrument(name = "db::tags::restore", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn restore(repository: &str, tag: &Tag) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO tags (repository, name, updated, manifest) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (repository, name) DO UPDATE SET updated = $3, manifest = $4",
+        &[
+            &repository,
+            &tag.name,
+            &tag.updated.timestamp(),
+            &tag.manifest,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::tags::delete", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn delete(repository: &str, tag: &str, actor: Option<&str>) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "
+    WITH deleted AS (
+        DELETE FROM tags WHERE repository = $1 AND name = $2 RETURNING manifest
+    )
+    INSERT INTO tag_history (repository, tag, old_manifest, new_manifest, updated, actor)
+        SELECT $1, $2, manifest, NULL, $3, $4 FROM deleted",
+        &[&repository, &tag, &Utc::now().timestamp(), &actor],
     )
     .await?;
+    tracing::info!("deleted tag {}:{}", repository, tag);
 
     Ok(())
 }
 
+#[tracing::instrument(name = "db::tags::history", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn history(repository: &str, limit: u32) -> Result<Vec<TagHistory>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, repository, tag, old_manifest, new_manifest, updated, actor FROM tag_history WHERE repository = $1 ORDER BY id DESC LIMIT $2",
+            &[&repository, &(limit as i64)],
+        )
+        .await?;
+
+    let history = rows
+        .iter()
+        .map(|row| TagHistory {
+            id: row.get(0),
+            repository: row.get(1),
+            tag: row.get(2),
+            old_manifest: row.get(3),
+            new_manifest: row.get(4),
+            updated: DateTime::<Utc>::from_utc(
+                NaiveDateTime::from_timestamp_opt(row.get::<usize, i64>(5), 0).unwrap_or_default(),
+                Utc,
+            ),
+            actor: row.get(6),
+        })
+        .collect();
+    Ok(history)
+}
+
+/// The changes to one tag, the latest first
+#[tracing::instrument(name = "db::tags::history_of", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn history_of(repository: &str, tag: &str) -> Result<Vec<TagHistory>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, repository, tag, old_manifest, new_manifest, updated, actor FROM tag_history WHERE repository = $1 AND tag = $2 ORDER BY id DESC",
+            &[&repository, &tag],
+        )
+        .await?;
+
+    let history = rows
+        .iter()
+        .map(|row| TagHistory {
+            id: row.get(0),
+            repository: row.get(1),
+            tag: row.get(2),
+            old_manifest: row.get(3),
+            new_manifest: row.get(4),
+            updated: DateTime::<Utc>::from_utc(
+                NaiveDateTime::from_timestamp_opt(row.get::<usize, i64>(5), 0).unwrap_or_default(),
+                Utc,
+            ),
+            actor: row.get(6),
+        })
+        .collect();
+    Ok(history)
+}
+
+#[tracing::instrument(name = "db::tags::get", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn get(repository: &str, tag: &str) -> Result<Tag, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let row = db
+        .query_one(
+            "SELECT name, updated, manifest FROM tags WHERE repository = $1 AND name = $2",
+            &[&repository, &tag],
+        )
+        .await?;
+
+    Ok(Tag {
+        name: row.get(0),
+        updated: DateTime::<Utc>::from_utc(
+            NaiveDateTime::from_timestamp_opt(row.get::<usize, i64>(1), 0).unwrap_or_default(),
+            Utc,
+        ),
+        manifest: row.get(2),
+    })
+}
+
+#[tracing::instrument(name = "db::tags::get_manifest", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn get_manifest(repository: &str, tag: &str) -> Result<String, PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     let manifest = db
         .query_one(
@@ -55,9 +197,10 @@ pub async fn get_manifest(repository: &str, tag: &str) -> Result<String, Postgre
     Ok(manifest)
 }
 
+#[tracing::instrument(name = "db::tags::get_size", skip_all, fields(db.system = "postgres"))]
 #[async_backtrace::framed]
 pub async fn get_size(digest: &str) -> Result<u32, PostgresError> {
-    let db = super::CLIENT.get_or_init(super::db).await;
+    let db = super::CLIENT.get_or_init(super::connect).await;
 
     let size = db.query_one("SELECT sum(length(value))::OID AS size FROM blobs WHERE digest IN (SELECT blob FROM manifest_blobs WHERE manifest = $1)",
 &[&digest])
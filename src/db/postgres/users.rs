@@ -0,0 +1,72 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tokio_postgres::{Error as PostgresError, Row};
+
+use crate::db::User;
+
+fn user(row: &Row) -> User {
+    User {
+        name: row.get(0),
+        password: row.get(1),
+        created: DateTime::<Utc>::from_utc(
+            NaiveDateTime::from_timestamp_opt(row.get::<usize, i64>(2), 0).unwrap_or_default(),
+            Utc,
+        ),
+    }
+}
+
+#[tracing::instrument(name = "db::users::list", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn list() -> Result<Vec<User>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT name, password, created FROM users ORDER BY name ASC",
+            &[],
+        )
+        .await?;
+
+    Ok(rows.iter().map(user).collect())
+}
+
+#[tracing::instrument(name = "db::users::get", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn get(name: &str) -> Result<User, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let row = db
+        .query_one(
+            "SELECT name, password, created FROM users WHERE name = $1",
+            &[&name],
+        )
+        .await?;
+
+    Ok(user(&row))
+}
+
+/// Create a user, or change the password of an existing one
+#[tracing::instrument(name = "db::users::save", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn save(name: &str, password: &str) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO users (name, password, created) VALUES ($1, $2, $3) ON CONFLICT (name) DO UPDATE SET password = excluded.password",
+        &[&name, &password, &Utc::now().timestamp()],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::users::delete", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn delete(name: &str) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute("DELETE FROM users WHERE name = $1", &[&name])
+        .await?;
+    tracing::info!("deleted user {}", name);
+
+    Ok(())
+}
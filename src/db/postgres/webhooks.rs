@@ -0,0 +1,179 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tokio_postgres::{Error as PostgresError, Row};
+
+use crate::db::{Webhook, WebhookDelivery};
+
+fn timestamp(seconds: i64) -> DateTime<Utc> {
+    DateTime::<Utc>::from_utc(
+        NaiveDateTime::from_timestamp_opt(seconds, 0).unwrap_or_default(),
+        Utc,
+    )
+}
+
+fn delivery(row: &Row) -> WebhookDelivery {
+    WebhookDelivery {
+        id: row.get(0),
+        webhook: row.get(1),
+        payload: row.get(2),
+        attempts: row.get(3),
+        next_attempt: timestamp(row.get(4)),
+        last_error: row.get(5),
+    }
+}
+
+#[tracing::instrument(name = "db::webhooks::list", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn list() -> Result<Vec<Webhook>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, url, repository, actions, secret, last_delivered, last_error FROM webhooks ORDER BY id ASC",
+            &[],
+        )
+        .await?;
+
+    let webhooks = rows
+        .iter()
+        .map(|row| Webhook {
+            id: row.get(0),
+            url: row.get(1),
+            repository: row.get(2),
+            actions: row.get(3),
+            secret: row.get(4),
+            last_delivered: row.get::<usize, Option<i64>>(5).map(timestamp),
+            last_error: row.get(6),
+        })
+        .collect();
+    Ok(webhooks)
+}
+
+#[tracing::instrument(name = "db::webhooks::save", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn save(webhook: &Webhook) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO webhooks (url, repository, actions, secret) VALUES ($1, $2, $3, $4)",
+        &[
+            &webhook.url,
+            &webhook.repository,
+            &webhook.actions,
+            &webhook.secret,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::webhooks::delete", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn delete(id: i64) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute("DELETE FROM webhooks WHERE id = $1", &[&id])
+        .await?;
+    tracing::info!("deleted webhook {}", id);
+
+    Ok(())
+}
+
+/// Record the outcome of a delivery to a webhook
+#[tracing::instrument(name = "db::webhooks::set_status", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn set_status(webhook: i64, error: Option<&str>) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    match error {
+        None => {
+            db.execute(
+                "UPDATE webhooks SET last_delivered = $1, last_error = NULL WHERE id = $2",
+                &[&Utc::now().timestamp(), &webhook],
+            )
+            .await?
+        }
+        Some(error) => {
+            db.execute(
+                "UPDATE webhooks SET last_error = $1 WHERE id = $2",
+                &[&error, &webhook],
+            )
+            .await?
+        }
+    };
+
+    Ok(())
+}
+
+/// Queue a notification for delivery
+#[tracing::instrument(name = "db::webhooks::enqueue", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn enqueue(webhook: i64, payload: &str) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "INSERT INTO webhook_deliveries (webhook, payload, next_attempt) VALUES ($1, $2, $3)",
+        &[&webhook, &payload, &Utc::now().timestamp()],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// List deliveries that are due to be attempted, oldest first
+#[tracing::instrument(name = "db::webhooks::due", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn due() -> Result<Vec<WebhookDelivery>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT id, webhook, payload, attempts, next_attempt, last_error FROM webhook_deliveries WHERE next_attempt <= $1 ORDER BY id ASC",
+            &[&Utc::now().timestamp()],
+        )
+        .await?;
+
+    Ok(rows.iter().map(delivery).collect())
+}
+
+/// Count the queued deliveries of each webhook
+#[tracing::instrument(name = "db::webhooks::pending", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn pending() -> Result<Vec<(i64, i64)>, PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    let rows = db
+        .query(
+            "SELECT webhook, count(*) FROM webhook_deliveries GROUP BY webhook",
+            &[],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+#[tracing::instrument(name = "db::webhooks::complete", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn complete(id: i64) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute("DELETE FROM webhook_deliveries WHERE id = $1", &[&id])
+        .await?;
+
+    Ok(())
+}
+
+/// Record a failed attempt and schedule the next one
+#[tracing::instrument(name = "db::webhooks::retry", skip_all, fields(db.system = "postgres"))]
+#[async_backtrace::framed]
+pub async fn retry(id: i64, error: &str, next_attempt: DateTime<Utc>) -> Result<(), PostgresError> {
+    let db = super::CLIENT.get_or_init(super::connect).await;
+
+    db.execute(
+        "UPDATE webhook_deliveries SET attempts = attempts + 1, last_error = $1, next_attempt = $2 WHERE id = $3",
+        &[&error, &next_attempt.timestamp(), &id],
+    )
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use rusqlite::Error as RusqliteError;
+
+use crate::db::{GroupMember, Permission};
+
+#[tracing::instrument(name = "db::access::permissions", skip_all, fields(db.system = "sqlite"))]
+pub async fn permissions() -> Result<Vec<Permission>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("SELECT id, subject, repository, action FROM permissions ORDER BY id ASC")?;
+    let rows = statement.query_map([], |row| {
+        Ok(Permission {
+            id: row.get(0)?,
+            subject: row.get(1)?,
+            repository: row.get(2)?,
+            action: row.get(3)?,
+        })
+    })?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::access::grant", skip_all, fields(db.system = "sqlite"))]
+pub async fn grant(subject: &str, repository: &str, action: &str) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute(
+        "INSERT INTO permissions (subject, repository, action) VALUES (?, ?, ?)",
+        [subject, repository, action],
+    )?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::access::revoke", skip_all, fields(db.system = "sqlite"))]
+pub async fn revoke(id: i64) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute("DELETE FROM permissions WHERE id = ?", [id])?;
+    tracing::info!("deleted permission {}", id);
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::access::members", skip_all, fields(db.system = "sqlite"))]
+pub async fn members() -> Result<Vec<GroupMember>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT group_name, user_name FROM group_members ORDER BY group_name ASC, user_name ASC",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok(GroupMember {
+            group: row.get(0)?,
+            user: row.get(1)?,
+        })
+    })?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::access::add_member", skip_all, fields(db.system = "sqlite"))]
+pub async fn add_member(group: &str, user: &str) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute(
+        "INSERT INTO group_members (group_name, user_name) VALUES (?, ?)",
+        [group, user],
+    )?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::access::remove_member", skip_all, fields(db.system = "sqlite"))]
+pub async fn remove_member(group: &str, user: &str) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute(
+        "DELETE FROM group_members WHERE group_name = ? AND user_name = ?",
+        [group, user],
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags};
+
+use crate::db::{BackupError, Snapshot};
+
+/// Backups are copies of the database file
+pub const EXTENSION: &str = "db";
+
+/// How many pages are copied at a time, and how long the other connections get to use the database in between
+const PAGES_PER_STEP: i32 = 1024;
+const PAUSE: Duration = Duration::from_millis(10);
+/// How often writes from other connections may make the copy start over before it takes the rest in one step
+const MAX_RESTARTS: u32 = 3;
+
+/// Copy the database a bounded number of pages at a time, pausing in between and while another connection is
+/// writing. Writes from other connections make the copy start over, so on a busy registry the rest is copied
+/// in one step once that has happened a few times, which holds off writes until it is done.
+fn copy(from: &Connection, to: &mut Connection) -> Result<(), rusqlite::Error> {
+    let backup = Backup::new(from, to)?;
+    let mut remaining = i32::MAX;
+    let mut restarts = 0;
+    loop {
+        let pages = match restarts < MAX_RESTARTS {
+            true => PAGES_PER_STEP,
+            false => -1,
+        };
+        match backup.step(pages)? {
+            StepResult::Done => return Ok(()),
+            StepResult::More => {
+                let progress = backup.progress();
+                if progress.remaining > remaining {
+                    restarts += 1;
+                }
+                remaining = progress.remaining;
+            }
+            _ => {}
+        }
+        std::thread::sleep(PAUSE);
+    }
+}
+
+/// Run blocking sqlite work, like copying or checking a whole database, off the async runtime
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, BackupError> + Send + 'static,
+) -> Result<T, BackupError> {
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(work))
+        .await
+        .map_err(|e| BackupError::Io(std::io::Error::other(e)))?
+}
+
+/// Write a consistent copy of the database to `path` with the online backup API
+#[tracing::instrument(name = "db::backup::create", skip_all, fields(db.system = "sqlite"))]
+pub async fn create(path: &Path) -> Result<Snapshot, BackupError> {
+    let path = path.to_path_buf();
+    blocking(move || {
+        copy(&super::open()?, &mut Connection::open(&path)?)?;
+        check(&path)
+    })
+    .await
+}
+
+/// Check that `path` is an intact pequod database this build can restore, and describe it
+#[tracing::instrument(name = "db::backup::validate", skip_all, fields(db.system = "sqlite"))]
+pub async fn validate(path: &Path) -> Result<Snapshot, BackupError> {
+    let path = path.to_path_buf();
+    blocking(move || check(&path)).await
+}
+
+fn check(path: &Path) -> Result<Snapshot, BackupError> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let integrity = conn
+        .prepare("PRAGMA integrity_check(5)")
+        .and_then(|mut statement| {
+            statement
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()
+        })
+        .map_err(|e| {
+            BackupError::Invalid(format!(
+                "{} is not a readable sqlite database: {}",
+                path.display(),
+                e
+            ))
+        })?;
+    if integrity != ["ok"] {
+        return Err(BackupError::Invalid(format!(
+            "{} is corrupt: {}",
+            path.display(),
+            integrity.join("; ")
+        )));
+    }
+
+    let schema_version = super::current_version(&conn)?;
+    if schema_version == 0 {
+        return Err(BackupError::Invalid(format!(
+            "{} is not a pequod database",
+            path.display()
+        )));
+    }
+    if schema_version > super::latest_version() {
+        return Err(BackupError::Invalid(format!(
+            "{} is at schema version {}, newer than the {} this version of pequod supports",
+            path.display(),
+            schema_version,
+            super::latest_version()
+        )));
+    }
+
+    let mut statement = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' AND name <> 'schema_version' ORDER BY name",
+    )?;
+    let names = statement
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    let mut tables = BTreeMap::new();
+    for name in names {
+        let rows: u64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM \"{}\"", name.replace('"', "\"\"")),
+            [],
+            |row| row.get(0),
+        )?;
+        tables.insert(name, rows);
+    }
+
+    Ok(Snapshot {
+        schema_version,
+        tables,
+    })
+}
+
+/// Replace the contents of the database with the backup at `path`, after validating it, and bring its schema
+/// up to date
+#[tracing::instrument(name = "db::backup::restore", skip_all, fields(db.system = "sqlite"))]
+pub async fn restore(path: &Path) -> Result<Snapshot, BackupError> {
+    let path = path.to_path_buf();
+    let snapshot = blocking(move || {
+        let snapshot = check(&path)?;
+        copy(
+            &Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)?,
+            &mut super::open()?,
+        )?;
+        Ok(snapshot)
+    })
+    .await?;
+    super::migrate().await?;
+    Ok(snapshot)
+}
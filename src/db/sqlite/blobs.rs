@@ -1,10 +1,11 @@
 use std::io::Read;
 
 use bytes::Bytes;
-use rusqlite::{Connection, Error as RusqliteError};
+use rusqlite::Error as RusqliteError;
 
+#[tracing::instrument(name = "db::blobs::get", skip_all, fields(db.system = "sqlite"))]
 pub async fn get(digest: &str) -> Result<Bytes, RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
     let mut statement = conn.prepare("SELECT value FROM blobs WHERE digest = ?")?;
     let mut rows = statement.query([digest])?;
 
@@ -22,8 +23,9 @@ pub async fn get(digest: &str) -> Result<Bytes, RusqliteError> {
     Ok(result)
 }
 
+#[tracing::instrument(name = "db::blobs::length", skip_all, fields(db.system = "sqlite"))]
 pub async fn length(digest: &str) -> Result<usize, RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
     let mut statement = conn.prepare("SELECT length(value) FROM blobs WHERE digest = ?")?;
     let mut rows = statement.query([digest])?;
 
@@ -39,8 +41,46 @@ pub async fn length(digest: &str) -> Result<usize, RusqliteError> {
     Ok(result)
 }
 
+/// The digests of all blobs, including the IDs of unfinished uploads
+#[tracing::instrument(name = "db::blobs::digests", skip_all, fields(db.system = "sqlite"))]
+pub async fn digests() -> Result<Vec<String>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare("SELECT digest FROM blobs")?;
+    let digests = statement
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(digests)
+}
+
+/// Up to `limit` digests of blobs after `after`, in order, to go through all blobs a batch at a time
+#[tracing::instrument(name = "db::blobs::digests_after", skip_all, fields(db.system = "sqlite"))]
+pub async fn digests_after(after: &str, limit: u32) -> Result<Vec<String>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("SELECT digest FROM blobs WHERE digest > ? ORDER BY digest LIMIT ?")?;
+    let digests = statement
+        .query_map(rusqlite::params![after, limit], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(digests)
+}
+
+/// The blobs associated with a manifest
+#[tracing::instrument(name = "db::blobs::associated", skip_all, fields(db.system = "sqlite"))]
+pub async fn associated(manifest_digest: &str) -> Result<Vec<String>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare("SELECT blob FROM manifest_blobs WHERE manifest = ?")?;
+    let blobs = statement
+        .query_map([manifest_digest], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(blobs)
+}
+
+#[tracing::instrument(name = "db::blobs::save", skip_all, fields(db.system = "sqlite"))]
 pub async fn save(digest: &str, value: &Bytes) -> Result<(), RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
     let mut statement = conn.prepare("INSERT INTO blobs (digest, value) VALUES (?, ?)")?;
     statement.execute(rusqlite::params![
         digest,
@@ -50,16 +90,18 @@ pub async fn save(digest: &str, value: &Bytes) -> Result<(), RusqliteError> {
     Ok(())
 }
 
+#[tracing::instrument(name = "db::blobs::update_digest", skip_all, fields(db.system = "sqlite"))]
 pub async fn update_digest(old_digest: &str, new_digest: &str) -> Result<(), RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
     let mut statement = conn.prepare("UPDATE blobs SET digest = ? WHERE digest = ?")?;
     statement.execute([new_digest, old_digest])?;
 
     Ok(())
 }
 
+#[tracing::instrument(name = "db::blobs::associate", skip_all, fields(db.system = "sqlite"))]
 pub async fn associate(manifest_digest: &str, layer_digest: &str) -> Result<(), RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
 
     let mut statement = conn.prepare("INSERT INTO manifest_blobs(manifest, blob) VALUES (?, ?)")?;
     statement.execute([manifest_digest, layer_digest])?;
@@ -68,8 +110,9 @@ pub async fn associate(manifest_digest: &str, layer_digest: &str) -> Result<(),
     Ok(())
 }
 
+#[tracing::instrument(name = "db::blobs::disassociate", skip_all, fields(db.system = "sqlite"))]
 pub async fn disassociate(repository: &str, layer_digest: &str) -> Result<(), RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
 
     let mut statement = conn.prepare("DELETE FROM manifest_blobs WHERE blob = ? AND manifest IN (SELECT digest FROM manifests WHERE repository = ?) RETURNING manifest, blob")?;
     let mut deleted = statement.query([dbg!(layer_digest), repository])?;
@@ -0,0 +1,72 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rusqlite::Error as RusqliteError;
+
+use crate::db::{AuditEvent, EventFilter};
+
+#[tracing::instrument(name = "db::events::save", skip_all, fields(db.system = "sqlite"))]
+pub async fn save(event: &AuditEvent) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "INSERT INTO events (timestamp, action, repository, reference, digest, addr, user_agent, principal) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    statement.execute(rusqlite::params![
+        event.timestamp.timestamp(),
+        event.action,
+        event.repository,
+        event.reference,
+        event.digest,
+        event.addr,
+        event.user_agent,
+        event.principal
+    ])?;
+
+    Ok(())
+}
+
+/// List events matching `filter`, newest first
+#[tracing::instrument(name = "db::events::list", skip_all, fields(db.system = "sqlite"))]
+pub async fn list(filter: &EventFilter) -> Result<Vec<AuditEvent>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "
+    SELECT id, timestamp, action, repository, reference, digest, addr, user_agent, principal
+        FROM events
+            WHERE (?1 IS NULL OR action = ?1)
+                AND (?2 IS NULL OR repository = ?2)
+                AND (?3 IS NULL OR principal = ?3)
+                AND (?4 IS NULL OR timestamp >= ?4)
+                AND (?5 IS NULL OR timestamp <= ?5)
+                AND (?6 IS NULL OR id < ?6)
+            ORDER BY id DESC
+            LIMIT ?7",
+    )?;
+    let rows = statement.query_map(
+        rusqlite::params![
+            filter.action,
+            filter.repository,
+            filter.principal,
+            filter.since.map(|s| s.timestamp()),
+            filter.until.map(|u| u.timestamp()),
+            filter.before,
+            filter.limit
+        ],
+        |row| {
+            Ok(AuditEvent {
+                id: row.get(0)?,
+                timestamp: DateTime::<Utc>::from_utc(
+                    NaiveDateTime::from_timestamp_opt(row.get::<usize, i64>(1)?, 0)
+                        .unwrap_or_default(),
+                    Utc,
+                ),
+                action: row.get(2)?,
+                repository: row.get(3)?,
+                reference: row.get(4)?,
+                digest: row.get(5)?,
+                addr: row.get(6)?,
+                user_agent: row.get(7)?,
+                principal: row.get(8)?,
+            })
+        },
+    )?;
+    rows.into_iter().collect()
+}
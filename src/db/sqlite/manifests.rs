@@ -1,7 +1,8 @@
-use rusqlite::{Connection, Error as RusqliteError};
+use rusqlite::{Error as RusqliteError, OptionalExtension};
 
+#[tracing::instrument(name = "db::manifests::get", skip_all, fields(db.system = "sqlite"))]
 pub async fn get(repository: &str, digest: &str) -> Result<String, RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
     let mut statement =
         conn.prepare("SELECT value FROM manifests WHERE repository = ? AND digest = ?")?;
     let mut rows = statement.query([repository, digest])?;
@@ -18,8 +19,33 @@ pub async fn get(repository: &str, digest: &str) -> Result<String, RusqliteError
     Ok(result)
 }
 
+/// The digests of all manifests in a repository
+#[tracing::instrument(name = "db::manifests::digests", skip_all, fields(db.system = "sqlite"))]
+pub async fn digests(repository: &str) -> Result<Vec<String>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare("SELECT digest FROM manifests WHERE repository = ?")?;
+    let digests = statement
+        .query_map([repository], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(digests)
+}
+
+/// The repository a manifest is stored in, as each is stored once
+#[tracing::instrument(name = "db::manifests::repository", skip_all, fields(db.system = "sqlite"))]
+pub async fn repository(digest: &str) -> Result<Option<String>, RusqliteError> {
+    let conn = super::open()?;
+    conn.query_row(
+        "SELECT repository FROM manifests WHERE digest = ?",
+        [digest],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+#[tracing::instrument(name = "db::manifests::save", skip_all, fields(db.system = "sqlite"))]
 pub async fn save(repository: &str, digest: &str, value: &str) -> Result<(), RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
     let mut statement =
         conn.prepare("INSERT INTO manifests (repository, digest, value) VALUES (?, ?, ?)")?;
     statement.execute([repository, digest, value])?;
@@ -27,8 +53,9 @@ pub async fn save(repository: &str, digest: &str, value: &str) -> Result<(), Rus
     Ok(())
 }
 
+#[tracing::instrument(name = "db::manifests::delete", skip_all, fields(db.system = "sqlite"))]
 pub async fn delete(repository: &str, digest: &str) -> Result<(), RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
 
     let mut statement =
         conn.prepare("DELETE FROM manifests WHERE repository = ? AND digest = ?")?;
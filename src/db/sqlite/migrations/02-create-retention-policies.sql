@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS retention_policies (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    repository TEXT NOT NULL,
+    keep_latest INTEGER,
+    max_age INTEGER,
+    tag_pattern TEXT,
+    exempt TEXT,
+    enforce INTEGER NOT NULL DEFAULT 0
+);
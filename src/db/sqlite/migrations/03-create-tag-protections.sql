@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS immutable_repositories (
+    name TEXT NOT NULL PRIMARY KEY ON CONFLICT IGNORE
+);
+
+CREATE TABLE IF NOT EXISTS tag_protections (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    repository TEXT NOT NULL,
+    tag TEXT NOT NULL
+);
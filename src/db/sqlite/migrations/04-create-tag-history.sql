@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS tag_history (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    repository TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    old_manifest TEXT,
+    new_manifest TEXT,
+    updated INTEGER NOT NULL,
+    actor TEXT
+);
+
+CREATE INDEX IF NOT EXISTS tag_history_repository ON tag_history (repository, tag);
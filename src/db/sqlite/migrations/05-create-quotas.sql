@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS quotas (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    repository TEXT NOT NULL,
+    max_bytes INTEGER,
+    max_tags INTEGER
+);
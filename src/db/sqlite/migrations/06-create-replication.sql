@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS replication_rules (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    repository TEXT NOT NULL,
+    tag_pattern TEXT,
+    target TEXT NOT NULL,
+    username TEXT,
+    password TEXT,
+    last_replicated INTEGER,
+    last_error TEXT
+);
+
+CREATE TABLE IF NOT EXISTS replication_tasks (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    rule INTEGER NOT NULL,
+    repository TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    manifest TEXT,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt INTEGER NOT NULL,
+    last_error TEXT,
+    FOREIGN KEY (rule) REFERENCES replication_rules(id) ON DELETE CASCADE
+);
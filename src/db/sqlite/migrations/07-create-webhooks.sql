@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS webhooks (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    repository TEXT NOT NULL,
+    actions TEXT,
+    secret TEXT,
+    last_delivered INTEGER,
+    last_error TEXT
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    webhook INTEGER NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt INTEGER NOT NULL,
+    last_error TEXT,
+    FOREIGN KEY (webhook) REFERENCES webhooks(id) ON DELETE CASCADE
+);
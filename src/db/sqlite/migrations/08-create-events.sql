@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL,
+    action TEXT NOT NULL,
+    repository TEXT,
+    reference TEXT,
+    digest TEXT,
+    addr TEXT,
+    user_agent TEXT,
+    principal TEXT
+);
+
+CREATE INDEX IF NOT EXISTS events_timestamp ON events (timestamp);
+CREATE INDEX IF NOT EXISTS events_repository ON events (repository);
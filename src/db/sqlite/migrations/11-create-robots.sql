@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS robots (
+    name TEXT NOT NULL PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    permissions TEXT NOT NULL,
+    expires INTEGER,
+    last_used INTEGER,
+    created INTEGER NOT NULL
+);
@@ -1,12 +1,182 @@
-use rusqlite::{Connection, Error as RusqliteError};
+use chrono::Utc;
+use rusqlite::{
+    Connection, DatabaseName, Error as RusqliteError, OpenFlags, OptionalExtension,
+    TransactionBehavior,
+};
 
+use super::{Garbage, MigrationError, Statistics};
+
+pub use rusqlite::Error;
+
+pub mod access;
+pub mod backup;
 pub mod blobs;
+pub mod events;
 pub mod manifests;
+pub mod protections;
+pub mod quotas;
+pub mod replication;
 pub mod repositories;
+pub mod retention;
+pub mod robots;
 pub mod tags;
+pub mod users;
+pub mod webhooks;
+
+/// Open the database file configured in `database.path`
+pub(crate) fn open() -> Result<Connection, RusqliteError> {
+    let mut conn = Connection::open(&crate::config::get().database.path)?;
+    conn.profile(Some(profile));
+    Ok(conn)
+}
+
+/// Open the database file without creating it if it is missing, for checks that shouldn't leave an empty one
+fn open_existing() -> Result<Connection, RusqliteError> {
+    let mut conn = Connection::open_with_flags(
+        &crate::config::get().database.path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    conn.profile(Some(profile));
+    Ok(conn)
+}
+
+fn profile(statement: &str, duration: std::time::Duration) {
+    crate::metrics::observe_query("sqlite", statement, duration);
+}
+
+/// The migrations creating the schema, in the order they are applied, numbered by the schema version they
+/// lead to. Applied migrations must never change, changes to the schema go into a new one.
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (
+        1,
+        "01-create-database",
+        include_str!("migrations/01-create-database.sql"),
+    ),
+    (
+        2,
+        "02-create-retention-policies",
+        include_str!("migrations/02-create-retention-policies.sql"),
+    ),
+    (
+        3,
+        "03-create-tag-protections",
+        include_str!("migrations/03-create-tag-protections.sql"),
+    ),
+    (
+        4,
+        "04-create-tag-history",
+        include_str!("migrations/04-create-tag-history.sql"),
+    ),
+    (
+        5,
+        "05-create-quotas",
+        include_str!("migrations/05-create-quotas.sql"),
+    ),
+    (
+        6,
+        "06-create-replication",
+        include_str!("migrations/06-create-replication.sql"),
+    ),
+    (
+        7,
+        "07-create-webhooks",
+        include_str!("migrations/07-create-webhooks.sql"),
+    ),
+    (
+        8,
+        "08-create-events",
+        include_str!("migrations/08-create-events.sql"),
+    ),
+    (
+        9,
+        "09-create-users",
+        include_str!("migrations/09-create-users.sql"),
+    ),
+    (
+        10,
+        "10-create-access",
+        include_str!("migrations/10-create-access.sql"),
+    ),
+    (
+        11,
+        "11-create-robots",
+        include_str!("migrations/11-create-robots.sql"),
+    ),
+];
+
+/// Keeps track of the migrations applied to a database
+const SCHEMA_VERSION: &str = "CREATE TABLE IF NOT EXISTS schema_version (
+    version INTEGER NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied INTEGER NOT NULL
+)";
+
+/// The schema version this build of pequod expects
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |(version, _, _)| *version)
+}
+
+fn current_version(conn: &Connection) -> Result<i64, RusqliteError> {
+    let tracked: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !tracked {
+        return Ok(0);
+    }
+    conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// The schema version of the database, 0 if it has none
+#[tracing::instrument(name = "db::schema_version", skip_all, fields(db.system = "sqlite"))]
+pub async fn schema_version() -> Result<i64, RusqliteError> {
+    current_version(&open()?)
+}
+
+/// Apply the migrations the database is missing, returning their names
+#[tracing::instrument(name = "db::migrate", skip_all, fields(db.system = "sqlite"))]
+pub async fn migrate() -> Result<Vec<&'static str>, MigrationError> {
+    let mut conn = open()?;
+    // an immediate transaction keeps other instances from migrating at the same time
+    let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    trans.execute_batch(SCHEMA_VERSION)?;
+    let current = current_version(&trans)?;
+    if current > latest_version() {
+        return Err(MigrationError::TooNew {
+            found: current,
+            supported: latest_version(),
+        });
+    }
+
+    let mut applied = Vec::new();
+    for (version, name, migration) in MIGRATIONS.iter().filter(|(v, _, _)| *v > current) {
+        trans.execute_batch(migration)?;
+        trans.execute(
+            "INSERT INTO schema_version (version, name, applied) VALUES (?, ?, ?)",
+            rusqlite::params![version, name, Utc::now().timestamp()],
+        )?;
+        tracing::info!("applied migration {}", name);
+        applied.push(*name);
+    }
+    trans.commit()?;
+
+    Ok(applied)
+}
 
+#[tracing::instrument(name = "db::cleanup", skip_all, fields(db.system = "sqlite"))]
 pub async fn cleanup() -> Result<(), RusqliteError> {
-    let mut conn = Connection::open("registry.db")?;
+    collect_garbage(false).await.map(|_| ())
+}
+
+/// Delete everything no manifest refers to. A dry run only counts what would be deleted.
+#[tracing::instrument(name = "db::collect_garbage", skip_all, fields(db.system = "sqlite"))]
+pub async fn collect_garbage(dry_run: bool) -> Result<Garbage, RusqliteError> {
+    let mut conn = open()?;
     let trans = conn.transaction()?;
 
     // delete assocations we don't have a manifest for
@@ -17,6 +187,11 @@ pub async fn cleanup() -> Result<(), RusqliteError> {
     tracing::info!("deleted {} orphaned assocations", assocs);
 
     // delete blobs we don't have an association for
+    let bytes: i64 = trans.query_row(
+        "SELECT COALESCE(sum(length(value)), 0) FROM blobs WHERE digest NOT IN (SELECT blob FROM manifest_blobs)",
+        [],
+        |row| row.get(0),
+    )?;
     let blobs = trans.execute(
         "DELETE FROM blobs WHERE digest NOT IN (SELECT blob FROM manifest_blobs)",
         [],
@@ -37,9 +212,127 @@ pub async fn cleanup() -> Result<(), RusqliteError> {
     )?;
     tracing::info!("deleted {} orphaned repositories", repositories);
 
+    let garbage = Garbage {
+        associations: assocs as u64,
+        blobs: blobs as u64,
+        bytes: bytes as u64,
+        tags: tags as u64,
+        repositories: repositories as u64,
+    };
+    if dry_run {
+        trans.rollback()?;
+        return Ok(garbage);
+    }
     trans.commit()?;
+    crate::metrics::collected(&garbage);
 
     conn.execute("VACUUM", [])?;
 
-    Ok(())
+    Ok(garbage)
+}
+
+/// Delete what only the given manifests referred to, once they are deleted. Unlike [collect_garbage] this
+/// leaves alone the blobs and unfinished uploads of pushes whose manifest hasn't arrived yet.
+#[tracing::instrument(name = "db::collect_deleted", skip_all, fields(db.system = "sqlite"))]
+pub async fn collect_deleted(manifests: &[(&str, &str)]) -> Result<Garbage, RusqliteError> {
+    let mut conn = open()?;
+    let trans = conn.transaction()?;
+    let mut garbage = Garbage::default();
+
+    for (repository, manifest) in manifests {
+        let mut statement = trans.prepare(
+            "DELETE FROM manifest_blobs WHERE manifest = ? AND manifest NOT IN (SELECT digest FROM manifests) RETURNING blob",
+        )?;
+        let blobs = statement
+            .query_map([manifest], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        garbage.associations += blobs.len() as u64;
+
+        for blob in blobs {
+            let bytes: Option<i64> = trans
+                .query_row(
+                    "DELETE FROM blobs WHERE digest = ? AND digest NOT IN (SELECT blob FROM manifest_blobs) RETURNING length(value)",
+                    [&blob],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(bytes) = bytes {
+                garbage.blobs += 1;
+                garbage.bytes += bytes as u64;
+            }
+        }
+
+        garbage.repositories += trans.execute(
+            "DELETE FROM repositories WHERE name = ? AND name NOT IN (SELECT repository FROM manifests)",
+            [repository],
+        )? as u64;
+    }
+    trans.commit()?;
+    tracing::info!(
+        "deleted {} blobs of {} deleted manifests",
+        garbage.blobs,
+        manifests.len()
+    );
+    crate::metrics::collected(&garbage);
+
+    Ok(garbage)
+}
+
+/// Check that the database can be opened and read
+#[tracing::instrument(name = "db::ping", skip_all, fields(db.system = "sqlite"))]
+pub async fn ping() -> Result<(), RusqliteError> {
+    open_existing()?.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+}
+
+/// Whether the database accepts writes, found by taking the write lock without writing anything
+#[tracing::instrument(name = "db::writable", skip_all, fields(db.system = "sqlite"))]
+pub async fn writable() -> Result<bool, RusqliteError> {
+    let conn = open_existing()?;
+    if conn.is_readonly(DatabaseName::Main)? {
+        return Ok(false);
+    }
+    conn.execute_batch("BEGIN IMMEDIATE; ROLLBACK")?;
+    Ok(true)
+}
+
+/// Count what the registry stores
+#[tracing::instrument(name = "db::statistics", skip_all, fields(db.system = "sqlite"))]
+pub async fn statistics() -> Result<Statistics, RusqliteError> {
+    let conn = open()?;
+    let count = |table: &str| -> Result<i64, RusqliteError> {
+        conn.query_row(&format!("SELECT count(*) FROM {}", table), [], |row| {
+            row.get(0)
+        })
+    };
+    // upload IDs are UUIDs, digests have an algorithm before a colon
+    let (blobs, blob_bytes, uploads) = conn.query_row(
+        "SELECT COALESCE(sum(CASE WHEN digest LIKE '%:%' THEN 1 ELSE 0 END), 0), COALESCE(sum(CASE WHEN digest LIKE '%:%' THEN length(value) ELSE 0 END), 0), COALESCE(sum(CASE WHEN digest LIKE '%:%' THEN 0 ELSE 1 END), 0) FROM blobs",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+
+    Ok(Statistics {
+        repositories: count("repositories")?,
+        tags: count("tags")?,
+        manifests: count("manifests")?,
+        blobs,
+        blob_bytes,
+        uploads,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::MIGRATIONS;
+
+    #[test]
+    fn test_migrations_are_numbered() {
+        for (i, (version, name, _)) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(*version, i as i64 + 1);
+            assert_eq!(
+                name.split('-').next(),
+                Some(format!("{:02}", version).as_str())
+            );
+        }
+    }
 }
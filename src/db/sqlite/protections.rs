@@ -0,0 +1,38 @@
+use rusqlite::Error as RusqliteError;
+
+use crate::db::TagProtection;
+
+#[tracing::instrument(name = "db::protections::list", skip_all, fields(db.system = "sqlite"))]
+pub async fn list() -> Result<Vec<TagProtection>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("SELECT id, repository, tag FROM tag_protections ORDER BY id ASC")?;
+    let rows = statement.query_map([], |row| {
+        Ok(TagProtection {
+            id: row.get(0)?,
+            repository: row.get(1)?,
+            tag: row.get(2)?,
+        })
+    })?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::protections::save", skip_all, fields(db.system = "sqlite"))]
+pub async fn save(repository: &str, tag: &str) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("INSERT INTO tag_protections (repository, tag) VALUES (?, ?)")?;
+    statement.execute([repository, tag])?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::protections::delete", skip_all, fields(db.system = "sqlite"))]
+pub async fn delete(id: i64) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare("DELETE FROM tag_protections WHERE id = ?")?;
+    statement.execute([id])?;
+    tracing::info!("deleted tag protection {}", id);
+
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use rusqlite::Error as RusqliteError;
+
+use crate::db::{Quota, Usage};
+
+#[tracing::instrument(name = "db::quotas::list", skip_all, fields(db.system = "sqlite"))]
+pub async fn list() -> Result<Vec<Quota>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("SELECT id, repository, max_bytes, max_tags FROM quotas ORDER BY id ASC")?;
+    let rows = statement.query_map([], |row| {
+        Ok(Quota {
+            id: row.get(0)?,
+            repository: row.get(1)?,
+            max_bytes: row.get(2)?,
+            max_tags: row.get(3)?,
+        })
+    })?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::quotas::save", skip_all, fields(db.system = "sqlite"))]
+pub async fn save(quota: &Quota) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("INSERT INTO quotas (repository, max_bytes, max_tags) VALUES (?, ?, ?)")?;
+    statement.execute(rusqlite::params![
+        quota.repository,
+        quota.max_bytes,
+        quota.max_tags
+    ])?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::quotas::delete", skip_all, fields(db.system = "sqlite"))]
+pub async fn delete(id: i64) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare("DELETE FROM quotas WHERE id = ?")?;
+    statement.execute([id])?;
+    tracing::info!("deleted quota {}", id);
+
+    Ok(())
+}
+
+/// Compute the storage used by `repositories`, as if the blobs in `additional` were also stored in them
+#[tracing::instrument(name = "db::quotas::usage", skip_all, fields(db.system = "sqlite"))]
+pub async fn usage(repositories: &[String], additional: &[String]) -> Result<Usage, RusqliteError> {
+    let conn = super::open()?;
+    let repository_params = vec!["?"; repositories.len()].join(", ");
+    let additional_params = vec!["?"; additional.len()].join(", ");
+
+    let bytes = conn.query_row(
+        &format!(
+            "SELECT COALESCE(sum(length(value)), 0) FROM blobs WHERE digest IN (SELECT blob FROM manifest_blobs WHERE manifest IN (SELECT digest FROM manifests WHERE repository IN ({}))) OR digest IN ({})",
+            repository_params, additional_params
+        ),
+        rusqlite::params_from_iter(repositories.iter().chain(additional.iter())),
+        |row| row.get(0),
+    )?;
+    let tags = conn.query_row(
+        &format!(
+            "SELECT count(*) FROM tags WHERE repository IN ({})",
+            repository_params
+        ),
+        rusqlite::params_from_iter(repositories.iter()),
+        |row| row.get(0),
+    )?;
+
+    Ok(Usage { bytes, tags })
+}
@@ -0,0 +1,167 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rusqlite::{Error as RusqliteError, Row};
+
+use crate::db::{ReplicationRule, ReplicationTask};
+
+fn timestamp(seconds: i64) -> DateTime<Utc> {
+    DateTime::<Utc>::from_utc(
+        NaiveDateTime::from_timestamp_opt(seconds, 0).unwrap_or_default(),
+        Utc,
+    )
+}
+
+fn task(row: &Row) -> Result<ReplicationTask, RusqliteError> {
+    Ok(ReplicationTask {
+        id: row.get(0)?,
+        rule: row.get(1)?,
+        repository: row.get(2)?,
+        tag: row.get(3)?,
+        manifest: row.get(4)?,
+        attempts: row.get(5)?,
+        next_attempt: timestamp(row.get(6)?),
+        last_error: row.get(7)?,
+    })
+}
+
+#[tracing::instrument(name = "db::replication::list_rules", skip_all, fields(db.system = "sqlite"))]
+pub async fn list_rules() -> Result<Vec<ReplicationRule>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT id, repository, tag_pattern, target, username, password, last_replicated, last_error FROM replication_rules ORDER BY id ASC",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok(ReplicationRule {
+            id: row.get(0)?,
+            repository: row.get(1)?,
+            tag_pattern: row.get(2)?,
+            target: row.get(3)?,
+            username: row.get(4)?,
+            password: row.get(5)?,
+            last_replicated: row.get::<usize, Option<i64>>(6)?.map(timestamp),
+            last_error: row.get(7)?,
+        })
+    })?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::replication::save_rule", skip_all, fields(db.system = "sqlite"))]
+pub async fn save_rule(rule: &ReplicationRule) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "INSERT INTO replication_rules (repository, tag_pattern, target, username, password) VALUES (?, ?, ?, ?, ?)",
+    )?;
+    statement.execute(rusqlite::params![
+        rule.repository,
+        rule.tag_pattern,
+        rule.target,
+        rule.username,
+        rule.password
+    ])?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::replication::delete_rule", skip_all, fields(db.system = "sqlite"))]
+pub async fn delete_rule(id: i64) -> Result<(), RusqliteError> {
+    let mut conn = super::open()?;
+    let trans = conn.transaction()?;
+    trans.execute("DELETE FROM replication_tasks WHERE rule = ?", [id])?;
+    trans.execute("DELETE FROM replication_rules WHERE id = ?", [id])?;
+    trans.commit()?;
+    tracing::info!("deleted replication rule {}", id);
+
+    Ok(())
+}
+
+/// Record the outcome of a task for a rule
+#[tracing::instrument(name = "db::replication::set_status", skip_all, fields(db.system = "sqlite"))]
+pub async fn set_status(rule: i64, error: Option<&str>) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    match error {
+        None => conn.execute(
+            "UPDATE replication_rules SET last_replicated = ?, last_error = NULL WHERE id = ?",
+            rusqlite::params![Utc::now().timestamp(), rule],
+        )?,
+        Some(error) => conn.execute(
+            "UPDATE replication_rules SET last_error = ? WHERE id = ?",
+            rusqlite::params![error, rule],
+        )?,
+    };
+
+    Ok(())
+}
+
+/// Queue a tag for replication, replacing any task for the same tag that has not run yet
+#[tracing::instrument(name = "db::replication::enqueue", skip_all, fields(db.system = "sqlite"))]
+pub async fn enqueue(
+    rule: i64,
+    repository: &str,
+    tag: &str,
+    manifest: Option<&str>,
+) -> Result<(), RusqliteError> {
+    let mut conn = super::open()?;
+    let trans = conn.transaction()?;
+    trans.execute(
+        "DELETE FROM replication_tasks WHERE rule = ? AND repository = ? AND tag = ?",
+        rusqlite::params![rule, repository, tag],
+    )?;
+    trans.execute(
+        "INSERT INTO replication_tasks (rule, repository, tag, manifest, next_attempt) VALUES (?, ?, ?, ?, ?)",
+        rusqlite::params![rule, repository, tag, manifest, Utc::now().timestamp()],
+    )?;
+    trans.commit()?;
+
+    Ok(())
+}
+
+/// List queued tasks, oldest first
+#[tracing::instrument(name = "db::replication::tasks", skip_all, fields(db.system = "sqlite"))]
+pub async fn tasks(limit: u32) -> Result<Vec<ReplicationTask>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT id, rule, repository, tag, manifest, attempts, next_attempt, last_error FROM replication_tasks ORDER BY id ASC LIMIT ?",
+    )?;
+    let rows = statement.query_map([limit], task)?;
+    rows.into_iter().collect()
+}
+
+/// List tasks that are due to be attempted, oldest first
+#[tracing::instrument(name = "db::replication::due", skip_all, fields(db.system = "sqlite"))]
+pub async fn due() -> Result<Vec<ReplicationTask>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT id, rule, repository, tag, manifest, attempts, next_attempt, last_error FROM replication_tasks WHERE next_attempt <= ? ORDER BY id ASC",
+    )?;
+    let rows = statement.query_map([Utc::now().timestamp()], task)?;
+    rows.into_iter().collect()
+}
+
+/// Count the queued tasks of each rule
+#[tracing::instrument(name = "db::replication::pending", skip_all, fields(db.system = "sqlite"))]
+pub async fn pending() -> Result<Vec<(i64, i64)>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("SELECT rule, count(*) FROM replication_tasks GROUP BY rule")?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::replication::complete", skip_all, fields(db.system = "sqlite"))]
+pub async fn complete(id: i64) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute("DELETE FROM replication_tasks WHERE id = ?", [id])?;
+
+    Ok(())
+}
+
+/// Record a failed attempt and schedule the next one
+#[tracing::instrument(name = "db::replication::retry", skip_all, fields(db.system = "sqlite"))]
+pub async fn retry(id: i64, error: &str, next_attempt: DateTime<Utc>) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute(
+        "UPDATE replication_tasks SET attempts = attempts + 1, last_error = ?, next_attempt = ? WHERE id = ?",
+        rusqlite::params![error, next_attempt.timestamp(), id],
+    )?;
+
+    Ok(())
+}
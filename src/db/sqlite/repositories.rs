@@ -1,18 +1,48 @@
-use rusqlite::{Connection, Error as RusqliteError};
+use rusqlite::Error as RusqliteError;
 
 use crate::db::Repository;
 
+#[tracing::instrument(name = "db::repositories::list", skip_all, fields(db.system = "sqlite"))]
 pub async fn list() -> Result<Vec<Repository>, RusqliteError> {
-    let conn = Connection::open("registry.db")?;
-    let mut statement = conn.prepare("SELECT name FROM repositories ORDER BY name ASC")?;
-    let rows = statement.query_map([], |row| Ok(Repository { name: row.get(0)? }))?;
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT name, name IN (SELECT name FROM immutable_repositories) FROM repositories ORDER BY name ASC",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok(Repository {
+            name: row.get(0)?,
+            immutable: row.get(1)?,
+        })
+    })?;
     rows.into_iter().collect()
 }
 
+#[tracing::instrument(name = "db::repositories::save", skip_all, fields(db.system = "sqlite"))]
 pub async fn save(name: &str) -> Result<(), RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
     let mut statement = conn.prepare("INSERT INTO repositories (name) VALUES (?)")?;
     statement.execute([name])?;
 
     Ok(())
 }
+
+#[tracing::instrument(name = "db::repositories::is_immutable", skip_all, fields(db.system = "sqlite"))]
+pub async fn is_immutable(name: &str) -> Result<bool, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("SELECT EXISTS (SELECT 1 FROM immutable_repositories WHERE name = ?)")?;
+    statement.query_row([name], |row| row.get(0))
+}
+
+#[tracing::instrument(name = "db::repositories::set_immutable", skip_all, fields(db.system = "sqlite"))]
+pub async fn set_immutable(name: &str, immutable: bool) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    let query = match immutable {
+        true => "INSERT INTO immutable_repositories (name) VALUES (?)",
+        false => "DELETE FROM immutable_repositories WHERE name = ?",
+    };
+    conn.execute(query, [name])?;
+    tracing::info!("set immutability of repository {} to {}", name, immutable);
+
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use rusqlite::Error as RusqliteError;
+
+use crate::db::RetentionPolicy;
+
+#[tracing::instrument(name = "db::retention::list", skip_all, fields(db.system = "sqlite"))]
+pub async fn list() -> Result<Vec<RetentionPolicy>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT id, repository, keep_latest, max_age, tag_pattern, exempt, enforce FROM retention_policies ORDER BY id ASC",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok(RetentionPolicy {
+            id: row.get(0)?,
+            repository: row.get(1)?,
+            keep_latest: row.get(2)?,
+            max_age: row.get(3)?,
+            tag_pattern: row.get(4)?,
+            exempt: row.get(5)?,
+            enforce: row.get(6)?,
+        })
+    })?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::retention::save", skip_all, fields(db.system = "sqlite"))]
+pub async fn save(policy: &RetentionPolicy) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "INSERT INTO retention_policies (repository, keep_latest, max_age, tag_pattern, exempt, enforce) VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+    statement.execute(rusqlite::params![
+        policy.repository,
+        policy.keep_latest,
+        policy.max_age,
+        policy.tag_pattern,
+        policy.exempt,
+        policy.enforce
+    ])?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::retention::delete", skip_all, fields(db.system = "sqlite"))]
+pub async fn delete(id: i64) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare("DELETE FROM retention_policies WHERE id = ?")?;
+    statement.execute([id])?;
+    tracing::info!("deleted retention policy {}", id);
+
+    Ok(())
+}
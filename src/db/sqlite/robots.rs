@@ -0,0 +1,81 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rusqlite::{Error as RusqliteError, Row};
+
+use crate::db::Robot;
+
+fn timestamp(seconds: i64) -> DateTime<Utc> {
+    DateTime::<Utc>::from_utc(
+        NaiveDateTime::from_timestamp_opt(seconds, 0).unwrap_or_default(),
+        Utc,
+    )
+}
+
+fn robot(row: &Row) -> Result<Robot, RusqliteError> {
+    Ok(Robot {
+        name: row.get(0)?,
+        token: row.get(1)?,
+        permissions: row.get(2)?,
+        expires: row.get::<usize, Option<i64>>(3)?.map(timestamp),
+        last_used: row.get::<usize, Option<i64>>(4)?.map(timestamp),
+        created: timestamp(row.get(5)?),
+    })
+}
+
+#[tracing::instrument(name = "db::robots::list", skip_all, fields(db.system = "sqlite"))]
+pub async fn list() -> Result<Vec<Robot>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT name, token, permissions, expires, last_used, created FROM robots ORDER BY name ASC",
+    )?;
+    let rows = statement.query_map([], robot)?;
+    rows.into_iter().collect()
+}
+
+/// Find the robot a token belongs to by the token's hash
+#[tracing::instrument(name = "db::robots::get_by_token", skip_all, fields(db.system = "sqlite"))]
+pub async fn get_by_token(token: &str) -> Result<Robot, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT name, token, permissions, expires, last_used, created FROM robots WHERE token = ?",
+    )?;
+    statement.query_row([token], robot)
+}
+
+/// Create a robot, or replace the token, permissions and expiry of an existing one
+#[tracing::instrument(name = "db::robots::save", skip_all, fields(db.system = "sqlite"))]
+pub async fn save(robot: &Robot) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute(
+        "INSERT INTO robots (name, token, permissions, expires, created) VALUES (?, ?, ?, ?, ?) ON CONFLICT (name) DO UPDATE SET token = excluded.token, permissions = excluded.permissions, expires = excluded.expires",
+        rusqlite::params![
+            robot.name,
+            robot.token,
+            robot.permissions,
+            robot.expires.map(|e| e.timestamp()),
+            robot.created.timestamp()
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Record that a robot authenticated just now
+#[tracing::instrument(name = "db::robots::touch", skip_all, fields(db.system = "sqlite"))]
+pub async fn touch(name: &str) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute(
+        "UPDATE robots SET last_used = ? WHERE name = ?",
+        rusqlite::params![Utc::now().timestamp(), name],
+    )?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::robots::delete", skip_all, fields(db.system = "sqlite"))]
+pub async fn delete(name: &str) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute("DELETE FROM robots WHERE name = ?", [name])?;
+    tracing::info!("deleted robot {}", name);
+
+    Ok(())
+}
@@ -1,9 +1,10 @@
-use crate::db::Tag;
+use crate::db::{Tag, TagHistory};
 use chrono::{DateTime, NaiveDateTime, Utc};
-use rusqlite::{Connection, Error as RusqliteError};
+use rusqlite::{Error as RusqliteError, OptionalExtension};
 
+#[tracing::instrument(name = "db::tags::list", skip_all, fields(db.system = "sqlite"))]
 pub async fn list(repository: &str) -> Result<Vec<Tag>, RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
     let mut statement = conn.prepare(
         "SELECT name, updated, manifest FROM tags WHERE repository = ? ORDER BY updated DESC",
     )?;
@@ -20,22 +21,144 @@ pub async fn list(repository: &str) -> Result<Vec<Tag>, RusqliteError> {
     rows.into_iter().collect()
 }
 
-pub async fn save(repository: &str, tag: &str, digest: &str) -> Result<(), RusqliteError> {
-    let conn = Connection::open("registry.db")?;
-    let mut statement =
-        conn.prepare("INSERT INTO tags (repository, name, updated, manifest) VALUES (?, ?, ?, ?)")?;
-    statement.execute(rusqlite::params![
-        repository,
-        tag,
-        chrono::Utc::now().timestamp(),
-        digest
-    ])?;
+#[tracing::instrument(name = "db::tags::save", skip_all, fields(db.system = "sqlite"))]
+pub async fn save(
+    repository: &str,
+    tag: &str,
+    digest: &str,
+    actor: Option<&str>,
+) -> Result<(), RusqliteError> {
+    let mut conn = super::open()?;
+    let trans = conn.transaction()?;
+    let now = chrono::Utc::now().timestamp();
+
+    let old: Option<String> = trans
+        .query_row(
+            "SELECT manifest FROM tags WHERE repository = ? AND name = ?",
+            [repository, tag],
+            |row| row.get(0),
+        )
+        .optional()?;
+    trans.execute(
+        "INSERT INTO tags (repository, name, updated, manifest) VALUES (?, ?, ?, ?) ON CONFLICT (name, repository) DO UPDATE SET updated = excluded.updated, manifest = excluded.manifest",
+        rusqlite::params![repository, tag, now, digest],
+    )?;
+    if old.as_deref() != Some(digest) {
+        trans.execute(
+            "INSERT INTO tag_history (repository, tag, old_manifest, new_manifest, updated, actor) VALUES (?, ?, ?, ?, ?, ?)",
+            rusqlite::params![repository, tag, old, digest, now, actor],
+        )?;
+    }
+
+    trans.commit()?;
+
+    Ok(())
+}
+
+/// Store a tag as it is elsewhere, keeping when it was last updated and leaving its history alone
+#[tracing::instrument(name = "db::tags::restore", skip_all, fields(db.system = "sqlite"))]
+pub async fn restore(repository: &str, tag: &Tag) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute(
+        "INSERT INTO tags (repository, name, updated, manifest) VALUES (?, ?, ?, ?) ON CONFLICT (name, repository) DO UPDATE SET updated = excluded.updated, manifest = excluded.manifest",
+        rusqlite::params![repository, tag.name, tag.updated.timestamp(), tag.manifest],
+    )?;
 
     Ok(())
 }
 
+#[tracing::instrument(name = "db::tags::delete", skip_all, fields(db.system = "sqlite"))]
+pub async fn delete(repository: &str, tag: &str, actor: Option<&str>) -> Result<(), RusqliteError> {
+    let mut conn = super::open()?;
+    let trans = conn.transaction()?;
+
+    let old: Option<String> = trans
+        .query_row(
+            "DELETE FROM tags WHERE repository = ? AND name = ? RETURNING manifest",
+            [repository, tag],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if old.is_some() {
+        trans.execute(
+            "INSERT INTO tag_history (repository, tag, old_manifest, new_manifest, updated, actor) VALUES (?, ?, ?, NULL, ?, ?)",
+            rusqlite::params![repository, tag, old, chrono::Utc::now().timestamp(), actor],
+        )?;
+    }
+
+    trans.commit()?;
+    tracing::info!("deleted tag {}:{}", repository, tag);
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::tags::history", skip_all, fields(db.system = "sqlite"))]
+pub async fn history(repository: &str, limit: u32) -> Result<Vec<TagHistory>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT id, repository, tag, old_manifest, new_manifest, updated, actor FROM tag_history WHERE repository = ? ORDER BY id DESC LIMIT ?",
+    )?;
+    let rows = statement.query_map(rusqlite::params![repository, limit], |row| {
+        Ok(TagHistory {
+            id: row.get(0)?,
+            repository: row.get(1)?,
+            tag: row.get(2)?,
+            old_manifest: row.get(3)?,
+            new_manifest: row.get(4)?,
+            updated: DateTime::<Utc>::from_utc(
+                NaiveDateTime::from_timestamp_opt(row.get::<usize, i64>(5)?, 0).unwrap_or_default(),
+                Utc,
+            ),
+            actor: row.get(6)?,
+        })
+    })?;
+    rows.into_iter().collect()
+}
+
+/// The changes to one tag, the latest first
+#[tracing::instrument(name = "db::tags::history_of", skip_all, fields(db.system = "sqlite"))]
+pub async fn history_of(repository: &str, tag: &str) -> Result<Vec<TagHistory>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT id, repository, tag, old_manifest, new_manifest, updated, actor FROM tag_history WHERE repository = ? AND tag = ? ORDER BY id DESC",
+    )?;
+    let rows = statement.query_map([repository, tag], |row| {
+        Ok(TagHistory {
+            id: row.get(0)?,
+            repository: row.get(1)?,
+            tag: row.get(2)?,
+            old_manifest: row.get(3)?,
+            new_manifest: row.get(4)?,
+            updated: DateTime::<Utc>::from_utc(
+                NaiveDateTime::from_timestamp_opt(row.get::<usize, i64>(5)?, 0).unwrap_or_default(),
+                Utc,
+            ),
+            actor: row.get(6)?,
+        })
+    })?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::tags::get", skip_all, fields(db.system = "sqlite"))]
+pub async fn get(repository: &str, tag: &str) -> Result<Tag, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("SELECT name, updated, manifest FROM tags WHERE repository = ? AND name = ?")?;
+    statement.query_row([repository, tag], |row| {
+        Ok(Tag {
+            name: row.get(0)?,
+            updated: DateTime::<Utc>::from_utc(
+                NaiveDateTime::from_timestamp_opt(row.get::<usize, i64>(1)?, 0).unwrap_or_default(),
+                Utc,
+            ),
+            manifest: row.get(2)?,
+        })
+    })
+}
+
+#[tracing::instrument(name = "db::tags::get_manifest", skip_all, fields(db.system = "sqlite"))]
 pub async fn get_manifest(repository: &str, tag: &str) -> Result<String, RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
     let mut statement =
         conn.prepare("SELECT manifest FROM tags WHERE repository = ? AND name = ?")?;
     let mut rows = statement.query([repository, tag])?;
@@ -52,8 +175,9 @@ pub async fn get_manifest(repository: &str, tag: &str) -> Result<String, Rusqlit
     Ok(result)
 }
 
+#[tracing::instrument(name = "db::tags::get_size", skip_all, fields(db.system = "sqlite"))]
 pub async fn get_size(digest: &str) -> Result<usize, RusqliteError> {
-    let conn = Connection::open("registry.db")?;
+    let conn = super::open()?;
     let mut statement = conn.prepare(
         "SELECT sum(length(value)) AS size FROM blobs WHERE digest IN (SELECT blob FROM manifest_blobs WHERE manifest = ?)",
     )?;
@@ -0,0 +1,52 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rusqlite::{Error as RusqliteError, Row};
+
+use crate::db::User;
+
+fn user(row: &Row) -> Result<User, RusqliteError> {
+    Ok(User {
+        name: row.get(0)?,
+        password: row.get(1)?,
+        created: DateTime::<Utc>::from_utc(
+            NaiveDateTime::from_timestamp_opt(row.get::<usize, i64>(2)?, 0).unwrap_or_default(),
+            Utc,
+        ),
+    })
+}
+
+#[tracing::instrument(name = "db::users::list", skip_all, fields(db.system = "sqlite"))]
+pub async fn list() -> Result<Vec<User>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("SELECT name, password, created FROM users ORDER BY name ASC")?;
+    let rows = statement.query_map([], user)?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::users::get", skip_all, fields(db.system = "sqlite"))]
+pub async fn get(name: &str) -> Result<User, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare("SELECT name, password, created FROM users WHERE name = ?")?;
+    statement.query_row([name], user)
+}
+
+/// Create a user, or change the password of an existing one
+#[tracing::instrument(name = "db::users::save", skip_all, fields(db.system = "sqlite"))]
+pub async fn save(name: &str, password: &str) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute(
+        "INSERT INTO users (name, password, created) VALUES (?, ?, ?) ON CONFLICT (name) DO UPDATE SET password = excluded.password",
+        rusqlite::params![name, password, Utc::now().timestamp()],
+    )?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::users::delete", skip_all, fields(db.system = "sqlite"))]
+pub async fn delete(name: &str) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute("DELETE FROM users WHERE name = ?", [name])?;
+    tracing::info!("deleted user {}", name);
+
+    Ok(())
+}
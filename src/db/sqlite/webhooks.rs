@@ -0,0 +1,140 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rusqlite::{Error as RusqliteError, Row};
+
+use crate::db::{Webhook, WebhookDelivery};
+
+fn timestamp(seconds: i64) -> DateTime<Utc> {
+    DateTime::<Utc>::from_utc(
+        NaiveDateTime::from_timestamp_opt(seconds, 0).unwrap_or_default(),
+        Utc,
+    )
+}
+
+fn delivery(row: &Row) -> Result<WebhookDelivery, RusqliteError> {
+    Ok(WebhookDelivery {
+        id: row.get(0)?,
+        webhook: row.get(1)?,
+        payload: row.get(2)?,
+        attempts: row.get(3)?,
+        next_attempt: timestamp(row.get(4)?),
+        last_error: row.get(5)?,
+    })
+}
+
+#[tracing::instrument(name = "db::webhooks::list", skip_all, fields(db.system = "sqlite"))]
+pub async fn list() -> Result<Vec<Webhook>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT id, url, repository, actions, secret, last_delivered, last_error FROM webhooks ORDER BY id ASC",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok(Webhook {
+            id: row.get(0)?,
+            url: row.get(1)?,
+            repository: row.get(2)?,
+            actions: row.get(3)?,
+            secret: row.get(4)?,
+            last_delivered: row.get::<usize, Option<i64>>(5)?.map(timestamp),
+            last_error: row.get(6)?,
+        })
+    })?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::webhooks::save", skip_all, fields(db.system = "sqlite"))]
+pub async fn save(webhook: &Webhook) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn
+        .prepare("INSERT INTO webhooks (url, repository, actions, secret) VALUES (?, ?, ?, ?)")?;
+    statement.execute(rusqlite::params![
+        webhook.url,
+        webhook.repository,
+        webhook.actions,
+        webhook.secret
+    ])?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "db::webhooks::delete", skip_all, fields(db.system = "sqlite"))]
+pub async fn delete(id: i64) -> Result<(), RusqliteError> {
+    let mut conn = super::open()?;
+    let trans = conn.transaction()?;
+    trans.execute("DELETE FROM webhook_deliveries WHERE webhook = ?", [id])?;
+    trans.execute("DELETE FROM webhooks WHERE id = ?", [id])?;
+    trans.commit()?;
+    tracing::info!("deleted webhook {}", id);
+
+    Ok(())
+}
+
+/// Record the outcome of a delivery to a webhook
+#[tracing::instrument(name = "db::webhooks::set_status", skip_all, fields(db.system = "sqlite"))]
+pub async fn set_status(webhook: i64, error: Option<&str>) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    match error {
+        None => conn.execute(
+            "UPDATE webhooks SET last_delivered = ?, last_error = NULL WHERE id = ?",
+            rusqlite::params![Utc::now().timestamp(), webhook],
+        )?,
+        Some(error) => conn.execute(
+            "UPDATE webhooks SET last_error = ? WHERE id = ?",
+            rusqlite::params![error, webhook],
+        )?,
+    };
+
+    Ok(())
+}
+
+/// Queue a notification for delivery
+#[tracing::instrument(name = "db::webhooks::enqueue", skip_all, fields(db.system = "sqlite"))]
+pub async fn enqueue(webhook: i64, payload: &str) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute(
+        "INSERT INTO webhook_deliveries (webhook, payload, next_attempt) VALUES (?, ?, ?)",
+        rusqlite::params![webhook, payload, Utc::now().timestamp()],
+    )?;
+
+    Ok(())
+}
+
+/// List deliveries that are due to be attempted, oldest first
+#[tracing::instrument(name = "db::webhooks::due", skip_all, fields(db.system = "sqlite"))]
+pub async fn due() -> Result<Vec<WebhookDelivery>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement = conn.prepare(
+        "SELECT id, webhook, payload, attempts, next_attempt, last_error FROM webhook_deliveries WHERE next_attempt <= ? ORDER BY id ASC",
+    )?;
+    let rows = statement.query_map([Utc::now().timestamp()], delivery)?;
+    rows.into_iter().collect()
+}
+
+/// Count the queued deliveries of each webhook
+#[tracing::instrument(name = "db::webhooks::pending", skip_all, fields(db.system = "sqlite"))]
+pub async fn pending() -> Result<Vec<(i64, i64)>, RusqliteError> {
+    let conn = super::open()?;
+    let mut statement =
+        conn.prepare("SELECT webhook, count(*) FROM webhook_deliveries GROUP BY webhook")?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.into_iter().collect()
+}
+
+#[tracing::instrument(name = "db::webhooks::complete", skip_all, fields(db.system = "sqlite"))]
+pub async fn complete(id: i64) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute("DELETE FROM webhook_deliveries WHERE id = ?", [id])?;
+
+    Ok(())
+}
+
+/// Record a failed attempt and schedule the next one
+#[tracing::instrument(name = "db::webhooks::retry", skip_all, fields(db.system = "sqlite"))]
+pub async fn retry(id: i64, error: &str, next_attempt: DateTime<Utc>) -> Result<(), RusqliteError> {
+    let conn = super::open()?;
+    conn.execute(
+        "UPDATE webhook_deliveries SET attempts = attempts + 1, last_error = ?, next_attempt = ? WHERE id = ?",
+        rusqlite::params![error, next_attempt.timestamp(), id],
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,509 @@
+//! Importing from docker/distribution.
+//!
+//! The reference registry (`registry:2`) keeps its data on disk under `docker/registry/v2`: blobs under
+//! `blobs/sha256/<prefix>/<hex>/data`, and for every repository the manifests it holds under
+//! `_manifests/revisions` and its tags under `_manifests/tags/<tag>/current/link`. The [Importer] walks that
+//! layout and stores everything through the database, blobs before the manifests referring to them and
+//! manifests before the tags pointing at them. Whatever is already stored is skipped, so an interrupted
+//! import picks up where it stopped when run again, and importing again later only brings over what changed.
+//! Tags that tag protection or an immutable repository keep from moving are left as they are and reported.
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::events::{self, RequestInfo};
+use crate::fsck::Problem;
+use crate::{db, oci, protection, replication};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DistributionError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Database(#[from] db::Error),
+    #[error("{0} is not a docker/distribution storage directory, docker/registry/v2 is missing")]
+    NotARegistry(PathBuf),
+}
+
+/// A repository of the registry being imported
+#[derive(Debug, Clone)]
+pub struct Repository {
+    pub name: String,
+    path: PathBuf,
+}
+
+/// How many of something were stored and how many were there already
+#[derive(Debug, Default, Serialize)]
+pub struct Count {
+    pub imported: usize,
+    pub existing: usize,
+}
+
+/// What an import stored, and what it had to leave out
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub repositories: usize,
+    pub blobs: Count,
+    pub manifests: Count,
+    pub tags: Count,
+    pub problems: Vec<Problem>,
+    /// Tags that weren't moved, as protection rules or an immutable repository refused it
+    pub refused: Vec<Problem>,
+}
+
+/// How the imported data compares to the registry it came from
+#[derive(Debug, Default, Serialize)]
+pub struct Verification {
+    pub tags: usize,
+    pub manifests: usize,
+    pub blobs: usize,
+    pub problems: Vec<Problem>,
+}
+
+fn problem(
+    problems: &mut Vec<Problem>,
+    repository: Option<&str>,
+    reference: &str,
+    message: String,
+) {
+    problems.push(Problem {
+        repository: repository.map(str::to_string),
+        reference: reference.to_string(),
+        message,
+    });
+}
+
+/// Whether the database holds the blob and it matches its digest
+#[async_backtrace::framed]
+async fn holds_blob(digest: &str) -> bool {
+    db::blobs::get(digest)
+        .await
+        .is_ok_and(|content| format!("sha256:{}", sha256::digest(&content[..])) == digest)
+}
+
+/// The digest in a `link` file, or None if there is none or it holds something else
+fn read_link(path: &Path) -> Result<Option<String>, std::io::Error> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let link = std::fs::read_to_string(path)?;
+    let link = link.trim();
+    Ok(crate::DIGEST_REGEX.is_match(link).then(|| link.to_string()))
+}
+
+/// The directories within `path`, by name
+fn directories(path: &Path) -> Result<Vec<(String, PathBuf)>, std::io::Error> {
+    if !path.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut directories = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            directories.push((
+                entry.file_name().to_string_lossy().to_string(),
+                entry.path(),
+            ));
+        }
+    }
+    directories.sort();
+    Ok(directories)
+}
+
+pub struct Importer {
+    /// The `docker/registry/v2` directory
+    root: PathBuf,
+    request: RequestInfo,
+    /// Blobs already counted in this run, as many manifests share them
+    blobs: HashSet<String>,
+    pub report: Report,
+}
+
+impl Importer {
+    /// Open the storage directory of a registry, as configured in its `rootdirectory`, or the
+    /// `docker/registry/v2` directory within it
+    pub fn open(path: &Path, request: RequestInfo) -> Result<Self, DistributionError> {
+        let root = match path.join("docker/registry/v2") {
+            root if root.is_dir() => root,
+            _ if path.join("repositories").is_dir() && path.join("blobs").is_dir() => {
+                path.to_path_buf()
+            }
+            _ => return Err(DistributionError::NotARegistry(path.to_path_buf())),
+        };
+        Ok(Importer {
+            root,
+            request,
+            blobs: HashSet::new(),
+            report: Report::default(),
+        })
+    }
+
+    /// The repositories of the registry, or only those given if any
+    pub fn repositories(&self, only: &[String]) -> Result<Vec<Repository>, DistributionError> {
+        let mut repositories = Vec::new();
+        let mut pending = vec![(String::new(), self.root.join("repositories"))];
+        while let Some((prefix, path)) = pending.pop() {
+            for (name, path) in directories(&path)? {
+                // _manifests, _layers and _uploads belong to the repository, anything else is nested
+                if name.starts_with('_') {
+                    continue;
+                }
+                let name = format!("{}{}", prefix, name);
+                if path.join("_manifests").is_dir() {
+                    repositories.push(Repository {
+                        name: name.clone(),
+                        path: path.clone(),
+                    });
+                }
+                pending.push((format!("{}/", name), path));
+            }
+        }
+
+        repositories.retain(|r| only.is_empty() || only.contains(&r.name));
+        repositories.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(repositories)
+    }
+
+    fn blob_path(&self, digest: &str) -> PathBuf {
+        let (algorithm, hex) = digest.split_once(':').unwrap_or(("sha256", digest));
+        self.root
+            .join("blobs")
+            .join(algorithm)
+            .join(hex.get(..2).unwrap_or(hex))
+            .join(hex)
+            .join("data")
+    }
+
+    /// Read a blob, after checking it matches its digest
+    fn read_blob(&self, digest: &str) -> Result<Bytes, String> {
+        let content = std::fs::read(self.blob_path(digest))
+            .map_err(|e| format!("failed to read blob {}: {}", digest, e))?;
+        if format!("sha256:{}", sha256::digest(content.as_slice())) != digest {
+            return Err(format!("blob {} does not match its digest", digest));
+        }
+        Ok(Bytes::from(content))
+    }
+
+    /// The digests of the manifests of a repository
+    fn revisions(repository: &Repository) -> Result<Vec<String>, DistributionError> {
+        let mut revisions = Vec::new();
+        for (_, algorithm) in directories(&repository.path.join("_manifests/revisions"))? {
+            for (_, revision) in directories(&algorithm)? {
+                if let Some(digest) = read_link(&revision.join("link"))? {
+                    revisions.push(digest);
+                }
+            }
+        }
+        Ok(revisions)
+    }
+
+    /// The tags of a repository and the manifests they point to
+    fn tags(repository: &Repository) -> Result<Vec<(String, String)>, DistributionError> {
+        let mut tags = Vec::new();
+        for (tag, path) in directories(&repository.path.join("_manifests/tags"))? {
+            if let Some(digest) = read_link(&path.join("current/link"))? {
+                tags.push((tag, digest));
+            }
+        }
+        Ok(tags)
+    }
+
+    /// Store a blob unless it is stored already, replacing a damaged one
+    #[async_backtrace::framed]
+    async fn import_blob(&mut self, digest: &str) -> Result<Result<(), String>, DistributionError> {
+        if holds_blob(digest).await {
+            if self.blobs.insert(digest.to_string()) {
+                self.report.blobs.existing += 1;
+            }
+            return Ok(Ok(()));
+        }
+        let content = match self.read_blob(digest) {
+            Ok(content) => content,
+            Err(e) => return Ok(Err(e)),
+        };
+        db::blobs::save(digest, &content).await?;
+        self.blobs.insert(digest.to_string());
+        self.report.blobs.imported += 1;
+        Ok(Ok(()))
+    }
+
+    /// Import the manifests and tags of a repository, along with the blobs they refer to
+    #[async_backtrace::framed]
+    pub async fn import(&mut self, repository: &Repository) -> Result<(), DistributionError> {
+        let name = repository.name.as_str();
+        self.report.repositories += 1;
+
+        // manifest lists refer to other manifests, which have to be stored first
+        let mut manifests = Vec::new();
+        for digest in Self::revisions(repository)? {
+            let raw = self.read_blob(&digest).and_then(|raw| {
+                String::from_utf8(raw.to_vec()).map_err(|_| "manifest is not UTF-8".to_string())
+            });
+            let manifest = raw.and_then(|raw| {
+                serde_json::from_str::<serde_json::Value>(&raw)
+                    .map(|manifest| (raw, manifest))
+                    .map_err(|e| format!("unreadable manifest: {}", e))
+            });
+            match manifest {
+                Ok((raw, manifest)) => manifests.push((digest, raw, manifest)),
+                Err(e) => problem(&mut self.report.problems, Some(name), &digest, e),
+            }
+        }
+        manifests.sort_by_key(|(_, _, manifest)| manifest["manifests"].is_array());
+
+        let mut stored = HashSet::new();
+        'manifests: for (digest, raw, manifest) in manifests {
+            let (children, blobs) = oci::references(&manifest);
+            for child in &children {
+                if !stored.contains(child) {
+                    let message = format!("manifest list refers to missing manifest {}", child);
+                    problem(&mut self.report.problems, Some(name), &digest, message);
+                    continue 'manifests;
+                }
+            }
+            for blob in &blobs {
+                if let Err(e) = self.import_blob(blob).await? {
+                    problem(&mut self.report.problems, Some(name), &digest, e);
+                    continue 'manifests;
+                }
+            }
+
+            match db::manifests::get(name, &digest).await {
+                Ok(_) => self.report.manifests.existing += 1,
+                Err(_) => {
+                    db::repositories::save(name).await?;
+                    db::manifests::save(name, &digest, &raw).await?;
+                    // a manifest is stored once, in the first repository it was pushed to
+                    if db::manifests::get(name, &digest).await.is_err() {
+                        let message = "manifest is already stored in another repository";
+                        problem(
+                            &mut self.report.problems,
+                            Some(name),
+                            &digest,
+                            message.into(),
+                        );
+                        continue 'manifests;
+                    }
+                    self.report.manifests.imported += 1;
+                }
+            }
+            // associating again is harmless, and completes an import interrupted right after the manifest
+            for blob in &blobs {
+                db::blobs::associate(&digest, blob).await?;
+            }
+            stored.insert(digest);
+        }
+
+        for (tag, digest) in Self::tags(repository)? {
+            if !stored.contains(&digest) {
+                let message = format!("tag points to manifest {} which was not imported", digest);
+                problem(&mut self.report.problems, Some(name), &tag, message);
+                continue;
+            }
+            if db::tags::get_manifest(name, &tag).await.ok().as_ref() == Some(&digest) {
+                self.report.tags.existing += 1;
+                continue;
+            }
+            if let Some(violation) = protection::check_overwrite(name, &tag, &digest).await? {
+                problem(
+                    &mut self.report.refused,
+                    Some(name),
+                    &tag,
+                    violation.to_string(),
+                );
+                continue;
+            }
+            db::tags::save(name, &tag, &digest, Some(&self.request.actor)).await?;
+            replication::enqueue(name, &tag, Some(&digest)).await;
+            events::audit(
+                &self.request,
+                "image.import",
+                Some(name),
+                Some(&tag),
+                Some(&digest),
+            )
+            .await;
+            self.report.tags.imported += 1;
+        }
+
+        tracing::info!("imported repository {}", name);
+        Ok(())
+    }
+
+    /// Compare the tags, manifests and blobs of the given repositories with what the database holds. Tags the
+    /// import refused to move are in its report already, and not compared again.
+    #[async_backtrace::framed]
+    pub async fn verify(
+        &self,
+        repositories: &[Repository],
+    ) -> Result<Verification, DistributionError> {
+        let mut verification = Verification::default();
+        let mut blobs = HashMap::new();
+
+        for repository in repositories {
+            let name = repository.name.as_str();
+            for (tag, digest) in Self::tags(repository)? {
+                let refused = self
+                    .report
+                    .refused
+                    .iter()
+                    .any(|r| r.repository.as_deref() == Some(name) && r.reference == tag);
+                if refused {
+                    continue;
+                }
+                verification.tags += 1;
+                match db::tags::get_manifest(name, &tag).await {
+                    Ok(stored) if stored == digest => {}
+                    Ok(stored) => {
+                        let message = format!("tag points to {} rather than {}", stored, digest);
+                        problem(&mut verification.problems, Some(name), &tag, message);
+                    }
+                    Err(_) => {
+                        let message = "tag is missing".to_string();
+                        problem(&mut verification.problems, Some(name), &tag, message);
+                    }
+                }
+            }
+
+            for digest in Self::revisions(repository)? {
+                verification.manifests += 1;
+                let raw = match db::manifests::get(name, &digest).await {
+                    Ok(raw) => raw,
+                    Err(_) => {
+                        let message = "manifest is missing".to_string();
+                        problem(&mut verification.problems, Some(name), &digest, message);
+                        continue;
+                    }
+                };
+                if format!("sha256:{}", sha256::digest(raw.as_str())) != digest {
+                    let message = "manifest does not match its digest".to_string();
+                    problem(&mut verification.problems, Some(name), &digest, message);
+                    continue;
+                }
+                if let Ok(manifest) = serde_json::from_str(&raw) {
+                    for blob in oci::references(&manifest).1 {
+                        blobs
+                            .entry(blob)
+                            .or_insert_with(|| (name.to_string(), digest.clone()));
+                    }
+                }
+            }
+        }
+
+        for (blob, (repository, manifest)) in blobs {
+            verification.blobs += 1;
+            // blobs removed from disk since the import can't be compared, only their presence is checked
+            let expected = std::fs::metadata(self.blob_path(&blob))
+                .ok()
+                .map(|m| m.len());
+            match db::blobs::length(&blob).await {
+                Ok(length) if expected.is_none_or(|e| e == length as u64) => {
+                    // a blob of the right length can still be a damaged one
+                    if !holds_blob(&blob).await {
+                        let message = format!("blob {} does not match its digest", blob);
+                        problem(
+                            &mut verification.problems,
+                            Some(&repository),
+                            &manifest,
+                            message,
+                        );
+                    }
+                }
+                Ok(length) => {
+                    let message = format!(
+                        "blob {} holds {} bytes rather than {}",
+                        blob,
+                        length,
+                        expected.unwrap_or_default()
+                    );
+                    problem(
+                        &mut verification.problems,
+                        Some(&repository),
+                        &manifest,
+                        message,
+                    );
+                }
+                Err(_) => {
+                    let message = format!("manifest refers to missing blob {}", blob);
+                    problem(
+                        &mut verification.problems,
+                        Some(&repository),
+                        &manifest,
+                        message,
+                    );
+                }
+            }
+        }
+
+        Ok(verification)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Importer, Repository};
+    use crate::events::RequestInfo;
+
+    #[test]
+    fn test_walk() {
+        let root = std::env::temp_dir().join(format!("pequod-distribution-{}", std::process::id()));
+        let v2 = root.join("docker/registry/v2");
+        let digest = format!("sha256:{}", sha256::digest("{}"));
+        let hex = digest.trim_start_matches("sha256:");
+        for (path, content) in [
+            (
+                format!("blobs/sha256/{}/{}/data", &hex[..2], hex),
+                "{}".to_string(),
+            ),
+            (
+                format!(
+                    "repositories/team/app/_manifests/revisions/sha256/{}/link",
+                    hex
+                ),
+                digest.clone(),
+            ),
+            (
+                "repositories/team/app/_manifests/tags/v1/current/link".to_string(),
+                format!("{}\n", digest),
+            ),
+            (
+                "repositories/team/app/_uploads/x/startedat".to_string(),
+                String::new(),
+            ),
+            (
+                "repositories/base/_manifests/tags/broken/current/link".to_string(),
+                "nonsense".to_string(),
+            ),
+        ] {
+            let path = v2.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        let importer = Importer::open(&root, RequestInfo::command("test")).unwrap();
+        let repositories = importer.repositories(&[]).unwrap();
+        let names = repositories
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["base", "team/app"]);
+
+        let app: &Repository = &repositories[1];
+        assert_eq!(
+            Importer::revisions(app).unwrap(),
+            std::slice::from_ref(&digest)
+        );
+        assert_eq!(
+            Importer::tags(app).unwrap(),
+            [("v1".to_string(), digest.clone())]
+        );
+        assert!(Importer::tags(&repositories[0]).unwrap().is_empty());
+        assert_eq!(importer.read_blob(&digest).unwrap().as_ref(), b"{}");
+        assert_eq!(importer.repositories(&["base".into()]).unwrap().len(), 1);
+
+        assert!(Importer::open(&v2.join("blobs"), RequestInfo::command("test")).is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+//! Registry events, in the envelope format of docker/distribution notifications.
+//!
+//! Handlers describe what happened as an [Event] and pass it to [emit], which records it in the audit log
+//! and hands it to the webhooks. Admin operations, which have no place in the envelope format, only go to
+//! the audit log through [audit]. The [RequestInfo] extractor collects the details of the request and the
+//! actor that caused an event.
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::header::{HOST, USER_AGENT};
+use axum::http::request::Parts;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Principal;
+use crate::db::{self, AuditEvent};
+use crate::webhooks;
+
+/// Media type of a notification envelope
+pub const ENVELOPE_MEDIA_TYPE: &str = "application/vnd.docker.distribution.events.v1+json";
+
+/// Media type reported for blobs, whose actual type is only known to the manifests referencing them
+pub const BLOB_MEDIA_TYPE: &str = "application/octet-stream";
+
+lazy_static! {
+    static ref INSTANCE_ID: String = uuid::Uuid::new_v4().as_hyphenated().to_string();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Push,
+    Pull,
+    Delete,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Push => "push",
+            Action::Pull => "pull",
+            Action::Delete => "delete",
+        }
+    }
+}
+
+/// What an event happened to
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Target {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<i64>,
+    pub repository: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+/// The request that caused an event
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestInfo {
+    pub id: String,
+    pub addr: String,
+    pub host: String,
+    pub method: String,
+    pub useragent: String,
+    /// Who made the request, the principal if authenticated and the client IP otherwise
+    #[serde(skip)]
+    pub actor: String,
+    /// The authenticated user, if any
+    #[serde(skip)]
+    pub principal: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Actor {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+}
+
+/// The registry instance an event happened on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Source {
+    pub addr: String,
+    #[serde(rename = "instanceID")]
+    pub instance_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    pub action: Action,
+    pub target: Target,
+    pub request: RequestInfo,
+    pub actor: Actor,
+    pub source: Source,
+}
+
+/// A batch of events as delivered to webhooks
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub events: Vec<Event>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let addr = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        let principal = parts.extensions.get::<Principal>().map(|p| p.name.clone());
+
+        Ok(RequestInfo {
+            id: Some(header("x-request-id"))
+                .filter(|id| !id.is_empty())
+                .unwrap_or_else(|| uuid::Uuid::new_v4().as_hyphenated().to_string()),
+            addr: addr.map(|a| a.to_string()).unwrap_or_default(),
+            host: header(HOST.as_str()),
+            method: parts.method.to_string(),
+            useragent: header(USER_AGENT.as_str()),
+            actor: principal
+                .clone()
+                .or_else(|| addr.map(|a| a.ip().to_string()))
+                .unwrap_or_default(),
+            principal,
+        })
+    }
+}
+
+impl RequestInfo {
+    /// Describe a command run on the command line, on behalf of the user running it
+    pub fn command(name: &str) -> Self {
+        let user = std::env::var("USER").ok().filter(|u| !u.is_empty());
+        RequestInfo {
+            id: uuid::Uuid::new_v4().as_hyphenated().to_string(),
+            method: name.to_string(),
+            useragent: format!("pequod/{}", env!("CARGO_PKG_VERSION")),
+            actor: user.clone().unwrap_or_else(|| "cli".to_string()),
+            principal: user,
+            ..Default::default()
+        }
+    }
+
+    /// Describe something the registry does on its own, like caching from upstream or applying retention
+    /// policies, with `actor` naming what did it
+    pub fn system(actor: &str) -> Self {
+        RequestInfo {
+            id: uuid::Uuid::new_v4().as_hyphenated().to_string(),
+            useragent: format!("pequod/{}", env!("CARGO_PKG_VERSION")),
+            actor: actor.to_string(),
+            principal: Some(actor.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// The URL a manifest or blob can be fetched from, as seen by the client making this request
+    pub fn url(&self, repository: &str, resource: &str, reference: &str) -> String {
+        format!(
+            "{}://{}/v2/{}/{}/{}",
+            crate::tls::scheme(),
+            self.host,
+            repository,
+            resource,
+            reference
+        )
+    }
+}
+
+impl Event {
+    pub fn new(action: Action, target: Target, request: &RequestInfo) -> Self {
+        Event {
+            id: uuid::Uuid::new_v4().as_hyphenated().to_string(),
+            timestamp: Utc::now(),
+            action,
+            target,
+            request: request.clone(),
+            actor: Actor {
+                name: request.actor.clone(),
+            },
+            source: Source {
+                addr: request.host.clone(),
+                instance_id: INSTANCE_ID.clone(),
+            },
+        }
+    }
+}
+
+/// Record an operation in the audit log
+///
+/// Failing to record is logged rather than returned, so it never fails the operation itself.
+#[async_backtrace::framed]
+pub async fn audit(
+    request: &RequestInfo,
+    action: &str,
+    repository: Option<&str>,
+    reference: Option<&str>,
+    digest: Option<&str>,
+) {
+    let event = AuditEvent {
+        id: 0,
+        timestamp: Utc::now(),
+        action: action.to_string(),
+        repository: repository.map(str::to_string),
+        reference: reference.map(str::to_string),
+        digest: digest.map(str::to_string),
+        addr: request
+            .addr
+            .parse::<SocketAddr>()
+            .map(|a| a.ip().to_string())
+            .ok(),
+        user_agent: Some(request.useragent.clone()).filter(|u| !u.is_empty()),
+        principal: request.principal.clone(),
+    };
+    if let Err(e) = db::events::save(&event).await {
+        tracing::error!("failed to record {} in the audit log: {}", action, e);
+    }
+}
+
+/// Record an event and hand it to everything listening for events
+#[async_backtrace::framed]
+pub async fn emit(event: Event) {
+    let target = &event.target;
+    audit(
+        &event.request,
+        event.action.as_str(),
+        Some(&target.repository),
+        target.tag.as_deref().or(target.digest.as_deref()),
+        target.digest.as_deref(),
+    )
+    .await;
+    webhooks::enqueue(&event).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, Envelope, Event, RequestInfo, Target};
+
+    #[test]
+    fn test_envelope_format() {
+        let request = RequestInfo {
+            host: "registry.example.com".to_string(),
+            actor: "alice".to_string(),
+            ..Default::default()
+        };
+        let target = Target {
+            media_type: Some("application/vnd.docker.distribution.manifest.v2+json".to_string()),
+            size: Some(708),
+            digest: Some("sha256:abc".to_string()),
+            repository: "library/nginx".to_string(),
+            tag: Some("latest".to_string()),
+            ..Default::default()
+        };
+        let envelope = Envelope {
+            events: vec![Event::new(Action::Push, target, &request)],
+        };
+
+        let json = serde_json::to_value(&envelope).unwrap();
+        let event = &json["events"][0];
+        assert_eq!(event["action"], "push");
+        assert_eq!(
+            event["target"]["mediaType"],
+            "application/vnd.docker.distribution.manifest.v2+json"
+        );
+        assert_eq!(event["target"]["tag"], "latest");
+        assert!(event["target"].get("url").is_none());
+        assert_eq!(event["actor"]["name"], "alice");
+        assert_eq!(event["source"]["addr"], "registry.example.com");
+        assert!(event["source"]["instanceID"].is_string());
+        assert!(event["request"].get("actor").is_none());
+        assert!(event["request"].get("principal").is_none());
+    }
+}
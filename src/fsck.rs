@@ -0,0 +1,152 @@
+//! Consistency checks.
+//!
+//! [check] walks the whole registry and reports tags pointing at missing manifests, manifests or blobs
+//! whose content doesn't match their digest, and manifests referring to blobs or manifests that are gone.
+//! It only reads, repairing is left to garbage collection and to pushing the affected images again.
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::api::manifests::Manifest;
+use crate::db;
+
+/// Something wrong with the stored data
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub repository: Option<String>,
+    pub reference: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.repository {
+            Some(repository) => write!(f, "{}@{}: {}", repository, self.reference, self.message),
+            None => write!(f, "{}: {}", self.reference, self.message),
+        }
+    }
+}
+
+/// What [check] looked at and found
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub repositories: usize,
+    pub tags: usize,
+    pub manifests: usize,
+    pub blobs: usize,
+    /// Blobs of uploads that were never finished
+    pub uploads: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    fn problem(&mut self, repository: Option<&str>, reference: &str, message: String) {
+        self.problems.push(Problem {
+            repository: repository.map(str::to_string),
+            reference: reference.to_string(),
+            message,
+        });
+    }
+}
+
+/// Whether `content` hashes to `digest`
+fn verify(digest: &str, content: &[u8]) -> bool {
+    format!("sha256:{}", sha256::digest(content)) == digest
+}
+
+/// Check every tag, manifest and blob
+#[async_backtrace::framed]
+pub async fn check() -> Result<Report, db::Error> {
+    let mut report = Report::default();
+
+    for repository in db::repositories::list().await? {
+        let name = repository.name.as_str();
+        report.repositories += 1;
+
+        let digests = db::manifests::digests(name).await?;
+        let known = digests.iter().cloned().collect::<HashSet<_>>();
+
+        for tag in db::tags::list(name).await? {
+            report.tags += 1;
+            if !known.contains(&tag.manifest) {
+                report.problem(
+                    Some(name),
+                    &tag.name,
+                    format!("tag points to missing manifest {}", tag.manifest),
+                );
+            }
+        }
+
+        for digest in &digests {
+            report.manifests += 1;
+            let raw = db::manifests::get(name, digest).await?;
+            if !verify(digest, raw.as_bytes()) {
+                report.problem(
+                    Some(name),
+                    digest,
+                    "manifest does not match its digest".to_string(),
+                );
+            }
+
+            match serde_json::from_str::<Manifest>(&raw) {
+                Ok(Manifest::Image(image)) => {
+                    let blobs = std::iter::once(&image.config.digest)
+                        .chain(image.layers.iter().map(|l| &l.digest));
+                    for blob in blobs {
+                        if db::blobs::length(blob).await.is_err() {
+                            report.problem(
+                                Some(name),
+                                digest,
+                                format!("manifest refers to missing blob {}", blob),
+                            );
+                        }
+                    }
+                }
+                Ok(Manifest::List(list)) => {
+                    for manifest in &list.manifests {
+                        if !known.contains(&manifest.digest) {
+                            report.problem(
+                                Some(name),
+                                digest,
+                                format!(
+                                    "manifest list refers to missing manifest {}",
+                                    manifest.digest
+                                ),
+                            );
+                        }
+                    }
+                }
+                Err(e) => report.problem(Some(name), digest, format!("unreadable manifest: {}", e)),
+            }
+        }
+    }
+
+    for digest in db::blobs::digests().await? {
+        // unfinished uploads are stored under their upload ID until they are complete
+        if !crate::DIGEST_REGEX.is_match(&digest) {
+            report.uploads += 1;
+            continue;
+        }
+        report.blobs += 1;
+        let blob = db::blobs::get(&digest).await?;
+        if !verify(&digest, &blob) {
+            report.problem(None, &digest, "blob does not match its digest".to_string());
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::verify;
+
+    #[test]
+    fn test_verify() {
+        let digest = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(verify(digest, b"hello"));
+        assert!(!verify(digest, b"hello!"));
+        assert!(!verify("md5:5d41402abc4b2a76b9719d911017c592", b"hello"));
+    }
+}
@@ -0,0 +1,185 @@
+//! Health checks for orchestrators.
+//!
+//! `/healthz` answers as long as the process is serving. `/readyz` checks what serving requests needs: that
+//! the database can be reached, that it accepts writes (blobs are stored in it), that the filesystems of the
+//! database and backups have `health.min_free_space` left, and that the schema is the one this build expects.
+//! Each check is reported as ok or failed in a JSON breakdown, with 503 if any of them fails, and why is
+//! logged. On SIGTERM or Ctrl-C, `/readyz` answers 503 for `health.shutdown_delay` before the server stops
+//! accepting connections and finishes the requests in flight, so the registry can be taken out of rotation
+//! first.
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytesize::ByteSize;
+use serde_json::{json, Map, Value};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::db;
+
+/// How long a check may take before it counts as failed
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Liveness, the process is up and handling requests
+#[async_backtrace::framed]
+pub async fn alive() -> Json<Value> {
+    Json(json!({ "status": "alive" }))
+}
+
+/// Readiness, every check passes and the server isn't shutting down
+#[async_backtrace::framed]
+pub async fn ready() -> Response {
+    if STOPPING.load(Ordering::SeqCst) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "status": "stopping" })),
+        )
+            .into_response();
+    }
+
+    let (database, disk) = tokio::join!(check(database()), check(async { disk() }));
+    // the other checks would create an empty sqlite database where a missing one was
+    let (storage, migrations) = match database {
+        Ok(_) => tokio::join!(check(storage()), check(migrations())),
+        Err(_) => {
+            let skipped = || Err("skipped, the database can't be reached".to_string());
+            (skipped(), skipped())
+        }
+    };
+    let mut ready = true;
+    let mut checks = Map::new();
+    for (name, result) in [
+        ("database", database),
+        ("storage", storage),
+        ("disk", disk),
+        ("migrations", migrations),
+    ] {
+        // anyone may ask, so paths, free space and errors are only logged
+        let outcome = match result {
+            Ok(details) => {
+                tracing::debug!("readiness check {} passed: {}", name, details);
+                "ok"
+            }
+            Err(e) => {
+                tracing::warn!("readiness check {} failed: {}", name, e);
+                ready = false;
+                "failed"
+            }
+        };
+        checks.insert(name.to_string(), json!(outcome));
+    }
+
+    let (status, summary) = match ready {
+        true => (StatusCode::OK, "ready"),
+        false => (StatusCode::SERVICE_UNAVAILABLE, "not ready"),
+    };
+    (status, Json(json!({ "status": summary, "checks": checks }))).into_response()
+}
+
+async fn check(check: impl Future<Output = Result<Value, String>>) -> Result<Value, String> {
+    tokio::time::timeout(TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err(format!("timed out after {}s", TIMEOUT.as_secs())))
+}
+
+async fn database() -> Result<Value, String> {
+    db::ping().await.map_err(|e| e.to_string())?;
+    Ok(json!({}))
+}
+
+async fn storage() -> Result<Value, String> {
+    match db::writable().await {
+        Ok(true) => Ok(json!({})),
+        Ok(false) => Err("the database does not accept writes".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn disk() -> Result<Value, String> {
+    let minimum = crate::config::get().health.min_free_space;
+    let mut paths = Vec::new();
+    for directory in directories() {
+        let free = fs2::available_space(&directory).map_err(|e| {
+            format!(
+                "can't read the free space of {}: {}",
+                directory.display(),
+                e
+            )
+        })?;
+        if free < minimum.as_u64() {
+            return Err(format!(
+                "{} has {} free, less than {}",
+                directory.display(),
+                ByteSize::b(free).to_string_as(true),
+                minimum.to_string_as(true)
+            ));
+        }
+        paths.push(json!({ "path": directory, "free": free }));
+    }
+    Ok(json!({ "paths": paths }))
+}
+
+async fn migrations() -> Result<Value, String> {
+    db::check_schema().await.map_err(|e| e.to_string())?;
+    Ok(json!({ "version": db::latest_version() }))
+}
+
+/// The directories the registry writes to, or the closest existing parents of those not created yet
+fn directories() -> Vec<PathBuf> {
+    let config = crate::config::get();
+    let mut directories = vec![existing(&config.backup.directory)];
+    #[cfg(feature = "sqlite")]
+    if let Some(parent) = config.database.path.parent() {
+        directories.push(existing(parent));
+    }
+    directories.sort();
+    directories.dedup();
+    directories
+}
+
+fn existing(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.is_dir())
+        .map_or_else(|| PathBuf::from("."), Path::to_path_buf)
+}
+
+/// Resolves `health.shutdown_delay` after the server is asked to stop, while `/readyz` reports not being
+/// ready
+#[async_backtrace::framed]
+pub async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM can be handled");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    STOPPING.store(true, Ordering::SeqCst);
+
+    let delay = crate::retention::parse_duration(&crate::config::get().health.shutdown_delay)
+        .unwrap_or_default();
+    tracing::info!("shutting down in {}s", delay);
+    tokio::time::sleep(Duration::from_secs(delay as u64)).await;
+    tracing::info!("no longer accepting connections, finishing the requests in flight");
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::existing;
+
+    #[test]
+    fn test_existing() {
+        assert_eq!(existing(Path::new("src/nope/deeper")), PathBuf::from("src"));
+        assert_eq!(existing(Path::new("nope")), PathBuf::from("."));
+        assert_eq!(existing(Path::new("")), PathBuf::from("."));
+        assert_eq!(existing(Path::new("/")), PathBuf::from("/"));
+    }
+}
@@ -1,23 +1,76 @@
+// the futures of commands nest deeply enough with both backends and their spans to need more than the default
+#![recursion_limit = "256"]
+
+use std::net::SocketAddr;
+
 use axum::extract::DefaultBodyLimit;
 use axum::http::Request;
 use axum::middleware::Next;
 use axum::response::Response;
 use axum::{routing, Extension, Router, ServiceExt};
+use clap::Parser;
 use lazy_static::lazy_static;
 use regex::Regex;
 use tower::Layer;
 
 pub mod api;
+pub mod auth;
+pub mod backup;
+pub mod cli;
+pub mod client;
+pub mod config;
+#[cfg(all(feature = "sqlite", feature = "postgres"))]
+pub mod copy;
 pub mod db;
+pub mod distribution;
+pub mod events;
+pub mod fsck;
+pub mod health;
+pub mod metrics;
+pub mod oci;
+pub mod pattern;
+pub mod protection;
+pub mod proxy;
+pub mod quota;
+pub mod ratelimit;
+pub mod replication;
+pub mod retention;
+pub mod telemetry;
+pub mod tls;
 pub mod ui;
+pub mod webhooks;
 
 lazy_static! {
+    /// The name is the longest one followed by a resource, as names may contain slashes and even `/blobs/`
     static ref URI_NAME_REGEX: Regex =
-        Regex::new(r"/v2/(?P<name>[\w/]+)/(?P<resource>(tags|manifests|blobs))/").unwrap();
+        Regex::new(r"^/v2/(?P<name>.+)/(?P<resource>tags|manifests|blobs)/(?P<rest>.*)$").unwrap();
     static ref DIGEST_REGEX: Regex =
         Regex::new(r"^(?P<algorithm>[A-Za-z0-9_+.-]+):(?P<hex>[A-Fa-f0-9]+)$").unwrap();
 }
 
+/// A `/v2` request path taken apart the way the router sees it
+#[derive(Debug, PartialEq)]
+pub struct RepositoryPath {
+    pub name: String,
+    /// `tags`, `manifests` or `blobs`
+    pub resource: String,
+    /// What follows the resource, like a reference, a digest or `uploads/<id>`
+    pub rest: String,
+}
+
+impl RepositoryPath {
+    /// Take a path apart, with or without its slashes encoded by [rewrite_request_uri]
+    pub fn parse(path: &str) -> Option<RepositoryPath> {
+        let path = path.replace("%2F", "/").replace("%2f", "/");
+        let captures = URI_NAME_REGEX.captures(&path)?;
+        Some(RepositoryPath {
+            name: captures["name"].to_string(),
+            resource: captures["resource"].to_string(),
+            rest: captures["rest"].to_string(),
+        })
+    }
+}
+
 #[async_backtrace::framed]
 async fn rewrite_request_uri<B>(mut req: Request<B>, next: Next<B>) -> Response {
     let captures = match URI_NAME_REGEX.captures(req.uri().path()) {
@@ -42,12 +95,54 @@ async fn rewrite_request_uri<B>(mut req: Request<B>, next: Next<B>) -> Response
 #[async_backtrace::framed]
 async fn main() {
     dotenvy::dotenv().ok();
+    let cli = cli::Cli::parse();
+    let command = cli.command.filter(|c| !matches!(c, cli::Command::Serve));
+    match config::Config::load(cli.overrides, command.is_none()) {
+        Ok(config) => config::init(config),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ::std::process::exit(1);
+        }
+    };
+
     if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info");
+        // commands print what they did, so only warnings need to be logged
+        let level = if command.is_some() { "warn" } else { "info" };
+        std::env::set_var("RUST_LOG", level);
+    }
+    if let Err(e) = telemetry::init() {
+        eprintln!("error: {}", e);
+        ::std::process::exit(1);
+    }
+
+    if let Some(command) = command {
+        let result = cli::run(command).await;
+        telemetry::shutdown();
+        if let Err(e) = result {
+            eprintln!("error: {}", e);
+            ::std::process::exit(1);
+        }
+        return;
+    }
+    serve().await;
+    telemetry::shutdown();
+}
+
+#[async_backtrace::framed]
+async fn serve() {
+    let config = config::get();
+
+    let schema = match config.database.migrate {
+        true => db::migrate().await.map(|_| ()),
+        false => db::check_schema().await,
+    };
+    if let Err(e) = schema {
+        tracing::error!("{}", e);
+        ::std::process::exit(1);
     }
-    tracing_subscriber::fmt::init();
 
-    let tera = match tera::Tera::new("src/ui/templates/**/*.html") {
+    let templates = config.server.templates.join("**/*.html");
+    let tera = match tera::Tera::new(&templates.to_string_lossy()) {
         Ok(t) => t,
         Err(e) => {
             println!("Parsing error(s): {}", e);
@@ -58,11 +153,120 @@ async fn main() {
         tracing::info!("loaded template {}", t);
     }
 
+    tokio::spawn(retention::schedule());
+    tokio::spawn(backup::schedule());
+    tokio::spawn(replication::worker());
+    tokio::spawn(webhooks::worker());
+
     let rewriter = axum::middleware::from_fn(rewrite_request_uri);
     let router = Router::new()
         .route("/", routing::get(ui::index))
         .route("/admin", routing::get(ui::admin))
         .route("/admin/cleanup", routing::post(ui::cleanup))
+        .route(
+            "/admin/access",
+            routing::get(ui::access::index).post(ui::access::grant),
+        )
+        .route("/admin/access/revoke", routing::post(ui::access::revoke))
+        .route(
+            "/admin/access/members",
+            routing::post(ui::access::add_member),
+        )
+        .route(
+            "/admin/access/members/delete",
+            routing::post(ui::access::remove_member),
+        )
+        .route("/admin/events", routing::get(ui::events::index))
+        .route(
+            "/admin/api/backups",
+            routing::get(ui::backup::list).post(ui::backup::create),
+        )
+        .route("/admin/api/events", routing::get(ui::events::list))
+        .route("/admin/api/export", routing::get(ui::oci::export))
+        // the import streams its body to disk and checks the body limit itself
+        .route("/admin/api/import", routing::post(ui::oci::import))
+        .route(
+            "/admin/protection",
+            routing::get(ui::protection::index).post(ui::protection::create),
+        )
+        .route(
+            "/admin/protection/delete",
+            routing::post(ui::protection::delete),
+        )
+        .route(
+            "/admin/protection/immutable",
+            routing::post(ui::protection::immutable),
+        )
+        .route("/admin/tags", routing::post(ui::protection::force_move))
+        .route("/admin/tags/rollback", routing::post(ui::rollback))
+        .route(
+            "/admin/tags/delete",
+            routing::post(ui::protection::force_delete),
+        )
+        .route(
+            "/admin/quotas",
+            routing::get(ui::quotas::index).post(ui::quotas::create),
+        )
+        .route("/admin/quotas/delete", routing::post(ui::quotas::delete))
+        .route(
+            "/admin/replication",
+            routing::get(ui::replication::index).post(ui::replication::create),
+        )
+        .route(
+            "/admin/replication/delete",
+            routing::post(ui::replication::delete),
+        )
+        .route(
+            "/admin/replication/sync",
+            routing::post(ui::replication::sync),
+        )
+        .route(
+            "/admin/retention",
+            routing::get(ui::retention::index).post(ui::retention::create),
+        )
+        .route(
+            "/admin/retention/delete",
+            routing::post(ui::retention::delete),
+        )
+        .route(
+            "/admin/retention/apply",
+            routing::post(ui::retention::apply),
+        )
+        .route(
+            "/admin/robots",
+            routing::get(ui::robots::index).post(ui::robots::create),
+        )
+        .route("/admin/robots/delete", routing::post(ui::robots::delete))
+        .route(
+            "/admin/api/robots",
+            routing::get(ui::robots::list).post(ui::robots::create_json),
+        )
+        .route(
+            "/admin/api/robots/:name",
+            routing::delete(ui::robots::delete_json),
+        )
+        .route(
+            "/admin/users",
+            routing::get(ui::users::index).post(ui::users::save),
+        )
+        .route("/admin/users/delete", routing::post(ui::users::delete))
+        .route(
+            "/admin/webhooks",
+            routing::get(ui::webhooks::index).post(ui::webhooks::create),
+        )
+        .route(
+            "/admin/webhooks/delete",
+            routing::post(ui::webhooks::delete),
+        )
+        .route("/token", routing::get(auth::token::issue))
+        .route(
+            "/login",
+            routing::get(auth::session::form).post(auth::session::login),
+        )
+        .route("/logout", routing::post(auth::session::logout))
+        .route("/metrics", routing::get(metrics::serve))
+        .route("/healthz", routing::get(health::alive))
+        .route("/readyz", routing::get(health::ready))
         .route("/*name", routing::get(ui::repo))
         .nest(
             "/v2",
@@ -90,29 +294,48 @@ async fn main() {
                     "/:name/blobs/uploads/:uuid",
                     routing::patch(api::blob::patch_uploads)
                         .put(api::blob::finish_uploads)
-                        .layer(DefaultBodyLimit::max(1024 * 1024 * 1024)),
-                ),
+                        .layer(DefaultBodyLimit::max(
+                            config.server.body_limit.as_u64() as usize
+                        )),
+                )
+                .route_layer(axum::middleware::from_fn(metrics::matched)),
         )
-        .layer(Extension(tera));
+        .layer(axum::middleware::from_fn(ratelimit::limit))
+        .layer(axum::middleware::from_fn(auth::authenticate))
+        .layer(axum::middleware::from_fn(ratelimit::limit_clients))
+        .layer(axum::middleware::from_fn(auth::csrf::protect))
+        .layer(Extension(tera))
+        .layer(axum::middleware::from_fn(metrics::track))
+        .layer(axum::middleware::from_fn(telemetry::trace));
 
     let app = rewriter.layer(router);
 
-    async_backtrace::frame!(
-        axum::Server::bind(&"0.0.0.0:5000".parse().unwrap()).serve(app.into_make_service())
-    )
-    .await
-    .unwrap();
+    let addr = config.server.bind;
+    match tls::config() {
+        Ok(Some(config)) => {
+            async_backtrace::frame!(tls::serve(addr, app, config, health::shutdown())).await
+        }
+        Ok(None) => async_backtrace::frame!(axum::Server::bind(&addr)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(health::shutdown()))
+        .await
+        .unwrap(),
+        Err(e) => {
+            tracing::error!("failed to set up TLS: {}", e);
+            ::std::process::exit(1);
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::URI_NAME_REGEX;
+    use super::{RepositoryPath, URI_NAME_REGEX};
 
     #[test]
     fn test_tags_matches_no_slash() {
         let uri = "/v2/nginx/tags/list";
         let captures = URI_NAME_REGEX.captures(uri);
-        assert_eq!(captures.is_some(), true);
+        assert!(captures.is_some());
         let captures = captures.unwrap();
         assert_eq!(captures.name("name").unwrap().as_str(), "nginx");
         assert_eq!(captures.name("resource").unwrap().as_str(), "tags");
@@ -122,9 +345,23 @@ mod test {
     fn test_tags_matches_with_slash() {
         let uri = "/v2/library/nginx/tags/list";
         let captures = URI_NAME_REGEX.captures(uri);
-        assert_eq!(captures.is_some(), true);
+        assert!(captures.is_some());
         let captures = captures.unwrap();
         assert_eq!(captures.name("name").unwrap().as_str(), "library/nginx");
         assert_eq!(captures.name("resource").unwrap().as_str(), "tags");
     }
+
+    #[test]
+    fn test_repository_path() {
+        let path = RepositoryPath::parse("/v2/a/blobs/b/manifests/latest").unwrap();
+        assert_eq!(path.name, "a/blobs/b");
+        assert_eq!(path.resource, "manifests");
+        assert_eq!(path.rest, "latest");
+        let path = RepositoryPath::parse("/v2/team%2Fmy-app/blobs/uploads/1234").unwrap();
+        assert_eq!(path.name, "team/my-app");
+        assert_eq!(path.resource, "blobs");
+        assert_eq!(path.rest, "uploads/1234");
+        assert_eq!(RepositoryPath::parse("/v2/_catalog"), None);
+        assert_eq!(RepositoryPath::parse("/admin/a/tags/list"), None);
+    }
 }
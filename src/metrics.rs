@@ -0,0 +1,203 @@
+//! Prometheus metrics.
+//!
+//! `/metrics` serves them in the Prometheus text format. Requests are counted and timed per route, method and
+//! status by [track], the handlers count the bytes of blobs and manifests they receive and send, garbage
+//! collection counts its runs, [crate::ratelimit] counts the requests it refuses, and both database backends
+//! time every statement through [observe_query]. The storage gauges and the number of upload sessions are read
+//! from the database on every scrape.
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::MatchedPath,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::db::{self, Garbage};
+
+lazy_static! {
+    static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "pequod_http_requests_total",
+        "HTTP requests handled, by route, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "pequod_http_request_duration_seconds",
+        "Time taken to handle HTTP requests, by route, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    static ref UPLOADED_BYTES: IntCounter = register_int_counter!(
+        "pequod_uploaded_bytes_total",
+        "Bytes of blobs and manifests pushed"
+    )
+    .unwrap();
+    static ref DOWNLOADED_BYTES: IntCounter = register_int_counter!(
+        "pequod_downloaded_bytes_total",
+        "Bytes of blobs and manifests pulled"
+    )
+    .unwrap();
+    static ref GC_RUNS: IntCounter =
+        register_int_counter!("pequod_gc_runs_total", "Garbage collections run").unwrap();
+    static ref GC_DELETED_BLOBS: IntCounter = register_int_counter!(
+        "pequod_gc_deleted_blobs_total",
+        "Blobs deleted by garbage collection"
+    )
+    .unwrap();
+    static ref GC_DELETED_BYTES: IntCounter = register_int_counter!(
+        "pequod_gc_deleted_bytes_total",
+        "Bytes of blobs deleted by garbage collection"
+    )
+    .unwrap();
+    static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "pequod_rate_limited_requests_total",
+        "Requests refused for being over a rate limit, by budget",
+        &["budget"]
+    )
+    .unwrap();
+    static ref QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "pequod_database_query_duration_seconds",
+        "Time taken by database statements, by backend and operation",
+        &["backend", "operation"],
+        exponential_buckets(0.0001, 4.0, 9).unwrap()
+    )
+    .unwrap();
+    static ref REPOSITORIES: IntGauge =
+        register_int_gauge!("pequod_repositories", "Repositories stored").unwrap();
+    static ref TAGS: IntGauge = register_int_gauge!("pequod_tags", "Tags stored").unwrap();
+    static ref MANIFESTS: IntGauge =
+        register_int_gauge!("pequod_manifests", "Manifests stored").unwrap();
+    static ref BLOBS: IntGauge = register_int_gauge!("pequod_blobs", "Blobs stored").unwrap();
+    static ref BLOB_BYTES: IntGauge =
+        register_int_gauge!("pequod_blob_bytes", "Bytes of blobs stored").unwrap();
+    static ref UPLOADS: IntGauge = register_int_gauge!(
+        "pequod_upload_sessions",
+        "Blob uploads started but not finished"
+    )
+    .unwrap();
+}
+
+/// Middleware counting and timing requests. Requests are labelled with the route they matched rather than
+/// their path, so repository names don't make a series each.
+#[async_backtrace::framed]
+pub async fn track<B>(req: Request<B>, next: Next<B>) -> Response {
+    let matched = req.extensions().get::<MatchedPath>().cloned();
+    let method = req.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+    let route = route(matched.as_ref(), &response).to_string();
+    let status = response.status().as_u16().to_string();
+    let labels = [route.as_str(), method.as_str(), status.as_str()];
+    REQUESTS.with_label_values(&labels).inc();
+    REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// The route a request matched, given the [MatchedPath] of the request for routes of the outer router and
+/// taken from the response for those of nested ones
+pub fn route<'a>(matched: Option<&'a MatchedPath>, response: &'a Response) -> &'a str {
+    matched
+        .or_else(|| response.extensions().get::<MatchedPath>())
+        .map_or("unmatched", |path| path.as_str())
+}
+
+/// Route middleware for nested routers, whose routes are only known inside them. Passes the route out to
+/// [track] with the response.
+#[async_backtrace::framed]
+pub async fn matched<B>(req: Request<B>, next: Next<B>) -> Response {
+    let matched = req.extensions().get::<MatchedPath>().cloned();
+    let mut response = next.run(req).await;
+    if let Some(matched) = matched {
+        response.extensions_mut().insert(matched);
+    }
+    response
+}
+
+pub fn uploaded(bytes: usize) {
+    UPLOADED_BYTES.inc_by(bytes as u64);
+}
+
+pub fn downloaded(bytes: usize) {
+    DOWNLOADED_BYTES.inc_by(bytes as u64);
+}
+
+/// Count a garbage collection that was not a dry run
+pub fn collected(garbage: &Garbage) {
+    GC_RUNS.inc();
+    GC_DELETED_BLOBS.inc_by(garbage.blobs);
+    GC_DELETED_BYTES.inc_by(garbage.bytes);
+}
+
+/// Count a request refused by [crate::ratelimit]
+pub fn rate_limited(budget: &str) {
+    RATE_LIMITED.with_label_values(&[budget]).inc();
+}
+
+/// The kind of a statement, by its first keyword
+fn operation(statement: &str) -> &'static str {
+    let keyword = statement.split_whitespace().next().unwrap_or_default();
+    ["select", "insert", "update", "delete"]
+        .into_iter()
+        .find(|operation| keyword.eq_ignore_ascii_case(operation))
+        .unwrap_or("other")
+}
+
+/// Record how long a database statement took
+pub fn observe_query(backend: &str, statement: &str, duration: Duration) {
+    QUERY_DURATION
+        .with_label_values(&[backend, operation(statement)])
+        .observe(duration.as_secs_f64());
+}
+
+/// Serve the metrics in the Prometheus text format
+#[async_backtrace::framed]
+pub async fn serve() -> Response {
+    match db::statistics().await {
+        Ok(statistics) => {
+            REPOSITORIES.set(statistics.repositories);
+            TAGS.set(statistics.tags);
+            MANIFESTS.set(statistics.manifests);
+            BLOBS.set(statistics.blobs);
+            BLOB_BYTES.set(statistics.blob_bytes);
+            UPLOADS.set(statistics.uploads);
+        }
+        // the other metrics are still worth serving, the gauges keep their last values
+        Err(e) => tracing::error!("failed to read storage statistics: {}", e),
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("failed to encode metrics: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::operation;
+
+    #[test]
+    fn test_operation() {
+        assert_eq!(operation("SELECT digest FROM blobs"), "select");
+        assert_eq!(operation("\n    insert into tags VALUES (?)"), "insert");
+        assert_eq!(operation("VACUUM"), "other");
+        assert_eq!(operation(""), "other");
+    }
+}
@@ -0,0 +1,848 @@
+//! OCI image layouts.
+//!
+//! [export] writes tags to an [OCI image layout](https://github.com/opencontainers/image-spec/blob/main/image-layout.md),
+//! with the manifests and blobs under `blobs/sha256` and the tags in `index.json`, annotated with the
+//! repository and tag they came from. [import] loads such a layout back, or a `docker save` archive, keeping
+//! the digests of manifests and blobs. A layout is either a directory or a tarball, optionally gzipped, which
+//! makes it easy to carry images into sites without network access.
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{db, protection, quota, replication};
+
+/// The annotation holding the tag of a manifest in `index.json`
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+/// The annotation holding the full name of an image, as written by containerd and docker
+const IMAGE_NAME: &str = "io.containerd.image.name";
+
+lazy_static! {
+    /// Repository names as allowed by the distribution spec
+    static ref NAME_REGEX: Regex = Regex::new(
+        r"^[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*(/[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*)*$"
+    )
+    .unwrap();
+}
+
+const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+const DOCKER_MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const DOCKER_CONFIG_MEDIA_TYPE: &str = "application/vnd.docker.container.image.v1+json";
+const DOCKER_LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar";
+
+#[derive(Debug, thiserror::Error)]
+pub enum OciError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Database(#[from] db::Error),
+    #[error("invalid JSON in {0}: {1}")]
+    Json(String, serde_json::Error),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0} does not exist")]
+    NotFound(String),
+    #[error("{0}")]
+    Denied(String),
+}
+
+/// A tag written to or read from a layout, or an untagged manifest
+#[derive(Debug, Clone, Serialize)]
+pub struct Image {
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: String,
+}
+
+impl fmt::Display for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.tag {
+            Some(tag) => write!(f, "{}:{}", self.repository, tag),
+            None => write!(f, "{}@{}", self.repository, self.digest),
+        }
+    }
+}
+
+/// An image layout to import, a directory or a tarball unpacked into a scratch directory. Files are read
+/// as they are needed, so layouts can be larger than memory.
+#[derive(Debug)]
+pub struct Layout {
+    root: PathBuf,
+    /// Whether `root` was made to unpack a tarball into, and goes away with the layout
+    unpacked: bool,
+}
+
+/// A path in the temporary directory to unpack or receive something into
+pub fn scratch(what: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("pequod-{}-{}", what, Uuid::new_v4()))
+}
+
+/// Whether a path names a tarball rather than a directory
+fn is_tarball(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+fn is_gzipped(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".gz") || name.ends_with(".tgz")
+}
+
+fn blob_path(digest: &str) -> Result<String, OciError> {
+    match digest.split_once(':') {
+        Some((algorithm, hex))
+            if !algorithm.is_empty() && !hex.is_empty() && !hex.contains(['/', '.']) =>
+        {
+            Ok(format!("blobs/{}/{}", algorithm, hex))
+        }
+        _ => Err(OciError::Invalid(format!("invalid digest {}", digest))),
+    }
+}
+
+fn sha256(content: &[u8]) -> String {
+    format!("sha256:{}", sha256::digest(content))
+}
+
+fn parse_json(name: &str, content: &[u8]) -> Result<Value, OciError> {
+    serde_json::from_slice(content).map_err(|e| OciError::Json(name.to_string(), e))
+}
+
+impl Layout {
+    /// Read a layout from a directory, or unpack it from a tarball
+    pub fn read(path: &Path) -> Result<Self, OciError> {
+        if path.is_dir() {
+            return Ok(Layout {
+                root: path.to_path_buf(),
+                unpacked: false,
+            });
+        }
+        Self::unpack(File::open(path)?)
+    }
+
+    /// Like [Layout::read], off the async runtime as unpacking a large tarball takes a while
+    #[async_backtrace::framed]
+    pub async fn load(path: PathBuf) -> Result<Self, OciError> {
+        tokio::task::spawn_blocking(move || Self::read(&path))
+            .await
+            .map_err(|e| OciError::Io(std::io::Error::other(e)))?
+    }
+
+    /// Unpack a layout from a tarball, which may be gzipped, into a scratch directory
+    pub fn unpack(reader: impl Read) -> Result<Self, OciError> {
+        let layout = Layout {
+            root: scratch("layout"),
+            unpacked: true,
+        };
+        std::fs::create_dir(&layout.root)?;
+
+        let mut reader = BufReader::new(reader);
+        match reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+            true => layout.unpack_entries(GzDecoder::new(reader))?,
+            false => layout.unpack_entries(reader)?,
+        }
+        Ok(layout)
+    }
+
+    fn unpack_entries(&self, reader: impl Read) -> Result<(), OciError> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            // entries with paths leading out of the layout are skipped
+            entry.unpack_in(&self.root)?;
+        }
+        Ok(())
+    }
+
+    /// The path of a file of the layout, which must not lead out of it
+    fn path(&self, name: &str) -> Result<PathBuf, OciError> {
+        let name = Path::new(name.trim_start_matches("./"));
+        if !name.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(OciError::Invalid(format!(
+                "invalid path {} in the layout",
+                name.display()
+            )));
+        }
+        let path = self.root.join(name);
+        match path.is_file() {
+            true => Ok(path),
+            false => Err(OciError::NotFound(name.display().to_string())),
+        }
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.path(name).is_ok()
+    }
+
+    fn file(&self, name: &str) -> Result<Bytes, OciError> {
+        Ok(Bytes::from(std::fs::read(self.path(name)?)?))
+    }
+
+    /// The digest and size of a file, read a bit at a time as layers can be large
+    fn digest(&self, name: &str) -> Result<(String, u64), OciError> {
+        let mut file = File::open(self.path(name)?)?;
+        let mut hash = Sha256::new();
+        let mut size = 0;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            size += read as u64;
+            hash.update(&buffer[..read]);
+        }
+        Ok((format!("sha256:{:x}", hash.finalize()), size))
+    }
+
+    /// The size of a blob of the layout, after checking it matches its digest
+    fn blob(&self, digest: &str) -> Result<u64, OciError> {
+        let path = blob_path(digest)?;
+        if !digest.starts_with("sha256:") {
+            return Err(OciError::Invalid(format!("unsupported digest {}", digest)));
+        }
+        let (actual, size) = self.digest(&path)?;
+        if actual != digest {
+            return Err(OciError::Invalid(format!(
+                "blob {} does not match its digest",
+                digest
+            )));
+        }
+        Ok(size)
+    }
+}
+
+impl Drop for Layout {
+    fn drop(&mut self) {
+        if self.unpacked {
+            if let Err(e) = std::fs::remove_dir_all(&self.root) {
+                tracing::warn!("failed to remove {}: {}", self.root.display(), e);
+            }
+        }
+    }
+}
+
+/// An image layout to export. The small files are ready, the blobs are read from the database one at a time
+/// as the layout is written.
+#[derive(Debug, Default)]
+pub struct Export {
+    files: BTreeMap<String, Bytes>,
+    blobs: BTreeSet<String>,
+}
+
+/// The header of a tar entry, which is followed by the content and [padding]
+fn tar_header(name: &str, size: u64) -> Result<Bytes, OciError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    // appending no content leaves just the header, and the entry holding the name if it is too long for it
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append_data(&mut header, name, std::io::empty())?;
+    Ok(Bytes::from(std::mem::take(builder.get_mut())))
+}
+
+/// The zeros padding the content of a tar entry to a whole block
+fn padding(size: usize) -> Bytes {
+    Bytes::from(vec![0; (512 - size % 512) % 512])
+}
+
+/// The two zero blocks ending a tarball
+const TAR_END: [u8; 1024] = [0; 1024];
+
+impl Export {
+    /// The files of the layout by their path in it
+    fn entries(self) -> impl Stream<Item = Result<(String, Bytes), OciError>> + Send {
+        let files = stream::iter(self.files.into_iter().map(Ok));
+        let blobs = stream::iter(self.blobs).then(|digest| async move {
+            let content = db::blobs::get(&digest)
+                .await
+                .map_err(|_| OciError::NotFound(format!("blob {}", digest)))?;
+            Ok((blob_path(&digest)?, content))
+        });
+        files.chain(blobs)
+    }
+
+    /// The layout as a tarball, a chunk at a time
+    pub fn tar(self) -> impl Stream<Item = Result<Bytes, OciError>> + Send {
+        self.entries()
+            .map(|entry| {
+                let (name, content) = entry?;
+                let header = tar_header(&name, content.len() as u64)?;
+                let padding = padding(content.len());
+                Ok::<_, OciError>(stream::iter([header, content, padding].map(Ok)))
+            })
+            .try_flatten()
+            .chain(stream::once(async { Ok(Bytes::from_static(&TAR_END)) }))
+    }
+
+    /// Write the layout to a directory, or to a tarball if the path ends in `.tar`, `.tar.gz` or `.tgz`
+    #[async_backtrace::framed]
+    pub async fn write(self, path: &Path) -> Result<(), OciError> {
+        if is_tarball(path) {
+            let file = File::create(path)?;
+            let file = match is_gzipped(path) {
+                true => copy(self.tar(), GzEncoder::new(file, Compression::default()))
+                    .await?
+                    .finish()?,
+                false => copy(self.tar(), file).await?,
+            };
+            return Ok(file.sync_all()?);
+        }
+
+        let entries = self.entries();
+        futures_util::pin_mut!(entries);
+        while let Some(entry) = entries.next().await {
+            let (name, content) = entry?;
+            let file = path.join(name);
+            if let Some(parent) = file.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(file, content)?;
+        }
+        Ok(())
+    }
+
+    fn add_blob(&mut self, digest: &str, content: Bytes) -> Result<(), OciError> {
+        self.files.insert(blob_path(digest)?, content);
+        Ok(())
+    }
+}
+
+/// Write the chunks of a stream to `output`
+async fn copy<W: Write>(
+    chunks: impl Stream<Item = Result<Bytes, OciError>>,
+    mut output: W,
+) -> Result<W, OciError> {
+    futures_util::pin_mut!(chunks);
+    while let Some(chunk) = chunks.next().await {
+        output.write_all(&chunk?)?;
+    }
+    Ok(output)
+}
+
+/// Split a reference like `app:v1` into repository and tag
+fn parse_reference(reference: &str) -> (&str, Option<&str>) {
+    // a colon after the last slash separates the tag, one before it belongs to a registry's port
+    match reference.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => (repository, Some(tag)),
+        _ => (reference, None),
+    }
+}
+
+/// The repository and tag of an image name like `docker.io/library/app:v1`, without the registry
+fn parse_name(name: &str) -> (String, Option<String>) {
+    let name = name.split('@').next().unwrap_or_default();
+    let (repository, tag) = parse_reference(name);
+    let repository = match repository.split_once('/') {
+        Some((domain, rest)) if domain.contains(['.', ':']) || domain == "localhost" => {
+            match domain {
+                "docker.io" | "index.docker.io" => rest.strip_prefix("library/").unwrap_or(rest),
+                _ => rest,
+            }
+        }
+        _ => repository,
+    };
+    (repository.to_string(), tag.map(str::to_string))
+}
+
+/// The digests of the manifests and blobs a manifest refers to
+pub(crate) fn references(manifest: &Value) -> (Vec<String>, Vec<String>) {
+    let digests = |key: &str| {
+        manifest[key]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|d| d["digest"].as_str().map(str::to_string))
+            .collect::<Vec<_>>()
+    };
+    let mut blobs = digests("layers");
+    if let Some(config) = manifest["config"]["digest"].as_str() {
+        blobs.insert(0, config.to_string());
+    }
+    (digests("manifests"), blobs)
+}
+
+/// Add a manifest of `repository` and everything it refers to to the layout
+#[async_backtrace::framed]
+async fn export_manifest(
+    layout: &mut Export,
+    repository: &str,
+    digest: &str,
+) -> Result<Value, OciError> {
+    let mut pending = vec![digest.to_string()];
+    let mut descriptor = Value::Null;
+    while let Some(digest) = pending.pop() {
+        let raw = db::manifests::get(repository, &digest)
+            .await
+            .map_err(|_| OciError::NotFound(format!("manifest {}@{}", repository, digest)))?;
+        let manifest = parse_json(&digest, raw.as_bytes())?;
+        if descriptor.is_null() {
+            descriptor = json!({
+                "mediaType": crate::api::manifests::media_type(&raw),
+                "digest": digest,
+                "size": raw.len(),
+            });
+        }
+
+        let (manifests, blobs) = references(&manifest);
+        for blob in blobs {
+            db::blobs::length(&blob)
+                .await
+                .map_err(|_| OciError::NotFound(format!("blob {}", blob)))?;
+            blob_path(&blob)?;
+            layout.blobs.insert(blob);
+        }
+        layout.add_blob(&digest, Bytes::from(raw))?;
+        pending.extend(manifests);
+    }
+    Ok(descriptor)
+}
+
+/// Export images, given as `repository` for all of its tags or `repository:tag`, returning the layout and
+/// the tags in it
+#[async_backtrace::framed]
+pub async fn export(images: &[String]) -> Result<(Export, Vec<Image>), OciError> {
+    let mut layout = Export::default();
+    let mut descriptors = Vec::new();
+    let mut exported = Vec::new();
+
+    for image in images {
+        let (repository, tag) = parse_reference(image);
+        let tags = match tag {
+            Some(tag) => vec![tag.to_string()],
+            None => db::tags::list(repository)
+                .await?
+                .into_iter()
+                .map(|t| t.name)
+                .collect(),
+        };
+        if tags.is_empty() {
+            return Err(OciError::NotFound(format!("repository {}", repository)));
+        }
+
+        for tag in tags {
+            let digest = db::tags::get_manifest(repository, &tag)
+                .await
+                .map_err(|_| OciError::NotFound(format!("tag {}:{}", repository, tag)))?;
+            let mut descriptor = export_manifest(&mut layout, repository, &digest).await?;
+            descriptor["annotations"] = json!({
+                REF_NAME: tag,
+                IMAGE_NAME: format!("{}:{}", repository, tag),
+            });
+            descriptors.push(descriptor);
+            exported.push(Image {
+                repository: repository.to_string(),
+                tag: Some(tag),
+                digest,
+            });
+        }
+    }
+
+    layout.files.insert(
+        "oci-layout".to_string(),
+        Bytes::from(json!({ "imageLayoutVersion": "1.0.0" }).to_string()),
+    );
+    let index = json!({
+        "schemaVersion": 2,
+        "mediaType": INDEX_MEDIA_TYPE,
+        "manifests": descriptors,
+    });
+    layout
+        .files
+        .insert("index.json".to_string(), Bytes::from(index.to_string()));
+
+    Ok((layout, exported))
+}
+
+/// A blob to store, found at `path` in the layout
+#[derive(Clone)]
+struct Blob {
+    digest: String,
+    path: String,
+    size: u64,
+}
+
+/// A manifest to store, after the blobs it refers to
+struct Manifest {
+    digest: String,
+    raw: String,
+    blobs: Vec<Blob>,
+}
+
+/// An image to import and where
+struct Pending {
+    repository: String,
+    tag: Option<String>,
+    /// The manifest the tag points to comes last, after the ones it refers to
+    manifests: Vec<Manifest>,
+}
+
+/// Collect a manifest from the layout along with everything it refers to
+fn collect(layout: &Layout, digest: &str) -> Result<Vec<Manifest>, OciError> {
+    layout.blob(digest)?;
+    let raw = layout.file(&blob_path(digest)?)?;
+    let manifest = parse_json(digest, &raw)?;
+    let (manifests, blobs) = references(&manifest);
+
+    let mut collected = Vec::new();
+    for child in manifests {
+        collected.extend(collect(layout, &child)?);
+    }
+    collected.push(Manifest {
+        digest: digest.to_string(),
+        raw: String::from_utf8(raw.to_vec())
+            .map_err(|_| OciError::Invalid(format!("manifest {} is not UTF-8", digest)))?,
+        blobs: blobs
+            .iter()
+            .map(|blob| {
+                Ok(Blob {
+                    digest: blob.clone(),
+                    path: blob_path(blob)?,
+                    size: layout.blob(blob)?,
+                })
+            })
+            .collect::<Result<_, OciError>>()?,
+    });
+    Ok(collected)
+}
+
+/// Where to put an image, `repository` overriding the one named in the layout
+fn destination(
+    name: Option<(String, Option<String>)>,
+    repository: Option<&str>,
+    what: &str,
+) -> Result<(String, Option<String>), OciError> {
+    let (repository, tag) = match (name, repository) {
+        (Some((_, tag)), Some(repository)) => (repository.to_string(), tag),
+        (Some(name), None) => name,
+        (None, Some(repository)) => (repository.to_string(), None),
+        (None, None) => {
+            return Err(OciError::Invalid(format!(
+                "{} names no repository, choose one to import it into",
+                what
+            )))
+        }
+    };
+    if !NAME_REGEX.is_match(&repository) {
+        return Err(OciError::Invalid(format!(
+            "invalid repository name {}",
+            repository
+        )));
+    }
+    Ok((repository, tag))
+}
+
+/// The images of an OCI layout
+fn oci_images(layout: &Layout, repository: Option<&str>) -> Result<Vec<Pending>, OciError> {
+    let index = parse_json("index.json", &layout.file("index.json")?)?;
+    let mut images = Vec::new();
+    for descriptor in index["manifests"].as_array().into_iter().flatten() {
+        let digest = descriptor["digest"].as_str().ok_or_else(|| {
+            OciError::Invalid("index.json lists a manifest without digest".into())
+        })?;
+        let annotations = &descriptor["annotations"];
+        let name = match (
+            annotations[IMAGE_NAME].as_str(),
+            annotations[REF_NAME].as_str(),
+        ) {
+            (Some(name), _) => Some(parse_name(name)),
+            // the reference name usually is just the tag, but may be a full reference
+            (None, Some(name)) if name.contains(['/', ':']) => Some(parse_name(name)),
+            (None, Some(tag)) => repository.map(|r| (r.to_string(), Some(tag.to_string()))),
+            (None, None) => None,
+        };
+        let (repository, tag) = destination(name, repository, digest)?;
+        images.push(Pending {
+            repository,
+            tag,
+            manifests: collect(layout, digest)?,
+        });
+    }
+    Ok(images)
+}
+
+/// The images of a `docker save` archive, for which manifests are made up from the config and layers
+fn docker_images(layout: &Layout, repository: Option<&str>) -> Result<Vec<Pending>, OciError> {
+    let entries = parse_json("manifest.json", &layout.file("manifest.json")?)?;
+    let mut images = Vec::new();
+    for entry in entries.as_array().into_iter().flatten() {
+        let descriptor = |path: &str, media_type: &str| -> Result<(Value, Blob), OciError> {
+            let (digest, size) = layout.digest(path)?;
+            let descriptor = json!({
+                "mediaType": media_type,
+                "size": size,
+                "digest": digest,
+            });
+            let blob = Blob {
+                digest,
+                path: path.to_string(),
+                size,
+            };
+            Ok((descriptor, blob))
+        };
+
+        let config = entry["Config"].as_str().ok_or_else(|| {
+            OciError::Invalid("manifest.json lists an image without config".into())
+        })?;
+        let (config, config_blob) = descriptor(config, DOCKER_CONFIG_MEDIA_TYPE)?;
+        let mut layers = Vec::new();
+        let mut blobs = vec![config_blob];
+        for layer in entry["Layers"].as_array().into_iter().flatten() {
+            let path = layer
+                .as_str()
+                .ok_or_else(|| OciError::Invalid("manifest.json lists an invalid layer".into()))?;
+            let (layer, blob) = descriptor(path, DOCKER_LAYER_MEDIA_TYPE)?;
+            layers.push(layer);
+            blobs.push(blob);
+        }
+        let raw = serde_json::to_string_pretty(&json!({
+            "schemaVersion": 2,
+            "mediaType": DOCKER_MANIFEST_MEDIA_TYPE,
+            "config": config,
+            "layers": layers,
+        }))
+        .expect("manifests can be serialized");
+        let digest = sha256(raw.as_bytes());
+
+        let tags = entry["RepoTags"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(|name| Some(parse_name(name)))
+            .collect::<Vec<_>>();
+        let tags = if tags.is_empty() { vec![None] } else { tags };
+        for name in tags {
+            let (repository, tag) = destination(name, repository, &digest)?;
+            images.push(Pending {
+                repository,
+                tag,
+                manifests: vec![Manifest {
+                    digest: digest.clone(),
+                    raw: raw.clone(),
+                    blobs: blobs.clone(),
+                }],
+            });
+        }
+    }
+    Ok(images)
+}
+
+/// What importing adds to a repository, for its quotas
+#[derive(Default)]
+struct Addition<'a> {
+    blobs: BTreeSet<String>,
+    tags: HashSet<&'a str>,
+    bytes: u64,
+}
+
+/// Check that all images can be imported before anything is written: their manifests mustn't be stored in
+/// another repository, and their tags must respect tag protection and quotas as if they were pushed
+#[async_backtrace::framed]
+async fn check(images: &[Pending]) -> Result<(), OciError> {
+    let mut refused = Vec::new();
+    let mut owners: HashMap<&str, &str> = HashMap::new();
+    let mut additions: BTreeMap<&str, Addition> = BTreeMap::new();
+    let mut incoming = HashSet::new();
+
+    for image in images {
+        let addition = additions.entry(&image.repository).or_default();
+        for manifest in &image.manifests {
+            // a manifest is stored once, in the first repository it was pushed to
+            let owner = match owners.get(manifest.digest.as_str()) {
+                Some(owner) => Some(owner.to_string()),
+                None => db::manifests::repository(&manifest.digest).await?,
+            };
+            match owner {
+                Some(owner) if owner != image.repository => refused.push(format!(
+                    "manifest {} is already stored in repository {}",
+                    manifest.digest, owner
+                )),
+                _ => {
+                    owners.insert(&manifest.digest, &image.repository);
+                }
+            }
+            for blob in &manifest.blobs {
+                addition.blobs.insert(blob.digest.clone());
+                if incoming.insert(&blob.digest) && db::blobs::length(&blob.digest).await.is_err() {
+                    addition.bytes += blob.size;
+                }
+            }
+        }
+
+        let (Some(tag), Some(manifest)) = (&image.tag, image.manifests.last()) else {
+            continue;
+        };
+        if let Some(violation) =
+            protection::check_overwrite(&image.repository, tag, &manifest.digest).await?
+        {
+            refused.push(violation.to_string());
+        }
+        if db::tags::get_manifest(&image.repository, tag)
+            .await
+            .is_err()
+        {
+            addition.tags.insert(tag);
+        }
+    }
+
+    for (repository, addition) in additions {
+        let blobs = addition.blobs.into_iter().collect::<Vec<_>>();
+        let new_tags = addition.tags.len() as i64;
+        if let Some(exceeded) =
+            quota::check_adding(repository, &blobs, new_tags, addition.bytes).await?
+        {
+            refused.push(format!("{}: {}", repository, exceeded));
+        }
+    }
+
+    // images sharing a manifest are refused for the same reason
+    let mut seen = HashSet::new();
+    refused.retain(|reason| seen.insert(reason.clone()));
+    match refused.is_empty() {
+        true => Ok(()),
+        false => Err(OciError::Denied(refused.join("; "))),
+    }
+}
+
+/// The images of a layout, with every blob checked against its digest
+fn images(layout: &Layout, repository: Option<&str>) -> Result<Vec<Pending>, OciError> {
+    match layout.contains("index.json") {
+        true => oci_images(layout, repository),
+        false if layout.contains("manifest.json") => docker_images(layout, repository),
+        false => Err(OciError::Invalid(
+            "neither an OCI layout nor a docker save archive, index.json and manifest.json are missing"
+                .to_string(),
+        )),
+    }
+}
+
+/// Import the images of an OCI layout or `docker save` archive, into `repository` if given and otherwise
+/// into the repositories named in the layout. Returns what was imported.
+#[async_backtrace::framed]
+pub async fn import(
+    layout: Layout,
+    repository: Option<&str>,
+    actor: &str,
+) -> Result<Vec<Image>, OciError> {
+    // reading the layout hashes every blob in it, which takes a while for large ones
+    let into = repository.map(str::to_string);
+    let (layout, images) = tokio::task::spawn_blocking(move || {
+        let images = images(&layout, into.as_deref())?;
+        Ok::<_, OciError>((layout, images))
+    })
+    .await
+    .map_err(|e| OciError::Io(std::io::Error::other(e)))??;
+
+    check(&images).await?;
+
+    let mut stored = HashSet::new();
+    let mut imported = Vec::new();
+    for image in images {
+        db::repositories::save(&image.repository).await?;
+        for manifest in &image.manifests {
+            if !stored.insert((image.repository.clone(), manifest.digest.clone())) {
+                continue;
+            }
+            for blob in &manifest.blobs {
+                if db::blobs::length(&blob.digest).await.is_err() {
+                    let content = Bytes::from(tokio::fs::read(layout.path(&blob.path)?).await?);
+                    db::blobs::save(&blob.digest, &content).await?;
+                }
+            }
+            db::manifests::save(&image.repository, &manifest.digest, &manifest.raw).await?;
+            for blob in &manifest.blobs {
+                db::blobs::associate(&manifest.digest, &blob.digest).await?;
+            }
+        }
+
+        let digest = match image.manifests.last() {
+            Some(manifest) => manifest.digest.clone(),
+            None => continue,
+        };
+        if let Some(tag) = &image.tag {
+            db::tags::save(&image.repository, tag, &digest, Some(actor)).await?;
+            replication::enqueue(&image.repository, tag, Some(&digest)).await;
+        }
+        let image = Image {
+            repository: image.repository,
+            tag: image.tag,
+            digest,
+        };
+        tracing::info!("imported {}", image);
+        imported.push(image);
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use bytes::Bytes;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::{blob_path, padding, parse_name, tar_header, Layout, TAR_END};
+
+    #[test]
+    fn test_parse_name() {
+        let name = |n| parse_name(n);
+        assert_eq!(name("app:v1"), ("app".into(), Some("v1".into())));
+        assert_eq!(
+            name("docker.io/library/nginx:latest"),
+            ("nginx".into(), Some("latest".into()))
+        );
+        assert_eq!(name("localhost:5000/team/app"), ("team/app".into(), None));
+        assert_eq!(
+            name("registry.example.com/team/app:v2@sha256:abc"),
+            ("team/app".into(), Some("v2".into()))
+        );
+    }
+
+    #[test]
+    fn test_tar_round_trip() {
+        let files = [
+            ("oci-layout".to_string(), Bytes::from_static(b"{}")),
+            ("blobs/sha256/abc".to_string(), Bytes::from(vec![7; 1000])),
+            // longer than a tar header has room for
+            (format!("blobs/sha512/{}", "a".repeat(128)), Bytes::new()),
+        ];
+        let mut tar = Vec::new();
+        for (name, content) in &files {
+            tar.extend_from_slice(&tar_header(name, content.len() as u64).unwrap());
+            tar.extend_from_slice(content);
+            tar.extend_from_slice(&padding(content.len()));
+        }
+        tar.extend_from_slice(&TAR_END);
+        let mut gzipped = GzEncoder::new(Vec::new(), Compression::default());
+        gzipped.write_all(&tar).unwrap();
+
+        for tarball in [tar, gzipped.finish().unwrap()] {
+            let layout = Layout::unpack(tarball.as_slice()).unwrap();
+            for (name, content) in &files {
+                assert_eq!(&layout.file(name).unwrap(), content);
+            }
+            assert!(layout.path("../oci-layout").is_err());
+            let root = layout.root.clone();
+            drop(layout);
+            assert!(!root.exists());
+        }
+        assert!(blob_path("sha256:../../etc").is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Helpers for matching repository names and tags against user-provided patterns.
+//!
+//! Repository patterns are globs where `*` matches anything except `/` and `**` matches anything,
+//! so `team-a/*` matches `team-a/app` but not `team-a/app/nightly`, while `team-a/**` matches both.
+//! Tag patterns are regular expressions that must match the whole tag.
+use regex::Regex;
+
+/// Check whether a repository name matches a glob pattern.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let mut expression = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                expression.push_str(".*");
+            }
+            '*' => expression.push_str("[^/]*"),
+            c => expression.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    expression.push('$');
+
+    Regex::new(&expression)
+        .map(|r| r.is_match(name))
+        .unwrap_or(false)
+}
+
+/// Compile a tag pattern, anchoring it so it has to match the entire tag.
+pub fn tag_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", pattern))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{matches, tag_regex};
+
+    #[test]
+    fn test_matches_exact() {
+        assert!(matches("nginx", "nginx"));
+        assert!(!matches("nginx", "library/nginx"));
+    }
+
+    #[test]
+    fn test_matches_single_star() {
+        assert!(matches("team-a/*", "team-a/app"));
+        assert!(!matches("team-a/*", "team-a/app/nightly"));
+        assert!(!matches("team-a/*", "team-b/app"));
+    }
+
+    #[test]
+    fn test_matches_double_star() {
+        assert!(matches("team-a/**", "team-a/app/nightly"));
+        assert!(matches("**", "library/nginx"));
+    }
+
+    #[test]
+    fn test_tag_regex_is_anchored() {
+        let regex = tag_regex(r"nightly-\d+").unwrap();
+        assert!(regex.is_match("nightly-20230401"));
+        assert!(!regex.is_match("nightly-20230401-debug"));
+    }
+}
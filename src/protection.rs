@@ -0,0 +1,99 @@
+//! Immutable repositories and protected tags.
+//!
+//! Tags in an immutable repository can be pushed once and then never moved to a different manifest,
+//! although they can still be deleted. Tags matching a protection rule can neither be moved nor deleted.
+//! Admins can bypass both through the admin UI.
+use crate::db::{self, TagProtection};
+use crate::pattern;
+
+/// Why an operation on a tag was refused
+#[derive(Debug, thiserror::Error)]
+pub enum Violation {
+    #[error("tag {0}:{1} already exists and repository {0} is immutable")]
+    Immutable(String, String),
+    #[error("tag {0}:{1} is protected by rule {2}")]
+    Protected(String, String, i64),
+}
+
+/// The tag protection rules, loaded once so they can be checked against many tags
+pub struct Rules {
+    protections: Vec<TagProtection>,
+}
+
+impl Rules {
+    #[async_backtrace::framed]
+    pub async fn load() -> Result<Self, db::Error> {
+        Ok(Rules {
+            protections: db::protections::list().await?,
+        })
+    }
+
+    /// Find the first rule protecting a tag, if any
+    pub fn protecting(&self, repository: &str, tag: &str) -> Option<&TagProtection> {
+        self.protections.iter().find(|p| {
+            pattern::matches(&p.repository, repository)
+                && pattern::tag_regex(&p.tag).is_ok_and(|r| r.is_match(tag))
+        })
+    }
+}
+
+/// Check whether `tag` may be pointed at the manifest `digest`. Pushing the same manifest again is
+/// always allowed, and so is creating a tag that does not exist yet.
+#[async_backtrace::framed]
+pub async fn check_overwrite(
+    repository: &str,
+    tag: &str,
+    digest: &str,
+) -> Result<Option<Violation>, db::Error> {
+    match db::tags::get_manifest(repository, tag).await {
+        Ok(current) if current != digest => {}
+        _ => return Ok(None),
+    }
+
+    if let Some(rule) = Rules::load().await?.protecting(repository, tag) {
+        return Ok(Some(Violation::Protected(
+            repository.to_string(),
+            tag.to_string(),
+            rule.id,
+        )));
+    }
+    if db::repositories::is_immutable(repository).await? {
+        return Ok(Some(Violation::Immutable(
+            repository.to_string(),
+            tag.to_string(),
+        )));
+    }
+
+    Ok(None)
+}
+
+/// Check whether `tag` may be deleted
+#[async_backtrace::framed]
+pub async fn check_delete(repository: &str, tag: &str) -> Result<Option<Violation>, db::Error> {
+    let violation = Rules::load()
+        .await?
+        .protecting(repository, tag)
+        .map(|rule| Violation::Protected(repository.to_string(), tag.to_string(), rule.id));
+
+    Ok(violation)
+}
+
+/// Check whether the manifest `digest` may be deleted, which is refused while a protected tag points at it
+#[async_backtrace::framed]
+pub async fn check_delete_manifest(
+    repository: &str,
+    digest: &str,
+) -> Result<Option<Violation>, db::Error> {
+    let rules = Rules::load().await?;
+    let violation = db::tags::list(repository)
+        .await?
+        .into_iter()
+        .filter(|t| t.manifest == digest)
+        .find_map(|t| {
+            rules
+                .protecting(repository, &t.name)
+                .map(|rule| Violation::Protected(repository.to_string(), t.name.clone(), rule.id))
+        });
+
+    Ok(violation)
+}
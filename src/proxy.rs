@@ -0,0 +1,278 @@
+//! Pull-through cache for an upstream registry.
+//!
+//! When `PROXY_REMOTE_URL` is set, manifests and blobs missing locally are fetched from the upstream
+//! registry, stored like any pushed content and served from then on. Tags cached from upstream are looked up
+//! there again once the local copy is older than `PROXY_TAG_TTL` seconds (five minutes by default), and the
+//! local copy keeps being served if the upstream registry can't be reached or doesn't have the tag. A cached
+//! tag only follows upstream where a push could move it, given immutable repositories and protected tags.
+//! Tags last written here some other way, like by a push, are never replaced by upstream ones.
+//! `PROXY_USERNAME` and `PROXY_PASSWORD` can be set if the upstream registry requires authentication.
+//!
+//! Docker Hub is special-cased so that `nginx` is fetched as `library/nginx` and `docker.io` can be used
+//! as the remote URL.
+use bytes::Bytes;
+use chrono::Utc;
+use lazy_static::lazy_static;
+
+use crate::api::manifests::Manifest;
+use crate::client::{Client, ClientError, RemoteManifest};
+use crate::db::{self, Tag};
+use crate::events::{self, Action, Event, RequestInfo, Target};
+use crate::{protection, replication};
+
+/// Who tags cached from upstream are written by, in their history
+const ACTOR: &str = "proxy";
+
+const DOCKER_HUB_HOSTS: [&str; 4] = [
+    "docker.io",
+    "index.docker.io",
+    "registry-1.docker.io",
+    "registry.hub.docker.com",
+];
+
+lazy_static! {
+    static ref UPSTREAM: Option<Client> = std::env::var("PROXY_REMOTE_URL").ok().map(|url| {
+        let credentials = std::env::var("PROXY_USERNAME").ok().map(|username| {
+            (
+                username,
+                std::env::var("PROXY_PASSWORD").unwrap_or_default(),
+            )
+        });
+        Client::new(&normalize_url(&url), credentials)
+    });
+    static ref TAG_TTL: i64 = std::env::var("PROXY_TAG_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(5 * 60);
+}
+
+fn is_docker_hub(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| DOCKER_HUB_HOSTS.contains(&host)))
+        .unwrap_or(false)
+}
+
+/// Point Docker Hub URLs at the host actually serving the registry API
+fn normalize_url(url: &str) -> String {
+    match is_docker_hub(url) {
+        true => "https://registry-1.docker.io".to_string(),
+        false => url.to_string(),
+    }
+}
+
+/// The name of a repository on the upstream registry. Docker Hub keeps official images in `library/`.
+fn upstream_name(url: &str, name: &str) -> String {
+    match is_docker_hub(url) && !name.contains('/') {
+        true => format!("library/{}", name),
+        false => name.to_string(),
+    }
+}
+
+/// Whether an upstream registry is configured
+pub fn enabled() -> bool {
+    UPSTREAM.is_some()
+}
+
+/// Store a manifest fetched from upstream through the same paths used for pushes
+async fn store(name: &str, tag: Option<&str>, manifest: &RemoteManifest) -> Result<(), db::Error> {
+    db::repositories::save(name).await?;
+    db::manifests::save(name, &manifest.digest, &manifest.body).await?;
+
+    if let Ok(Manifest::Image(image)) = serde_json::from_str::<Manifest>(&manifest.body) {
+        for layer in
+            std::iter::once(&image.config.digest).chain(image.layers.iter().map(|l| &l.digest))
+        {
+            db::blobs::associate(&manifest.digest, layer).await?;
+        }
+    }
+
+    if let Some(tag) = tag {
+        db::tags::save(name, tag, &manifest.digest, Some(ACTOR)).await?;
+    }
+
+    tracing::info!(
+        "cached manifest {} ({}) for {}",
+        manifest.digest,
+        manifest.media_type,
+        name
+    );
+    Ok(())
+}
+
+/// Whether the cache was the last to write a tag. Tags pushed, rolled back or imported here are never
+/// replaced by upstream ones.
+async fn cached(name: &str, tag: &str) -> Result<bool, db::Error> {
+    let history = db::tags::history_of(name, tag).await?;
+    Ok(history.first().and_then(|h| h.actor.as_deref()) == Some(ACTOR))
+}
+
+/// Cache a tag fetched from upstream, returning whether it may be served. A cached tag that upstream moved
+/// only follows if the protection rules allow moving it, like a push would have to.
+async fn cache(name: &str, tag: &str, local: Option<&Tag>, manifest: &RemoteManifest) -> bool {
+    let moved = local.is_none_or(|local| local.manifest != manifest.digest);
+    if moved {
+        match protection::check_overwrite(name, tag, &manifest.digest).await {
+            Ok(None) => {}
+            Ok(Some(violation)) => {
+                tracing::info!("not following {}:{} upstream: {}", name, tag, violation);
+                return false;
+            }
+            Err(e) => {
+                tracing::error!("failed to check protection of {}:{}: {}", name, tag, e);
+                return false;
+            }
+        }
+    }
+
+    if let Err(e) = store(name, Some(tag), manifest).await {
+        tracing::error!("failed to cache manifest for {}:{}: {}", name, tag, e);
+        return true;
+    }
+    if moved {
+        replication::enqueue(name, tag, Some(&manifest.digest)).await;
+        let target = Target {
+            media_type: Some(manifest.media_type.clone()),
+            size: Some(manifest.body.len() as i64),
+            digest: Some(manifest.digest.clone()),
+            length: Some(manifest.body.len() as i64),
+            repository: name.to_string(),
+            tag: Some(tag.to_string()),
+            ..Default::default()
+        };
+        events::emit(Event::new(
+            Action::Push,
+            target,
+            &RequestInfo::system(ACTOR),
+        ))
+        .await;
+    }
+    true
+}
+
+/// Resolve a tag to a manifest digest, asking the upstream registry unless the local copy is fresh or
+/// wasn't cached from there
+#[async_backtrace::framed]
+pub async fn resolve_tag(name: &str, tag: &str) -> Option<String> {
+    let upstream = UPSTREAM.as_ref()?;
+
+    let local = db::tags::get(name, tag).await.ok();
+    if let Some(local) = &local {
+        let fresh = (Utc::now() - local.updated).num_seconds() < *TAG_TTL;
+        if fresh || !cached(name, tag).await.unwrap_or(false) {
+            return Some(local.manifest.clone());
+        }
+    }
+
+    let remote = upstream_name(upstream.url(), name);
+    let remote = match upstream.get_manifest(&remote, tag).await {
+        Ok(Some(manifest)) => match cache(name, tag, local.as_ref(), &manifest).await {
+            true => Ok(Some(manifest.digest)),
+            false => Ok(None),
+        },
+        Ok(None) => {
+            tracing::debug!("{}:{} is not upstream", name, tag);
+            Ok(None)
+        }
+        Err(e) => {
+            tracing::warn!("failed to resolve {}:{} upstream: {}", name, tag, e);
+            Err(e)
+        }
+    };
+    resolution(local.map(|l| l.manifest), remote)
+}
+
+/// What a tag resolves to given the upstream answer. A tag upstream doesn't have, can't look up or that may
+/// not be moved keeps resolving to the local one.
+fn resolution(
+    local: Option<String>,
+    remote: Result<Option<String>, ClientError>,
+) -> Option<String> {
+    match remote {
+        Ok(Some(digest)) => Some(digest),
+        Ok(None) | Err(_) => local,
+    }
+}
+
+/// Fetch a manifest missing locally from the upstream registry
+#[async_backtrace::framed]
+pub async fn fetch_manifest(name: &str, digest: &str) -> Option<String> {
+    let upstream = UPSTREAM.as_ref()?;
+
+    let remote = upstream_name(upstream.url(), name);
+    match upstream.get_manifest(&remote, digest).await {
+        Ok(Some(manifest)) => {
+            if let Err(e) = store(name, None, &manifest).await {
+                tracing::error!("failed to cache manifest {}: {}", digest, e);
+            }
+            Some(manifest.body)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            tracing::warn!("failed to fetch manifest {} upstream: {}", digest, e);
+            None
+        }
+    }
+}
+
+/// Fetch a blob missing locally from the upstream registry
+#[async_backtrace::framed]
+pub async fn fetch_blob(name: &str, digest: &str) -> Option<Bytes> {
+    let upstream = UPSTREAM.as_ref()?;
+
+    let remote = upstream_name(upstream.url(), name);
+    match upstream.get_blob(&remote, digest).await {
+        Ok(Some(blob)) => {
+            match db::blobs::save(digest, &blob).await {
+                Ok(()) => tracing::info!("cached blob {} (size: {})", digest, blob.len()),
+                Err(e) => tracing::error!("failed to cache blob {}: {}", digest, e),
+            }
+            Some(blob)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            tracing::warn!("failed to fetch blob {} upstream: {}", digest, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::StatusCode;
+
+    use super::{normalize_url, resolution, upstream_name};
+    use crate::client::ClientError;
+
+    #[test]
+    fn test_docker_hub_library_prefix() {
+        let url = normalize_url("https://docker.io");
+        assert_eq!(url, "https://registry-1.docker.io");
+        assert_eq!(upstream_name(&url, "nginx"), "library/nginx");
+        assert_eq!(upstream_name(&url, "grafana/grafana"), "grafana/grafana");
+    }
+
+    #[test]
+    fn test_other_registry_untouched() {
+        let url = normalize_url("http://localhost:5001");
+        assert_eq!(url, "http://localhost:5001");
+        assert_eq!(upstream_name(&url, "nginx"), "nginx");
+    }
+
+    #[test]
+    fn test_resolution() {
+        let local = || Some("sha256:local".to_string());
+        let upstream = Ok(Some("sha256:upstream".to_string()));
+        assert_eq!(
+            resolution(local(), upstream),
+            Some("sha256:upstream".to_string())
+        );
+        assert_eq!(resolution(local(), Ok(None)), local());
+        let unreachable = Err(ClientError::Status(
+            StatusCode::BAD_GATEWAY,
+            "upstream".to_string(),
+        ));
+        assert_eq!(resolution(local(), unreachable), local());
+        assert_eq!(resolution(None, Ok(None)), None);
+    }
+}
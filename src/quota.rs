@@ -0,0 +1,114 @@
+//! Storage quotas for repositories and namespaces.
+//!
+//! A quota applies to all repositories matching its glob together, so `team-a/**` limits everything pushed
+//! below `team-a/` while `team-a/app` limits a single repository. Usage is computed from the blobs
+//! associated with the manifests in those repositories, counting blobs shared between them once.
+use bytesize::ByteSize;
+use serde::Serialize;
+
+use crate::db::{self, Quota, Usage};
+use crate::pattern;
+
+/// Why a push was refused
+#[derive(Debug, thiserror::Error)]
+pub enum Exceeded {
+    #[error("pushing would use {used} of the {limit} quota for {pattern}")]
+    Bytes {
+        pattern: String,
+        used: String,
+        limit: String,
+    },
+    #[error("pushing would create {used} tags, but the quota for {pattern} only allows {limit}")]
+    Tags {
+        pattern: String,
+        used: i64,
+        limit: i64,
+    },
+}
+
+/// A quota along with its current usage
+#[derive(Debug, Serialize)]
+pub struct QuotaUsage {
+    pub quota: Quota,
+    pub usage: Usage,
+}
+
+async fn matching_repositories(pattern: &str) -> Result<Vec<String>, db::Error> {
+    let repositories = db::repositories::list()
+        .await?
+        .into_iter()
+        .map(|r| r.name)
+        .filter(|r| pattern::matches(pattern, r))
+        .collect();
+
+    Ok(repositories)
+}
+
+/// List all quotas with their current usage
+#[async_backtrace::framed]
+pub async fn list() -> Result<Vec<QuotaUsage>, db::Error> {
+    let mut quotas = Vec::new();
+    for quota in db::quotas::list().await? {
+        let repositories = matching_repositories(&quota.repository).await?;
+        let usage = db::quotas::usage(&repositories, &[]).await?;
+        quotas.push(QuotaUsage { quota, usage });
+    }
+
+    Ok(quotas)
+}
+
+/// Check whether pushing a manifest referencing `blobs` to `repository` stays within all quotas that apply
+/// to it. `new_tag` should be set if the push creates a tag that did not exist before.
+#[async_backtrace::framed]
+pub async fn check(
+    repository: &str,
+    blobs: &[String],
+    new_tag: bool,
+) -> Result<Option<Exceeded>, db::Error> {
+    check_adding(repository, blobs, new_tag as i64, 0).await
+}
+
+/// Like [check], for a change that also creates `new_tags` tags and stores `bytes` of blobs that aren't
+/// stored yet
+#[async_backtrace::framed]
+pub async fn check_adding(
+    repository: &str,
+    blobs: &[String],
+    new_tags: i64,
+    bytes: u64,
+) -> Result<Option<Exceeded>, db::Error> {
+    for quota in db::quotas::list().await? {
+        if !pattern::matches(&quota.repository, repository) {
+            continue;
+        }
+
+        let mut repositories = matching_repositories(&quota.repository).await?;
+        if !repositories.iter().any(|r| r == repository) {
+            repositories.push(repository.to_string());
+        }
+        let usage = db::quotas::usage(&repositories, blobs).await?;
+
+        if let Some(limit) = quota.max_bytes {
+            let used = usage.bytes as u64 + bytes;
+            if used > limit as u64 {
+                return Ok(Some(Exceeded::Bytes {
+                    pattern: quota.repository,
+                    used: ByteSize::b(used).to_string_as(true),
+                    limit: ByteSize::b(limit as u64).to_string_as(true),
+                }));
+            }
+        }
+        if let Some(limit) = quota.max_tags {
+            let tags = usage.tags + new_tags;
+            if tags > limit {
+                return Ok(Some(Exceeded::Tags {
+                    pattern: quota.repository,
+                    used: tags,
+                    limit,
+                }));
+            }
+        }
+    }
+
+    Ok(None)
+}
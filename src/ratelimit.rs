@@ -0,0 +1,336 @@
+//! Rate limiting.
+//!
+//! Registry requests are throttled with token buckets, one for each budget in `rate_limit` and whoever it is
+//! kept for. A bucket holds up to the amount of its budget and refills at that amount per period. Requests to
+//! `/v2` and `/token` are counted by client IP before they are authenticated, so credentials can't be guessed
+//! at will, and those to `/v2` by principal, by repository and, for manifests, by client once they are. Blob
+//! bytes are counted by client as they are pulled and pushed, which can take a bucket below empty, so the
+//! client's next blob requests wait until it has refilled. Requests over a budget are answered with
+//! `TOOMANYREQUESTS` and a `Retry-After` header.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::ConnectInfo,
+    http::{header::RETRY_AFTER, HeaderValue, Method, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bytesize::ByteSize;
+use futures_util::TryStreamExt;
+use lazy_static::lazy_static;
+use serde_json::json;
+
+use crate::api::errors::RegistryError;
+use crate::auth::Principal;
+
+/// Full buckets are dropped once there are more than this many, or twice as many as were left the last time
+const PRUNE_ABOVE: usize = 10_000;
+
+static PRUNE_AT: AtomicUsize = AtomicUsize::new(PRUNE_ABOVE);
+
+lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<(Budget, String), Bucket>> = Mutex::new(HashMap::new());
+}
+
+/// A budget from the configuration, `<amount>/<period>` like `600/1m` or `10 GiB/1m`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    capacity: f64,
+    per_second: f64,
+}
+
+impl Rate {
+    /// Parse a rate, whose amount is a number of requests or, with `bytes`, a size
+    pub fn parse(rate: &str, bytes: bool) -> Option<Rate> {
+        let (amount, period) = rate.split_once('/')?;
+        let amount = match bytes {
+            true => amount.trim().parse::<ByteSize>().ok()?.as_u64(),
+            false => amount.trim().parse::<u64>().ok()?,
+        };
+        let period = crate::retention::parse_duration(period)?;
+        (amount > 0 && period > 0).then(|| Rate {
+            capacity: amount as f64,
+            per_second: amount as f64 / period as f64,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Budget {
+    Ip,
+    Principal,
+    Repository,
+    Manifests,
+    BlobBytes,
+}
+
+impl Budget {
+    fn name(self) -> &'static str {
+        match self {
+            Budget::Ip => "ip",
+            Budget::Principal => "principal",
+            Budget::Repository => "repository",
+            Budget::Manifests => "manifests",
+            Budget::BlobBytes => "blob_bytes",
+        }
+    }
+
+    /// The rate of the budget, if it is limited
+    fn rate(self) -> Option<Rate> {
+        let config = &crate::config::get().rate_limit;
+        let (rate, bytes) = match self {
+            Budget::Ip => (&config.ip, false),
+            Budget::Principal => (&config.principal, false),
+            Budget::Repository => (&config.repository, false),
+            Budget::Manifests => (&config.manifests, false),
+            Budget::BlobBytes => (&config.blob_bytes, true),
+        };
+        rate.as_deref().and_then(|rate| Rate::parse(rate, bytes))
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Rate, now: Instant) -> Bucket {
+        Bucket {
+            tokens: rate.capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.capacity);
+        self.updated = now;
+    }
+
+    /// How long until the bucket holds `needed` tokens
+    fn wait(&self, rate: Rate, needed: f64) -> Option<Duration> {
+        (self.tokens < needed)
+            .then(|| Duration::from_secs_f64((needed - self.tokens) / rate.per_second))
+    }
+}
+
+/// What a request takes from a budget: it needs the bucket to hold `needed` tokens, and takes `cost` of them
+struct Take<'a> {
+    budget: Budget,
+    key: &'a str,
+    needed: f64,
+    cost: f64,
+}
+
+/// Take from every bucket, or from none of them if one of them doesn't hold enough, returning the budget that
+/// doesn't and how long until it does
+fn take(takes: &[Take]) -> Result<(), (Budget, Duration)> {
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().unwrap();
+    if buckets.len() > PRUNE_AT.load(Ordering::Relaxed) {
+        // a full bucket is the same as none at all
+        buckets.retain(|(budget, _), bucket| match budget.rate() {
+            Some(rate) => {
+                bucket.refill(rate, now);
+                bucket.tokens < rate.capacity
+            }
+            None => false,
+        });
+        PRUNE_AT.store((buckets.len() * 2).max(PRUNE_ABOVE), Ordering::Relaxed);
+    }
+
+    let mut limited = Vec::new();
+    for take in takes {
+        let Some(rate) = take.budget.rate() else {
+            continue;
+        };
+        let bucket = buckets
+            .entry((take.budget, take.key.to_string()))
+            .or_insert_with(|| Bucket::new(rate, now));
+        bucket.refill(rate, now);
+        if let Some(wait) = bucket.wait(rate, take.needed) {
+            return Err((take.budget, wait));
+        }
+        limited.push(take);
+    }
+    for take in limited {
+        if let Some(bucket) = buckets.get_mut(&(take.budget, take.key.to_string())) {
+            bucket.tokens -= take.cost;
+        }
+    }
+    Ok(())
+}
+
+fn too_many_requests(budget: Budget, wait: Duration) -> Response {
+    tracing::debug!("over the {} rate limit", budget.name());
+    crate::metrics::rate_limited(budget.name());
+    let mut response = RegistryError::TooManyRequests
+        .with_detail(json!({ "budget": budget.name() }))
+        .into_response();
+    let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+fn ip<B>(req: &Request<B>) -> Option<String> {
+    req.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+}
+
+/// Whether `path` is of `resource` of a repository, like `/manifests/`, rather than a deeper one
+fn is_resource(path: &str, resource: &str) -> bool {
+    path.rsplit_once(resource)
+        .is_some_and(|(_, rest)| !rest.is_empty() && !rest.contains('/'))
+}
+
+/// Middleware limiting registry requests by client IP, to be run before they are authenticated
+#[async_backtrace::framed]
+pub async fn limit_clients<B>(req: Request<B>, next: Next<B>) -> Response {
+    let path = req.uri().path();
+    let registry = path == "/v2" || path.starts_with("/v2/") || path == "/token";
+    if let Some(ip) = ip(&req).filter(|_| registry) {
+        let request = Take {
+            budget: Budget::Ip,
+            key: &ip,
+            needed: 1.0,
+            cost: 1.0,
+        };
+        if let Err((budget, wait)) = take(&[request]) {
+            return too_many_requests(budget, wait);
+        }
+    }
+    next.run(req).await
+}
+
+/// Middleware limiting authenticated registry requests by principal, repository and kind, and the blob bytes
+/// clients pull and push
+#[async_backtrace::framed]
+pub async fn limit(req: Request<Body>, next: Next<Body>) -> Response {
+    let path = req.uri().path();
+    if !path.starts_with("/v2/") {
+        return next.run(req).await;
+    }
+    let principal = req.extensions().get::<Principal>().map(|p| p.name.clone());
+    let client = principal.clone().or_else(|| ip(&req)).unwrap_or_default();
+    let (repository, _, _) = crate::telemetry::target(req.uri());
+    let manifest = is_resource(path, "/manifests/");
+    let upload = path.contains("/blobs/uploads/");
+    let download = !upload
+        && is_resource(path, "/blobs/")
+        && [Method::GET, Method::HEAD].contains(req.method());
+
+    let request = |budget, key| Take {
+        budget,
+        key,
+        needed: 1.0,
+        cost: 1.0,
+    };
+    let mut takes = Vec::new();
+    if let Some(principal) = &principal {
+        takes.push(request(Budget::Principal, principal));
+    }
+    if let Some(repository) = &repository {
+        takes.push(request(Budget::Repository, repository));
+    }
+    if manifest {
+        takes.push(request(Budget::Manifests, &client));
+    }
+    // bytes are taken once they have been transferred, the bucket only needs to have some left
+    if upload || download {
+        takes.push(Take {
+            budget: Budget::BlobBytes,
+            key: &client,
+            needed: 1.0,
+            cost: 0.0,
+        });
+    }
+    if let Err((budget, wait)) = take(&takes) {
+        return too_many_requests(budget, wait);
+    }
+    if !(upload || download) || Budget::BlobBytes.rate().is_none() {
+        return next.run(req).await;
+    }
+
+    let uploaded = Arc::new(AtomicU64::new(0));
+    let req = match upload {
+        true => {
+            let uploaded = uploaded.clone();
+            req.map(|body| {
+                Body::wrap_stream(body.inspect_ok(move |chunk| {
+                    uploaded.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }))
+            })
+        }
+        false => req,
+    };
+    let response = next.run(req).await;
+    let downloaded = match download && response.status().is_success() {
+        true => response.body().size_hint().exact().unwrap_or_default(),
+        false => 0,
+    };
+    let transferred = uploaded.load(Ordering::Relaxed) + downloaded;
+    take(&[Take {
+        budget: Budget::BlobBytes,
+        key: &client,
+        needed: f64::NEG_INFINITY,
+        cost: transferred as f64,
+    }])
+    .ok();
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{is_resource, Bucket, Rate};
+
+    #[test]
+    fn test_parse() {
+        let rate = Rate::parse("600/1m", false).unwrap();
+        assert_eq!(rate.capacity, 600.0);
+        assert_eq!(rate.per_second, 10.0);
+        let rate = Rate::parse("1 KiB/2s", true).unwrap();
+        assert_eq!(rate.capacity, 1024.0);
+        assert_eq!(rate.per_second, 512.0);
+        assert_eq!(Rate::parse("1 KiB/2s", false), None);
+        assert_eq!(Rate::parse("0/1m", false), None);
+        assert_eq!(Rate::parse("600", false), None);
+        assert_eq!(Rate::parse("600/soon", false), None);
+    }
+
+    #[test]
+    fn test_bucket() {
+        let rate = Rate::parse("2/2s", false).unwrap();
+        let start = Instant::now();
+        let mut bucket = Bucket::new(rate, start);
+        bucket.tokens -= 2.0;
+        assert_eq!(bucket.wait(rate, 1.0), Some(Duration::from_secs(1)));
+
+        bucket.refill(rate, start + Duration::from_millis(1500));
+        assert_eq!(bucket.wait(rate, 1.0), None);
+        bucket.refill(rate, start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn test_is_resource() {
+        assert!(is_resource("/v2/team/app/manifests/latest", "/manifests/"));
+        assert!(!is_resource(
+            "/v2/manifests/app/blobs/sha256:00",
+            "/manifests/"
+        ));
+        assert!(is_resource("/v2/manifests/app/blobs/sha256:00", "/blobs/"));
+        assert!(!is_resource("/v2/app/tags/list", "/blobs/"));
+    }
+}
@@ -0,0 +1,286 @@
+//! Push-based replication to other registries.
+//!
+//! A replication rule selects tags by repository glob and an optional tag pattern, and names the registry
+//! they are copied to. Every change to a matching tag queues a task for the rule, replacing any older task
+//! for the same tag that has not run yet. A background worker copies the tagged manifest, the manifests of
+//! a manifest list and all blobs the target does not have yet, or deletes the tag on the target. Failed
+//! tasks are retried with exponential backoff, and each rule records when it last succeeded and why it
+//! last failed.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use tokio::sync::Notify;
+
+use crate::api::manifests::{self, ImageManifest, Manifest};
+use crate::client::{Client, ClientError};
+use crate::db::{self, ReplicationRule, ReplicationTask};
+use crate::pattern;
+
+/// Tasks are dropped after failing this many times
+const MAX_ATTEMPTS: i64 = 10;
+
+/// How often the queue is checked for tasks due to be retried
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref WAKE: Notify = Notify::new();
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplicationError {
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error(transparent)]
+    Database(#[from] db::Error),
+}
+
+/// Whether a rule applies to a tag
+pub fn matches(rule: &ReplicationRule, repository: &str, tag: &str) -> bool {
+    pattern::matches(&rule.repository, repository)
+        && rule
+            .tag_pattern
+            .as_ref()
+            .is_none_or(|p| pattern::tag_regex(p).is_ok_and(|r| r.is_match(tag)))
+}
+
+/// How long to wait before retrying a task that has failed `attempts` times
+pub fn backoff(attempts: i64) -> chrono::Duration {
+    let seconds = 30i64.saturating_mul(1 << attempts.clamp(0, 16));
+    chrono::Duration::seconds(seconds.min(60 * 60))
+}
+
+/// Queue a tag change for every rule it matches. `manifest` is `None` if the tag was deleted.
+///
+/// Failing to queue is logged rather than returned, so it never fails the change itself.
+#[async_backtrace::framed]
+pub async fn enqueue(repository: &str, tag: &str, manifest: Option<&str>) {
+    let rules = match db::replication::list_rules().await {
+        Ok(rules) => rules,
+        Err(e) => {
+            tracing::error!("failed to load replication rules: {}", e);
+            return;
+        }
+    };
+
+    let mut queued = false;
+    for rule in rules.iter().filter(|r| matches(r, repository, tag)) {
+        match db::replication::enqueue(rule.id, repository, tag, manifest).await {
+            Ok(()) => queued = true,
+            Err(e) => tracing::error!(
+                "failed to queue replication of {}:{} to {}: {}",
+                repository,
+                tag,
+                rule.target,
+                e
+            ),
+        }
+    }
+
+    if queued {
+        WAKE.notify_one();
+    }
+}
+
+/// Queue every existing tag matching a rule, e.g. after creating it
+#[async_backtrace::framed]
+pub async fn sync(rule: &ReplicationRule) -> Result<usize, db::Error> {
+    let mut queued = 0;
+    for repository in db::repositories::list().await? {
+        if !pattern::matches(&rule.repository, &repository.name) {
+            continue;
+        }
+        for tag in db::tags::list(&repository.name).await? {
+            if matches(rule, &repository.name, &tag.name) {
+                db::replication::enqueue(rule.id, &repository.name, &tag.name, Some(&tag.manifest))
+                    .await?;
+                queued += 1;
+            }
+        }
+    }
+
+    WAKE.notify_one();
+    Ok(queued)
+}
+
+/// Copy the blobs of an image manifest the target does not have yet
+async fn copy_blobs(
+    client: &Client,
+    repository: &str,
+    image: &ImageManifest,
+) -> Result<(), ReplicationError> {
+    for digest in
+        std::iter::once(&image.config.digest).chain(image.layers.iter().map(|l| &l.digest))
+    {
+        if client.has_blob(repository, digest).await? {
+            tracing::debug!("{} already has blob {}", client.url(), digest);
+            continue;
+        }
+        let blob = db::blobs::get(digest).await?;
+        client.upload_blob(repository, digest, blob).await?;
+        tracing::info!("replicated blob {} to {}", digest, client.url());
+    }
+
+    Ok(())
+}
+
+/// Copy a manifest and everything it references, pushing it under `reference`
+async fn copy_manifest(
+    client: &Client,
+    repository: &str,
+    digest: &str,
+    reference: &str,
+) -> Result<(), ReplicationError> {
+    let raw = db::manifests::get(repository, digest).await?;
+    match serde_json::from_str::<Manifest>(&raw) {
+        Ok(Manifest::Image(image)) => copy_blobs(client, repository, &image).await?,
+        Ok(Manifest::List(list)) => {
+            for child in list.manifests {
+                let child_raw = db::manifests::get(repository, &child.digest).await?;
+                if let Ok(Manifest::Image(image)) = serde_json::from_str(&child_raw) {
+                    copy_blobs(client, repository, &image).await?;
+                }
+                client
+                    .put_manifest(
+                        repository,
+                        &child.digest,
+                        &manifests::media_type(&child_raw),
+                        &child_raw,
+                    )
+                    .await?;
+            }
+        }
+        Err(_) => {}
+    }
+
+    client
+        .put_manifest(repository, reference, &manifests::media_type(&raw), &raw)
+        .await?;
+    Ok(())
+}
+
+async fn replicate(client: &Client, task: &ReplicationTask) -> Result<(), ReplicationError> {
+    match &task.manifest {
+        Some(digest) => copy_manifest(client, &task.repository, digest, &task.tag).await,
+        None => Ok(client.delete_manifest(&task.repository, &task.tag).await?),
+    }
+}
+
+/// Attempt all tasks that are due, returning how many succeeded
+async fn run(clients: &mut HashMap<i64, Client>) -> Result<usize, db::Error> {
+    let rules = db::replication::list_rules()
+        .await?
+        .into_iter()
+        .map(|r| (r.id, r))
+        .collect::<HashMap<_, _>>();
+
+    let mut replicated = 0;
+    for task in db::replication::due().await? {
+        let rule = match rules.get(&task.rule) {
+            Some(rule) => rule,
+            None => {
+                db::replication::complete(task.id).await?;
+                continue;
+            }
+        };
+        let client = clients.entry(rule.id).or_insert_with(|| {
+            let credentials = rule
+                .username
+                .clone()
+                .map(|username| (username, rule.password.clone().unwrap_or_default()));
+            Client::new(&rule.target, credentials)
+        });
+
+        match replicate(client, &task).await {
+            Ok(()) => {
+                tracing::info!(
+                    "replicated {}:{} to {}",
+                    task.repository,
+                    task.tag,
+                    rule.target
+                );
+                db::replication::complete(task.id).await?;
+                db::replication::set_status(rule.id, None).await?;
+                replicated += 1;
+            }
+            Err(e) => {
+                let error = format!("{}:{}: {}", task.repository, task.tag, e);
+                if task.attempts + 1 >= MAX_ATTEMPTS {
+                    tracing::error!(
+                        "giving up replicating to {} after {} attempts: {}",
+                        rule.target,
+                        MAX_ATTEMPTS,
+                        error
+                    );
+                    db::replication::complete(task.id).await?;
+                } else {
+                    let next_attempt = Utc::now() + backoff(task.attempts);
+                    tracing::warn!(
+                        "replicating to {} failed, retrying at {}: {}",
+                        rule.target,
+                        next_attempt,
+                        error
+                    );
+                    db::replication::retry(task.id, &error, next_attempt).await?;
+                }
+                db::replication::set_status(rule.id, Some(&error)).await?;
+            }
+        }
+    }
+
+    Ok(replicated)
+}
+
+/// Work through the replication queue, running as soon as tasks are queued
+#[async_backtrace::framed]
+pub async fn worker() {
+    let mut clients = HashMap::new();
+    loop {
+        match run(&mut clients).await {
+            Ok(0) => {}
+            Ok(replicated) => tracing::info!("replicated {} tags", replicated),
+            Err(e) => tracing::error!("replication run failed: {}", e),
+        }
+
+        tokio::select! {
+            _ = WAKE.notified() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{backoff, matches};
+    use crate::db::ReplicationRule;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0).num_seconds(), 30);
+        assert_eq!(backoff(1).num_seconds(), 60);
+        assert_eq!(backoff(3).num_seconds(), 240);
+        assert_eq!(backoff(9).num_seconds(), 60 * 60);
+        assert_eq!(backoff(100).num_seconds(), 60 * 60);
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut rule = ReplicationRule {
+            id: 1,
+            repository: "team-a/**".to_string(),
+            tag_pattern: None,
+            target: "http://localhost:5001".to_string(),
+            username: None,
+            password: None,
+            last_replicated: None,
+            last_error: None,
+        };
+        assert!(matches(&rule, "team-a/app", "latest"));
+        assert!(!matches(&rule, "team-b/app", "latest"));
+
+        rule.tag_pattern = Some(r"v\d+(\.\d+)*".to_string());
+        assert!(matches(&rule, "team-a/app", "v1.0"));
+        assert!(!matches(&rule, "team-a/app", "latest"));
+    }
+}
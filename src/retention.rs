@@ -0,0 +1,353 @@
+//! Tag retention policies.
+//!
+//! A policy applies to every repository matching its glob and selects tags for deletion based on how many
+//! tags to keep, how old a tag may get and which tags it considers at all. Protected tags are never
+//! selected. Policies start out in dry-run mode, where they only report what they would delete. Enforced
+//! policies delete the selected tags, along with any manifest left untagged by it, and then the blobs only
+//! those manifests referred to. Other unreferenced blobs, like those of pushes still in progress, are left to
+//! [db::cleanup].
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::db::{self, RetentionPolicy, Tag};
+use crate::events::{self, Action, Event, RequestInfo, Target};
+use crate::{pattern, protection, replication};
+
+/// Who deletions made by retention policies are attributed to
+const ACTOR: &str = "retention";
+
+/// A tag selected for deletion by a retention policy
+#[derive(Debug, Serialize)]
+pub struct Deletion {
+    pub policy: i64,
+    pub repository: String,
+    pub tag: String,
+    pub manifest: String,
+    pub updated: DateTime<Utc>,
+    pub enforced: bool,
+}
+
+/// Parse a duration such as `90m`, `12h`, `30d` or `2w` into seconds. A bare number is taken as seconds.
+pub fn parse_duration(duration: &str) -> Option<i64> {
+    let duration = duration.trim();
+    let (value, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => duration.split_at(index),
+        None => (duration, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return None,
+    };
+    value.parse::<i64>().ok()?.checked_mul(multiplier)
+}
+
+/// Format a number of seconds using the largest unit [parse_duration] accepts that divides it evenly.
+pub fn format_duration(seconds: i64) -> String {
+    for (unit, size) in [
+        ("w", 60 * 60 * 24 * 7),
+        ("d", 60 * 60 * 24),
+        ("h", 60 * 60),
+        ("m", 60),
+    ] {
+        if seconds != 0 && seconds % size == 0 {
+            return format!("{}{}", seconds / size, unit);
+        }
+    }
+    format!("{}s", seconds)
+}
+
+/// Select the tags a policy would delete. `tags` must be sorted by `updated`, newest first, as returned by
+/// [db::tags::list].
+pub fn select<'a>(
+    policy: &RetentionPolicy,
+    tags: &'a [Tag],
+    now: DateTime<Utc>,
+) -> Result<Vec<&'a Tag>, regex::Error> {
+    let tag_pattern = policy
+        .tag_pattern
+        .as_deref()
+        .map(pattern::tag_regex)
+        .transpose()?;
+    let exempt = policy
+        .exempt
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(pattern::tag_regex)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let candidates = tags
+        .iter()
+        .filter(|t| tag_pattern.as_ref().is_none_or(|p| p.is_match(&t.name)))
+        .filter(|t| !exempt.iter().any(|e| e.is_match(&t.name)));
+
+    let selected = candidates
+        .enumerate()
+        .filter(|(i, _)| policy.keep_latest.is_none_or(|n| *i as i64 >= n))
+        .filter(|(_, t)| {
+            policy
+                .max_age
+                .is_none_or(|age| (now - t.updated).num_seconds() > age)
+        })
+        .map(|(_, t)| t)
+        .collect();
+
+    Ok(selected)
+}
+
+/// Evaluate every retention policy against every repository without deleting anything.
+#[async_backtrace::framed]
+pub async fn plan() -> Result<Vec<Deletion>, db::Error> {
+    let policies = db::retention::list().await?;
+    let protections = protection::Rules::load().await?;
+    let repositories = db::repositories::list().await?;
+    let now = Utc::now();
+
+    let mut deletions: Vec<Deletion> = Vec::new();
+    for repository in repositories {
+        let matching = policies
+            .iter()
+            .filter(|p| pattern::matches(&p.repository, &repository.name))
+            .collect::<Vec<_>>();
+        if matching.is_empty() {
+            continue;
+        }
+
+        let tags = db::tags::list(&repository.name).await?;
+        for policy in matching {
+            let selected = match select(policy, &tags, now) {
+                Ok(selected) => selected,
+                Err(e) => {
+                    tracing::error!("skipping retention policy {}: {}", policy.id, e);
+                    continue;
+                }
+            };
+            for tag in selected {
+                if protections
+                    .protecting(&repository.name, &tag.name)
+                    .is_some()
+                {
+                    continue;
+                }
+                // a tag selected by several policies is only reported once, enforced if any of them is
+                match deletions
+                    .iter_mut()
+                    .find(|d| d.repository == repository.name && d.tag == tag.name)
+                {
+                    Some(deletion) => deletion.enforced |= policy.enforce,
+                    None => deletions.push(Deletion {
+                        policy: policy.id,
+                        repository: repository.name.clone(),
+                        tag: tag.name.clone(),
+                        manifest: tag.manifest.clone(),
+                        updated: tag.updated,
+                        enforced: policy.enforce,
+                    }),
+                }
+            }
+        }
+    }
+
+    Ok(deletions)
+}
+
+/// Delete the tags selected by enforced policies, along with manifests that are no longer tagged and the
+/// blobs only they referred to, emitting the same events as deleting them through the API would. Returns
+/// everything the policies selected, including dry-run results.
+#[async_backtrace::framed]
+pub async fn apply() -> Result<Vec<Deletion>, db::Error> {
+    let deletions = plan().await?;
+    let request = RequestInfo::system(ACTOR);
+
+    let mut affected: HashSet<(&str, &str)> = HashSet::new();
+    for deletion in deletions.iter() {
+        if !deletion.enforced {
+            tracing::info!(
+                "retention policy {} would delete {}:{} (dry run)",
+                deletion.policy,
+                deletion.repository,
+                deletion.tag
+            );
+            continue;
+        }
+        tracing::info!(
+            "retention policy {} deleting {}:{}",
+            deletion.policy,
+            deletion.repository,
+            deletion.tag
+        );
+        db::tags::delete(&deletion.repository, &deletion.tag, Some(ACTOR)).await?;
+        replication::enqueue(&deletion.repository, &deletion.tag, None).await;
+        events::emit(Event::new(
+            Action::Delete,
+            Target {
+                digest: Some(deletion.manifest.clone()),
+                repository: deletion.repository.clone(),
+                tag: Some(deletion.tag.clone()),
+                ..Default::default()
+            },
+            &request,
+        ))
+        .await;
+        events::audit(
+            &request,
+            "retention.delete",
+            Some(&deletion.repository),
+            Some(&deletion.policy.to_string()),
+            Some(&deletion.manifest),
+        )
+        .await;
+        affected.insert((&deletion.repository, &deletion.manifest));
+    }
+
+    if affected.is_empty() {
+        return Ok(deletions);
+    }
+
+    let mut deleted = Vec::new();
+    for (repository, manifest) in affected {
+        let tagged = db::tags::list(repository)
+            .await?
+            .iter()
+            .any(|t| t.manifest == manifest);
+        if !tagged {
+            db::manifests::delete(repository, manifest).await?;
+            events::emit(Event::new(
+                Action::Delete,
+                Target {
+                    digest: Some(manifest.to_string()),
+                    repository: repository.to_string(),
+                    ..Default::default()
+                },
+                &request,
+            ))
+            .await;
+            deleted.push((repository, manifest));
+        }
+    }
+    db::collect_deleted(&deleted).await?;
+
+    Ok(deletions)
+}
+
+/// Apply retention policies every `RETENTION_INTERVAL` seconds (one hour by default). An interval of `0`
+/// disables scheduled runs.
+#[async_backtrace::framed]
+pub async fn schedule() {
+    let seconds = std::env::var("RETENTION_INTERVAL")
+        .ok()
+        .and_then(|i| i.parse::<u64>().ok())
+        .unwrap_or(60 * 60);
+    if seconds == 0 {
+        tracing::info!("scheduled retention is disabled");
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(seconds));
+    loop {
+        interval.tick().await;
+        match apply().await {
+            Ok(deletions) => tracing::info!(
+                "retention run selected {} tags ({} enforced)",
+                deletions.len(),
+                deletions.iter().filter(|d| d.enforced).count()
+            ),
+            Err(e) => tracing::error!("retention run failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, Utc};
+
+    use super::{format_duration, parse_duration, select};
+    use crate::db::{RetentionPolicy, Tag};
+
+    fn policy() -> RetentionPolicy {
+        RetentionPolicy {
+            id: 1,
+            repository: "**".to_string(),
+            keep_latest: None,
+            max_age: None,
+            tag_pattern: None,
+            exempt: None,
+            enforce: false,
+        }
+    }
+
+    fn tags() -> Vec<Tag> {
+        let now = Utc::now();
+        ["latest", "nightly-3", "nightly-2", "v1.0.0", "nightly-1"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Tag {
+                name: name.to_string(),
+                updated: now - Duration::days(i as i64 * 10),
+                manifest: format!("sha256:{}", i),
+            })
+            .collect()
+    }
+
+    fn names(tags: Vec<&Tag>) -> Vec<&str> {
+        tags.into_iter().map(|t| t.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("3600"), Some(3600));
+        assert_eq!(parse_duration("90m"), Some(90 * 60));
+        assert_eq!(parse_duration("30d"), Some(30 * 24 * 60 * 60));
+        assert_eq!(parse_duration("2w"), Some(14 * 24 * 60 * 60));
+        assert_eq!(parse_duration("2y"), None);
+        assert_eq!(parse_duration("d"), None);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(14 * 24 * 60 * 60), "2w");
+        assert_eq!(format_duration(36 * 60 * 60), "36h");
+        assert_eq!(format_duration(61), "61s");
+    }
+
+    #[test]
+    fn test_select_keep_latest() {
+        let tags = tags();
+        let policy = RetentionPolicy {
+            keep_latest: Some(2),
+            tag_pattern: Some(r"nightly-\d+".to_string()),
+            ..policy()
+        };
+        let selected = select(&policy, &tags, Utc::now()).unwrap();
+        assert_eq!(names(selected), vec!["nightly-1"]);
+    }
+
+    #[test]
+    fn test_select_max_age_with_exemptions() {
+        let tags = tags();
+        let policy = RetentionPolicy {
+            max_age: parse_duration("15d"),
+            exempt: Some(r"latest v\d+\.\d+\.\d+".to_string()),
+            ..policy()
+        };
+        let selected = select(&policy, &tags, Utc::now()).unwrap();
+        assert_eq!(names(selected), vec!["nightly-2", "nightly-1"]);
+    }
+
+    #[test]
+    fn test_select_invalid_pattern() {
+        let tags = tags();
+        let policy = RetentionPolicy {
+            tag_pattern: Some("nightly-(".to_string()),
+            ..policy()
+        };
+        assert!(select(&policy, &tags, Utc::now()).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! Tracing.
+//!
+//! Log lines are written to stderr, filtered by `RUST_LOG`. Spans are also exported over OTLP/HTTP to
+//! `telemetry.otlp_endpoint` and written as JSON lines to `telemetry.file`, when those are set. [trace] opens a
+//! span for every request, continuing the trace of an incoming `traceparent` header, and every `db::*` call
+//! opens a span of its own inside it. Each request gets an ID, taken from its `X-Request-Id` header or
+//! generated, which is returned in the same header and logged with every line about the request.
+use std::fs::OpenOptions;
+use std::time::Duration;
+
+use axum::{
+    http::{HeaderMap, HeaderName, HeaderValue, Request, Uri},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self as sdktrace, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::field::{self, Field, Visit};
+use tracing::span::Record;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FormatFields, FormattedFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+#[derive(Debug, thiserror::Error)]
+pub enum TelemetryError {
+    #[error("failed to open {0}: {1}")]
+    File(String, std::io::Error),
+    #[error("failed to set up the OTLP exporter: {0}")]
+    Exporter(#[from] TraceError),
+}
+
+/// Set up logging, and exporting spans if configured
+pub fn init() -> Result<(), TelemetryError> {
+    let config = &crate::config::get().telemetry;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let mut provider =
+        TracerProvider::builder().with_config(sdktrace::config().with_resource(Resource::new([
+            KeyValue::new("service.name", config.service_name.clone()),
+        ])));
+    if let Some(endpoint) = &config.otlp_endpoint {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(endpoint)
+            .with_timeout(Duration::from_secs(10))
+            .build_span_exporter()?;
+        provider = provider.with_batch_exporter(exporter, runtime::Tokio);
+    }
+    if let Some(path) = &config.file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| TelemetryError::File(path.display().to_string(), e))?;
+        let exporter = opentelemetry_stdout::SpanExporter::builder()
+            .with_writer(file)
+            .build();
+        provider = provider.with_batch_exporter(exporter, runtime::Tokio);
+    }
+
+    // spans are exported regardless of RUST_LOG, which only decides what is logged
+    let exporting = (config.otlp_endpoint.is_some() || config.file.is_some()).then(|| {
+        let provider = provider.build();
+        let tracer = provider.tracer("pequod");
+        global::set_tracer_provider(provider);
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(LevelFilter::INFO)
+    });
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(Fields)
+                .with_filter(EnvFilter::from_default_env()),
+        )
+        .with(exporting)
+        .init();
+    Ok(())
+}
+
+/// Formats log lines without the dotted OpenTelemetry attributes of spans, which are only exported
+struct Fields;
+
+struct Visitor<'w> {
+    writer: Writer<'w>,
+    first: bool,
+    result: std::fmt::Result,
+}
+
+impl Visit for Visitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if self.result.is_err() || field.name().contains('.') {
+            return;
+        }
+        let separator = if self.first { "" } else { " " };
+        self.first = false;
+        self.result = match field.name() {
+            "message" => write!(self.writer, "{}{:?}", separator, value),
+            name => write!(self.writer, "{}{}={:?}", separator, name, value),
+        };
+    }
+}
+
+impl<'w> FormatFields<'w> for Fields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'w>, fields: R) -> std::fmt::Result {
+        let mut visitor = Visitor {
+            writer,
+            first: true,
+            result: Ok(()),
+        };
+        fields.record(&mut visitor);
+        visitor.result
+    }
+
+    fn add_fields(
+        &self,
+        current: &'w mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> std::fmt::Result {
+        // spans mostly record dotted attributes later, which would otherwise leave a trailing space
+        let mut added = FormattedFields::<Self>::new(String::new());
+        self.format_fields(added.as_writer(), fields)?;
+        if !added.fields.is_empty() {
+            if !current.fields.is_empty() {
+                current.fields.push(' ');
+            }
+            current.fields.push_str(&added.fields);
+        }
+        Ok(())
+    }
+}
+
+/// Export the spans that haven't been yet
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeadersMut<'a>(&'a mut HeaderMap);
+
+impl Injector for HeadersMut<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// The `traceparent` header continuing the trace of the current span in a request to another service
+pub fn propagation() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeadersMut(&mut headers))
+    });
+    headers
+}
+
+/// A request ID given by the client is kept if it is short and printable, so it can't garble log lines
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string)
+}
+
+/// The repository, reference and digest a registry API request is about
+pub fn target(uri: &Uri) -> (Option<String>, Option<String>, Option<String>) {
+    let Some(path) = crate::RepositoryPath::parse(uri.path()) else {
+        return (None, None, None);
+    };
+    let digest_query = || {
+        url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+            .find(|(key, _)| key == "digest")
+            .map(|(_, digest)| digest.to_string())
+    };
+
+    let repository = Some(path.name);
+    let rest = Some(path.rest).filter(|r| !r.is_empty());
+    match path.resource.as_str() {
+        "manifests"
+            if rest
+                .as_deref()
+                .is_some_and(|r| crate::DIGEST_REGEX.is_match(r)) =>
+        {
+            (repository, None, rest)
+        }
+        "manifests" => (repository, rest, None),
+        "blobs" => match rest.as_deref().and_then(|r| r.strip_prefix("uploads/")) {
+            // the reference of an upload is its ID, the digest is given when it is finished
+            Some(id) => (
+                repository,
+                Some(id.to_string()).filter(|id| !id.is_empty()),
+                digest_query(),
+            ),
+            None => (repository, None, rest),
+        },
+        _ => (repository, None, None),
+    }
+}
+
+/// Middleware opening a span for every request and giving it an ID
+#[async_backtrace::framed]
+pub async fn trace<B>(req: Request<B>, next: Next<B>) -> Response {
+    let id = request_id(req.headers());
+    let (repository, reference, digest) = target(req.uri());
+    let method = req.method().clone();
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %method,
+        otel.kind = "server",
+        otel.status_code = field::Empty,
+        request_id = %id,
+        http.request.method = %method,
+        url.path = %req.uri().path(),
+        http.route = field::Empty,
+        http.response.status_code = field::Empty,
+        repository = repository.as_deref(),
+        reference = reference.as_deref(),
+        digest = digest.as_deref(),
+    );
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&Headers(req.headers())));
+    span.set_parent(parent);
+
+    let matched = req
+        .extensions()
+        .get::<axum::extract::MatchedPath>()
+        .cloned();
+    let mut response = next.run(req).instrument(span.clone()).await;
+
+    let route = crate::metrics::route(matched.as_ref(), &response);
+    span.record("otel.name", format!("{} {}", method, route));
+    span.record("http.route", route);
+    span.record("http.response.status_code", response.status().as_u16());
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, id);
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::{HeaderMap, Uri};
+
+    use super::{request_id, target, REQUEST_ID};
+
+    #[test]
+    fn test_target() {
+        let target = |uri: &str| target(&uri.parse::<Uri>().unwrap());
+        let digest = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(
+            target("/v2/team%2Fapp/manifests/v1"),
+            (Some("team/app".to_string()), Some("v1".to_string()), None)
+        );
+        assert_eq!(
+            target(&format!("/v2/app/manifests/{}", digest)),
+            (Some("app".to_string()), None, Some(digest.to_string()))
+        );
+        assert_eq!(
+            target(&format!("/v2/app/blobs/uploads/1234?digest={}", digest)),
+            (
+                Some("app".to_string()),
+                Some("1234".to_string()),
+                Some(digest.to_string())
+            )
+        );
+        assert_eq!(
+            target("/v2/app/tags/list"),
+            (Some("app".to_string()), None, None)
+        );
+        assert_eq!(
+            target("/v2/a/blobs/b/manifests/v1"),
+            (Some("a/blobs/b".to_string()), Some("v1".to_string()), None)
+        );
+        assert_eq!(target("/v2/_catalog"), (None, None, None));
+        assert_eq!(target("/admin"), (None, None, None));
+    }
+
+    #[test]
+    fn test_request_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(request_id(&headers).len(), 36);
+        headers.insert(REQUEST_ID, "abc-123".parse().unwrap());
+        assert_eq!(request_id(&headers), "abc-123");
+        headers.insert(REQUEST_ID, "a b".parse().unwrap());
+        assert_ne!(request_id(&headers), "a b");
+    }
+}
@@ -0,0 +1,362 @@
+//! TLS termination and client certificates.
+//!
+//! With `TLS_CERT` and `TLS_KEY` pointing at a PEM certificate chain and private key, the registry serves
+//! HTTPS. Both files are checked for changes every `TLS_RELOAD_INTERVAL` seconds (30 by default) and
+//! reloaded, so renewed certificates are picked up without a restart. An interval of `0` disables reloading.
+//!
+//! With `TLS_CLIENT_CA`, clients may also authenticate with a certificate issued by one of the CAs in that
+//! PEM file, and with `TLS_CLIENT_REQUIRED=true` they have to. The principal of a client certificate is the
+//! common name of its subject, unless the file at `TLS_CLIENT_PRINCIPALS` maps the subject to another
+//! principal, one `<principal> <subject>` pair per line, like `robot$ci O=Example, CN=ci.example.com`. The
+//! subject is written the way `openssl x509 -noout -subject` prints it.
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::Response;
+use hyper::server::conn::Http;
+use lazy_static::lazy_static;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    ResolvesServerCert,
+};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tower::{Service, ServiceExt};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// How long a client has to complete the TLS handshake before the connection is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref CERT: Option<PathBuf> = std::env::var("TLS_CERT").ok().map(PathBuf::from);
+    static ref KEY: Option<PathBuf> = std::env::var("TLS_KEY").ok().map(PathBuf::from);
+    static ref CLIENT_CA: Option<PathBuf> = std::env::var("TLS_CLIENT_CA").ok().map(PathBuf::from);
+    static ref CLIENT_REQUIRED: bool =
+        std::env::var("TLS_CLIENT_REQUIRED").as_deref() == Ok("true");
+    static ref CLIENT_PRINCIPALS: Option<PathBuf> = std::env::var("TLS_CLIENT_PRINCIPALS")
+        .ok()
+        .map(PathBuf::from);
+    static ref RELOAD_INTERVAL: u64 = std::env::var("TLS_RELOAD_INTERVAL")
+        .ok()
+        .and_then(|i| i.parse().ok())
+        .unwrap_or(30);
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("failed to read {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("no certificates found in {0}")]
+    NoCertificates(PathBuf),
+    #[error("no private key found in {0}")]
+    NoKey(PathBuf),
+    #[error("unsupported private key in {0}")]
+    UnsupportedKey(PathBuf),
+    #[error(transparent)]
+    Rustls(#[from] tokio_rustls::rustls::Error),
+}
+
+/// The client certificate a connection was authenticated with
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub subject: String,
+    /// Who the certificate identifies, for the authorization layer
+    pub principal: String,
+}
+
+/// Whether the registry serves HTTPS
+pub fn enabled() -> bool {
+    CERT.is_some() && KEY.is_some()
+}
+
+/// The scheme of URLs pointing at this registry
+pub fn scheme() -> &'static str {
+    match enabled() {
+        true => "https",
+        false => "http",
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|e| TlsError::Io(path.to_path_buf(), e))
+}
+
+fn certificates(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let pem = read(path)?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(pem.as_slice()))
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn private_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let pem = read(path)?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(pem.as_slice()))
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| TlsError::NoKey(path.to_path_buf()))
+}
+
+fn certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, TlsError> {
+    let signing_key = sign::any_supported_type(&private_key(key)?)
+        .map_err(|_| TlsError::UnsupportedKey(key.to_path_buf()))?;
+    Ok(CertifiedKey::new(certificates(cert)?, signing_key))
+}
+
+/// Hands out the current certificate, which [reload] replaces when the files change
+struct Resolver {
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+fn modified(paths: &[&Path]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Load the certificate again whenever the certificate or key file changes
+#[async_backtrace::framed]
+async fn reload(resolver: Arc<Resolver>, cert: PathBuf, key: PathBuf) {
+    if *RELOAD_INTERVAL == 0 {
+        tracing::info!("reloading the TLS certificate is disabled");
+        return;
+    }
+
+    let mut last = modified(&[&cert, &key]);
+    let mut interval = tokio::time::interval(Duration::from_secs(*RELOAD_INTERVAL));
+    loop {
+        interval.tick().await;
+        let current = modified(&[&cert, &key]);
+        if current == last {
+            continue;
+        }
+        match certified_key(&cert, &key) {
+            Ok(certified) => {
+                *resolver.key.write().unwrap() = Arc::new(certified);
+                last = current;
+                tracing::info!("reloaded TLS certificate {}", cert.display());
+            }
+            // the files may be replaced one after the other, so try again next time
+            Err(e) => tracing::warn!("failed to reload TLS certificate: {}", e),
+        }
+    }
+}
+
+/// Build the TLS configuration, or `None` if TLS is off
+pub fn config() -> Result<Option<Arc<ServerConfig>>, TlsError> {
+    let (cert, key) = match (CERT.as_ref(), KEY.as_ref()) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+
+    let resolver = Arc::new(Resolver {
+        key: RwLock::new(Arc::new(certified_key(cert, key)?)),
+    });
+    tokio::spawn(reload(resolver.clone(), cert.clone(), key.clone()));
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let mut config = match CLIENT_CA.as_ref() {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in certificates(ca)? {
+                roots.add(&certificate)?;
+            }
+            let verifier = match *CLIENT_REQUIRED {
+                true => AllowAnyAuthenticatedClient::new(roots).boxed(),
+                false => AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+            };
+            builder
+                .with_client_cert_verifier(verifier)
+                .with_cert_resolver(resolver)
+        }
+        None => builder.with_no_client_auth().with_cert_resolver(resolver),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(Some(Arc::new(config)))
+}
+
+/// Normalize a distinguished name so differences in spacing don't matter
+fn normalize(subject: &str) -> String {
+    subject
+        .split(',')
+        .map(|part| {
+            part.split_once('=')
+                .map(|(k, v)| format!("{}={}", k.trim(), v.trim()))
+                .unwrap_or_else(|| part.trim().to_string())
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse a `TLS_CLIENT_PRINCIPALS` file into principals by normalized subject
+pub fn parse_principals(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .map(|(principal, subject)| (normalize(subject), principal.to_string()))
+        .collect()
+}
+
+/// Work out who a client certificate identifies
+fn identify(der: &[u8]) -> Option<ClientCertificate> {
+    let (_, certificate) = X509Certificate::from_der(der).ok()?;
+    let subject = certificate.subject().to_string();
+
+    let mapped = CLIENT_PRINCIPALS.as_ref().and_then(|path| {
+        let principals = parse_principals(&std::fs::read_to_string(path).ok()?);
+        principals.get(&normalize(&subject)).cloned()
+    });
+    let principal = match mapped {
+        Some(principal) => principal,
+        None => certificate
+            .subject()
+            .iter_common_name()
+            .next()?
+            .as_str()
+            .ok()?
+            .to_string(),
+    };
+
+    Some(ClientCertificate { subject, principal })
+}
+
+/// Serve `app` over TLS, adding the remote address and the client certificate, if any, to every request. Once
+/// `shutdown` resolves, no more connections are accepted and those open are closed after their requests.
+pub async fn serve<S>(
+    addr: SocketAddr,
+    app: S,
+    config: Arc<ServerConfig>,
+    shutdown: impl Future<Output = ()>,
+) where
+    S: Service<Request<Body>, Response = Response, Error = std::convert::Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let acceptor = TlsAcceptor::from(config);
+    tracing::info!("serving HTTPS on {}", addr);
+
+    // connections are told to finish through `stopping`, and hold a sender of `open` until they have
+    let (stopping, _) = watch::channel(false);
+    let (open, mut closed) = mpsc::channel::<()>(1);
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, remote) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::error!("failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let mut stopping = stopping.subscribe();
+        let open = open.clone();
+        tokio::spawn(async move {
+            let _open = open;
+            // a client that never finishes the handshake would otherwise keep the server from stopping
+            let handshake = tokio::select! {
+                handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)) => handshake,
+                _ = stopping.changed() => return,
+            };
+            let stream = match handshake {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", remote, e);
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!("TLS handshake with {} timed out", remote);
+                    return;
+                }
+            };
+            let client = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .and_then(|certificate| identify(&certificate.0));
+
+            let service = tower::service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(ConnectInfo(remote));
+                if let Some(client) = &client {
+                    req.extensions_mut().insert(client.clone());
+                }
+                app.clone().oneshot(req)
+            });
+            let connection = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades();
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = &mut connection => result,
+                _ = stopping.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                tracing::debug!("connection with {} failed: {}", remote, e);
+            }
+        });
+    }
+
+    stopping.send_replace(true);
+    drop(open);
+    closed.recv().await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{normalize, parse_principals};
+
+    #[test]
+    fn test_parse_principals() {
+        let principals = parse_principals(
+            "# client certificates\nrobot$ci CN=ci.example.com, O=Example\n\nalice  CN = Alice,O=Example\n",
+        );
+        assert_eq!(
+            principals.get(&normalize("CN=ci.example.com,O=Example")),
+            Some(&"robot$ci".to_string())
+        );
+        assert_eq!(
+            principals.get(&normalize("CN=Alice, O=Example")),
+            Some(&"alice".to_string())
+        );
+    }
+}
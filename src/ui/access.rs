@@ -0,0 +1,156 @@
+use axum::{http::StatusCode, response::IntoResponse, Extension, Form};
+use serde::Deserialize;
+use tera::{Context, Tera};
+
+use crate::auth::policy::Action;
+use crate::db;
+use crate::events::{self, RequestInfo};
+
+#[derive(Debug, Deserialize)]
+pub struct Grant {
+    subject: String,
+    repository: String,
+    action: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Revoke {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Member {
+    group: String,
+    user: String,
+}
+
+async fn render(tera: &Tera, mut context: Context, status: StatusCode) -> impl IntoResponse {
+    context.insert("permissions", &db::access::permissions().await.unwrap());
+    context.insert("members", &db::access::members().await.unwrap());
+
+    (
+        status,
+        [("Content-Type", "text/html; charset=utf-8")],
+        tera.render("access.html", &context).unwrap(),
+    )
+}
+
+#[async_backtrace::framed]
+pub async fn index(Extension(tera): Extension<Tera>) -> impl IntoResponse {
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+fn parse(form: &Grant) -> Result<(&str, &str, &str), String> {
+    let subject = form.subject.trim();
+    let repository = form.repository.trim();
+    if subject.is_empty() || subject == "@" {
+        return Err("a user, @group or * is required".to_string());
+    }
+    if repository.is_empty() {
+        return Err("a repository pattern is required".to_string());
+    }
+    if form.action == "*" || Action::parse(&form.action).is_none() {
+        return Err(format!("unknown action {}", form.action));
+    }
+
+    Ok((subject, repository, &form.action))
+}
+
+#[async_backtrace::framed]
+pub async fn grant(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<Grant>,
+) -> impl IntoResponse {
+    let (subject, repository, action) = match parse(&form) {
+        Ok(permission) => permission,
+        Err(e) => {
+            let mut context = Context::new();
+            context.insert("error", &e);
+            return render(&tera, context, StatusCode::BAD_REQUEST).await;
+        }
+    };
+
+    db::access::grant(subject, repository, action)
+        .await
+        .unwrap();
+    tracing::info!("granted {} on {} to {}", action, repository, subject);
+    events::audit(
+        &request,
+        "permission.grant",
+        Some(repository),
+        Some(&format!("{}:{}", subject, action)),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn revoke(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<Revoke>,
+) -> impl IntoResponse {
+    db::access::revoke(form.id).await.unwrap();
+    events::audit(
+        &request,
+        "permission.revoke",
+        None,
+        Some(&form.id.to_string()),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn add_member(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<Member>,
+) -> impl IntoResponse {
+    let group = form.group.trim().trim_start_matches('@');
+    let user = form.user.trim();
+    if group.is_empty() || user.is_empty() {
+        let mut context = Context::new();
+        context.insert("error", "a group and a user are required");
+        return render(&tera, context, StatusCode::BAD_REQUEST).await;
+    }
+
+    db::access::add_member(group, user).await.unwrap();
+    tracing::info!("added {} to group {}", user, group);
+    events::audit(
+        &request,
+        "group.add",
+        None,
+        Some(&format!("{}:{}", group, user)),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn remove_member(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<Member>,
+) -> impl IntoResponse {
+    db::access::remove_member(&form.group, &form.user)
+        .await
+        .unwrap();
+    events::audit(
+        &request,
+        "group.remove",
+        None,
+        Some(&format!("{}:{}", form.group, form.user)),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
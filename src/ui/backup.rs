@@ -0,0 +1,49 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::backup;
+use crate::events::{self, RequestInfo};
+
+/// The backups in the backup directory, newest first
+#[async_backtrace::framed]
+pub async fn list() -> Response {
+    match backup::list() {
+        Ok(backups) => Json(json!({ "backups": backups })).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": e.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Back up the database into the backup directory, deleting the backups beyond `backup.keep`
+#[async_backtrace::framed]
+pub async fn create(request: RequestInfo) -> Response {
+    let (created, snapshot) = match backup::create_in_directory().await {
+        Ok(created) => created,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": e.to_string() })),
+            )
+                .into_response()
+        }
+    };
+    events::audit(&request, "registry.backup", None, Some(&created.name), None).await;
+
+    (
+        StatusCode::CREATED,
+        Json(json!({
+            "name": created.name,
+            "size": created.size,
+            "schema_version": snapshot.schema_version,
+            "tables": snapshot.tables,
+        })),
+    )
+        .into_response()
+}
@@ -0,0 +1,143 @@
+use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension, Json};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tera::{Context, Tera};
+
+use crate::db::{self, EventFilter};
+use crate::retention;
+
+/// Events listed when no limit is given
+const DEFAULT_LIMIT: u32 = 100;
+
+/// The most events listed at once
+const MAX_LIMIT: u32 = 1000;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct EventQuery {
+    action: Option<String>,
+    repository: Option<String>,
+    principal: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    before: Option<String>,
+    limit: Option<String>,
+}
+
+/// Parse a point in time, either RFC 3339 or a duration before now such as `24h`
+fn parse_time(time: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    retention::parse_duration(time)
+        .map(|seconds| Utc::now() - Duration::seconds(seconds))
+        .ok_or_else(|| {
+            format!(
+                "invalid time {}, use RFC 3339 or a duration such as 24h",
+                time
+            )
+        })
+}
+
+fn parse(query: &EventQuery) -> Result<EventFilter, String> {
+    let text = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    };
+    let number = |name: &str, value: &Option<String>| {
+        text(value)
+            .map(|v| {
+                v.parse::<i64>()
+                    .map_err(|_| format!("invalid {} {}", name, v))
+            })
+            .transpose()
+    };
+
+    Ok(EventFilter {
+        action: text(&query.action),
+        repository: text(&query.repository),
+        principal: text(&query.principal),
+        since: text(&query.since).map(|s| parse_time(&s)).transpose()?,
+        until: text(&query.until).map(|u| parse_time(&u)).transpose()?,
+        before: number("event id", &query.before)?,
+        limit: number("limit", &query.limit)?
+            .map_or(DEFAULT_LIMIT, |l| l.clamp(1, MAX_LIMIT as i64) as u32),
+    })
+}
+
+/// List audit events as JSON
+#[async_backtrace::framed]
+pub async fn list(Query(query): Query<EventQuery>) -> impl IntoResponse {
+    let filter = match parse(&query) {
+        Ok(filter) => filter,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))),
+    };
+
+    let events = db::events::list(&filter).await.unwrap();
+    (StatusCode::OK, Json(json!({ "events": events })))
+}
+
+#[async_backtrace::framed]
+pub async fn index(
+    Extension(tera): Extension<Tera>,
+    Query(query): Query<EventQuery>,
+) -> impl IntoResponse {
+    let mut context = Context::new();
+    let status = match parse(&query) {
+        Ok(filter) => {
+            let events = db::events::list(&filter).await.unwrap();
+            if events.len() == filter.limit as usize {
+                context.insert("older", &events.last().map(|e| e.id));
+            }
+            context.insert("events", &events);
+            StatusCode::OK
+        }
+        Err(e) => {
+            context.insert("error", &e);
+            context.insert("events", &Vec::<db::AuditEvent>::new());
+            StatusCode::BAD_REQUEST
+        }
+    };
+    context.insert("query", &query);
+
+    (
+        status,
+        [("Content-Type", "text/html; charset=utf-8")],
+        tera.render("events.html", &context).unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use super::{parse, parse_time, EventQuery};
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time("2023-04-01T12:00:00Z").unwrap().to_rfc3339(),
+            "2023-04-01T12:00:00+00:00"
+        );
+        let day_ago = parse_time("24h").unwrap();
+        assert_eq!((Utc::now() - day_ago).num_hours(), 24);
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let filter = parse(&EventQuery {
+            action: Some("push".to_string()),
+            repository: Some(" ".to_string()),
+            limit: Some("5000".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(filter.action.as_deref(), Some("push"));
+        assert_eq!(filter.repository, None);
+        assert_eq!(filter.limit, 1000);
+    }
+}
@@ -3,23 +3,39 @@ use std::collections::{HashMap, HashSet};
 use axum::{
     extract::Path,
     http::{HeaderMap, StatusCode},
-    response::IntoResponse,
-    Extension,
+    response::{IntoResponse, Redirect, Response},
+    Extension, Form,
 };
 use bytesize::ByteSize;
 use chrono::{DateTime, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tera::{Context, Tera};
 
-use crate::db;
+use crate::auth::policy::{Action, Policy};
+use crate::db::{self, TagHistory};
+use crate::events::RequestInfo;
+
+pub mod access;
+pub mod backup;
+pub mod events;
+pub mod oci;
+pub mod protection;
+pub mod quotas;
+pub mod replication;
+pub mod retention;
+pub mod robots;
+pub mod users;
+pub mod webhooks;
 
 #[async_backtrace::framed]
-pub async fn index(Extension(tera): Extension<Tera>) -> impl IntoResponse {
+pub async fn index(Extension(tera): Extension<Tera>, request: RequestInfo) -> impl IntoResponse {
+    let policy = Policy::load().await.unwrap();
     let repos: Vec<String> = db::repositories::list()
         .await
         .unwrap()
         .into_iter()
         .map(|r| r.name)
+        .filter(|r| policy.allows(request.principal.as_deref(), r, Action::Pull))
         .collect();
 
     let categories = {
@@ -62,17 +78,35 @@ struct TagGrouping {
     updated: DateTime<Utc>,
 }
 
+#[derive(Debug, Serialize)]
+struct HistoryEntry {
+    #[serde(flatten)]
+    entry: TagHistory,
+    can_roll_back: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Rollback {
+    repository: String,
+    tag: String,
+    digest: String,
+}
+
 #[async_backtrace::framed]
 pub async fn repo(
     Path(name): Path<String>,
     Extension(tera): Extension<Tera>,
     headers: HeaderMap,
+    request: RequestInfo,
 ) -> impl IntoResponse {
+    let policy = Policy::load().await.unwrap();
+    let user = request.principal.as_deref();
     let repos: Vec<String> = db::repositories::list()
         .await
         .unwrap()
         .into_iter()
         .map(|r| r.name)
+        .filter(|r| policy.allows(user, r, Action::Pull))
         .filter(|r| r.contains('/') && r.starts_with(&name))
         .map(|r| r.replace(&name, "").trim_start_matches('/').to_string())
         .collect();
@@ -88,7 +122,12 @@ pub async fn repo(
         .map(|r| r.to_string())
         .collect::<HashSet<String>>();
 
-    let tags = db::tags::list(&name).await.unwrap();
+    // the page of a namespace lists the repositories below it, even if the namespace can't be pulled
+    let readable = policy.allows(user, &name, Action::Pull);
+    let tags = match readable {
+        true => db::tags::list(&name).await.unwrap(),
+        false => Vec::new(),
+    };
     let mut groupings: HashMap<String, TagGrouping> = HashMap::new();
     for tag in tags {
         match groupings.get_mut(&tag.manifest) {
@@ -135,11 +174,29 @@ pub async fn repo(
         other => other,
     });
 
+    let mut history = Vec::new();
+    let entries = match readable {
+        true => db::tags::history(&name, 25).await.unwrap(),
+        false => Vec::new(),
+    };
+    for entry in entries {
+        // a tag can only be rolled back to a manifest that has not been garbage collected yet
+        let can_roll_back = match &entry.old_manifest {
+            Some(old) => db::manifests::get(&name, old).await.is_ok(),
+            None => false,
+        };
+        history.push(HistoryEntry {
+            entry,
+            can_roll_back,
+        });
+    }
+
     let mut context = Context::new();
     context.insert("name", &name.trim_matches('/'));
     context.insert("categories", &categories);
     context.insert("repos", &repos);
     context.insert("groupings", &groupings);
+    context.insert("history", &history);
     context.insert("host", headers.get("host").unwrap().to_str().unwrap());
 
     (
@@ -150,7 +207,9 @@ pub async fn repo(
 }
 
 pub async fn admin(Extension(tera): Extension<Tera>) -> impl IntoResponse {
-    let size = std::fs::metadata("registry.db").unwrap().len();
+    let size = std::fs::metadata(&crate::config::get().database.path)
+        .unwrap()
+        .len();
     let size = ByteSize::b(size).to_string_as(true);
 
     let mut context = Context::new();
@@ -162,13 +221,18 @@ pub async fn admin(Extension(tera): Extension<Tera>) -> impl IntoResponse {
     )
 }
 
-pub async fn cleanup(Extension(tera): Extension<Tera>) -> impl IntoResponse {
-    let old_size = std::fs::metadata("registry.db").unwrap().len();
+pub async fn cleanup(Extension(tera): Extension<Tera>, request: RequestInfo) -> impl IntoResponse {
+    let old_size = std::fs::metadata(&crate::config::get().database.path)
+        .unwrap()
+        .len();
     let old_size = ByteSize::b(old_size).to_string_as(true);
 
     db::cleanup().await.unwrap();
+    crate::events::audit(&request, "registry.cleanup", None, None, None).await;
 
-    let size = std::fs::metadata("registry.db").unwrap().len();
+    let size = std::fs::metadata(&crate::config::get().database.path)
+        .unwrap()
+        .len();
     let size = ByteSize::b(size).to_string_as(true);
 
     let mut context = Context::new();
@@ -180,3 +244,81 @@ pub async fn cleanup(Extension(tera): Extension<Tera>) -> impl IntoResponse {
         tera.render("admin.html", &context).unwrap(),
     )
 }
+
+/// Point a tag back at a manifest it referenced before, within the protection rules
+#[async_backtrace::framed]
+pub async fn rollback(request: RequestInfo, Form(form): Form<Rollback>) -> Response {
+    let history = match db::tags::history_of(&form.repository, &form.tag).await {
+        Ok(history) => history,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let referenced = history.iter().any(|h| {
+        h.old_manifest.as_deref() == Some(&form.digest)
+            || h.new_manifest.as_deref() == Some(&form.digest)
+    });
+    if !referenced {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "tag {}:{} never referenced manifest {}",
+                form.repository, form.tag, form.digest
+            ),
+        )
+            .into_response();
+    }
+    if db::manifests::get(&form.repository, &form.digest)
+        .await
+        .is_err()
+    {
+        return (
+            StatusCode::NOT_FOUND,
+            format!(
+                "manifest {} no longer exists in repository {}",
+                form.digest, form.repository
+            ),
+        )
+            .into_response();
+    }
+    match crate::protection::check_overwrite(&form.repository, &form.tag, &form.digest).await {
+        Ok(None) => {}
+        Ok(Some(violation)) => {
+            return (
+                StatusCode::FORBIDDEN,
+                format!(
+                    "{}, an administrator can still move it from the tag protection page",
+                    violation
+                ),
+            )
+                .into_response()
+        }
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+
+    if let Err(e) = db::tags::save(
+        &form.repository,
+        &form.tag,
+        &form.digest,
+        Some(&request.actor),
+    )
+    .await
+    {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    crate::replication::enqueue(&form.repository, &form.tag, Some(&form.digest)).await;
+    crate::events::audit(
+        &request,
+        "tag.rollback",
+        Some(&form.repository),
+        Some(&form.tag),
+        Some(&form.digest),
+    )
+    .await;
+    tracing::info!(
+        "rolled back tag {}:{} to {}",
+        form.repository,
+        form.tag,
+        form.digest
+    );
+
+    Redirect::to(&format!("/{}", form.repository)).into_response()
+}
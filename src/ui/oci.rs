@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use axum::{
+    body::StreamBody,
+    extract::{BodyStream, Query, RawQuery},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::{StreamExt, TryStreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+
+use crate::events::{self, RequestInfo};
+use crate::oci::{self, OciError};
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    repository: Option<String>,
+}
+
+fn error(e: OciError) -> Response {
+    let status = match e {
+        OciError::NotFound(_) => StatusCode::NOT_FOUND,
+        OciError::Denied(_) => StatusCode::FORBIDDEN,
+        OciError::Invalid(_) | OciError::Json(..) => StatusCode::BAD_REQUEST,
+        OciError::Io(_) | OciError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, Json(json!({ "error": e.to_string() }))).into_response()
+}
+
+/// Download images as an OCI image layout tarball, given as `image=repository[:tag]`, repeated
+#[async_backtrace::framed]
+pub async fn export(request: RequestInfo, RawQuery(query): RawQuery) -> Response {
+    let images = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key == "image")
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+    if images.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "no image given, use ?image=repository[:tag]" })),
+        )
+            .into_response();
+    }
+
+    let (layout, exported) = match oci::export(&images).await {
+        Ok(exported) => exported,
+        Err(e) => return error(e),
+    };
+    for image in &exported {
+        events::audit(
+            &request,
+            "image.export",
+            Some(&image.repository),
+            image.tag.as_deref(),
+            Some(&image.digest),
+        )
+        .await;
+    }
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-tar"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"images.tar\"",
+            ),
+        ],
+        // blobs are read one at a time as the tarball is sent, a failure cuts it short
+        StreamBody::new(
+            layout
+                .tar()
+                .inspect_err(|e| tracing::error!("failed to export images: {}", e)),
+        ),
+    )
+        .into_response()
+}
+
+/// Write a request body to `path` as it arrives, up to the body limit
+#[async_backtrace::framed]
+async fn receive(mut body: BodyStream, path: &Path) -> Result<(), Response> {
+    let limit = crate::config::get().server.body_limit.as_u64();
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .map_err(|e| error(e.into()))?;
+    let mut received = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| error(OciError::Invalid(e.to_string())))?;
+        received += chunk.len() as u64;
+        if received > limit {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!({ "error": "the tarball is larger than the body limit" })),
+            )
+                .into_response());
+        }
+        file.write_all(&chunk).await.map_err(|e| error(e.into()))?;
+    }
+    file.flush().await.map_err(|e| error(e.into()))
+}
+
+/// Load an OCI image layout or `docker save` tarball, optionally into `?repository=`
+#[async_backtrace::framed]
+pub async fn import(
+    request: RequestInfo,
+    Query(query): Query<ImportQuery>,
+    body: BodyStream,
+) -> Response {
+    let repository = query
+        .repository
+        .as_deref()
+        .map(str::trim)
+        .filter(|r| !r.is_empty());
+
+    // the tarball is kept on disk rather than in memory, and unpacked from there
+    let upload = oci::scratch("import");
+    let layout = match receive(body, &upload).await {
+        Ok(()) => oci::Layout::load(upload.clone()).await,
+        Err(response) => {
+            std::fs::remove_file(&upload).ok();
+            return response;
+        }
+    };
+    std::fs::remove_file(&upload).ok();
+    let layout = match layout {
+        Ok(layout) => layout,
+        Err(e) => return error(OciError::Invalid(format!("invalid tarball: {}", e))),
+    };
+    let imported = match oci::import(layout, repository, &request.actor).await {
+        Ok(imported) => imported,
+        Err(e) => return error(e),
+    };
+    for image in &imported {
+        events::audit(
+            &request,
+            "image.import",
+            Some(&image.repository),
+            image.tag.as_deref(),
+            Some(&image.digest),
+        )
+        .await;
+    }
+
+    (StatusCode::CREATED, Json(json!({ "imported": imported }))).into_response()
+}
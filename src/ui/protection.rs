@@ -0,0 +1,217 @@
+use axum::{http::StatusCode, response::IntoResponse, Extension, Form};
+use serde::Deserialize;
+use tera::{Context, Tera};
+
+use crate::events::{self, RequestInfo};
+use crate::{db, replication};
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRule {
+    repository: String,
+    tag: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteRule {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetImmutable {
+    repository: String,
+    immutable: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForceDelete {
+    repository: String,
+    tag: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForceMove {
+    repository: String,
+    tag: String,
+    digest: String,
+}
+
+async fn render(tera: &Tera, mut context: Context, status: StatusCode) -> impl IntoResponse {
+    context.insert("rules", &db::protections::list().await.unwrap());
+    context.insert("repositories", &db::repositories::list().await.unwrap());
+
+    (
+        status,
+        [("Content-Type", "text/html; charset=utf-8")],
+        tera.render("protection.html", &context).unwrap(),
+    )
+}
+
+fn message(message: &str, status: StatusCode) -> (Context, StatusCode) {
+    let mut context = Context::new();
+    match status.is_success() {
+        true => context.insert("message", message),
+        false => context.insert("error", message),
+    }
+    (context, status)
+}
+
+#[async_backtrace::framed]
+pub async fn index(Extension(tera): Extension<Tera>) -> impl IntoResponse {
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn create(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<CreateRule>,
+) -> impl IntoResponse {
+    let (repository, tag) = (form.repository.trim(), form.tag.trim());
+    let (context, status) = if repository.is_empty() || tag.is_empty() {
+        message(
+            "a repository pattern and a tag pattern are required",
+            StatusCode::BAD_REQUEST,
+        )
+    } else if let Err(e) = crate::pattern::tag_regex(tag) {
+        message(
+            &format!("invalid pattern {}: {}", tag, e),
+            StatusCode::BAD_REQUEST,
+        )
+    } else {
+        db::protections::save(repository, tag).await.unwrap();
+        tracing::info!("protected tags {} in {}", tag, repository);
+        events::audit(
+            &request,
+            "protection.create",
+            None,
+            Some(&format!("{}:{}", repository, tag)),
+            None,
+        )
+        .await;
+        (Context::new(), StatusCode::OK)
+    };
+
+    render(&tera, context, status).await
+}
+
+#[async_backtrace::framed]
+pub async fn delete(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<DeleteRule>,
+) -> impl IntoResponse {
+    db::protections::delete(form.id).await.unwrap();
+    events::audit(
+        &request,
+        "protection.delete",
+        None,
+        Some(&form.id.to_string()),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn immutable(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<SetImmutable>,
+) -> impl IntoResponse {
+    db::repositories::set_immutable(&form.repository, form.immutable.is_some())
+        .await
+        .unwrap();
+    let action = match form.immutable.is_some() {
+        true => "repository.immutable",
+        false => "repository.mutable",
+    };
+    events::audit(&request, action, Some(&form.repository), None, None).await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+/// Delete a tag regardless of the protection rules
+#[async_backtrace::framed]
+pub async fn force_delete(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<ForceDelete>,
+) -> impl IntoResponse {
+    let (context, status) = match db::tags::get_manifest(&form.repository, &form.tag).await {
+        Ok(digest) => {
+            db::tags::delete(&form.repository, &form.tag, Some(&request.actor))
+                .await
+                .unwrap();
+            replication::enqueue(&form.repository, &form.tag, None).await;
+            events::audit(
+                &request,
+                "tag.force_delete",
+                Some(&form.repository),
+                Some(&form.tag),
+                Some(&digest),
+            )
+            .await;
+            tracing::warn!(
+                "force deleted tag {}:{} from the admin ui",
+                form.repository,
+                form.tag
+            );
+            message(
+                &format!("deleted {}:{}", form.repository, form.tag),
+                StatusCode::OK,
+            )
+        }
+        Err(_) => message(
+            &format!("tag {}:{} does not exist", form.repository, form.tag),
+            StatusCode::NOT_FOUND,
+        ),
+    };
+
+    render(&tera, context, status).await
+}
+
+/// Point a tag at a different manifest regardless of immutability and the protection rules
+#[async_backtrace::framed]
+pub async fn force_move(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<ForceMove>,
+) -> impl IntoResponse {
+    let digest = form.digest.trim();
+    let (context, status) = match db::manifests::get(&form.repository, digest).await {
+        Ok(_) => {
+            db::tags::save(&form.repository, &form.tag, digest, Some(&request.actor))
+                .await
+                .unwrap();
+            replication::enqueue(&form.repository, &form.tag, Some(digest)).await;
+            events::audit(
+                &request,
+                "tag.force_move",
+                Some(&form.repository),
+                Some(&form.tag),
+                Some(digest),
+            )
+            .await;
+            tracing::warn!(
+                "force moved tag {}:{} to {} from the admin ui",
+                form.repository,
+                form.tag,
+                digest
+            );
+            message(
+                &format!("moved {}:{} to {}", form.repository, form.tag, digest),
+                StatusCode::OK,
+            )
+        }
+        Err(_) => message(
+            &format!(
+                "manifest {} does not exist in repository {}",
+                digest, form.repository
+            ),
+            StatusCode::NOT_FOUND,
+        ),
+    };
+
+    render(&tera, context, status).await
+}
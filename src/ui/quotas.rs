@@ -0,0 +1,136 @@
+use axum::{http::StatusCode, response::IntoResponse, Extension, Form};
+use bytesize::ByteSize;
+use serde::{Deserialize, Serialize};
+use tera::{Context, Tera};
+
+use crate::db::{self, Quota};
+use crate::events::{self, RequestInfo};
+use crate::quota::{self, QuotaUsage};
+
+#[derive(Debug, Serialize)]
+struct QuotaView {
+    #[serde(flatten)]
+    quota: QuotaUsage,
+    max_bytes: Option<String>,
+    bytes: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateQuota {
+    repository: String,
+    max_bytes: String,
+    max_tags: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteQuota {
+    id: i64,
+}
+
+async fn render(tera: &Tera, mut context: Context, status: StatusCode) -> impl IntoResponse {
+    let quotas = quota::list()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|q| QuotaView {
+            max_bytes: q
+                .quota
+                .max_bytes
+                .map(|b| ByteSize::b(b as u64).to_string_as(true)),
+            bytes: ByteSize::b(q.usage.bytes as u64).to_string_as(true),
+            quota: q,
+        })
+        .collect::<Vec<QuotaView>>();
+    context.insert("quotas", &quotas);
+
+    (
+        status,
+        [("Content-Type", "text/html; charset=utf-8")],
+        tera.render("quotas.html", &context).unwrap(),
+    )
+}
+
+#[async_backtrace::framed]
+pub async fn index(Extension(tera): Extension<Tera>) -> impl IntoResponse {
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+fn parse(form: CreateQuota) -> Result<Quota, String> {
+    let repository = form.repository.trim();
+    if repository.is_empty() {
+        return Err("a repository pattern is required".to_string());
+    }
+    let max_bytes = Some(form.max_bytes.trim())
+        .filter(|b| !b.is_empty())
+        .map(|b| {
+            b.parse::<ByteSize>()
+                .map(|b| b.as_u64() as i64)
+                .map_err(|_| format!("invalid size {}, use something like 200 GB", b))
+        })
+        .transpose()?;
+    let max_tags = Some(form.max_tags.trim())
+        .filter(|t| !t.is_empty())
+        .map(|t| {
+            t.parse::<i64>()
+                .map_err(|_| format!("invalid number of tags {}", t))
+        })
+        .transpose()?;
+    if max_bytes.is_none() && max_tags.is_none() {
+        return Err("a quota needs a size limit, a tag limit or both".to_string());
+    }
+
+    Ok(Quota {
+        id: 0,
+        repository: repository.to_string(),
+        max_bytes,
+        max_tags,
+    })
+}
+
+#[async_backtrace::framed]
+pub async fn create(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<CreateQuota>,
+) -> impl IntoResponse {
+    let quota = match parse(form) {
+        Ok(quota) => quota,
+        Err(e) => {
+            let mut context = Context::new();
+            context.insert("error", &e);
+            return render(&tera, context, StatusCode::BAD_REQUEST).await;
+        }
+    };
+
+    db::quotas::save(&quota).await.unwrap();
+    tracing::info!("created quota for {}", quota.repository);
+    events::audit(
+        &request,
+        "quota.create",
+        None,
+        Some(&quota.repository),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn delete(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<DeleteQuota>,
+) -> impl IntoResponse {
+    db::quotas::delete(form.id).await.unwrap();
+    events::audit(
+        &request,
+        "quota.delete",
+        None,
+        Some(&form.id.to_string()),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Form};
+use serde::{Deserialize, Serialize};
+use tera::{Context, Tera};
+
+use crate::db::{self, ReplicationRule};
+use crate::events::{self, RequestInfo};
+use crate::replication;
+
+#[derive(Debug, Serialize)]
+struct RuleView {
+    #[serde(flatten)]
+    rule: ReplicationRule,
+    pending: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRule {
+    repository: String,
+    tag_pattern: String,
+    target: String,
+    username: String,
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RuleId {
+    id: i64,
+}
+
+async fn render(tera: &Tera, mut context: Context, status: StatusCode) -> impl IntoResponse {
+    let pending = db::replication::pending()
+        .await
+        .unwrap()
+        .into_iter()
+        .collect::<HashMap<i64, i64>>();
+    let rules = db::replication::list_rules()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|rule| RuleView {
+            pending: pending.get(&rule.id).copied().unwrap_or_default(),
+            rule,
+        })
+        .collect::<Vec<RuleView>>();
+    context.insert("rules", &rules);
+    context.insert("tasks", &db::replication::tasks(50).await.unwrap());
+
+    (
+        status,
+        [("Content-Type", "text/html; charset=utf-8")],
+        tera.render("replication.html", &context).unwrap(),
+    )
+}
+
+#[async_backtrace::framed]
+pub async fn index(Extension(tera): Extension<Tera>) -> impl IntoResponse {
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+fn parse(form: CreateRule) -> Result<ReplicationRule, String> {
+    let repository = form.repository.trim();
+    if repository.is_empty() {
+        return Err("a repository pattern is required".to_string());
+    }
+    let tag_pattern = Some(form.tag_pattern.trim())
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            crate::pattern::tag_regex(p)
+                .map(|_| p.to_string())
+                .map_err(|e| format!("invalid pattern {}: {}", p, e))
+        })
+        .transpose()?;
+    let target = form.target.trim();
+    match reqwest::Url::parse(target) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        _ => {
+            return Err(format!(
+                "invalid target {}, use something like https://registry.example.com",
+                target
+            ))
+        }
+    }
+    let username = Some(form.username.trim())
+        .filter(|u| !u.is_empty())
+        .map(str::to_string);
+
+    Ok(ReplicationRule {
+        id: 0,
+        repository: repository.to_string(),
+        tag_pattern,
+        target: target.to_string(),
+        password: username.as_ref().map(|_| form.password),
+        username,
+        last_replicated: None,
+        last_error: None,
+    })
+}
+
+#[async_backtrace::framed]
+pub async fn create(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<CreateRule>,
+) -> impl IntoResponse {
+    let rule = match parse(form) {
+        Ok(rule) => rule,
+        Err(e) => {
+            let mut context = Context::new();
+            context.insert("error", &e);
+            return render(&tera, context, StatusCode::BAD_REQUEST).await;
+        }
+    };
+
+    db::replication::save_rule(&rule).await.unwrap();
+    tracing::info!(
+        "created replication rule for {} to {}",
+        rule.repository,
+        rule.target
+    );
+    events::audit(
+        &request,
+        "replication.create",
+        None,
+        Some(&format!("{} to {}", rule.repository, rule.target)),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn delete(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<RuleId>,
+) -> impl IntoResponse {
+    db::replication::delete_rule(form.id).await.unwrap();
+    events::audit(
+        &request,
+        "replication.delete",
+        None,
+        Some(&form.id.to_string()),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+/// Queue all existing tags matching a rule
+#[async_backtrace::framed]
+pub async fn sync(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<RuleId>,
+) -> impl IntoResponse {
+    let mut context = Context::new();
+    let rules = db::replication::list_rules().await.unwrap();
+    let status = match rules.iter().find(|r| r.id == form.id) {
+        Some(rule) => {
+            let queued = replication::sync(rule).await.unwrap();
+            events::audit(
+                &request,
+                "replication.sync",
+                None,
+                Some(&form.id.to_string()),
+                None,
+            )
+            .await;
+            context.insert(
+                "message",
+                &format!("queued {} tags for replication to {}", queued, rule.target),
+            );
+            StatusCode::OK
+        }
+        None => {
+            context.insert(
+                "error",
+                &format!("replication rule {} does not exist", form.id),
+            );
+            StatusCode::NOT_FOUND
+        }
+    };
+
+    render(&tera, context, status).await
+}
@@ -0,0 +1,158 @@
+use axum::{http::StatusCode, response::IntoResponse, Extension, Form};
+use serde::{Deserialize, Serialize};
+use tera::{Context, Tera};
+
+use crate::db::{self, RetentionPolicy};
+use crate::events::{self, RequestInfo};
+use crate::retention;
+
+#[derive(Debug, Serialize)]
+struct PolicyView {
+    #[serde(flatten)]
+    policy: RetentionPolicy,
+    max_age: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePolicy {
+    repository: String,
+    keep_latest: String,
+    max_age: String,
+    tag_pattern: String,
+    exempt: String,
+    enforce: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeletePolicy {
+    id: i64,
+}
+
+async fn render(tera: &Tera, mut context: Context, status: StatusCode) -> impl IntoResponse {
+    let policies = db::retention::list()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|p| PolicyView {
+            max_age: p.max_age.map(retention::format_duration),
+            policy: p,
+        })
+        .collect::<Vec<PolicyView>>();
+    if !context.contains_key("deletions") {
+        context.insert("deletions", &retention::plan().await.unwrap());
+    }
+    context.insert("policies", &policies);
+
+    (
+        status,
+        [("Content-Type", "text/html; charset=utf-8")],
+        tera.render("retention.html", &context).unwrap(),
+    )
+}
+
+#[async_backtrace::framed]
+pub async fn index(Extension(tera): Extension<Tera>) -> impl IntoResponse {
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+fn parse(form: CreatePolicy) -> Result<RetentionPolicy, String> {
+    let optional = |s: String| {
+        let s = s.trim().to_string();
+        (!s.is_empty()).then_some(s)
+    };
+
+    let repository = optional(form.repository).ok_or("a repository pattern is required")?;
+    let keep_latest = optional(form.keep_latest)
+        .map(|n| n.parse::<i64>().ok().filter(|n| *n >= 0))
+        .map(|n| n.ok_or("the number of tags to keep must be a positive number"))
+        .transpose()?;
+    let max_age = optional(form.max_age)
+        .map(|d| retention::parse_duration(&d))
+        .map(|d| d.ok_or("the maximum age must be a duration like 12h, 30d or 2w"))
+        .transpose()?;
+    let tag_pattern = optional(form.tag_pattern);
+    let exempt = optional(form.exempt);
+
+    if keep_latest.is_none() && max_age.is_none() && tag_pattern.is_none() {
+        return Err(
+            "a policy needs at least one of a number of tags to keep, a maximum age or a tag pattern"
+                .to_string(),
+        );
+    }
+    for p in tag_pattern
+        .iter()
+        .chain(exempt.iter())
+        .flat_map(|p| p.split_whitespace())
+    {
+        crate::pattern::tag_regex(p).map_err(|e| format!("invalid pattern {}: {}", p, e))?;
+    }
+
+    Ok(RetentionPolicy {
+        id: 0,
+        repository,
+        keep_latest,
+        max_age,
+        tag_pattern,
+        exempt,
+        enforce: form.enforce.is_some(),
+    })
+}
+
+#[async_backtrace::framed]
+pub async fn create(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<CreatePolicy>,
+) -> impl IntoResponse {
+    let policy = match parse(form) {
+        Ok(policy) => policy,
+        Err(e) => {
+            let mut context = Context::new();
+            context.insert("error", &e);
+            return render(&tera, context, StatusCode::BAD_REQUEST).await;
+        }
+    };
+
+    db::retention::save(&policy).await.unwrap();
+    tracing::info!("created retention policy for {}", policy.repository);
+    events::audit(
+        &request,
+        "retention.create",
+        None,
+        Some(&policy.repository),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn delete(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<DeletePolicy>,
+) -> impl IntoResponse {
+    db::retention::delete(form.id).await.unwrap();
+    events::audit(
+        &request,
+        "retention.delete",
+        None,
+        Some(&form.id.to_string()),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn apply(Extension(tera): Extension<Tera>, request: RequestInfo) -> impl IntoResponse {
+    let deletions = retention::apply().await.unwrap();
+    events::audit(&request, "retention.apply", None, None, None).await;
+
+    let mut context = Context::new();
+    context.insert("applied", &true);
+    context.insert("deletions", &deletions);
+    render(&tera, context, StatusCode::OK).await
+}
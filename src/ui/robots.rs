@@ -0,0 +1,155 @@
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Form, Json,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use tera::{Context, Tera};
+
+use crate::auth::{basic, robots};
+use crate::db::{self, Robot};
+use crate::events::{self, RequestInfo};
+use crate::retention;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRobot {
+    name: String,
+    permissions: String,
+    #[serde(default)]
+    expires: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RobotName {
+    name: String,
+}
+
+async fn render(tera: &Tera, mut context: Context, status: StatusCode) -> impl IntoResponse {
+    context.insert("robots", &db::robots::list().await.unwrap());
+
+    (
+        status,
+        [("Content-Type", "text/html; charset=utf-8")],
+        tera.render("robots.html", &context).unwrap(),
+    )
+}
+
+#[async_backtrace::framed]
+pub async fn index(Extension(tera): Extension<Tera>) -> impl IntoResponse {
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+/// Validate a new robot, returning it along with its token
+fn parse(form: CreateRobot) -> Result<(Robot, String), String> {
+    let name = form.name.trim();
+    if name.is_empty() || name.contains(':') || name.contains(char::is_whitespace) {
+        return Err("a name without colons or spaces is required".to_string());
+    }
+    let permissions = robots::parse_permissions(&form.permissions)?;
+    if permissions.is_empty() {
+        return Err("a robot needs at least one permission".to_string());
+    }
+    let expires = form
+        .expires
+        .as_deref()
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|e| {
+            retention::parse_duration(e)
+                .map(|seconds| Utc::now() + Duration::seconds(seconds))
+                .ok_or_else(|| format!("invalid expiry {}, use a duration like 90d", e))
+        })
+        .transpose()?;
+
+    let token = robots::generate();
+    let robot = Robot {
+        name: name.to_string(),
+        token: robots::hash(&token),
+        permissions: form
+            .permissions
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+        expires,
+        last_used: None,
+        created: Utc::now(),
+    };
+    Ok((robot, token))
+}
+
+/// Create or replace a robot. The token is only ever shown here.
+#[async_backtrace::framed]
+pub async fn create(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<CreateRobot>,
+) -> impl IntoResponse {
+    let mut context = Context::new();
+    let (robot, token) = match parse(form) {
+        Ok(robot) => robot,
+        Err(e) => {
+            context.insert("error", &e);
+            return render(&tera, context, StatusCode::BAD_REQUEST).await;
+        }
+    };
+
+    db::robots::save(&robot).await.unwrap();
+    basic::forget();
+    tracing::info!("saved robot {}", robot.name);
+    events::audit(&request, "robot.save", None, Some(&robot.name), None).await;
+
+    context.insert("created", &robot);
+    context.insert("token", &token);
+    render(&tera, context, StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn delete(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<RobotName>,
+) -> impl IntoResponse {
+    db::robots::delete(&form.name).await.unwrap();
+    basic::forget();
+    events::audit(&request, "robot.delete", None, Some(&form.name), None).await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+/// List robots as JSON
+#[async_backtrace::framed]
+pub async fn list() -> impl IntoResponse {
+    Json(json!({ "robots": db::robots::list().await.unwrap() }))
+}
+
+/// Create or replace a robot from JSON, answering with the robot and its token
+#[async_backtrace::framed]
+pub async fn create_json(request: RequestInfo, Json(body): Json<CreateRobot>) -> Response {
+    let (robot, token) = match parse(body) {
+        Ok(robot) => robot,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response(),
+    };
+
+    db::robots::save(&robot).await.unwrap();
+    basic::forget();
+    tracing::info!("saved robot {}", robot.name);
+    events::audit(&request, "robot.save", None, Some(&robot.name), None).await;
+
+    (
+        StatusCode::CREATED,
+        Json(json!({ "robot": robot, "token": token })),
+    )
+        .into_response()
+}
+
+#[async_backtrace::framed]
+pub async fn delete_json(request: RequestInfo, Path(name): Path<String>) -> impl IntoResponse {
+    db::robots::delete(&name).await.unwrap();
+    basic::forget();
+    events::audit(&request, "robot.delete", None, Some(&name), None).await;
+
+    StatusCode::NO_CONTENT
+}
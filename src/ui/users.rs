@@ -0,0 +1,79 @@
+use axum::{http::StatusCode, response::IntoResponse, Extension, Form};
+use serde::Deserialize;
+use tera::{Context, Tera};
+
+use crate::auth::{self, basic, robots};
+use crate::db;
+use crate::events::{self, RequestInfo};
+
+#[derive(Debug, Deserialize)]
+pub struct SaveUser {
+    name: String,
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteUser {
+    name: String,
+}
+
+async fn render(tera: &Tera, mut context: Context, status: StatusCode) -> impl IntoResponse {
+    context.insert("users", &db::users::list().await.unwrap());
+    context.insert("enabled", &(*auth::MODE != auth::Mode::None));
+
+    (
+        status,
+        [("Content-Type", "text/html; charset=utf-8")],
+        tera.render("users.html", &context).unwrap(),
+    )
+}
+
+#[async_backtrace::framed]
+pub async fn index(Extension(tera): Extension<Tera>) -> impl IntoResponse {
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+/// Create a user or change their password
+#[async_backtrace::framed]
+pub async fn save(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<SaveUser>,
+) -> impl IntoResponse {
+    let name = form.name.trim();
+    if name.starts_with(robots::PREFIX) {
+        let mut context = Context::new();
+        context.insert(
+            "error",
+            "names starting with robot$ are reserved for robots",
+        );
+        return render(&tera, context, StatusCode::BAD_REQUEST).await;
+    }
+    if name.is_empty() || name.contains(':') || form.password.is_empty() {
+        let mut context = Context::new();
+        context.insert("error", "a name without colons and a password are required");
+        return render(&tera, context, StatusCode::BAD_REQUEST).await;
+    }
+
+    db::users::save(name, &basic::hash(&form.password).await)
+        .await
+        .unwrap();
+    basic::forget();
+    tracing::info!("saved user {}", name);
+    events::audit(&request, "user.save", None, Some(name), None).await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn delete(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<DeleteUser>,
+) -> impl IntoResponse {
+    db::users::delete(&form.name).await.unwrap();
+    basic::forget();
+    events::audit(&request, "user.delete", None, Some(&form.name), None).await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
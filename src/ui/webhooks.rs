@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Form};
+use serde::{Deserialize, Serialize};
+use tera::{Context, Tera};
+
+use crate::db::{self, Webhook};
+use crate::events::{self, RequestInfo};
+
+#[derive(Debug, Serialize)]
+struct WebhookView {
+    #[serde(flatten)]
+    webhook: Webhook,
+    signed: bool,
+    pending: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhook {
+    url: String,
+    repository: String,
+    push: Option<String>,
+    pull: Option<String>,
+    delete: Option<String>,
+    secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteWebhook {
+    id: i64,
+}
+
+async fn render(tera: &Tera, mut context: Context, status: StatusCode) -> impl IntoResponse {
+    let pending = db::webhooks::pending()
+        .await
+        .unwrap()
+        .into_iter()
+        .collect::<HashMap<i64, i64>>();
+    let webhooks = db::webhooks::list()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|webhook| WebhookView {
+            signed: webhook.secret.is_some(),
+            pending: pending.get(&webhook.id).copied().unwrap_or_default(),
+            webhook,
+        })
+        .collect::<Vec<WebhookView>>();
+    context.insert("webhooks", &webhooks);
+
+    (
+        status,
+        [("Content-Type", "text/html; charset=utf-8")],
+        tera.render("webhooks.html", &context).unwrap(),
+    )
+}
+
+#[async_backtrace::framed]
+pub async fn index(Extension(tera): Extension<Tera>) -> impl IntoResponse {
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+fn parse(form: CreateWebhook) -> Result<Webhook, String> {
+    let url = form.url.trim();
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => {}
+        _ => return Err(format!("invalid url {}", url)),
+    }
+    let repository = Some(form.repository.trim())
+        .filter(|r| !r.is_empty())
+        .unwrap_or("**");
+    let actions = [
+        ("push", form.push.is_some()),
+        ("pull", form.pull.is_some()),
+        ("delete", form.delete.is_some()),
+    ]
+    .iter()
+    .filter(|(_, selected)| *selected)
+    .map(|(action, _)| *action)
+    .collect::<Vec<&str>>();
+    if actions.is_empty() {
+        return Err("a webhook needs at least one action".to_string());
+    }
+
+    Ok(Webhook {
+        id: 0,
+        url: url.to_string(),
+        repository: repository.to_string(),
+        actions: Some(actions.join(" ")).filter(|_| actions.len() < 3),
+        secret: Some(form.secret).filter(|s| !s.is_empty()),
+        last_delivered: None,
+        last_error: None,
+    })
+}
+
+#[async_backtrace::framed]
+pub async fn create(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<CreateWebhook>,
+) -> impl IntoResponse {
+    let webhook = match parse(form) {
+        Ok(webhook) => webhook,
+        Err(e) => {
+            let mut context = Context::new();
+            context.insert("error", &e);
+            return render(&tera, context, StatusCode::BAD_REQUEST).await;
+        }
+    };
+
+    db::webhooks::save(&webhook).await.unwrap();
+    tracing::info!("created webhook {}", webhook.url);
+    events::audit(&request, "webhook.create", None, Some(&webhook.url), None).await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
+
+#[async_backtrace::framed]
+pub async fn delete(
+    Extension(tera): Extension<Tera>,
+    request: RequestInfo,
+    Form(form): Form<DeleteWebhook>,
+) -> impl IntoResponse {
+    db::webhooks::delete(form.id).await.unwrap();
+    events::audit(
+        &request,
+        "webhook.delete",
+        None,
+        Some(&form.id.to_string()),
+        None,
+    )
+    .await;
+
+    render(&tera, Context::new(), StatusCode::OK).await
+}
@@ -0,0 +1,194 @@
+//! Webhook notifications for registry events.
+//!
+//! Every webhook whose repository glob and actions match an event gets the event posted to it in a
+//! docker/distribution notification envelope. Notifications go through a queue in the database, so they
+//! survive restarts, and failed deliveries are retried with exponential backoff. Webhooks with a secret
+//! get an `X-Pequod-Signature: sha256=<hex>` header containing the HMAC-SHA256 of the body.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use reqwest::header::CONTENT_TYPE;
+use sha2::Sha256;
+use tokio::sync::Notify;
+
+use crate::db::{self, Webhook, WebhookDelivery};
+use crate::events::{Envelope, Event, ENVELOPE_MEDIA_TYPE};
+use crate::{pattern, replication};
+
+/// Deliveries are dropped after failing this many times
+const MAX_ATTEMPTS: i64 = 10;
+
+/// How often the queue is checked for deliveries due to be retried
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a webhook gets to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header carrying the signature of a notification
+pub const SIGNATURE_HEADER: &str = "X-Pequod-Signature";
+
+lazy_static! {
+    static ref WAKE: Notify = Notify::new();
+}
+
+/// Whether a webhook wants to be notified about an event
+pub fn matches(webhook: &Webhook, event: &Event) -> bool {
+    pattern::matches(&webhook.repository, &event.target.repository)
+        && webhook.actions.as_ref().is_none_or(|actions| {
+            actions
+                .split_whitespace()
+                .any(|a| a == event.action.as_str())
+        })
+}
+
+/// Sign a notification body with a webhook's secret
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    let signature = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    format!("sha256={}", signature)
+}
+
+/// Queue an event for every webhook interested in it
+///
+/// Failing to queue is logged rather than returned, so it never fails the request causing the event.
+#[async_backtrace::framed]
+pub async fn enqueue(event: &Event) {
+    let webhooks = match db::webhooks::list().await {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            tracing::error!("failed to load webhooks: {}", e);
+            return;
+        }
+    };
+
+    let envelope = Envelope {
+        events: vec![event.clone()],
+    };
+    let payload = serde_json::to_string(&envelope).unwrap();
+
+    let mut queued = false;
+    for webhook in webhooks.iter().filter(|w| matches(w, event)) {
+        match db::webhooks::enqueue(webhook.id, &payload).await {
+            Ok(()) => queued = true,
+            Err(e) => tracing::error!("failed to queue notification for {}: {}", webhook.url, e),
+        }
+    }
+
+    if queued {
+        WAKE.notify_one();
+    }
+}
+
+async fn deliver(
+    http: &reqwest::Client,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+) -> Result<(), String> {
+    let mut request = http
+        .post(&webhook.url)
+        .timeout(TIMEOUT)
+        .header(CONTENT_TYPE, ENVELOPE_MEDIA_TYPE)
+        .body(delivery.payload.clone());
+    if let Some(secret) = &webhook.secret {
+        request = request.header(SIGNATURE_HEADER, sign(secret, &delivery.payload));
+    }
+
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(format!("unexpected status {} from {}", status, webhook.url)),
+    }
+}
+
+/// Attempt all deliveries that are due, returning how many succeeded
+async fn run(http: &reqwest::Client) -> Result<usize, db::Error> {
+    let webhooks = db::webhooks::list()
+        .await?
+        .into_iter()
+        .map(|w| (w.id, w))
+        .collect::<HashMap<_, _>>();
+
+    let mut delivered = 0;
+    for delivery in db::webhooks::due().await? {
+        let webhook = match webhooks.get(&delivery.webhook) {
+            Some(webhook) => webhook,
+            None => {
+                db::webhooks::complete(delivery.id).await?;
+                continue;
+            }
+        };
+
+        match deliver(http, webhook, &delivery).await {
+            Ok(()) => {
+                db::webhooks::complete(delivery.id).await?;
+                db::webhooks::set_status(webhook.id, None).await?;
+                delivered += 1;
+            }
+            Err(error) => {
+                if delivery.attempts + 1 >= MAX_ATTEMPTS {
+                    tracing::error!(
+                        "giving up notifying {} after {} attempts: {}",
+                        webhook.url,
+                        MAX_ATTEMPTS,
+                        error
+                    );
+                    db::webhooks::complete(delivery.id).await?;
+                } else {
+                    let next_attempt = Utc::now() + replication::backoff(delivery.attempts);
+                    tracing::warn!(
+                        "notifying {} failed, retrying at {}: {}",
+                        webhook.url,
+                        next_attempt,
+                        error
+                    );
+                    db::webhooks::retry(delivery.id, &error, next_attempt).await?;
+                }
+                db::webhooks::set_status(webhook.id, Some(&error)).await?;
+            }
+        }
+    }
+
+    Ok(delivered)
+}
+
+/// Work through the notification queue, running as soon as notifications are queued
+#[async_backtrace::framed]
+pub async fn worker() {
+    let http = reqwest::Client::new();
+    loop {
+        match run(&http).await {
+            Ok(0) => {}
+            Ok(delivered) => tracing::debug!("delivered {} notifications", delivered),
+            Err(e) => tracing::error!("webhook run failed: {}", e),
+        }
+
+        tokio::select! {
+            _ = WAKE.notified() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::sign;
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("secret", r#"{"events":[]}"#),
+            "sha256=a642b59553c93e227ec0f2f38910fbf71231a2197c00899833c00478cec86f34"
+        );
+    }
+}